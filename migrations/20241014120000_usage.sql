@@ -0,0 +1,14 @@
+CREATE TABLE usage
+(
+    id                integer primary key autoincrement,
+    client_id         text    not null,
+    model             text    not null,
+    task              text    not null,
+    success           integer not null,
+    prompt_tokens     integer not null,
+    completion_tokens integer not null,
+    duration_ms       integer not null,
+    created_at        integer not null
+);
+
+CREATE INDEX usage_created_at ON usage (created_at);
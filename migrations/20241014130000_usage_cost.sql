@@ -0,0 +1,4 @@
+ALTER TABLE usage
+    ADD COLUMN audio_seconds real not null default 0;
+ALTER TABLE usage
+    ADD COLUMN cost real not null default 0;
@@ -0,0 +1,19 @@
+CREATE TABLE documents
+(
+    id           text    primary key not null,
+    client_id    text    not null,
+    name         text    not null,
+    content_type text    not null,
+    length       integer not null,
+    created_at   integer not null
+);
+
+CREATE TABLE document_chunks
+(
+    document_id text    not null references documents (id) on delete cascade,
+    position    integer not null,
+    content     text    not null,
+    primary key (document_id, position)
+);
+
+CREATE INDEX documents_client_id ON documents (client_id);
@@ -0,0 +1,21 @@
+CREATE TABLE sessions
+(
+    id         text    primary key not null,
+    client_id  text    not null,
+    model      text    not null,
+    max_length integer not null,
+    created_at integer not null,
+    updated_at integer not null
+);
+
+CREATE TABLE session_messages
+(
+    session_id text    not null references sessions (id) on delete cascade,
+    position   integer not null,
+    role       text    not null,
+    content    text    not null,
+    created_at integer not null,
+    primary key (session_id, position)
+);
+
+CREATE INDEX sessions_client_id ON sessions (client_id);
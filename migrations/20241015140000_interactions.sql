@@ -0,0 +1,20 @@
+CREATE TABLE interactions
+(
+    id         text    primary key not null,
+    client_id  text    not null,
+    model      text    not null,
+    task       text    not null,
+    input      text    not null,
+    output     text    not null,
+    created_at integer not null
+);
+
+CREATE TABLE feedback
+(
+    interaction_id text    primary key not null references interactions (id) on delete cascade,
+    rating         integer not null,
+    comment        text,
+    created_at     integer not null
+);
+
+CREATE INDEX interactions_created_at ON interactions (created_at);
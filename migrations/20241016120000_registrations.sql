@@ -0,0 +1,16 @@
+CREATE TABLE registrations
+(
+    id          text    primary key not null,
+    key         text    not null,
+    name        text    not null,
+    contact     text,
+    reason      text,
+    permissions integer not null,
+    status      text    not null,
+    client_id   text,
+    created_at  integer not null,
+    decided_at  integer,
+    decided_by  text
+);
+
+CREATE INDEX registrations_status ON registrations (status);
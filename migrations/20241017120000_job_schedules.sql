@@ -0,0 +1,23 @@
+CREATE TABLE job_schedules
+(
+    id          text    primary key not null,
+    name        text    not null,
+    cron        text    not null,
+    task        text    not null,
+    created_by  text    not null,
+    created_at  integer not null,
+    last_run_at integer
+);
+
+CREATE TABLE job_runs
+(
+    id          text    primary key not null,
+    schedule_id text    not null references job_schedules (id) on delete cascade,
+    status      text    not null,
+    output      text,
+    error       text,
+    started_at  integer not null,
+    finished_at integer
+);
+
+CREATE INDEX job_runs_schedule_id ON job_runs (schedule_id, started_at);
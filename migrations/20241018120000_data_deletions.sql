@@ -0,0 +1,13 @@
+CREATE TABLE data_deletions
+(
+    id               text    primary key not null,
+    client_id        text    not null,
+    requested_by     text    not null,
+    interactions     integer not null,
+    sessions         integer not null,
+    documents        integer not null,
+    usage_anonymized integer not null,
+    deleted_at       integer not null
+);
+
+CREATE INDEX data_deletions_client_id ON data_deletions (client_id);
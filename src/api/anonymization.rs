@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Salt of the hashes client ids are replaced with, ids are stored as they are if unset
+static SALT: OnceLock<String> = OnceLock::new();
+
+#[tracing::instrument(level = "info", skip(salt))]
+pub(crate) fn configure_anonymization(salt: String) {
+    if SALT.set(salt).is_err() {
+        warn!("Anonymization is already configured");
+    }
+}
+
+pub(crate) fn anonymization_enabled() -> bool {
+    SALT.get().is_some()
+}
+
+/// The id the client is recorded under in usage records, logged interactions and logs. With
+/// anonymization enabled this is a salted hash, which stays the same for the client so that
+/// aggregates per client remain meaningful.
+pub(crate) fn recorded_client_id(id: &str) -> Cow<'_, str> {
+    let Some(salt) = SALT.get() else {
+        return Cow::Borrowed(id);
+    };
+    let digest = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update([0])
+        .chain_update(id.as_bytes())
+        .finalize();
+    // Half of the digest is plenty to tell clients apart
+    Cow::Owned(
+        digest[..16]
+            .iter()
+            .fold(String::with_capacity(32), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            }),
+    )
+}
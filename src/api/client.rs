@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::fmt::{Debug, Display};
 use std::str::FromStr;
 use std::time::SystemTime;
 
@@ -10,10 +10,11 @@ use password_hash::{PasswordHash, PasswordVerifier, SaltString};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 
+use crate::api::anonymization::{anonymization_enabled, recorded_client_id};
 use crate::api::auth::{Auth, AuthToken};
 
 #[allow(dead_code)]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone)]
 pub struct ApiClient {
     pub name: Option<String>,
     pub token: AuthToken,
@@ -46,6 +47,28 @@ pub(crate) struct ApiClientUpdateRequest {
     pub(crate) permissions: Vec<Permission>,
 }
 
+/// A client as stored in the database, carrying the hash of its key but never the key itself
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ClientRecord {
+    pub(crate) id: String,
+    pub(crate) name: Option<String>,
+    /// The argon2 hash of the token key in PHC string format
+    pub(crate) key_hash: String,
+    pub(crate) permissions: Permission,
+    pub(crate) created_at: i64,
+    pub(crate) updated_at: i64,
+    pub(crate) created_by: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct ImportSummary {
+    pub(crate) imported: usize,
+    /// Clients whose id already existed and that were left untouched
+    pub(crate) skipped: usize,
+}
+
 bitflags! {
     // i64 is used to store the bitflags in the sqlite db
     #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -61,6 +84,7 @@ bitflags! {
         const DELETE_OTHER    = 1 << 7;
         const UPDATE_SELF     = 1 << 8;
         const UPDATE_OTHER    = 1 << 9;
+        const ADMIN           = 1 << 10;
     }
 }
 
@@ -96,6 +120,27 @@ impl Display for ApiClient {
     }
 }
 
+/// Clients are part of most traces, with anonymization enabled they only show their recorded id
+impl Debug for ApiClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if anonymization_enabled() {
+            return f
+                .debug_struct("ApiClient")
+                .field("id", &recorded_client_id(&self.token.id))
+                .field("permissions", &self.permissions)
+                .finish_non_exhaustive();
+        }
+        f.debug_struct("ApiClient")
+            .field("name", &self.name)
+            .field("token", &self.token)
+            .field("permissions", &self.permissions)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .field("created_by", &self.created_by)
+            .finish()
+    }
+}
+
 impl ApiClient {
     #[tracing::instrument(level = "info", skip(auth, pool))]
     pub async fn new(
@@ -141,6 +186,64 @@ impl ApiClient {
         })
     }
 
+    /// Creates the client with the given fixed token or updates it if it already exists. Fails if
+    /// a client with the id exists that was not created by `creator_id`.
+    #[tracing::instrument(level = "info", skip(key_hash, pool))]
+    pub(crate) async fn upsert_with_token(
+        id: &str,
+        key_hash: &PasswordHash<'_>,
+        name: &str,
+        permission: &Permission,
+        creator_id: &str,
+        pool: &SqlitePool,
+    ) -> Result<()> {
+        let existing = sqlx::query!("SELECT created_by FROM client WHERE id = ?", id)
+            .fetch_optional(pool)
+            .await?;
+        if existing.is_some_and(|existing| existing.created_by.as_deref() != Some(creator_id)) {
+            bail!("Client {id} already exists and was not created by {creator_id}");
+        }
+
+        let key_hash = key_hash.to_string();
+        let unix_now: i64 = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?;
+        let permission_bits = permission.bits();
+        sqlx::query!(
+            "INSERT INTO client (id, name, key, permissions, created_at, updated_at, created_by) VALUES (?, ?, ?, ?, ?, ?, ?) \
+            ON CONFLICT (id) DO UPDATE SET name = excluded.name, key = excluded.key, permissions = excluded.permissions, updated_at = excluded.updated_at \
+            WHERE created_by IS excluded.created_by AND (name IS NOT excluded.name OR key != excluded.key OR permissions != excluded.permissions)",
+            id,
+            name,
+            key_hash,
+            permission_bits,
+            unix_now,
+            unix_now,
+            creator_id
+        )
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the id of the client with the given name that was created by `creator_id`
+    #[tracing::instrument(level = "info", skip(pool))]
+    pub(crate) async fn find_id_by_name(
+        name: &str,
+        creator_id: &str,
+        pool: &SqlitePool,
+    ) -> Result<Option<String>> {
+        let record = sqlx::query!(
+            "SELECT id FROM client WHERE name = ? AND created_by = ?",
+            name,
+            creator_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(record.map(|record| record.id))
+    }
+
     #[tracing::instrument(level = "info", skip(pool))]
     pub(crate) async fn with_id(id: &str, pool: &SqlitePool) -> Result<Self> {
         let client_record = sqlx::query!(
@@ -239,3 +342,83 @@ impl ApiClient {
         Ok(())
     }
 }
+
+/// Returns all clients with their key hashes, oldest first
+#[allow(dead_code)]
+#[tracing::instrument(level = "info", skip(pool))]
+pub(crate) async fn export_clients(pool: &SqlitePool) -> Result<Vec<ClientRecord>> {
+    sqlx::query!(
+        "SELECT id, name, key, permissions, created_at, updated_at, created_by FROM client ORDER BY created_at"
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| {
+        Ok(ClientRecord {
+            id: record.id,
+            name: record.name,
+            key_hash: record.key,
+            permissions: Permission::from_bits(record.permissions)
+                .ok_or_else(|| anyhow!("Permission not found"))?,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+            created_by: record.created_by,
+        })
+    })
+    .collect()
+}
+
+/// Inserts the exported clients in a single transaction, replacing clients with the same id only
+/// if `overwrite` is set
+#[allow(dead_code)]
+#[tracing::instrument(level = "info", skip(records, pool))]
+pub(crate) async fn import_clients(
+    records: &[ClientRecord],
+    overwrite: bool,
+    pool: &SqlitePool,
+) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    let mut transaction = pool.begin().await?;
+    for record in records {
+        PasswordHash::new(&record.key_hash)
+            .map_err(|e| anyhow!("Invalid key hash of client {}: {}", record.id, e))?;
+        let permission_bits = record.permissions.bits();
+        let result = if overwrite {
+            sqlx::query!(
+                "INSERT INTO client (id, name, key, permissions, created_at, updated_at, created_by) VALUES (?, ?, ?, ?, ?, ?, ?) \
+                ON CONFLICT (id) DO UPDATE SET name = excluded.name, key = excluded.key, permissions = excluded.permissions, \
+                created_at = excluded.created_at, updated_at = excluded.updated_at, created_by = excluded.created_by",
+                record.id,
+                record.name,
+                record.key_hash,
+                permission_bits,
+                record.created_at,
+                record.updated_at,
+                record.created_by
+            )
+            .execute(&mut *transaction)
+            .await?
+        } else {
+            sqlx::query!(
+                "INSERT INTO client (id, name, key, permissions, created_at, updated_at, created_by) VALUES (?, ?, ?, ?, ?, ?, ?) \
+                ON CONFLICT (id) DO NOTHING",
+                record.id,
+                record.name,
+                record.key_hash,
+                permission_bits,
+                record.created_at,
+                record.updated_at,
+                record.created_by
+            )
+            .execute(&mut *transaction)
+            .await?
+        };
+        if result.rows_affected() > 0 {
+            summary.imported += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+    transaction.commit().await?;
+    Ok(summary)
+}
@@ -0,0 +1,21 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+/// Returns the current time in seconds since the Unix epoch, as stored in the database
+#[tracing::instrument(level = "trace")]
+pub fn unix_now() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+#[tracing::instrument(level = "trace")]
+pub fn unix_now_millis() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis()
+        .try_into()?)
+}
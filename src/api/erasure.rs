@@ -0,0 +1,109 @@
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::api::anonymization::recorded_client_id;
+use crate::api::clock::unix_now;
+
+/// Client id the usage records of clients whose data was deleted are attributed to
+pub(crate) const DELETED_CLIENT_ID: &str = "deleted";
+
+/// Audit record of the deletion of the data stored for a client
+#[derive(Serialize, Debug)]
+pub(crate) struct DataDeletion {
+    pub(crate) id: String,
+    pub(crate) client_id: String,
+    /// The client that requested the deletion
+    pub(crate) requested_by: String,
+    /// Number of logged interactions removed together with their feedback
+    pub(crate) interactions: i64,
+    pub(crate) sessions: i64,
+    pub(crate) documents: i64,
+    /// Number of usage records kept for statistics but no longer attributed to the client
+    pub(crate) usage_anonymized: i64,
+    pub(crate) deleted_at: i64,
+}
+
+/// Removes the prompts, outputs, sessions and documents stored for the client and detaches its
+/// usage records from it so that aggregate statistics stay intact. Also works for clients that
+/// were already deleted. Records made before anonymization was enabled carry the plain id.
+#[tracing::instrument(level = "info", skip(client_id, requested_by, pool))]
+pub(crate) async fn delete_client_data(
+    client_id: &str,
+    requested_by: &str,
+    pool: &SqlitePool,
+) -> Result<DataDeletion> {
+    let recorded_id = recorded_client_id(client_id);
+    let mut transaction = pool.begin().await?;
+    let interactions = sqlx::query!(
+        "DELETE FROM interactions WHERE client_id IN (?, ?)",
+        client_id,
+        recorded_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    let sessions = sqlx::query!("DELETE FROM sessions WHERE client_id = ?", client_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    let documents = sqlx::query!("DELETE FROM documents WHERE client_id = ?", client_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    let usage_anonymized = sqlx::query!(
+        "UPDATE usage SET client_id = ? WHERE client_id IN (?, ?)",
+        DELETED_CLIENT_ID,
+        client_id,
+        recorded_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    // The contact details given when registering are personal data as well
+    sqlx::query!(
+        "UPDATE registrations SET contact = NULL, reason = NULL WHERE client_id = ?",
+        client_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let deletion = DataDeletion {
+        id: format!("{:032x}", rand::random::<u128>()),
+        client_id: recorded_id.into(),
+        requested_by: recorded_client_id(requested_by).into(),
+        interactions: interactions.try_into()?,
+        sessions: sessions.try_into()?,
+        documents: documents.try_into()?,
+        usage_anonymized: usage_anonymized.try_into()?,
+        deleted_at: unix_now()?,
+    };
+    sqlx::query!(
+        "INSERT INTO data_deletions (id, client_id, requested_by, interactions, sessions, documents, usage_anonymized, deleted_at) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        deletion.id,
+        deletion.client_id,
+        deletion.requested_by,
+        deletion.interactions,
+        deletion.sessions,
+        deletion.documents,
+        deletion.usage_anonymized,
+        deletion.deleted_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(deletion)
+}
+
+/// Lists the audit trail of data deletions, newest first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn list_data_deletions(pool: &SqlitePool) -> Result<Vec<DataDeletion>> {
+    Ok(sqlx::query_as!(
+        DataDeletion,
+        "SELECT id, client_id, requested_by, interactions, sessions, documents, usage_anonymized, deleted_at \
+        FROM data_deletions ORDER BY deleted_at DESC"
+    )
+    .fetch_all(pool)
+    .await?)
+}
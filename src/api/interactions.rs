@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::api::clock::unix_now;
+
+/// Whether prompts and outputs of text generations are stored, required for feedback
+static LOG_INTERACTIONS: AtomicBool = AtomicBool::new(false);
+
+#[tracing::instrument(level = "info")]
+pub(crate) fn configure_interaction_log(enabled: bool) {
+    LOG_INTERACTIONS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn interaction_log_enabled() -> bool {
+    LOG_INTERACTIONS.load(Ordering::Relaxed)
+}
+
+/// Stores a finished text generation, returning the id clients attach feedback to
+#[tracing::instrument(level = "trace", skip(input, output, pool))]
+pub(crate) async fn insert_interaction(
+    client_id: &str,
+    model: &str,
+    task: &str,
+    input: &str,
+    output: &str,
+    pool: &SqlitePool,
+) -> Result<String> {
+    let id = format!("{:032x}", rand::random::<u128>());
+    let created_at = unix_now()?;
+    sqlx::query!(
+        "INSERT INTO interactions (id, client_id, model, task, input, output, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        id,
+        client_id,
+        model,
+        task,
+        input,
+        output,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(id)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Rating {
+    Positive,
+    Negative,
+}
+
+impl Rating {
+    pub(crate) const fn score(self) -> i64 {
+        match self {
+            Self::Positive => 1,
+            Self::Negative => -1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedbackRequest {
+    /// The id of the response the feedback is about
+    pub(crate) response_id: String,
+    pub(crate) rating: Rating,
+    pub(crate) comment: Option<String>,
+}
+
+/// Stores feedback on an interaction of the client, replacing earlier feedback.
+/// Returns false if the client has no such interaction.
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn insert_feedback(
+    client_id: &str,
+    feedback: &FeedbackRequest,
+    pool: &SqlitePool,
+) -> Result<bool> {
+    let rating = feedback.rating.score();
+    let created_at = unix_now()?;
+    let result = sqlx::query!(
+        "INSERT INTO feedback (interaction_id, rating, comment, created_at) \
+        SELECT id, ?, ?, ? FROM interactions WHERE id = ? AND client_id = ? \
+        ON CONFLICT (interaction_id) DO UPDATE SET rating = excluded.rating, comment = excluded.comment, created_at = excluded.created_at",
+        rating,
+        feedback.comment,
+        created_at,
+        feedback.response_id,
+        client_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// A logged interaction together with the feedback it received, exported by the CLI
+#[allow(dead_code)]
+#[derive(Serialize, Debug)]
+pub(crate) struct LoggedInteraction {
+    pub(crate) id: String,
+    pub(crate) model: String,
+    pub(crate) task: String,
+    pub(crate) input: String,
+    pub(crate) output: String,
+    /// 1 for positive and -1 for negative feedback
+    pub(crate) rating: Option<i64>,
+    pub(crate) comment: Option<String>,
+    pub(crate) created_at: i64,
+}
+
+/// Returns all logged interactions with their feedback, oldest first
+#[allow(dead_code)]
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn logged_interactions(pool: &SqlitePool) -> Result<Vec<LoggedInteraction>> {
+    Ok(sqlx::query_as!(
+        LoggedInteraction,
+        r#"SELECT interactions.id, model, task, input, output, rating AS "rating?", comment, interactions.created_at
+        FROM interactions LEFT JOIN feedback ON feedback.interaction_id = interactions.id
+        ORDER BY interactions.created_at"#,
+    )
+    .fetch_all(pool)
+    .await?)
+}
@@ -1,2 +1,9 @@
+pub mod anonymization;
 pub mod auth;
 pub mod client;
+pub mod clock;
+pub mod erasure;
+pub mod interactions;
+pub mod registration;
+pub mod retention;
+pub mod usage;
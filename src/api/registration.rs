@@ -0,0 +1,329 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use password_hash::rand_core::OsRng;
+use password_hash::{PasswordHash, PasswordVerifier, SaltString};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::api::auth::{Auth, AuthToken};
+use crate::api::client::{ApiClient, Permission};
+use crate::api::clock::unix_now_millis;
+
+/// Creator id of the clients issued through self-registration
+pub(crate) const REGISTRATION_CREATOR_ID: &str = "registration";
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct RegistrationRequest {
+    pub(crate) name: String,
+    /// How an admin can reach the requester, e.g. an email address or team channel
+    #[serde(default)]
+    pub(crate) contact: Option<String>,
+    #[serde(default)]
+    pub(crate) reason: Option<String>,
+    pub(crate) permissions: Vec<Permission>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct RegistrationClaimRequest {
+    /// The registration token returned when registering
+    pub(crate) token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct RegistrationDecisionRequest {
+    pub(crate) id: String,
+    /// Permissions granted instead of the requested ones when approving
+    #[serde(default)]
+    pub(crate) permissions: Option<Vec<Permission>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct RegistrationListQuery {
+    #[serde(default)]
+    pub(crate) status: Option<RegistrationStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RegistrationStatus {
+    Pending,
+    Approved,
+    Denied,
+    /// Approved and the client was handed out to the requester
+    Issued,
+}
+
+impl RegistrationStatus {
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Denied => "denied",
+            Self::Issued => "issued",
+        }
+    }
+}
+
+impl FromStr for RegistrationStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "pending" => Self::Pending,
+            "approved" => Self::Approved,
+            "denied" => Self::Denied,
+            "issued" => Self::Issued,
+            _ => bail!("Invalid registration status {s}"),
+        })
+    }
+}
+
+impl Display for RegistrationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Registration {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) contact: Option<String>,
+    pub(crate) reason: Option<String>,
+    pub(crate) permissions: Permission,
+    pub(crate) status: RegistrationStatus,
+    /// The id of the issued client
+    pub(crate) client_id: Option<String>,
+    pub(crate) created_at: i64,
+    pub(crate) decided_at: Option<i64>,
+    pub(crate) decided_by: Option<String>,
+}
+
+/// Returned once when registering, the token is needed to claim the client after approval
+#[derive(Serialize, Debug)]
+pub(crate) struct RegistrationTicket {
+    pub(crate) id: String,
+    pub(crate) token: String,
+    pub(crate) status: RegistrationStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct RegistrationClaim {
+    pub(crate) status: RegistrationStatus,
+    /// The issued client with its token, only returned by the first claim after approval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) client: Option<ApiClient>,
+}
+
+struct RegistrationRecord {
+    id: String,
+    name: String,
+    contact: Option<String>,
+    reason: Option<String>,
+    permissions: i64,
+    status: String,
+    client_id: Option<String>,
+    created_at: i64,
+    decided_at: Option<i64>,
+    decided_by: Option<String>,
+}
+
+impl TryFrom<RegistrationRecord> for Registration {
+    type Error = anyhow::Error;
+
+    fn try_from(record: RegistrationRecord) -> Result<Self> {
+        Ok(Self {
+            id: record.id,
+            name: record.name,
+            contact: record.contact,
+            reason: record.reason,
+            permissions: Permission::from_bits(record.permissions)
+                .ok_or_else(|| anyhow!("Permission not found"))?,
+            status: record.status.parse()?,
+            client_id: record.client_id,
+            created_at: record.created_at,
+            decided_at: record.decided_at,
+            decided_by: record.decided_by,
+        })
+    }
+}
+
+/// Queues a registration for approval
+#[tracing::instrument(level = "info", skip(auth, pool))]
+pub(crate) async fn create_registration(
+    auth: &Auth,
+    request: &RegistrationRequest,
+    pool: &SqlitePool,
+) -> Result<RegistrationTicket> {
+    let salt = SaltString::generate(&mut OsRng);
+    let token = AuthToken::new(&auth.argon, &salt)?;
+    let key_hash = token
+        .key_hash
+        .as_ref()
+        .ok_or_else(|| anyhow!("Hash not found"))?
+        .to_string();
+    let permissions = request
+        .permissions
+        .iter()
+        .cloned()
+        .collect::<Permission>()
+        .bits();
+    let status = RegistrationStatus::Pending.as_str();
+    let now = unix_now_millis()?;
+    sqlx::query!(
+        "INSERT INTO registrations (id, key, name, contact, reason, permissions, status, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        token.id,
+        key_hash,
+        request.name,
+        request.contact,
+        request.reason,
+        permissions,
+        status,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(RegistrationTicket {
+        id: token.id.clone(),
+        token: token.to_string(),
+        status: RegistrationStatus::Pending,
+    })
+}
+
+/// Lists the registrations with the status, oldest first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn list_registrations(
+    status: Option<RegistrationStatus>,
+    pool: &SqlitePool,
+) -> Result<Vec<Registration>> {
+    let status = status.map(RegistrationStatus::as_str);
+    sqlx::query_as!(
+        RegistrationRecord,
+        "SELECT id, name, contact, reason, permissions, status, client_id, created_at, decided_at, decided_by \
+        FROM registrations WHERE ?1 IS NULL OR status = ?1 ORDER BY created_at",
+        status
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(Registration::try_from)
+    .collect()
+}
+
+/// Approves or denies a pending registration, returning whether it was pending
+#[tracing::instrument(level = "info", skip(pool))]
+pub(crate) async fn decide_registration(
+    id: &str,
+    approve: bool,
+    permissions: Option<&Permission>,
+    decided_by: &str,
+    pool: &SqlitePool,
+) -> Result<bool> {
+    let status = if approve {
+        RegistrationStatus::Approved
+    } else {
+        RegistrationStatus::Denied
+    }
+    .as_str();
+    let pending = RegistrationStatus::Pending.as_str();
+    let permissions = permissions.map(Permission::bits);
+    let now = unix_now_millis()?;
+    let result = sqlx::query!(
+        "UPDATE registrations SET status = ?, permissions = COALESCE(?, permissions), decided_at = ?, decided_by = ? \
+        WHERE id = ? AND status = ?",
+        status,
+        permissions,
+        now,
+        decided_by,
+        id,
+        pending
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Reports the status of the registration and issues its client on the first claim after approval
+#[tracing::instrument(level = "info", skip(auth, token, pool))]
+pub(crate) async fn claim_registration(
+    auth: &Auth,
+    token: &AuthToken,
+    pool: &SqlitePool,
+) -> Result<RegistrationClaim> {
+    let record = sqlx::query!(
+        "SELECT key, name, permissions, status FROM registrations WHERE id = ?",
+        token.id
+    )
+    .fetch_one(pool)
+    .await?;
+    let key_hash = PasswordHash::new(&record.key).map_err(|e| anyhow!(e))?;
+    let key = token
+        .key_raw
+        .as_ref()
+        .ok_or_else(|| anyhow!("Token key not found"))?;
+    auth.argon
+        .verify_password(key.as_bytes(), &key_hash)
+        .map_err(|e| anyhow!(e))?;
+
+    let status: RegistrationStatus = record.status.parse()?;
+    if status != RegistrationStatus::Approved {
+        return Ok(RegistrationClaim {
+            status,
+            client: None,
+        });
+    }
+
+    // Marking the registration first makes sure concurrent claims issue a single client
+    let approved = RegistrationStatus::Approved.as_str();
+    let issued = RegistrationStatus::Issued.as_str();
+    let result = sqlx::query!(
+        "UPDATE registrations SET status = ? WHERE id = ? AND status = ?",
+        issued,
+        token.id,
+        approved
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(RegistrationClaim {
+            status: RegistrationStatus::Issued,
+            client: None,
+        });
+    }
+    let permissions =
+        Permission::from_bits(record.permissions).ok_or_else(|| anyhow!("Permission not found"))?;
+    let client = match ApiClient::new(
+        auth,
+        &record.name,
+        &permissions,
+        &Some(REGISTRATION_CREATOR_ID.into()),
+        pool,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            sqlx::query!(
+                "UPDATE registrations SET status = ? WHERE id = ?",
+                approved,
+                token.id
+            )
+            .execute(pool)
+            .await?;
+            return Err(e);
+        }
+    };
+    sqlx::query!(
+        "UPDATE registrations SET client_id = ? WHERE id = ?",
+        client.token.id,
+        token.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(RegistrationClaim {
+        status: RegistrationStatus::Issued,
+        client: Some(client),
+    })
+}
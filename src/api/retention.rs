@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::{error, info};
+
+use crate::api::clock::unix_now;
+
+/// How often the background purger removes expired records
+const PURGE_INTERVAL: Duration = Duration::from_hours(1);
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Number of days records are kept, records of a kind without a period are kept forever
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub(crate) struct RetentionPolicy {
+    /// Finished runs of scheduled jobs with their outputs
+    #[serde(default, alias = "job-runs")]
+    pub(crate) job_runs: Option<u32>,
+    /// Logged prompts and outputs together with their feedback
+    #[serde(default)]
+    pub(crate) interactions: Option<u32>,
+    /// Usage records of single requests, usage statistics only cover the records that are kept
+    #[serde(default)]
+    pub(crate) usage: Option<u32>,
+    /// Audit records of deleted client data
+    #[serde(default, alias = "data-deletions")]
+    pub(crate) data_deletions: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub(crate) const fn is_enabled(&self) -> bool {
+        self.job_runs.is_some()
+            || self.interactions.is_some()
+            || self.usage.is_some()
+            || self.data_deletions.is_some()
+    }
+}
+
+/// Number of records of each kind that were or would be removed
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct PurgeSummary {
+    pub(crate) job_runs: u64,
+    pub(crate) interactions: u64,
+    pub(crate) usage: u64,
+    pub(crate) data_deletions: u64,
+}
+
+/// Removes the records older than their retention period, only counting them on a dry run
+#[tracing::instrument(level = "info", skip(pool))]
+pub(crate) async fn purge(
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    pool: &SqlitePool,
+) -> Result<PurgeSummary> {
+    let now = unix_now()?;
+    let cutoff = |days: u32| now - i64::from(days) * SECONDS_PER_DAY;
+    let mut summary = PurgeSummary::default();
+
+    if let Some(cutoff) = policy.job_runs.map(cutoff) {
+        summary.job_runs = if dry_run {
+            sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM job_runs WHERE finished_at IS NOT NULL AND started_at < ?",
+                cutoff
+            )
+            .fetch_one(pool)
+            .await?
+            .try_into()?
+        } else {
+            sqlx::query!(
+                "DELETE FROM job_runs WHERE finished_at IS NOT NULL AND started_at < ?",
+                cutoff
+            )
+            .execute(pool)
+            .await?
+            .rows_affected()
+        };
+    }
+    if let Some(cutoff) = policy.interactions.map(cutoff) {
+        summary.interactions = if dry_run {
+            sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM interactions WHERE created_at < ?",
+                cutoff
+            )
+            .fetch_one(pool)
+            .await?
+            .try_into()?
+        } else {
+            sqlx::query!("DELETE FROM interactions WHERE created_at < ?", cutoff)
+                .execute(pool)
+                .await?
+                .rows_affected()
+        };
+    }
+    // Usage is recorded in milliseconds
+    if let Some(cutoff) = policy.usage.map(cutoff).map(|cutoff| cutoff * 1000) {
+        summary.usage = if dry_run {
+            sqlx::query_scalar!("SELECT COUNT(*) FROM usage WHERE created_at < ?", cutoff)
+                .fetch_one(pool)
+                .await?
+                .try_into()?
+        } else {
+            sqlx::query!("DELETE FROM usage WHERE created_at < ?", cutoff)
+                .execute(pool)
+                .await?
+                .rows_affected()
+        };
+    }
+    if let Some(cutoff) = policy.data_deletions.map(cutoff) {
+        summary.data_deletions = if dry_run {
+            sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM data_deletions WHERE deleted_at < ?",
+                cutoff
+            )
+            .fetch_one(pool)
+            .await?
+            .try_into()?
+        } else {
+            sqlx::query!("DELETE FROM data_deletions WHERE deleted_at < ?", cutoff)
+                .execute(pool)
+                .await?
+                .rows_affected()
+        };
+    }
+    Ok(summary)
+}
+
+/// Purges expired records once an hour
+#[tracing::instrument(level = "info", skip(pool))]
+pub(crate) async fn run_purger(policy: RetentionPolicy, pool: SqlitePool) {
+    loop {
+        match purge(&policy, false, &pool).await {
+            Ok(summary) => info!(
+                "Purged {} job runs, {} interactions, {} usage records and {} data deletions",
+                summary.job_runs, summary.interactions, summary.usage, summary.data_deletions
+            ),
+            Err(err) => error!("Failed to purge expired records: {:?}", err),
+        }
+        tokio::time::sleep(PURGE_INTERVAL).await;
+    }
+}
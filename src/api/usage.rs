@@ -0,0 +1,251 @@
+#![allow(clippy::cast_precision_loss)]
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::warn;
+
+/// Prices of a model in an arbitrary currency unit, used to estimate the cost of usage for chargeback
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub(crate) struct ModelCost {
+    #[serde(alias = "prompt-token")]
+    pub(crate) prompt_token: f64,
+    #[serde(alias = "completion-token")]
+    pub(crate) completion_token: f64,
+    #[serde(alias = "audio-second")]
+    pub(crate) audio_second: f64,
+}
+
+static COSTS: OnceLock<BTreeMap<String, ModelCost>> = OnceLock::new();
+
+#[tracing::instrument(level = "info")]
+pub(crate) fn configure_costs(costs: BTreeMap<String, ModelCost>) {
+    if COSTS.set(costs).is_err() {
+        warn!("Model costs are already configured");
+    }
+}
+
+/// What a request consumed, its cost is estimated from
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Consumption {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    pub(crate) audio_seconds: f64,
+}
+
+impl Consumption {
+    /// Returns the cost of the consumption with the prices configured for the model
+    #[tracing::instrument(level = "trace")]
+    fn estimated_cost(&self, model: &str) -> f64 {
+        COSTS
+            .get()
+            .and_then(|costs| costs.get(model))
+            .map_or(0.0, |cost| {
+                self.audio_seconds.mul_add(
+                    cost.audio_second,
+                    (self.prompt_tokens as f64).mul_add(
+                        cost.prompt_token,
+                        self.completion_tokens as f64 * cost.completion_token,
+                    ),
+                )
+            })
+    }
+}
+
+/// A finished inference request, recorded for capacity planning and chargeback
+#[derive(Debug)]
+pub(crate) struct UsageRecord<'a> {
+    pub(crate) client_id: &'a str,
+    pub(crate) model: &'a str,
+    pub(crate) task: &'a str,
+    pub(crate) success: bool,
+    pub(crate) consumption: Consumption,
+    pub(crate) duration: Duration,
+}
+
+impl UsageRecord<'_> {
+    #[tracing::instrument(level = "trace", skip(pool))]
+    pub(crate) async fn insert(&self, pool: &SqlitePool) -> Result<()> {
+        let unix_now: i64 = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?;
+        let prompt_tokens: i64 = self.consumption.prompt_tokens.try_into()?;
+        let completion_tokens: i64 = self.consumption.completion_tokens.try_into()?;
+        let audio_seconds = self.consumption.audio_seconds;
+        let cost = self.consumption.estimated_cost(self.model);
+        let duration_ms: i64 = self.duration.as_millis().try_into()?;
+        sqlx::query!(
+            "INSERT INTO usage (client_id, model, task, success, prompt_tokens, completion_tokens, audio_seconds, cost, duration_ms, created_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.client_id,
+            self.model,
+            self.task,
+            self.success,
+            prompt_tokens,
+            completion_tokens,
+            audio_seconds,
+            cost,
+            duration_ms,
+            unix_now
+        )
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The period usage statistics are aggregated over, ending now
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StatsWindow {
+    Hour,
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl StatsWindow {
+    const fn duration(self) -> Duration {
+        let hours = match self {
+            Self::Hour => 1,
+            Self::Day => 24,
+            Self::Week => 7 * 24,
+            Self::Month => 30 * 24,
+        };
+        Duration::from_secs(hours * 60 * 60)
+    }
+
+    /// Returns the start of the window as unix timestamp in milliseconds
+    fn start(self) -> Result<i64> {
+        Ok((SystemTime::now() - self.duration())
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ModelStatsRequest {
+    #[serde(default)]
+    pub(crate) window: StatsWindow,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModelStats {
+    pub(crate) model: String,
+    pub(crate) requests: i64,
+    pub(crate) errors: i64,
+    pub(crate) error_rate: f64,
+    pub(crate) prompt_tokens: i64,
+    pub(crate) completion_tokens: i64,
+    /// Generated tokens per second of successful requests
+    pub(crate) tokens_per_second: f64,
+    pub(crate) average_latency_ms: f64,
+    pub(crate) audio_seconds: f64,
+    pub(crate) estimated_cost: f64,
+}
+
+/// Aggregates the usage of every model over the window, ordered by the number of requests
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn model_stats(window: StatsWindow, pool: &SqlitePool) -> Result<Vec<ModelStats>> {
+    let since = window.start()?;
+    let records = sqlx::query!(
+        r#"SELECT model,
+            COUNT(*) AS "requests!: i64",
+            SUM(success = 0) AS "errors!: i64",
+            SUM(prompt_tokens) AS "prompt_tokens!: i64",
+            SUM(completion_tokens) AS "completion_tokens!: i64",
+            SUM(CASE WHEN success THEN duration_ms ELSE 0 END) AS "generation_ms!: i64",
+            AVG(duration_ms) AS "average_latency_ms!: f64",
+            SUM(audio_seconds) AS "audio_seconds!: f64",
+            SUM(cost) AS "estimated_cost!: f64"
+        FROM usage WHERE created_at >= ? GROUP BY model ORDER BY COUNT(*) DESC"#,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| ModelStats {
+            model: record.model,
+            requests: record.requests,
+            errors: record.errors,
+            error_rate: record.errors as f64 / record.requests as f64,
+            prompt_tokens: record.prompt_tokens,
+            completion_tokens: record.completion_tokens,
+            tokens_per_second: if record.generation_ms > 0 {
+                record.completion_tokens as f64 * 1000.0 / record.generation_ms as f64
+            } else {
+                0.0
+            },
+            average_latency_ms: record.average_latency_ms,
+            audio_seconds: record.audio_seconds,
+            estimated_cost: record.estimated_cost,
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ClientUsageRequest {
+    /// The client to report on, defaults to the requesting client
+    pub(crate) id: Option<String>,
+    #[serde(default)]
+    pub(crate) window: StatsWindow,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ClientUsage {
+    pub(crate) id: String,
+    pub(crate) models: Vec<ModelUsage>,
+    pub(crate) estimated_cost: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModelUsage {
+    pub(crate) model: String,
+    pub(crate) requests: i64,
+    pub(crate) prompt_tokens: i64,
+    pub(crate) completion_tokens: i64,
+    pub(crate) audio_seconds: f64,
+    pub(crate) estimated_cost: f64,
+}
+
+/// Sums up the usage of the client per model over the window
+#[tracing::instrument(level = "trace", skip(pool))]
+pub(crate) async fn client_usage(
+    id: &str,
+    window: StatsWindow,
+    pool: &SqlitePool,
+) -> Result<ClientUsage> {
+    let since = window.start()?;
+    let models: Vec<ModelUsage> = sqlx::query_as!(
+        ModelUsage,
+        r#"SELECT model,
+            COUNT(*) AS "requests!: i64",
+            SUM(prompt_tokens) AS "prompt_tokens!: i64",
+            SUM(completion_tokens) AS "completion_tokens!: i64",
+            SUM(audio_seconds) AS "audio_seconds!: f64",
+            SUM(cost) AS "estimated_cost!: f64"
+        FROM usage WHERE client_id = ? AND created_at >= ? GROUP BY model ORDER BY model"#,
+        id,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(ClientUsage {
+        id: id.into(),
+        estimated_cost: models
+            .iter()
+            .fold(0.0, |total, usage| total + usage.estimated_cost),
+        models,
+    })
+}
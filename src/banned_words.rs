@@ -0,0 +1,30 @@
+use std::sync::OnceLock;
+
+use tracing::warn;
+
+use crate::config::BannedWords;
+
+static RULES: OnceLock<Vec<BannedWords>> = OnceLock::new();
+
+#[tracing::instrument(level = "info")]
+pub fn configure_banned_words(rules: Vec<BannedWords>) {
+    if RULES.set(rules).is_err() {
+        warn!("Banned words are already configured");
+    }
+}
+
+/// Returns the strings the model must not generate for the client
+#[tracing::instrument(level = "trace")]
+pub fn banned_words(model: &str, client: Option<&str>) -> Vec<String> {
+    RULES
+        .get()
+        .map_or(&[][..], Vec::as_slice)
+        .iter()
+        .filter(|rule| rule.models.is_empty() || rule.models.iter().any(|m| m == model))
+        .filter(|rule| {
+            rule.clients.is_empty()
+                || client.is_some_and(|name| rule.clients.iter().any(|c| c == name))
+        })
+        .flat_map(|rule| rule.words.iter().cloned())
+        .collect()
+}
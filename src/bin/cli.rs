@@ -1,15 +1,30 @@
-use anyhow::Result;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
 use clap::Parser;
 use clap::Subcommand;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::SqlitePool;
 
 use crate::api::auth::Auth;
-use crate::api::client::{ApiClient, Permission};
+use crate::api::client::{export_clients, import_clients, ApiClient, ClientRecord, Permission};
+use crate::api::interactions::{logged_interactions, LoggedInteraction, Rating};
+use crate::api::registration::{decide_registration, list_registrations, RegistrationStatus};
+use crate::api::retention::{purge, RetentionPolicy};
+use crate::migration::{lock_database, pending_migrations, run_migrations, MigrationInfo};
 
 #[allow(dead_code)]
 #[path = "../api/mod.rs"]
 mod api;
 
+#[allow(dead_code)]
+#[path = "../migration.rs"]
+mod migration;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -17,6 +32,10 @@ struct Args {
     #[arg(short, long, env, default_value = "model_runner.db")]
     pub sqlite_file_path: String,
 
+    /// The format results and errors are printed in
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
     #[command(subcommand)]
     cmd: Commands,
 }
@@ -36,6 +55,194 @@ enum Commands {
         #[clap(short, long, value_parser = clap::value_parser ! (Permission), num_args = 1.., value_delimiter = ',', default_values_t = vec ! [Permission::USE_SELF, Permission::STATUS_SELF, Permission::DELETE_SELF, Permission::UPDATE_SELF])]
         permission: Vec<Permission>,
     },
+    /// Apply all pending database migrations
+    Migrate {
+        /// Only list the pending migrations without applying them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print logged interactions that received feedback as JSON lines
+    ExportFeedback,
+    /// Print logged interactions as a fine-tuning dataset in JSON lines
+    ExportDataset {
+        /// Layout of every line, `jsonl` is an alias for `chatml`
+        #[clap(short, long, value_enum, default_value_t = DatasetFormat::Chatml)]
+        format: DatasetFormat,
+
+        /// Only export interactions matching all filters, as `rating=positive`, `model=phi3` or `task=instruct`
+        #[clap(long, num_args = 1.., value_delimiter = ',')]
+        filter: Vec<InteractionFilter>,
+    },
+    /// Print all clients with their key hashes as JSON lines, keys are never stored and can not be exported
+    ExportClients,
+    /// Import clients from JSON lines written by `export-clients`
+    ImportClients {
+        /// File to read the clients from, standard input if not given
+        file: Option<PathBuf>,
+
+        /// Replace clients that already exist with the same ID instead of skipping them
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// List the registrations waiting for approval or with the given status
+    ListRegistrations {
+        /// One of `pending`, `approved`, `denied` or `issued`
+        #[clap(short, long, default_value = "pending")]
+        status: RegistrationStatus,
+    },
+    /// Approve a registration, the client is issued when the requester claims it
+    ApproveRegistration {
+        /// ID of the registration
+        id: String,
+
+        /// Scope of permissions granted instead of the requested ones
+        #[clap(short, long, value_parser = clap::value_parser ! (Permission), num_args = 1.., value_delimiter = ',')]
+        permission: Vec<Permission>,
+    },
+    /// Deny a registration
+    DenyRegistration {
+        /// ID of the registration
+        id: String,
+    },
+    /// Remove the records older than the retention policy of the configuration file
+    Purge {
+        /// The configuration file of the server the retention policy is read from
+        #[clap(short, long, default_value = "ModelRunner.toml")]
+        config_file: PathBuf,
+
+        /// Only count the records that would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+/// The part of the server configuration the CLI reads
+#[derive(Deserialize, Default)]
+struct RetentionConfig {
+    #[serde(default)]
+    retention: RetentionPolicy,
+}
+
+/// Recorded as the deciding client of registrations decided through the CLI
+const CLI_DECIDER_ID: &str = "cli";
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum DatasetFormat {
+    /// `{"messages": [{"role": "user", ...}, {"role": "assistant", ...}]}`
+    #[value(alias = "jsonl")]
+    Chatml,
+    /// `{"conversations": [{"from": "human", ...}, {"from": "gpt", ...}]}`
+    Sharegpt,
+}
+
+impl DatasetFormat {
+    fn line(self, interaction: &LoggedInteraction) -> serde_json::Value {
+        match self {
+            Self::Chatml => json!({
+                "messages": [
+                    {"role": "user", "content": interaction.input},
+                    {"role": "assistant", "content": interaction.output},
+                ]
+            }),
+            Self::Sharegpt => json!({
+                "conversations": [
+                    {"from": "human", "value": interaction.input},
+                    {"from": "gpt", "value": interaction.output},
+                ]
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum InteractionFilter {
+    Rating(Rating),
+    Model(String),
+    Task(String),
+}
+
+impl FromStr for InteractionFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((key, value)) = s.split_once('=') else {
+            bail!("Filter must be of the form key=value");
+        };
+        Ok(match key {
+            "rating" => Self::Rating(match value {
+                "positive" => Rating::Positive,
+                "negative" => Rating::Negative,
+                _ => bail!("Rating must be positive or negative"),
+            }),
+            "model" => Self::Model(value.into()),
+            "task" => Self::Task(value.into()),
+            _ => bail!("Unknown filter {key}, expected rating, model or task"),
+        })
+    }
+}
+
+impl InteractionFilter {
+    fn matches(&self, interaction: &LoggedInteraction) -> bool {
+        match self {
+            Self::Rating(rating) => interaction.rating == Some(rating.score()),
+            Self::Model(model) => &interaction.model == model,
+            Self::Task(task) => &interaction.task == task,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Exit codes of the CLI, usage errors exit with 2 as reported by clap
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    Other = 1,
+    NotFound = 3,
+    PermissionDenied = 4,
+    Io = 5,
+}
+
+impl Failure {
+    fn of(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<sqlx::Error>() {
+            return match err {
+                sqlx::Error::RowNotFound => Self::NotFound,
+                sqlx::Error::Io(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    Self::PermissionDenied
+                }
+                sqlx::Error::Io(_) | sqlx::Error::Database(_) => Self::Io,
+                _ => Self::Other,
+            };
+        }
+        match err
+            .downcast_ref::<std::io::Error>()
+            .map(std::io::Error::kind)
+        {
+            Some(std::io::ErrorKind::NotFound) => Self::NotFound,
+            Some(std::io::ErrorKind::PermissionDenied) => Self::PermissionDenied,
+            Some(_) => Self::Io,
+            None => Self::Other,
+        }
+    }
+
+    const fn code(self) -> &'static str {
+        match self {
+            Self::Other => "error",
+            Self::NotFound => "not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::Io => "io",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput<'a> {
+    error: String,
+    code: &'a str,
 }
 
 struct AppState {
@@ -44,8 +251,31 @@ struct AppState {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let args = Args::parse();
+    let output = args.output;
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            let failure = Failure::of(&err);
+            match output {
+                OutputFormat::Text => eprintln!("Error: {err:?}"),
+                OutputFormat::Json => eprintln!(
+                    "{}",
+                    serde_json::to_string(&ErrorOutput {
+                        error: format!("{err:#}"),
+                        code: failure.code(),
+                    })
+                    .unwrap_or_default()
+                ),
+            }
+            ExitCode::from(failure as u8)
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
     let db_pool = SqlitePool::connect(&args.sqlite_file_path).await?;
     let auth = Auth::default();
     let state = AppState { db_pool, auth };
@@ -64,8 +294,149 @@ async fn main() -> Result<()> {
                 &state.db_pool,
             )
             .await?;
-            println!("Generated new API client token:\n{}", &client);
+            match args.output {
+                OutputFormat::Text => println!("Generated new API client token:\n{}", &client),
+                OutputFormat::Json => println!("{}", serde_json::to_string(&client)?),
+            }
+        }
+        Commands::Migrate { dry_run } => {
+            let pending = pending_migrations(&state.db_pool).await?;
+            if !dry_run {
+                let _lock = lock_database(&args.sqlite_file_path)?;
+                run_migrations(&state.db_pool).await?;
+            }
+            print_migrations(args.output, &pending, dry_run)?;
+        }
+        Commands::ExportFeedback => {
+            for interaction in logged_interactions(&state.db_pool).await? {
+                if interaction.rating.is_some() {
+                    println!("{}", serde_json::to_string(&interaction)?);
+                }
+            }
+        }
+        Commands::ExportDataset { format, filter } => {
+            for interaction in logged_interactions(&state.db_pool).await? {
+                if filter.iter().all(|filter| filter.matches(&interaction)) {
+                    println!("{}", format.line(&interaction));
+                }
+            }
+        }
+        Commands::ExportClients => {
+            for client in export_clients(&state.db_pool).await? {
+                println!("{}", serde_json::to_string(&client)?);
+            }
+        }
+        Commands::ImportClients { file, overwrite } => {
+            let input = match file {
+                Some(file) => std::fs::read_to_string(file)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let records = input
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<ClientRecord>, _>>()?;
+            let summary = import_clients(&records, overwrite, &state.db_pool).await?;
+            match args.output {
+                OutputFormat::Text => println!(
+                    "Imported {} clients, skipped {} existing clients",
+                    summary.imported, summary.skipped
+                ),
+                OutputFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+            }
+        }
+        Commands::ListRegistrations { status } => {
+            let registrations = list_registrations(Some(status), &state.db_pool).await?;
+            match args.output {
+                OutputFormat::Text => {
+                    for registration in registrations {
+                        println!(
+                            "{} {} {} {} {}",
+                            registration.id,
+                            registration.status,
+                            registration.name,
+                            registration.permissions,
+                            registration.reason.unwrap_or_default()
+                        );
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string(&registrations)?),
+            }
+        }
+        Commands::ApproveRegistration { id, permission } => {
+            let permissions =
+                (!permission.is_empty()).then(|| permission.into_iter().collect::<Permission>());
+            decide(&state, &id, true, permissions.as_ref()).await?;
+        }
+        Commands::DenyRegistration { id } => decide(&state, &id, false, None).await?,
+        Commands::Purge {
+            config_file,
+            dry_run,
+        } => {
+            let config: RetentionConfig = toml::from_str(&std::fs::read_to_string(config_file)?)?;
+            if !config.retention.is_enabled() {
+                bail!("No retention policy is configured");
+            }
+            let summary = purge(&config.retention, dry_run, &state.db_pool).await?;
+            match args.output {
+                OutputFormat::Text => println!(
+                    "{} {} job runs, {} interactions, {} usage records and {} data deletions",
+                    if dry_run { "Would purge" } else { "Purged" },
+                    summary.job_runs,
+                    summary.interactions,
+                    summary.usage,
+                    summary.data_deletions
+                ),
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string(&json!({
+                        "dry_run": dry_run,
+                        "purged": summary,
+                    }))?
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn decide(
+    state: &AppState,
+    id: &str,
+    approve: bool,
+    permissions: Option<&Permission>,
+) -> Result<()> {
+    if !decide_registration(id, approve, permissions, CLI_DECIDER_ID, &state.db_pool).await? {
+        bail!("Failed to find pending registration {id}");
+    }
+    Ok(())
+}
+
+fn print_migrations(
+    output: OutputFormat,
+    migrations: &[MigrationInfo],
+    dry_run: bool,
+) -> Result<()> {
+    match output {
+        OutputFormat::Text => {
+            if migrations.is_empty() {
+                println!("Database is up to date");
+            }
+            let action = if dry_run { "Pending" } else { "Applied" };
+            for migration in migrations {
+                println!(
+                    "{} migration {} {}",
+                    action, migration.version, migration.description
+                );
+            }
         }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string(&serde_json::json!({
+                "dry_run": dry_run,
+                "migrations": migrations,
+            }))?
+        ),
     }
     Ok(())
 }
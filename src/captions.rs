@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::inference::model_slot::ModelSlot;
+use crate::inference::task::transcribe::TranscribeHandler;
+
+/// Sampling rate of the PCM frames sent to the live captioning endpoint
+const SAMPLE_RATE: usize = 16_000;
+/// Number of samples voice activity is detected on at once, 30 ms
+const VAD_FRAME: usize = SAMPLE_RATE * 3 / 100;
+/// RMS of a frame above which it is considered to contain speech
+const SPEECH_RMS: f32 = 0.01;
+/// Silence after speech that completes the current segment, 600 ms
+const FINAL_SILENCE: usize = SAMPLE_RATE * 6 / 10;
+/// Audio received since the last interim transcript before another one is emitted, 1 s
+const INTERIM_INTERVAL: usize = SAMPLE_RATE;
+/// Silence kept in front of a segment so that the first word is not cut off, 300 ms
+const LEADING_SILENCE: usize = SAMPLE_RATE * 3 / 10;
+/// Longest segment transcribed at once, well below the 30 second window of whisper
+const MAX_SEGMENT: usize = SAMPLE_RATE * 20;
+
+#[derive(Deserialize, Debug)]
+pub struct CaptionQuery {
+    pub model: String,
+    pub language: String,
+}
+
+/// Messages sent to the client of a live captioning connection
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CaptionEvent {
+    /// Transcript of the segment so far, superseded by later events of the same segment
+    Interim {
+        segment: u64,
+        start: f64,
+        text: String,
+        /// Milliseconds between receiving the last transcribed audio and sending the transcript
+        latency_ms: f64,
+    },
+    /// Transcript of a completed segment
+    Final {
+        segment: u64,
+        start: f64,
+        duration: f64,
+        text: String,
+        latency_ms: f64,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Audio of a segment to be transcribed
+#[derive(Debug)]
+struct Chunk {
+    segment: u64,
+    /// Offset of the segment from the start of the stream in samples
+    offset: usize,
+    /// Number of samples of the segment, kept when they are handed off for transcription
+    length: usize,
+    samples: Vec<f32>,
+    is_final: bool,
+    received: Instant,
+}
+
+/// Splits a continuous stream of samples into segments at pauses in speech
+#[derive(Debug, Default)]
+struct SegmentBuffer {
+    segment: u64,
+    offset: usize,
+    samples: Vec<f32>,
+    /// Samples not yet checked for voice activity as they do not fill a frame
+    unchecked: usize,
+    has_speech: bool,
+    trailing_silence: usize,
+    since_interim: usize,
+}
+
+impl SegmentBuffer {
+    fn push(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+        self.unchecked += samples.len();
+        self.since_interim += samples.len();
+        while self.unchecked >= VAD_FRAME {
+            let start = self.samples.len() - self.unchecked;
+            let frame = &self.samples[start..start + VAD_FRAME];
+            self.unchecked -= VAD_FRAME;
+            if rms(frame) >= SPEECH_RMS {
+                self.has_speech = true;
+                self.trailing_silence = 0;
+            } else {
+                self.trailing_silence += VAD_FRAME;
+            }
+        }
+
+        // Silence before any speech is not worth transcribing
+        if !self.has_speech && self.samples.len() > LEADING_SILENCE + VAD_FRAME {
+            let excess = self.samples.len() - LEADING_SILENCE - self.unchecked;
+            self.samples.drain(..excess);
+            self.offset += excess;
+            self.since_interim = 0;
+        }
+    }
+
+    /// Returns the segment if a pause completed it or it reached its maximum length
+    fn take_final(&mut self) -> Option<Chunk> {
+        if !self.has_speech
+            || (self.trailing_silence < FINAL_SILENCE && self.samples.len() < MAX_SEGMENT)
+        {
+            return None;
+        }
+        Some(self.take())
+    }
+
+    /// Returns the segment completed by the end of the stream
+    fn finish(&mut self) -> Option<Chunk> {
+        self.has_speech.then(|| self.take())
+    }
+
+    fn take(&mut self) -> Chunk {
+        let samples = std::mem::take(&mut self.samples);
+        let chunk = Chunk {
+            segment: self.segment,
+            offset: self.offset,
+            length: samples.len(),
+            samples,
+            is_final: true,
+            received: Instant::now(),
+        };
+        self.segment += 1;
+        self.offset += chunk.length;
+        self.unchecked = 0;
+        self.has_speech = false;
+        self.trailing_silence = 0;
+        self.since_interim = 0;
+        chunk
+    }
+
+    /// Returns a copy of the segment so far if enough audio arrived since the last one
+    fn interim(&mut self) -> Option<Chunk> {
+        if !self.has_speech || self.since_interim < INTERIM_INTERVAL {
+            return None;
+        }
+        self.since_interim = 0;
+        Some(Chunk {
+            segment: self.segment,
+            offset: self.offset,
+            length: self.samples.len(),
+            samples: self.samples.clone(),
+            is_final: false,
+            received: Instant::now(),
+        })
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn rms(frame: &[f32]) -> f32 {
+    (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt()
+}
+
+/// Decodes a frame of 16 bit little endian mono PCM
+#[tracing::instrument(level = "trace", skip(frame))]
+fn decode_frame(frame: &[u8]) -> Result<Vec<f32>> {
+    if !frame.len().is_multiple_of(2) {
+        bail!("Frames must contain 16 bit samples");
+    }
+    Ok(frame
+        .chunks_exact(2)
+        .map(|bytes| f32::from(i16::from_le_bytes([bytes[0], bytes[1]])) / 32768.0)
+        .collect())
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn seconds(samples: usize) -> f64 {
+    samples as f64 / SAMPLE_RATE as f64
+}
+
+/// Transcribes the PCM frames received over the socket until the client closes it or sends `end`,
+/// emitting interim transcripts of the current segment and final ones once it is complete.
+/// Returns the seconds of audio received.
+#[tracing::instrument(level = "info", skip(socket, model))]
+pub async fn run_captions<M>(
+    mut socket: WebSocket,
+    model: &'static ModelSlot<M>,
+    language: String,
+) -> f64
+where
+    M: TranscribeHandler + Clone + Send + Sync + 'static,
+{
+    let mut buffer = SegmentBuffer::default();
+    let mut finals = VecDeque::new();
+    let mut transcriptions = JoinSet::new();
+    let mut received = 0;
+    let mut ended = false;
+
+    loop {
+        if transcriptions.is_empty() {
+            let next = finals
+                .pop_front()
+                .or_else(|| if ended { None } else { buffer.interim() });
+            if let Some(mut chunk) = next {
+                let samples = std::mem::take(&mut chunk.samples);
+                let language = language.clone();
+                transcriptions.spawn(async move {
+                    let result = model
+                        .run(move |mut model| {
+                            let segments = model.run_transcribe_samples(&samples, &language)?;
+                            Ok(segments
+                                .iter()
+                                .map(|segment| segment.text().trim())
+                                .filter(|text| !text.is_empty())
+                                .collect::<Vec<_>>()
+                                .join(" "))
+                        })
+                        .await;
+                    (chunk, result)
+                });
+            } else if ended {
+                break;
+            }
+        }
+
+        tokio::select! {
+            message = socket.recv(), if !ended => {
+                match message {
+                    Some(Ok(Message::Binary(frame))) => match decode_frame(&frame) {
+                        Ok(samples) => {
+                            received += samples.len();
+                            buffer.push(&samples);
+                            finals.extend(buffer.take_final());
+                        }
+                        Err(err) => {
+                            let _ = send(&mut socket, &CaptionEvent::Error { error: err.to_string() }).await;
+                        }
+                    },
+                    Some(Ok(Message::Text(text))) if text.trim() == "end" => {
+                        finals.extend(buffer.finish());
+                        ended = true;
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+            Some(joined) = transcriptions.join_next() => {
+                let event = match joined {
+                    Ok((chunk, Ok(text))) => transcript(&chunk, text),
+                    Ok((_, Err(err))) => CaptionEvent::Error { error: err.to_string() },
+                    Err(err) => CaptionEvent::Error { error: err.to_string() },
+                };
+                if let Err(err) = send(&mut socket, &event).await {
+                    debug!("Live captioning client went away: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    if ended {
+        let _ = socket.send(Message::Close(None)).await;
+    }
+    seconds(received)
+}
+
+fn transcript(chunk: &Chunk, text: String) -> CaptionEvent {
+    let latency_ms = chunk.received.elapsed().as_secs_f64() * 1000.0;
+    if chunk.is_final {
+        CaptionEvent::Final {
+            segment: chunk.segment,
+            start: seconds(chunk.offset),
+            duration: seconds(chunk.length),
+            text,
+            latency_ms,
+        }
+    } else {
+        CaptionEvent::Interim {
+            segment: chunk.segment,
+            start: seconds(chunk.offset),
+            text,
+            latency_ms,
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, event: &CaptionEvent) -> Result<()> {
+    let message = serde_json::to_string(event)?;
+    if let Err(err) = socket.send(Message::Text(message)).await {
+        warn!("Failed to send live caption: {}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::{HttpErrorResponse, ModelResult};
+use crate::runner;
+
+/// Status codes injected errors are answered with
+const ERROR_STATUSES: [StatusCode; 4] = [
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+static CONFIG: OnceLock<ChaosConfig> = OnceLock::new();
+
+/// Faults injected into model requests to test how clients and the circuit breakers cope with them,
+/// each rate is the probability between 0 and 1 that a request is affected
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ChaosConfig {
+    /// Faults are only injected if enabled, never enable this in production
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default, alias = "latency-rate")]
+    pub latency_rate: f64,
+    /// Milliseconds an affected request is delayed by
+    #[serde(default = "default_latency")]
+    pub latency: u64,
+    /// Requests answered with a random 5xx status instead of being handled
+    #[serde(default, alias = "error-rate")]
+    pub error_rate: f64,
+    /// Inferences whose worker panics before running the model
+    #[serde(default, alias = "panic-rate")]
+    pub panic_rate: f64,
+}
+
+const fn default_latency() -> u64 {
+    1000
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_chaos(config: ChaosConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    for (name, rate) in [
+        ("latency", config.latency_rate),
+        ("error", config.error_rate),
+        ("panic", config.panic_rate),
+    ] {
+        if !(0.0..=1.0).contains(&rate) {
+            bail!("Chaos {} rate {} is not between 0 and 1", name, rate);
+        }
+    }
+    warn!(
+        "Chaos mode is enabled, delaying {}% of requests by {} ms, failing {}% and panicking in {}% of inferences",
+        config.latency_rate * 100.0,
+        config.latency,
+        config.error_rate * 100.0,
+        config.panic_rate * 100.0
+    );
+    if CONFIG.set(config).is_err() {
+        warn!("Chaos mode was already configured");
+    }
+    Ok(())
+}
+
+pub fn chaos_enabled() -> bool {
+    CONFIG.get().is_some()
+}
+
+/// Delays the request or answers it with an error at the configured rates
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn inject_faults(request: Request, next: Next) -> ModelResult<Response> {
+    let Some(config) = CONFIG.get() else {
+        return Ok(next.run(request).await);
+    };
+
+    if rand::thread_rng().gen_bool(config.latency_rate) {
+        info!(monotonic_counter.chaos_faults = 1, fault = "latency");
+        tokio::time::sleep(Duration::from_millis(config.latency)).await;
+    }
+    if rand::thread_rng().gen_bool(config.error_rate) {
+        info!(monotonic_counter.chaos_faults = 1, fault = "error");
+        let status = *ERROR_STATUSES
+            .choose(&mut rand::thread_rng())
+            .unwrap_or(&StatusCode::INTERNAL_SERVER_ERROR);
+        return Err(runner!(status, "Injected fault").with_code("injected_fault"));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Panics at the configured rate, called by inference workers before they run the model
+pub fn inject_panic(model: &str) {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    if rand::thread_rng().gen_bool(config.panic_rate) {
+        info!(monotonic_counter.chaos_faults = 1, fault = "panic", model);
+        panic!("Injected fault in the inference worker of model {model}");
+    }
+}
@@ -1,8 +1,18 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
 use anyhow::Result;
 use clap::ArgAction;
 use clap_serde_derive::ClapSerde;
 use serde::Deserialize;
 
+use crate::api::retention::RetentionPolicy;
+use crate::api::usage::ModelCost;
+use crate::chaos::ChaosConfig;
+use crate::jobs::JobTask;
+use crate::notifications::NotificationEvent;
+
+#[allow(clippy::struct_excessive_bools)]
 #[derive(ClapSerde, Deserialize)]
 pub struct Config {
     /// The address the listener binds to
@@ -13,6 +23,11 @@ pub struct Config {
     #[arg(short, long, env, default_value = "25566")]
     pub port: u16,
 
+    /// Serve the `/admin`, `/auth` and `/jobs` endpoints on a separate listener at this address
+    /// and port, e.g. `127.0.0.1:25567`, instead of alongside the inference endpoints
+    #[arg(long, env)]
+    pub admin_address: Option<String>,
+
     /// The OpenTelemetry collector endpoint, enables telemetry
     #[arg(short, long, env)]
     pub otel_endpoint: Option<String>,
@@ -22,10 +37,41 @@ pub struct Config {
     pub console: bool,
 
     /// Enable saving traces locally with tracing-chrome crate.
-    /// This will save the traces in the current working directory as `trace-timestamp.json`
+    /// This will save the traces in the working directory, or the current directory if none is set,
+    /// as `trace-timestamp.json`
     #[arg(long, env, action(ArgAction::SetTrue))]
     pub trace_local: bool,
 
+    /// Directory for temporary files and artifacts such as spooled uploads and local traces,
+    /// defaults to the system temp directory
+    #[arg(long, env)]
+    pub work_dir: Option<PathBuf>,
+
+    /// Directory local traces are written to, overrides the working directory for traces
+    #[arg(long, env)]
+    pub trace_dir: Option<PathBuf>,
+
+    /// Delete local traces older than this many days, checked hourly, 0 keeps them
+    #[arg(long, env, default_value = "0")]
+    pub trace_retention: u64,
+
+    /// Record generation milestones as span events every given number of tokens, 0 disables them
+    #[arg(long, env, default_value = "0")]
+    pub milestone_interval: usize,
+
+    /// Where model artifacts are loaded from: `hub`, `dir:<path>` or an http(s) base url,
+    /// e.g. a public or presigned S3 bucket
+    #[arg(long, env, default_value = "hub")]
+    pub artifact_source: String,
+
+    /// Maximum number of model artifacts downloaded at the same time, 0 is unlimited
+    #[arg(long, env, default_value = "0")]
+    pub max_concurrent_downloads: usize,
+
+    /// Bytes per second shared by all artifact downloads from an http(s) source, 0 is unlimited
+    #[arg(long, env, default_value = "0")]
+    pub download_bandwidth_limit: u64,
+
     /// The TLS configuration
     #[serde(default)]
     #[command(flatten)]
@@ -34,6 +80,402 @@ pub struct Config {
     /// The `SQLite` database file path
     #[arg(short, long, env, default_value = "model_runner.db")]
     pub sqlite_file_path: String,
+
+    /// Refuse to start if the binary was compiled without CPU features the host supports, e.g. avx2 or neon
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub require_optimized_build: bool,
+
+    /// Do not apply pending database migrations at startup, they have to be applied with the CLI instead
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub skip_migrations: bool,
+
+    /// Number of consecutive failed inferences after which a model is marked as unhealthy
+    #[arg(long, env, default_value = "5")]
+    pub breaker_threshold: u32,
+
+    /// Seconds an unhealthy model rejects requests while it is being reloaded
+    #[arg(long, env, default_value = "30")]
+    pub breaker_cooldown: u64,
+
+    /// Seconds a generation may go without producing a token before it is aborted, 0 disables the watchdog
+    #[arg(long, env, default_value = "120")]
+    pub watchdog_timeout: u64,
+
+    /// Seconds between two health probes of every loaded model, 0 disables the probes
+    #[arg(long, env, default_value = "0")]
+    pub probe_interval: u64,
+
+    /// Milliseconds a health probe may take before it counts as failed
+    #[arg(long, env, default_value = "10000")]
+    pub probe_max_latency: u64,
+
+    /// Number of consecutive failed health probes after which a model is marked as degraded
+    #[arg(long, env, default_value = "3")]
+    pub probe_failure_threshold: u32,
+
+    /// Maximum number of concurrent HTTP/2 streams per connection
+    #[arg(long, env, default_value = "200")]
+    pub http2_max_concurrent_streams: u32,
+
+    /// Seconds between HTTP/2 keep-alive pings, 0 disables them
+    #[arg(long, env, default_value = "20")]
+    pub keep_alive_interval: u64,
+
+    /// Seconds to wait for a keep-alive ping to be acknowledged before closing the connection
+    #[arg(long, env, default_value = "20")]
+    pub keep_alive_timeout: u64,
+
+    /// Disable HTTP/2 over plaintext (h2c) connections, TLS connections still negotiate h2 via ALPN
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub disable_h2c: bool,
+
+    /// Disable the unauthenticated `/status` endpoint summarizing availability for status pages
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub disable_status_page: bool,
+
+    /// Leave out the endpoints creating, updating and deleting clients, for deployments that only
+    /// provision clients through the configuration file or the CLI
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub auth_read_only: bool,
+
+    /// Let anyone request a client through `/auth/register`, issued once an admin approves it
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub allow_registration: bool,
+
+    /// Send HSTS, `X-Content-Type-Options`, `Referrer-Policy` and a restrictive CSP with every
+    /// response, defaults to on when TLS is enabled
+    #[arg(long, env)]
+    pub security_headers: Option<bool>,
+
+    /// The `max-age` of the HSTS header in seconds, 0 leaves the header out
+    #[arg(long, env, default_value = "31536000")]
+    pub hsts_max_age: u64,
+
+    /// Store prompts and outputs of text generations so clients can give feedback on them
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub log_interactions: bool,
+
+    /// Replace client ids with salted hashes in usage records, logged interactions and traces,
+    /// requires an anonymization salt
+    #[arg(long, env, action(ArgAction::SetTrue))]
+    pub anonymize_clients: bool,
+
+    /// Secret salt of the hashes replacing client ids, keep it stable so that usage stays
+    /// attributable to the same hash across restarts
+    #[arg(long, env)]
+    pub anonymization_salt: Option<String>,
+
+    /// Routes that can be requested without a token, supported are `/health`, `/capabilities`,
+    /// `/status` and `/model/info`
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "/health,/capabilities,/status"
+    )]
+    pub anonymous_routes: Vec<String>,
+
+    /// Maximum number of tokens a text generation may be asked to produce, 0 disables the limit
+    #[arg(long, env, default_value = "0")]
+    pub max_length: usize,
+
+    /// Maximum duration of uploaded audio in seconds, 0 disables the limit
+    #[arg(long, env, default_value = "0")]
+    pub max_audio_duration: u64,
+
+    /// Maximum number of characters of a document chunk summarized in one generation
+    #[arg(long, env, default_value = "6000")]
+    pub summarize_chunk_length: usize,
+
+    /// The device models are placed on, `cpu`, `cuda:<ordinal>` or `metal:<ordinal>`, overridable
+    /// per model in the runtime section of the configuration file
+    #[arg(long, env, default_value = "cpu")]
+    pub device: String,
+
+    /// Sample only from this many of the most likely tokens unless a request overrides it
+    #[arg(long, env)]
+    pub top_k: Option<usize>,
+
+    /// Sample only from tokens at least this fraction as likely as the most likely token unless a
+    /// request overrides it
+    #[arg(long, env)]
+    pub min_p: Option<f64>,
+
+    /// Number of prompt prefixes whose key-value cache is kept per text model, 0 disables it
+    #[arg(long, env, default_value = "0")]
+    pub prefix_cache_size: usize,
+
+    /// Minimum number of leading tokens a prompt has to share with a recent one for the shared
+    /// prefix to be cached
+    #[arg(long, env, default_value = "64")]
+    pub prefix_cache_min_tokens: usize,
+
+    /// Maximum number of inputs of a `/text/batch` request
+    #[arg(long, env, default_value = "32")]
+    pub max_batch_size: usize,
+
+    /// Text model restoring punctuation and casing of transcripts when requested
+    #[arg(long, env, default_value = "stablelm2zephyr")]
+    pub punctuation_model: String,
+
+    /// Number of times output not matching the requested response format is regenerated
+    #[arg(long, env, default_value = "2")]
+    pub structured_output_repairs: usize,
+
+    /// Secret key of the statistical watermark embedded into generated text, watermarking is
+    /// disabled when unset
+    #[arg(long, env)]
+    pub watermark_key: Option<String>,
+
+    /// Logit bias added to the green tokens of the watermark, higher is easier to detect
+    #[arg(long, env, default_value = "2.0")]
+    pub watermark_strength: f32,
+
+    /// Names of the clients whose generations are watermarked, all clients if empty, only
+    /// configurable in the configuration file
+    #[serde(default, alias = "watermark-clients")]
+    #[arg(skip)]
+    pub watermark_clients: Vec<String>,
+
+    /// Strings whose tokens are masked while sampling so models never generate them, only
+    /// configurable in the configuration file
+    #[serde(default, alias = "banned-words")]
+    #[arg(skip)]
+    pub banned_words: Vec<BannedWords>,
+
+    /// Audio uploads larger than this many bytes are spooled to a temporary file instead of
+    /// being held in memory, 0 disables spooling
+    #[arg(long, env, default_value = "1000000")]
+    pub spool_threshold: usize,
+
+    /// Cron expression of the maintenance window in which models pinned to `main` are refreshed
+    /// with their latest weights, e.g. `0 0 3 * * Sun` for every Sunday at 03:00 UTC
+    #[arg(long, env)]
+    pub reload_schedule: Option<String>,
+
+    /// Rhai script choosing the model of text requests before dispatch, which can read `model`,
+    /// `task`, `prompt_length`, `client`, `hour` (UTC) and `weekday` (0 is Monday) and returns
+    /// a model name, e.g. `if prompt_length < 200 { "stablelm2" }`
+    #[arg(long, env)]
+    pub routing_script: Option<String>,
+
+    /// Models the `auto` model routes to by the characteristics of the prompt, only configurable
+    /// in the configuration file
+    #[serde(default, alias = "auto-routing")]
+    #[arg(skip)]
+    pub auto_routing: AutoRoutingPolicy,
+
+    /// Models a text request falls through to in order when its model is degraded, failing or
+    /// overloaded, e.g. `mistral7b = ["openhermes", "stablelm2zephyr"]`, only configurable in the
+    /// configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub fallbacks: BTreeMap<String, Vec<String>>,
+
+    /// Number of copies of a model kept ready for requests, trading memory for a lower latency of
+    /// the first requests after idle periods, e.g. `mistral7b = 2`, only configurable in the
+    /// configuration file and adjustable at runtime through `/admin/warm_pools`
+    #[serde(default, alias = "warm-pools")]
+    #[arg(skip)]
+    pub warm_pools: BTreeMap<String, usize>,
+
+    /// Cron expressions of when models are loaded and unloaded again, e.g. to only keep a model
+    /// in memory during business hours, requests outside of the window are rejected until it
+    /// opens, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub availability: BTreeMap<String, AvailabilityWindow>,
+
+    /// Threads, device and memory mapping per model where they should differ from the defaults,
+    /// only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub runtime: BTreeMap<String, RuntimeOverrides>,
+
+    /// Directories watched for new audio or text files that are transcribed or summarized as soon
+    /// as they appear, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub watch: Vec<JobTask>,
+
+    /// Number of days job runs, logged interactions and usage records are kept before they are
+    /// purged, all records are kept if unset, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub retention: RetentionPolicy,
+
+    /// Artificial latency, errors and worker panics injected into requests to test retries and
+    /// circuit breakers, disabled unless enabled explicitly, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub chaos: ChaosConfig,
+
+    /// Seconds between two scans of the watched directories
+    #[arg(long, env, default_value = "10")]
+    pub watch_interval: u64,
+
+    /// Pass requests on to the next fallback while the 95th percentile queue wait of a model
+    /// exceeds this many milliseconds, 0 disables it
+    #[arg(long, env, default_value = "0")]
+    pub fallback_queue_latency: u64,
+
+    /// Prices per model that the cost of usage is estimated with, only configurable in the
+    /// configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub costs: BTreeMap<String, ModelCost>,
+
+    /// Clients that are provisioned at startup, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub clients: Vec<ClientDefinition>,
+
+    /// Plugins applied to JSON requests and responses in order, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub plugins: Vec<PluginDefinition>,
+
+    /// YAML file with guardrail rules evaluated against the text sent to and generated by models
+    #[arg(long, env)]
+    pub policy_file: Option<PathBuf>,
+
+    /// Tools models may call from `/text/agent`, only configurable in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub tools: Vec<ToolDefinition>,
+
+    /// Endpoints notified of operational events with Slack compatible payloads, only configurable
+    /// in the configuration file
+    #[serde(default)]
+    #[arg(skip)]
+    pub webhooks: Vec<Webhook>,
+
+    /// Free disk space in MiB of the database, working and model cache directories below which
+    /// webhooks are notified
+    #[arg(long, env, default_value = "1024")]
+    pub min_free_disk_space: u64,
+
+    /// Days before the TLS certificate expires from which webhooks are notified
+    #[arg(long, env, default_value = "14")]
+    pub certificate_expiry_warning: u64,
+
+    /// Maximum number of tool calls of a single `/text/agent` request
+    #[arg(long, env, default_value = "4")]
+    pub tool_max_depth: usize,
+}
+
+/// Models the `auto` model picks by the characteristics of the prompt, checked in order of the fields
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AutoRoutingPolicy {
+    /// Model for prompts that look like source code
+    pub code: String,
+    /// Model for prompts that are mostly written in a non-latin script
+    pub multilingual: String,
+    /// Prompts with at most this many characters are considered short
+    #[serde(alias = "short-prompt-length")]
+    pub short_prompt_length: usize,
+    pub short: String,
+    pub long: String,
+}
+
+impl Default for AutoRoutingPolicy {
+    fn default() -> Self {
+        Self {
+            code: "phi3".into(),
+            multilingual: "openhermes".into(),
+            short_prompt_length: 500,
+            short: "stablelm2zephyr".into(),
+            long: "mistral7b".into(),
+        }
+    }
+}
+
+/// A compiled-in plugin and its settings, selected by `kind`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PluginDefinition {
+    /// Prepends the prompt to the input of text generations
+    SystemPrompt { prompt: String },
+    /// Rejects text generations whose input contains any of the words
+    BlockWords { words: Vec<String> },
+}
+
+/// Strings models must not generate, limited to some models or clients
+#[derive(Deserialize, Debug, Clone)]
+pub struct BannedWords {
+    pub words: Vec<String>,
+    /// Names of the models the words are banned for, all models if empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Names of the clients the words are banned for, all clients if empty
+    #[serde(default)]
+    pub clients: Vec<String>,
+}
+
+/// The window in which a model is loaded, from each `load` until the following `unload`
+#[derive(Deserialize, Debug, Clone)]
+pub struct AvailabilityWindow {
+    /// Cron expression of when the model is loaded, e.g. `0 0 8 * * Mon-Fri`
+    pub load: String,
+    /// Cron expression of when the model is unloaded, e.g. `0 0 18 * * Mon-Fri`
+    pub unload: String,
+}
+
+/// How a model is executed where it should differ from the defaults of the process
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RuntimeOverrides {
+    /// Number of threads of a dedicated thread pool the model computes on, the model shares the
+    /// global thread pool sized by `RAYON_NUM_THREADS` if unset
+    pub threads: Option<usize>,
+    /// The device the model is placed on, `cpu`, `cuda:<ordinal>` or `metal:<ordinal>`
+    pub device: Option<String>,
+    /// Read the weights through a memory map instead of buffered reads
+    #[serde(default)]
+    pub mmap: bool,
+}
+
+/// An endpoint receiving notifications of operational events
+#[derive(Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// Events sent to the endpoint, all events if empty
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+}
+
+/// A tool the agent loop may invoke, selected by `kind`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ToolDefinition {
+    /// Posts the arguments as JSON to the URL and passes the response body back to the model
+    Http {
+        name: String,
+        description: String,
+        url: String,
+    },
+    /// Evaluates arithmetic expressions
+    Calculator,
+    /// Returns the current date and time
+    Datetime,
+}
+
+/// A client that is created or updated at startup to match its definition
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientDefinition {
+    /// The name of the client, used to find it again if no fixed token is given
+    pub name: String,
+
+    /// The permission names of the client, e.g. `USE_SELF`
+    #[serde(default)]
+    pub permissions: Vec<String>,
+
+    /// The fixed token id; must be used in conjunction with `key_hash`
+    pub id: Option<String>,
+
+    /// The argon2 hash of the fixed token key in PHC string format
+    #[serde(alias = "key-hash")]
+    pub key_hash: Option<String>,
 }
 
 #[derive(ClapSerde, Deserialize, Debug)]
@@ -47,6 +489,30 @@ pub struct TlsConfig {
     #[serde(alias = "private-key")]
     #[arg(long, env, requires = "certificate")]
     pub private_key: String,
+
+    /// The lowest accepted TLS version, `1.2` or `1.3`, defaults to `1.2`
+    #[serde(alias = "min-version")]
+    #[arg(long = "tls-min-version", env = "TLS_MIN_VERSION")]
+    pub min_version: String,
+
+    /// The accepted cipher suites in order of preference, e.g. `TLS13_AES_256_GCM_SHA384`,
+    /// defaults to all suites of the crypto provider
+    #[serde(alias = "cipher-suites")]
+    #[arg(
+        long = "tls-cipher-suites",
+        env = "TLS_CIPHER_SUITES",
+        value_delimiter = ','
+    )]
+    pub cipher_suites: Vec<String>,
+
+    /// The ALPN protocols offered in order of preference, defaults to `h2` and `http/1.1`
+    #[serde(alias = "alpn-protocols")]
+    #[arg(
+        long = "tls-alpn-protocols",
+        env = "TLS_ALPN_PROTOCOLS",
+        value_delimiter = ','
+    )]
+    pub alpn_protocols: Vec<String>,
 }
 
 impl Config {
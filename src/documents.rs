@@ -0,0 +1,201 @@
+#![allow(clippy::cast_precision_loss)]
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::inference::task::summarize::{chunk_length, split_chunks};
+
+/// Content types documents can be uploaded as
+pub const VALID_DOCUMENT_MIME_TYPES: [&str; 3] = ["text/plain", "text/markdown", "application/pdf"];
+
+/// An uploaded document, stored as chunks of its extracted text
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocumentInfo {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    /// Number of characters of the extracted text
+    pub length: i64,
+    pub chunks: i64,
+    pub created_at: i64,
+}
+
+/// Extracts the text of an uploaded document
+#[tracing::instrument(level = "trace", skip(bytes))]
+pub fn extract_text(content_type: &str, bytes: &[u8]) -> Result<String> {
+    let text = match content_type {
+        "application/pdf" => {
+            pdf_extract::extract_text_from_mem(bytes).context("Failed to extract text from PDF")?
+        }
+        _ => std::str::from_utf8(bytes)
+            .context("Document is not valid UTF-8")?
+            .to_string(),
+    };
+    if text.trim().is_empty() {
+        bail!("Document does not contain any text");
+    }
+    Ok(text)
+}
+
+/// Chunks the text and stores it as a new document of the client
+#[tracing::instrument(level = "trace", skip(text, pool))]
+pub async fn insert_document(
+    client_id: &str,
+    name: &str,
+    content_type: &str,
+    text: &str,
+    pool: &SqlitePool,
+) -> Result<DocumentInfo> {
+    let id = format!("{:032x}", rand::random::<u128>());
+    let chunks = split_chunks(text, chunk_length());
+    let length: i64 = text.chars().count().try_into()?;
+    let created_at: i64 = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO documents (id, client_id, name, content_type, length, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        id,
+        client_id,
+        name,
+        content_type,
+        length,
+        created_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for (position, chunk) in chunks.iter().enumerate() {
+        let position: i64 = position.try_into()?;
+        sqlx::query!(
+            "INSERT INTO document_chunks (document_id, position, content) VALUES (?, ?, ?)",
+            id,
+            position,
+            chunk
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(DocumentInfo {
+        id,
+        name: name.into(),
+        content_type: content_type.into(),
+        length,
+        chunks: chunks.len().try_into()?,
+        created_at,
+    })
+}
+
+/// Lists the documents of the client, newest first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn list_documents(client_id: &str, pool: &SqlitePool) -> Result<Vec<DocumentInfo>> {
+    Ok(sqlx::query_as!(
+        DocumentInfo,
+        r#"SELECT id, name, content_type, length,
+            (SELECT COUNT(*) FROM document_chunks WHERE document_chunks.document_id = documents.id) AS "chunks!: i64",
+            created_at
+        FROM documents WHERE client_id = ? ORDER BY created_at DESC"#,
+        client_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn document_info(
+    id: &str,
+    client_id: &str,
+    pool: &SqlitePool,
+) -> Result<Option<DocumentInfo>> {
+    Ok(sqlx::query_as!(
+        DocumentInfo,
+        r#"SELECT id, name, content_type, length,
+            (SELECT COUNT(*) FROM document_chunks WHERE document_chunks.document_id = documents.id) AS "chunks!: i64",
+            created_at
+        FROM documents WHERE id = ? AND client_id = ?"#,
+        id,
+        client_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Returns the chunks of a document of the client in order, empty if there is no such document
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn document_chunks(id: &str, client_id: &str, pool: &SqlitePool) -> Result<Vec<String>> {
+    Ok(sqlx::query_scalar!(
+        "SELECT content FROM document_chunks \
+        WHERE document_id = (SELECT id FROM documents WHERE id = ? AND client_id = ?) \
+        ORDER BY position",
+        id,
+        client_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Removes a document of the client, returning whether it existed
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn delete_document(id: &str, client_id: &str, pool: &SqlitePool) -> Result<bool> {
+    let result = sqlx::query!(
+        "DELETE FROM documents WHERE id = ? AND client_id = ?",
+        id,
+        client_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Ranks the chunks by their BM25 score for the query, returning the indices and scores of the
+/// best `limit` chunks that share any term with it
+#[tracing::instrument(level = "trace", skip(chunks))]
+pub fn rank_chunks(query: &str, chunks: &[&str], limit: usize) -> Vec<(usize, f64)> {
+    const K1: f64 = 1.2;
+    const B: f64 = 0.75;
+
+    let query_terms = terms(query);
+    let chunk_terms: Vec<Vec<String>> = chunks.iter().map(|chunk| terms(chunk)).collect();
+    let average_length =
+        chunk_terms.iter().map(Vec::len).sum::<usize>() as f64 / chunk_terms.len().max(1) as f64;
+
+    let mut scores: Vec<(usize, f64)> = chunk_terms
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let length_norm = 1.0 - B + B * chunk.len() as f64 / average_length.max(1.0);
+            let score = query_terms
+                .iter()
+                .map(|term| {
+                    let frequency = chunk.iter().filter(|t| *t == term).count() as f64;
+                    if frequency == 0.0 {
+                        return 0.0;
+                    }
+                    let containing = chunk_terms.iter().filter(|c| c.contains(term)).count() as f64;
+                    let idf = ((chunk_terms.len() as f64 - containing + 0.5) / (containing + 0.5))
+                        .ln_1p();
+                    idf * frequency * (K1 + 1.0) / K1.mul_add(length_norm, frequency)
+                })
+                .sum();
+            (index, score)
+        })
+        .filter(|(_, score)| *score > 0.0)
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(limit);
+    scores
+}
+
+/// Splits text into lowercase words, ignoring very short ones
+fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 2)
+        .map(str::to_lowercase)
+        .collect()
+}
@@ -1,25 +1,54 @@
 use std::fmt::{Display, Formatter};
 
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::Serialize;
 
+use crate::inference::error::InferenceError;
+use crate::locale::localized_message;
+
 #[derive(Debug)]
 pub struct ModelRunnerError {
     pub status: StatusCode,
     pub message: HttpErrorResponse,
+    /// Sent as `Retry-After` header in seconds
+    pub retry_after: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct HttpErrorResponse {
     error: String,
+    /// Machine-readable code that stays stable across changes to the error message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    /// Dynamic details of the message, interpolated into its localized version
+    #[serde(skip)]
+    params: Vec<String>,
+}
+
+impl HttpErrorResponse {
+    #[must_use]
+    pub const fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    #[must_use]
+    pub fn with_params(mut self, params: Vec<String>) -> Self {
+        self.params = params;
+        self
+    }
 }
 
 impl From<String> for HttpErrorResponse {
     #[tracing::instrument(level = "trace")]
     fn from(message: String) -> Self {
-        Self { error: message }
+        Self {
+            error: message,
+            code: None,
+            params: Vec::new(),
+        }
     }
 }
 
@@ -28,10 +57,27 @@ impl From<&str> for HttpErrorResponse {
     fn from(message: &str) -> Self {
         Self {
             error: message.to_string(),
+            code: None,
+            params: Vec::new(),
         }
     }
 }
 
+impl ModelRunnerError {
+    #[must_use]
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.message = self.message.with_code(code);
+        self
+    }
+
+    /// Adds a dynamic detail of the message, which localized messages refer to by its position
+    #[must_use]
+    pub fn with_param(mut self, param: impl Display) -> Self {
+        self.message.params.push(param.to_string());
+        self
+    }
+}
+
 impl Display for ModelRunnerError {
     #[tracing::instrument(level = "trace", skip(f))]
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -41,9 +87,20 @@ impl Display for ModelRunnerError {
 
 impl IntoResponse for ModelRunnerError {
     #[tracing::instrument(level = "trace")]
-    fn into_response(self) -> Response {
+    fn into_response(mut self) -> Response {
+        if let Some(message) = self
+            .message
+            .code
+            .and_then(|code| localized_message(code, &self.message.params))
+        {
+            self.message.error = message;
+        }
         let mut res = Json(self.message).into_response();
         *res.status_mut() = self.status;
+        if let Some(retry_after) = self.retry_after {
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
         res
     }
 }
@@ -54,9 +111,21 @@ where
 {
     #[tracing::instrument(level = "trace", skip(err))]
     fn from(err: E) -> Self {
+        let err = err.into();
+        if let Some(inference_err) = err.downcast_ref::<InferenceError>() {
+            return Self {
+                status: inference_err.status(),
+                message: HttpErrorResponse::from(inference_err.to_string())
+                    .with_code(inference_err.code())
+                    .with_params(inference_err.params()),
+                retry_after: inference_err.retry_after(),
+            };
+        }
+
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: HttpErrorResponse::from(err.into().to_string()),
+            message: HttpErrorResponse::from(err.to_string()),
+            retry_after: None,
         }
     }
 }
@@ -66,15 +135,16 @@ pub type ModelResult<T, E = ModelRunnerError> = Result<T, E>;
 #[macro_export]
 macro_rules! bail_runner {
     ($error_message:expr) => {
-        return Err($crate::error::ModelRunnerError { status: StatusCode::INTERNAL_SERVER_ERROR, message: HttpErrorResponse::from($error_message) })
+        return Err($crate::error::ModelRunnerError { status: StatusCode::INTERNAL_SERVER_ERROR, message: HttpErrorResponse::from($error_message), retry_after: None })
     };
     ($status_code:expr, $error_message:expr) => {
-        return Err($crate::error::ModelRunnerError { status: $status_code, message: HttpErrorResponse::from($error_message) })
+        return Err($crate::error::ModelRunnerError { status: $status_code, message: HttpErrorResponse::from($error_message), retry_after: None })
     };
     ($status_code:expr, $fmt:expr $(, $arg:expr)*) => {
         return Err(ModelRunnerError {
             status: $status_code,
             message: HttpErrorResponse::from(format!($fmt $(, $arg)*)),
+            retry_after: None,
         })
     };
 }
@@ -82,15 +152,16 @@ macro_rules! bail_runner {
 #[macro_export]
 macro_rules! runner {
     ($error_message:expr) => {
-        $crate::error::ModelRunnerError { status: StatusCode::INTERNAL_SERVER_ERROR, message: HttpErrorResponse::from($error_message) }
+        $crate::error::ModelRunnerError { status: StatusCode::INTERNAL_SERVER_ERROR, message: HttpErrorResponse::from($error_message), retry_after: None }
     };
     ($status_code:expr, $error_message:expr) => {
-        $crate::error::ModelRunnerError { status: $status_code, message: HttpErrorResponse::from($error_message) }
+        $crate::error::ModelRunnerError { status: $status_code, message: HttpErrorResponse::from($error_message), retry_after: None }
     };
     ($status_code:expr, $fmt:expr $(, $arg:expr)*) => {
         ModelRunnerError {
             status: $status_code,
             message: HttpErrorResponse::from(format!($fmt $(, $arg)*)),
+            retry_after: None,
         }
     };
 }
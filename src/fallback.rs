@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::inference::model_slot::{ManagedModel, ModelStatus};
+use crate::inference::queue::wait_percentile;
+
+static CHAINS: OnceLock<BTreeMap<String, Vec<String>>> = OnceLock::new();
+/// Queue wait in milliseconds above which a model is skipped for its fallbacks, zero disables it
+static MAX_QUEUE_LATENCY: AtomicU64 = AtomicU64::new(0);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_fallbacks(chains: BTreeMap<String, Vec<String>>, max_queue_latency: Duration) {
+    MAX_QUEUE_LATENCY.store(
+        max_queue_latency.as_millis().try_into().unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+    if CHAINS.set(chains).is_err() {
+        warn!("Fallbacks are already configured");
+    }
+}
+
+/// Returns the model followed by its fallbacks in the order they are tried
+#[tracing::instrument(level = "trace")]
+pub fn fallback_chain(model: &str) -> Vec<String> {
+    let mut chain = vec![model.to_string()];
+    if let Some(fallbacks) = CHAINS.get().and_then(|chains| chains.get(model)) {
+        chain.extend(
+            fallbacks
+                .iter()
+                .filter(|fallback| *fallback != model)
+                .cloned(),
+        );
+    }
+    chain
+}
+
+/// Whether the model should get the request instead of passing it on to its next fallback
+#[tracing::instrument(level = "trace", skip(model), fields(model = model.name()))]
+pub fn is_preferred(model: &dyn ManagedModel) -> bool {
+    if matches!(
+        model.status(),
+        ModelStatus::Degraded | ModelStatus::Unhealthy | ModelStatus::Offline
+    ) {
+        return false;
+    }
+    let max_latency = MAX_QUEUE_LATENCY.load(Ordering::Relaxed);
+    max_latency == 0
+        || wait_percentile(Some(model.name()), 95)
+            .is_none_or(|wait| wait <= Duration::from_millis(max_latency))
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use hf_hub::api::sync::{Api, ApiRepo};
+use hf_hub::{Cache, CacheRepo, Repo};
+use tracing::info;
+
+/// A source that model artifacts such as weights, tokenizers and configs are loaded from
+pub trait ArtifactStore: Send + Sync {
+    /// Returns the local path of the artifact, fetching it first if required
+    fn get(&self, filename: &str) -> Result<PathBuf>;
+}
+
+/// Where all model loaders fetch their artifacts from
+#[derive(Debug, Clone)]
+pub enum ArtifactSource {
+    /// The Hugging Face hub, using its local cache
+    Hub,
+    /// A local directory with one sub directory per repository id
+    Directory(PathBuf),
+    /// An HTTP server or bucket serving `<url>/<repo id>/<revision>/<filename>`
+    Http(String),
+}
+
+static SOURCE: OnceLock<ArtifactSource> = OnceLock::new();
+/// Number of artifacts downloaded at the same time, zero is unlimited
+static MAX_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes per second shared by all downloads from an HTTP source, zero is unlimited
+static BANDWIDTH_LIMIT: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_DOWNLOADS: Mutex<usize> = Mutex::new(0);
+static DOWNLOAD_FINISHED: Condvar = Condvar::new();
+/// Cache folders of the hub repositories opened at their `main` revision by model loaders
+static MAIN_REPOS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Parses `hub`, `dir:<path>` or an `http(s)://` base url
+#[tracing::instrument(level = "trace")]
+pub fn parse_source(source: &str) -> Result<ArtifactSource> {
+    if source == "hub" {
+        Ok(ArtifactSource::Hub)
+    } else if let Some(path) = source.strip_prefix("dir:") {
+        Ok(ArtifactSource::Directory(path.into()))
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        Ok(ArtifactSource::Http(source.trim_end_matches('/').into()))
+    } else {
+        bail!("Unknown artifact source {source}, expected hub, dir:<path> or an http(s) url")
+    }
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_artifacts(source: ArtifactSource) {
+    if SOURCE.set(source).is_err() {
+        tracing::warn!("Artifact source is already configured");
+    }
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_downloads(max_concurrent: usize, bandwidth_limit: u64) {
+    MAX_DOWNLOADS.store(max_concurrent, Ordering::Relaxed);
+    BANDWIDTH_LIMIT.store(bandwidth_limit, Ordering::Relaxed);
+}
+
+/// Holds one of the concurrent download slots until dropped
+struct DownloadPermit;
+
+impl DownloadPermit {
+    /// Blocks until fewer than the maximum number of downloads are running
+    #[tracing::instrument(level = "trace")]
+    fn acquire() -> Self {
+        let max = MAX_DOWNLOADS.load(Ordering::Relaxed);
+        let mut active = ACTIVE_DOWNLOADS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        while max > 0 && *active >= max {
+            info!("Waiting for one of {} running downloads to finish", *active);
+            active = DOWNLOAD_FINISHED
+                .wait(active)
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+        }
+        *active += 1;
+        Self
+    }
+
+    /// The share of the bandwidth limit of every running download
+    fn bandwidth() -> u64 {
+        let active = *ACTIVE_DOWNLOADS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        BANDWIDTH_LIMIT.load(Ordering::Relaxed) / u64::try_from(active.max(1)).unwrap_or(1)
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        let mut active = ACTIVE_DOWNLOADS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *active = active.saturating_sub(1);
+        drop(active);
+        DOWNLOAD_FINISHED.notify_one();
+    }
+}
+
+/// Copies the body to the file, sleeping whenever it runs ahead of its share of the bandwidth
+#[tracing::instrument(level = "trace", skip(body, file))]
+#[allow(clippy::cast_precision_loss)]
+fn throttled_copy(body: &mut impl Read, file: &mut impl Write) -> Result<()> {
+    let started = Instant::now();
+    let mut copied = 0u64;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = body.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        file.write_all(&buffer[..read])?;
+        copied += read as u64;
+        let bandwidth = DownloadPermit::bandwidth();
+        if bandwidth > 0 {
+            let due = Duration::from_secs_f64(copied as f64 / bandwidth as f64);
+            if let Some(ahead) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(ahead);
+            }
+        }
+    }
+}
+
+/// Opens the repository in the configured artifact source
+#[tracing::instrument(level = "trace", skip(api, repo), fields(repo = repo.url()))]
+pub fn open_repo(api: &Api, repo: Repo) -> Box<dyn ArtifactStore> {
+    match SOURCE.get().unwrap_or(&ArtifactSource::Hub) {
+        ArtifactSource::Hub => {
+            if repo.revision() == "main" {
+                MAIN_REPOS
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(repo.folder_name());
+            }
+            Box::new(HubStore {
+                cache: Cache::default().repo(repo.clone()),
+                repo: api.repo(repo),
+            })
+        }
+        ArtifactSource::Directory(root) => Box::new(DirectoryStore {
+            root: root.join(repo.url()),
+        }),
+        ArtifactSource::Http(base_url) => Box::new(HttpStore {
+            url: format!("{base_url}/{}/{}", repo.url(), repo.revision()),
+            cache_dir: Cache::default()
+                .path()
+                .join("http")
+                .join(repo.folder_name())
+                .join(repo.revision()),
+        }),
+    }
+}
+
+/// Returns the cache folders of the hub repositories that model loaders opened at `main`
+pub fn main_repo_folders() -> Vec<String> {
+    MAIN_REPOS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .cloned()
+        .collect()
+}
+
+struct HubStore {
+    repo: ApiRepo,
+    cache: CacheRepo,
+}
+
+impl ArtifactStore for HubStore {
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get(&self, filename: &str) -> Result<PathBuf> {
+        if let Some(path) = self.cache.get(filename) {
+            return Ok(path);
+        }
+        let _permit = DownloadPermit::acquire();
+        Ok(self.repo.get(filename)?)
+    }
+}
+
+struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl ArtifactStore for DirectoryStore {
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get(&self, filename: &str) -> Result<PathBuf> {
+        let path = self.root.join(filename);
+        if !path.is_file() {
+            bail!("Artifact {} not found", path.display());
+        }
+        Ok(path)
+    }
+}
+
+struct HttpStore {
+    url: String,
+    cache_dir: PathBuf,
+}
+
+impl ArtifactStore for HttpStore {
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get(&self, filename: &str) -> Result<PathBuf> {
+        let path = self.cache_dir.join(filename);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let url = format!("{}/{}", self.url, filename);
+        let _permit = DownloadPermit::acquire();
+        info!("Downloading artifact {}", url);
+        let mut response = reqwest::blocking::get(&url)
+            .and_then(reqwest::blocking::Response::error_for_status)
+            .with_context(|| format!("Failed to download artifact {url}"))?;
+
+        // Download next to the target first so that an aborted download is never picked up
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.cache_dir))?;
+        let partial = path.with_extension("part");
+        let mut file = std::fs::File::create(&partial)?;
+        throttled_copy(&mut response, &mut file)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hf_hub::RepoType;
+
+    use super::*;
+
+    #[test]
+    fn tracks_only_repos_opened_at_main() {
+        let api = Api::new().unwrap();
+        open_repo(
+            &api,
+            Repo::with_revision("test/main".into(), RepoType::Model, "main".into()),
+        );
+        open_repo(
+            &api,
+            Repo::with_revision("test/pinned".into(), RepoType::Model, "5eef2ce".into()),
+        );
+        let folders = main_repo_folders();
+        assert!(folders.contains(&"models--test--main".to_string()));
+        assert!(!folders.contains(&"models--test--pinned".to_string()));
+    }
+}
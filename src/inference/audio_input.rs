@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use symphonia::core::io::MediaSource;
+
+/// Uploaded audio, either held in memory or spooled to a temporary file
+#[derive(Debug)]
+pub enum AudioInput {
+    Memory(Box<[u8]>),
+    Spooled(SpooledFile),
+}
+
+impl AudioInput {
+    #[tracing::instrument(level = "trace")]
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Memory(bytes) => bytes.is_empty(),
+            Self::Spooled(file) => file.len == 0,
+        }
+    }
+
+    /// Opens the audio for decoding, a spooled file is removed once the source is dropped
+    #[tracing::instrument(level = "trace")]
+    pub fn into_media_source(self) -> Result<Box<dyn MediaSource>> {
+        Ok(match self {
+            Self::Memory(bytes) => Box::new(Cursor::new(bytes)),
+            Self::Spooled(spooled) => Box::new(SpooledSource {
+                file: File::open(&spooled.path)?,
+                spooled,
+            }),
+        })
+    }
+}
+
+/// A temporary file that is removed when dropped, including when the upload is aborted
+#[derive(Debug)]
+pub struct SpooledFile {
+    pub path: PathBuf,
+    pub len: u64,
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!(
+                "Failed to remove spooled upload {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+struct SpooledSource {
+    file: File,
+    spooled: SpooledFile,
+}
+
+impl Read for SpooledSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for SpooledSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl MediaSource for SpooledSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.spooled.len)
+    }
+}
@@ -1,10 +1,8 @@
 #![allow(clippy::cast_precision_loss)]
 #![allow(clippy::cast_possible_truncation)]
 
-use std::io::Cursor;
-
-use anyhow::{bail, Result};
-use candle_core::{Device, IndexOp, Tensor, D};
+use anyhow::{anyhow, bail, Result};
+use candle_core::{DType, Device, IndexOp, Tensor, D};
 use candle_nn::ops::softmax;
 use candle_transformers::models::whisper;
 use candle_transformers::models::whisper::quantized_model::Whisper;
@@ -13,14 +11,21 @@ use candle_transformers::models::whisper::{
     NO_SPEECH_THRESHOLD, NO_SPEECH_TOKENS, NO_TIMESTAMPS_TOKEN, SAMPLE_RATE, SOT_TOKEN,
     TEMPERATURES, TRANSCRIBE_TOKEN, TRANSLATE_TOKEN,
 };
-use candle_transformers::quantized_var_builder::VarBuilder;
-use hf_hub::api::sync::ApiRepo;
 use rand::distributions::Distribution;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::{debug, error};
 
+use crate::inference::artifact_store::ArtifactStore;
+use crate::inference::audio_input::AudioInput;
+use crate::inference::audio_quality::{analyze, AudioWarning};
+use crate::inference::error::InferenceError;
 use crate::inference::pcm_decode::pcm_decode;
+use crate::inference::runtime::{
+    current_device, gguf_file_quantization, gguf_var_builder, RuntimeInfo,
+};
+use crate::inference::watchdog;
+use crate::limits::max_audio_duration;
 
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples/examples/whisper/main.rs
 
@@ -30,6 +35,8 @@ pub struct AudioGeneratorPipeline {
     tokenizer: Tokenizer,
     config: Config,
     mel_filters: Vec<f32>,
+    device: Device,
+    /// Additive logit mask for the suppressed tokens, on the same device as the model
     suppress_tokens: Tensor,
     sot_token: u32,
     transcribe_token: u32,
@@ -39,6 +46,9 @@ pub struct AudioGeneratorPipeline {
     no_timestamps_token: u32,
     timestamps: bool,
     seed: rand::rngs::StdRng,
+    quantization: String,
+    /// The data type of the activations of the last encoding
+    dtype: Option<DType>,
 }
 
 impl Clone for AudioGeneratorPipeline {
@@ -49,6 +59,7 @@ impl Clone for AudioGeneratorPipeline {
             tokenizer: self.tokenizer.clone(),
             config: self.config.clone(),
             mel_filters: self.mel_filters.clone(),
+            device: self.device.clone(),
             suppress_tokens: self.suppress_tokens.clone(),
             sot_token: self.sot_token,
             transcribe_token: self.transcribe_token,
@@ -58,6 +69,8 @@ impl Clone for AudioGeneratorPipeline {
             no_timestamps_token: self.no_timestamps_token,
             timestamps: self.timestamps,
             seed: self.seed.clone(),
+            quantization: self.quantization.clone(),
+            dtype: self.dtype,
         }
     }
 }
@@ -65,7 +78,7 @@ impl Clone for AudioGeneratorPipeline {
 impl AudioGeneratorPipeline {
     #[tracing::instrument(level = "trace", skip(repo))]
     pub fn with_gguf_model(
-        repo: &ApiRepo,
+        repo: &dyn ArtifactStore,
         config_filename: &str,
         tokenizer_filename: &str,
         gguf_filename: &str,
@@ -78,9 +91,12 @@ impl AudioGeneratorPipeline {
         let model_path = repo.get(gguf_filename)?;
 
         let config: Config = serde_json::from_str(&std::fs::read_to_string(config_path)?)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_path).unwrap();
+        let tokenizer = Tokenizer::from_file(tokenizer_path)
+            .map_err(|e| InferenceError::TokenizerLoad(e.to_string()))?;
 
-        let vb = VarBuilder::from_gguf(model_path, &Device::Cpu)?;
+        let device = current_device();
+        let quantization = gguf_file_quantization(&model_path)?;
+        let vb = gguf_var_builder(&model_path, &device)?;
         let model = Whisper::load(&vb, config.clone())?;
 
         let mel_bytes = &*std::fs::read(mel_filters_filename)?;
@@ -91,18 +107,8 @@ impl AudioGeneratorPipeline {
         );
 
         let no_timestamps_token = token_id(&tokenizer, NO_TIMESTAMPS_TOKEN)?;
-        let suppress_tokens: Vec<f32> = (0..model.config.vocab_size as u32)
-            .map(|i| {
-                if model.config.suppress_tokens.contains(&i)
-                    || timestamps && i == no_timestamps_token
-                {
-                    f32::NEG_INFINITY
-                } else {
-                    0f32
-                }
-            })
-            .collect();
-        let suppress_tokens = Tensor::new(suppress_tokens.as_slice(), &Device::Cpu)?;
+        let suppress_tokens =
+            suppress_tokens_mask(&model.config, no_timestamps_token, timestamps, &device)?;
         let start_of_transcript_token = token_id(&tokenizer, SOT_TOKEN)?;
         let transcribe_token = token_id(&tokenizer, TRANSCRIBE_TOKEN)?;
         let translate_token = token_id(&tokenizer, TRANSLATE_TOKEN)?;
@@ -120,6 +126,7 @@ impl AudioGeneratorPipeline {
             tokenizer,
             config,
             mel_filters,
+            device,
             suppress_tokens,
             sot_token: start_of_transcript_token,
             transcribe_token,
@@ -129,12 +136,45 @@ impl AudioGeneratorPipeline {
             no_timestamps_token,
             timestamps,
             seed,
+            quantization,
+            dtype: None,
         })
     }
 
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn runtime_info(&self) -> RuntimeInfo {
+        RuntimeInfo::new(&self.device, self.dtype, &self.quantization)
+    }
+
+    /// Transcribes the audio, returning its segments, the duration of the audio in seconds and
+    /// warnings about its quality
     #[tracing::instrument(level = "trace", skip(input))]
-    pub fn transcribe(&mut self, input: Box<[u8]>, language_token: &str) -> Result<Vec<Segment>> {
-        let mel = self.load_mel(input)?;
+    pub fn transcribe(
+        &mut self,
+        input: AudioInput,
+        language_token: &str,
+        max_decode_steps: Option<usize>,
+    ) -> Result<(Vec<Segment>, f64, Vec<AudioWarning>)> {
+        let pcm_data = self.load_pcm(input)?;
+        let warnings = analyze(&pcm_data);
+        if !warnings.is_empty() {
+            debug!("audio quality warnings {warnings:?}");
+        }
+        let (segments, duration) =
+            self.transcribe_samples(&pcm_data, language_token, max_decode_steps)?;
+        Ok((segments, duration, warnings))
+    }
+
+    /// Transcribes mono samples at the sampling rate of the model, returning the segments and the
+    /// duration of the samples in seconds
+    #[tracing::instrument(level = "trace", skip(self, pcm_data))]
+    pub fn transcribe_samples(
+        &mut self,
+        pcm_data: &[f32],
+        language_token: &str,
+        max_decode_steps: Option<usize>,
+    ) -> Result<(Vec<Segment>, f64)> {
+        let mel = self.pcm_to_mel(pcm_data)?;
         let (_, _, content_frames) = mel.dims3()?;
         let mut seek = 0;
         let mut segments = vec![];
@@ -147,7 +187,7 @@ impl AudioGeneratorPipeline {
             let segment_size = usize::min(content_frames - seek, whisper::N_FRAMES);
             let mel_segment = mel.narrow(2, seek, segment_size)?;
             let segment_duration = (segment_size * HOP_LENGTH) as f64 / SAMPLE_RATE as f64;
-            let dr = self.decode_with_fallback(&mel_segment, language_token)?;
+            let dr = self.decode_with_fallback(&mel_segment, language_token, max_decode_steps)?;
             seek += segment_size;
             if dr.no_speech_prob > NO_SPEECH_THRESHOLD && dr.avg_logprob < LOGPROB_THRESHOLD {
                 debug!("no speech detected, skipping {seek} {dr:?}");
@@ -160,7 +200,8 @@ impl AudioGeneratorPipeline {
             };
             segments.push(segment);
         }
-        Ok(segments)
+        let audio_duration = (content_frames * HOP_LENGTH) as f64 / SAMPLE_RATE as f64;
+        Ok((segments, audio_duration))
     }
 
     #[tracing::instrument(level = "trace", skip(self, segment, language_token))]
@@ -168,9 +209,11 @@ impl AudioGeneratorPipeline {
         &mut self,
         segment: &Tensor,
         language_token: u32,
+        max_decode_steps: Option<usize>,
     ) -> Result<DecodingResult> {
         for (i, &t) in TEMPERATURES.iter().enumerate() {
-            let dr: Result<DecodingResult> = self.decode(segment, t, language_token);
+            let dr: Result<DecodingResult> =
+                self.decode(segment, t, language_token, max_decode_steps);
             if i == TEMPERATURES.len() - 1 {
                 return dr;
             }
@@ -192,12 +235,25 @@ impl AudioGeneratorPipeline {
     }
 
     #[tracing::instrument(level = "trace", skip(self, mel, t, language_token))]
-    fn decode(&mut self, mel: &Tensor, t: f64, language_token: u32) -> Result<DecodingResult> {
+    fn decode(
+        &mut self,
+        mel: &Tensor,
+        t: f64,
+        language_token: u32,
+        max_decode_steps: Option<usize>,
+    ) -> Result<DecodingResult> {
         let model = &mut self.model;
         let audio_features = model.encoder.forward(mel, true)?;
+        self.dtype = Some(audio_features.dtype());
         debug!("audio features: {:?}", audio_features.dims());
 
-        let sample_len = model.config.max_target_positions / 2;
+        // The requested cap can only lower the number of decoder steps, never raise it above the
+        // limit given by the model itself
+        let model_sample_len = model.config.max_target_positions / 2;
+        let sample_len = max_decode_steps.map_or(model_sample_len, |max_decode_steps| {
+            max_decode_steps.min(model_sample_len)
+        });
+        let mut truncated = max_decode_steps.is_some();
         let mut sum_logprob = 0f64;
         let mut no_speech_prob = f64::NAN;
         let mut tokens = vec![self.sot_token];
@@ -208,6 +264,7 @@ impl AudioGeneratorPipeline {
             tokens.push(self.no_timestamps_token);
         }
         for i in 0..sample_len {
+            watchdog::tick()?;
             let tokens_t = Tensor::new(tokens.as_slice(), mel.device())?;
 
             // The model expects a batch dim but this inference loop does not handle
@@ -245,7 +302,7 @@ impl AudioGeneratorPipeline {
                     .enumerate()
                     .max_by(|(_, u), (_, v)| u.total_cmp(v))
                     .map(|(i, _)| i as u32)
-                    .unwrap()
+                    .ok_or_else(|| anyhow!("Decoder returned empty logits"))?
             };
             tokens.push(next_token);
             let prob = f64::from(
@@ -254,11 +311,15 @@ impl AudioGeneratorPipeline {
                     .to_scalar::<f32>()?,
             );
             if next_token == self.eot_token || tokens.len() > model.config.max_target_positions {
+                truncated = false;
                 break;
             }
             sum_logprob += prob.ln();
         }
-        let text = self.tokenizer.decode(&tokens, true).unwrap();
+        let text = self
+            .tokenizer
+            .decode(&tokens, true)
+            .map_err(|e| InferenceError::Detokenize(e.to_string()))?;
         let avg_logprob = sum_logprob / tokens.len() as f64;
 
         Ok(DecodingResult {
@@ -267,18 +328,28 @@ impl AudioGeneratorPipeline {
             no_speech_prob,
             temperature: t,
             compression_ratio: f64::NAN,
+            truncated: truncated && sample_len < model_sample_len,
         })
     }
 
     #[tracing::instrument(level = "trace", skip(self, input))]
-    fn load_mel(&self, input: Box<[u8]>) -> Result<Tensor> {
-        let cursor = Cursor::new(input);
-        let (pcm_data, sample_rate) = pcm_decode(cursor)?;
+    fn load_pcm(&self, input: AudioInput) -> Result<Vec<f32>> {
+        let (pcm_data, sample_rate) = pcm_decode(input)?;
         if sample_rate != u32::try_from(SAMPLE_RATE)? {
             bail!("Input file must have a {} sampling rate", SAMPLE_RATE)
         }
+        if let Some(max) = max_audio_duration() {
+            if pcm_data.len() as u64 > max * u64::from(sample_rate) {
+                return Err(InferenceError::AudioTooLong(max).into());
+            }
+        }
         debug!("pcm data loaded {}", pcm_data.len());
-        let mel = audio::pcm_to_mel(&self.config, &pcm_data, &self.mel_filters);
+        Ok(pcm_data)
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, pcm_data))]
+    fn pcm_to_mel(&self, pcm_data: &[f32]) -> Result<Tensor> {
+        let mel = audio::pcm_to_mel(&self.config, pcm_data, &self.mel_filters);
         let mel_len = mel.len();
         let mel = Tensor::from_vec(
             mel,
@@ -287,7 +358,7 @@ impl AudioGeneratorPipeline {
                 self.config.num_mel_bins,
                 mel_len / self.config.num_mel_bins,
             ),
-            &Device::Cpu,
+            &self.device,
         )?;
         debug!("loaded mel: {:?}", mel.dims());
         Ok(mel)
@@ -296,18 +367,46 @@ impl AudioGeneratorPipeline {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Segment {
-    start: f64,
-    duration: f64,
-    dr: DecodingResult,
+    pub start: f64,
+    pub duration: f64,
+    pub dr: DecodingResult,
+}
+
+impl Segment {
+    pub fn text(&self) -> &str {
+        &self.dr.text
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DecodingResult {
-    text: String,
-    avg_logprob: f64,
-    no_speech_prob: f64,
-    temperature: f64,
-    compression_ratio: f64,
+    pub text: String,
+    pub avg_logprob: f64,
+    pub no_speech_prob: f64,
+    pub temperature: f64,
+    pub compression_ratio: f64,
+    /// Whether decoding stopped early because the requested `max_decode_steps` were reached
+    pub truncated: bool,
+}
+
+/// Builds the additive logit mask for the suppressed tokens directly on the inference device
+#[tracing::instrument(level = "trace", skip(config))]
+fn suppress_tokens_mask(
+    config: &Config,
+    no_timestamps_token: u32,
+    timestamps: bool,
+    device: &Device,
+) -> Result<Tensor> {
+    let mask: Vec<f32> = (0..config.vocab_size as u32)
+        .map(|i| {
+            if config.suppress_tokens.contains(&i) || timestamps && i == no_timestamps_token {
+                f32::NEG_INFINITY
+            } else {
+                0f32
+            }
+        })
+        .collect();
+    Ok(Tensor::new(mask.as_slice(), device)?)
 }
 
 #[tracing::instrument(level = "trace", skip(tokenizer, token))]
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+/// Magnitude from which a sample is considered clipped
+const CLIPPING_LEVEL: f32 = 0.999;
+/// Share of clipped samples above which the audio is reported as clipped
+const MAX_CLIPPED_RATIO: f64 = 0.001;
+/// RMS in dBFS below which the audio is reported as too quiet
+const MIN_RMS_DBFS: f64 = -45.0;
+/// Mean of the samples beyond which the audio is reported to have a DC offset
+const MAX_DC_OFFSET: f64 = 0.05;
+
+/// Properties of the audio that commonly lead to garbled transcripts
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum AudioWarning {
+    /// A share of the samples hit full scale, distorting the speech
+    Clipping { ratio: f64 },
+    /// The audio is so quiet that speech may not be recognized
+    LowVolume { rms_dbfs: f64 },
+    /// The waveform is shifted away from zero, usually by a faulty microphone or converter
+    DcOffset { offset: f64 },
+}
+
+/// Checks the decoded samples for clipping, a very low volume and a DC offset
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip(samples))]
+pub fn analyze(samples: &[f32]) -> Vec<AudioWarning> {
+    if samples.is_empty() {
+        return vec![];
+    }
+    let count = samples.len() as f64;
+    let clipped = samples
+        .iter()
+        .filter(|sample| sample.abs() >= CLIPPING_LEVEL)
+        .count() as f64;
+    let mean = samples.iter().map(|&sample| f64::from(sample)).sum::<f64>() / count;
+    let rms = (samples
+        .iter()
+        .map(|&sample| f64::from(sample).powi(2))
+        .sum::<f64>()
+        / count)
+        .sqrt();
+    let rms_dbfs = 20.0 * rms.max(f64::MIN_POSITIVE).log10();
+
+    let mut warnings = vec![];
+    if clipped / count > MAX_CLIPPED_RATIO {
+        warnings.push(AudioWarning::Clipping {
+            ratio: clipped / count,
+        });
+    }
+    if rms_dbfs < MIN_RMS_DBFS {
+        warnings.push(AudioWarning::LowVolume { rms_dbfs });
+    }
+    if mean.abs() > MAX_DC_OFFSET {
+        warnings.push(AudioWarning::DcOffset { offset: mean });
+    }
+    warnings
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use tracing::{error, info, warn};
+
+use crate::config::AvailabilityWindow;
+use crate::inference::model_slot::ManagedModel;
+
+static WINDOWS: OnceLock<BTreeMap<String, Window>> = OnceLock::new();
+
+/// The times at which a model is loaded and unloaded again
+struct Window {
+    load: Schedule,
+    unload: Schedule,
+}
+
+impl Window {
+    /// Whether the model was loaded more recently than it was unloaded
+    fn is_open(&self, now: &DateTime<Utc>) -> bool {
+        let loaded = self.load.after(now).next_back();
+        let unloaded = self.unload.after(now).next_back();
+        match (loaded, unloaded) {
+            (Some(loaded), Some(unloaded)) => loaded > unloaded,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Parses the cron expressions of the availability windows of the models
+#[tracing::instrument(level = "info")]
+pub fn configure_availability(windows: &BTreeMap<String, AvailabilityWindow>) -> Result<()> {
+    let windows = windows
+        .iter()
+        .map(|(model, window)| {
+            let parse = |expression: &str| {
+                Schedule::from_str(expression).with_context(|| {
+                    format!("Invalid cron expression for availability of {model}: {expression}")
+                })
+            };
+            Ok((
+                model.clone(),
+                Window {
+                    load: parse(&window.load)?,
+                    unload: parse(&window.unload)?,
+                },
+            ))
+        })
+        .collect::<Result<_>>()?;
+    if WINDOWS.set(windows).is_err() {
+        warn!("Availability windows are already configured");
+    }
+    Ok(())
+}
+
+/// Returns the time until the availability window of the model opens again, if it is closed
+#[tracing::instrument(level = "trace")]
+pub fn closed_for(model: &str) -> Option<Duration> {
+    let window = WINDOWS.get()?.get(model)?;
+    let now = Utc::now();
+    if window.is_open(&now) {
+        return None;
+    }
+    let opens = window.load.after(&now).next()?;
+    Some((opens - now).to_std().unwrap_or_default())
+}
+
+/// Loads and unloads the models each time their availability windows open and close
+#[tracing::instrument(level = "info", skip(models))]
+pub async fn run_availability_schedule(models: Vec<&'static dyn ManagedModel>) {
+    let Some(windows) = WINDOWS.get() else {
+        return;
+    };
+    let mut schedules = tokio::task::JoinSet::new();
+    for model in models {
+        if let Some(window) = windows.get(model.name()) {
+            schedules.spawn(run_window(model, window));
+        }
+    }
+    while schedules.join_next().await.is_some() {}
+}
+
+#[tracing::instrument(level = "info", skip(model, window), fields(model = model.name()))]
+async fn run_window(model: &'static dyn ManagedModel, window: &'static Window) {
+    let mut open = window.is_open(&Utc::now());
+    loop {
+        let result = if open {
+            info!("Availability window of model {} opened", model.name());
+            tokio::task::spawn_blocking(move || model.preload()).await
+        } else {
+            info!("Availability window of model {} closed", model.name());
+            tokio::task::spawn_blocking(move || model.unload()).await
+        };
+        if let Err(err) = result {
+            error!(
+                "Changing availability of model {} failed: {:?}",
+                model.name(),
+                err
+            );
+        }
+
+        let schedule = if open { &window.unload } else { &window.load };
+        let Some(next) = schedule.upcoming(Utc).next() else {
+            warn!(
+                "Availability window of model {} has no upcoming changes",
+                model.name()
+            );
+            return;
+        };
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        open = !open;
+    }
+}
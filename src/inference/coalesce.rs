@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Coalesces identical in-flight requests onto a single execution and fans out its result.
+/// Followers only share successful results, if the leader fails or is cancelled they run on their own.
+pub struct Coalescer<R> {
+    name: &'static str,
+    in_flight: Mutex<BTreeMap<String, broadcast::Sender<R>>>,
+}
+
+impl<R: Clone> Coalescer<R> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            in_flight: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Runs the task unless an identical one is already in flight, in which case its result is reused
+    #[tracing::instrument(level = "trace", skip(self, key, task), fields(coalescer = self.name))]
+    pub async fn run<E, F, Fut>(&self, key: String, task: F) -> Result<R, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+    {
+        let follower = {
+            let mut in_flight = self.in_flight();
+            let follower = in_flight.get(&key).map(broadcast::Sender::subscribe);
+            if follower.is_none() {
+                in_flight.insert(key.clone(), broadcast::channel(1).0);
+            }
+            follower
+        };
+
+        if let Some(mut receiver) = follower {
+            if let Ok(result) = receiver.recv().await {
+                info!(
+                    monotonic_counter.requests_coalesced = 1,
+                    coalescer = self.name
+                );
+                return Ok(result);
+            }
+            return task().await;
+        }
+
+        let mut leader = Leader {
+            coalescer: self,
+            key,
+            finished: false,
+        };
+        let result = task().await;
+        if let Ok(result) = &result {
+            let sender = self.in_flight().remove(&leader.key);
+            if let Some(sender) = sender {
+                // Sending only fails without followers which is fine
+                let _ = sender.send(result.clone());
+            }
+            leader.finished = true;
+        }
+        result
+    }
+
+    fn in_flight(&self) -> MutexGuard<'_, BTreeMap<String, broadcast::Sender<R>>> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Removes the in-flight entry if the leader fails or is cancelled,
+/// closing the channel so that waiting followers stop waiting for a result
+struct Leader<'a, R: Clone> {
+    coalescer: &'a Coalescer<R>,
+    key: String,
+    finished: bool,
+}
+
+impl<R: Clone> Drop for Leader<'_, R> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.coalescer.in_flight().remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    /// Runs a task counting its executions that finishes once `release` is notified
+    async fn run(
+        coalescer: &Coalescer<usize>,
+        runs: &AtomicUsize,
+        release: &Notify,
+        result: Result<usize, ()>,
+    ) -> Result<usize, ()> {
+        coalescer
+            .run("key".to_string(), || async {
+                runs.fetch_add(1, Ordering::SeqCst);
+                release.notified().await;
+                result
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn followers_share_the_result_of_the_leader() {
+        let coalescer = Coalescer::new("test");
+        let runs = AtomicUsize::new(0);
+        let release = Notify::new();
+        let release_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            release.notify_waiters();
+        };
+        let (first, second, ()) = tokio::join!(
+            run(&coalescer, &runs, &release, Ok(1)),
+            run(&coalescer, &runs, &release, Ok(2)),
+            release_later
+        );
+        assert_eq!((first, second), (Ok(1), Ok(1)));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(coalescer.in_flight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn followers_run_on_their_own_if_the_leader_fails() {
+        let coalescer = Coalescer::new("test");
+        let runs = AtomicUsize::new(0);
+        let release = Notify::new();
+        let release_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            release.notify_waiters();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            release.notify_waiters();
+        };
+        let (first, second, ()) = tokio::join!(
+            run(&coalescer, &runs, &release, Err(())),
+            run(&coalescer, &runs, &release, Ok(2)),
+            release_later
+        );
+        assert_eq!((first, second), (Err(()), Ok(2)));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(coalescer.in_flight().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::{Display, Formatter};
+
+use axum::http::StatusCode;
+
+/// Errors caused by the input of an inference request, each mapped to a stable machine-readable code
+#[derive(Debug)]
+pub enum InferenceError {
+    /// The audio container could not be probed
+    UnsupportedContainer(String),
+    /// The container holds no track with a known codec
+    NoAudioTrack,
+    /// The codec of the audio track is not supported
+    UnsupportedCodec(String),
+    /// A packet of the audio track could not be decoded
+    CorruptAudio(String),
+    /// The upload or the decoded audio track contains no samples
+    EmptyAudio,
+    /// The decoded audio track is longer than the configured maximum of seconds
+    AudioTooLong(u64),
+    /// The tokenizer file of a model could not be loaded
+    TokenizerLoad(String),
+    /// The input could not be encoded into tokens
+    Tokenize(String),
+    /// The generated tokens could not be decoded into text
+    Detokenize(String),
+    /// The model failed to load and is marked as degraded
+    ModelUnavailable { model: &'static str, reason: String },
+    /// The inference worker panicked while running the request
+    WorkerPanic(String),
+    /// The circuit breaker of the model is open and rejects requests for `retry_after` seconds
+    ModelUnhealthy {
+        model: &'static str,
+        retry_after: u64,
+    },
+    /// The model is outside of its availability window, which opens in `retry_after` seconds
+    ModelOffline {
+        model: &'static str,
+        retry_after: u64,
+    },
+    /// The watchdog aborted the generation as it made no progress
+    GenerationStalled,
+    /// The client stopped receiving the streamed tokens
+    StreamClosed,
+}
+
+impl InferenceError {
+    pub const fn status(&self) -> StatusCode {
+        match self {
+            Self::UnsupportedContainer(_)
+            | Self::NoAudioTrack
+            | Self::UnsupportedCodec(_)
+            | Self::CorruptAudio(_)
+            | Self::EmptyAudio
+            | Self::Tokenize(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::StreamClosed => StatusCode::BAD_REQUEST,
+            Self::AudioTooLong(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TokenizerLoad(_)
+            | Self::Detokenize(_)
+            | Self::WorkerPanic(_)
+            | Self::GenerationStalled => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ModelUnavailable { .. }
+            | Self::ModelUnhealthy { .. }
+            | Self::ModelOffline { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::UnsupportedContainer(_) => "audio_unsupported_container",
+            Self::NoAudioTrack => "audio_no_track",
+            Self::UnsupportedCodec(_) => "audio_unsupported_codec",
+            Self::CorruptAudio(_) => "audio_corrupt",
+            Self::EmptyAudio => "audio_empty",
+            Self::AudioTooLong(_) => "audio_too_long",
+            Self::TokenizerLoad(_) => "tokenizer_load_failed",
+            Self::Tokenize(_) => "tokenize_failed",
+            Self::Detokenize(_) => "detokenize_failed",
+            Self::ModelUnavailable { .. } => "model_unavailable",
+            Self::WorkerPanic(_) => "worker_panic",
+            Self::ModelUnhealthy { .. } => "model_unhealthy",
+            Self::ModelOffline { .. } => "model_offline",
+            Self::GenerationStalled => "generation_stalled",
+            Self::StreamClosed => "stream_closed",
+        }
+    }
+
+    /// Dynamic details of the error in the order they are interpolated into its localized message
+    pub fn params(&self) -> Vec<String> {
+        match self {
+            Self::UnsupportedContainer(err)
+            | Self::UnsupportedCodec(err)
+            | Self::CorruptAudio(err)
+            | Self::TokenizerLoad(err)
+            | Self::Tokenize(err)
+            | Self::Detokenize(err)
+            | Self::WorkerPanic(err) => vec![err.clone()],
+            Self::AudioTooLong(max) => vec![max.to_string()],
+            Self::ModelUnavailable { model, reason } => vec![(*model).to_string(), reason.clone()],
+            Self::ModelUnhealthy { model, .. } | Self::ModelOffline { model, .. } => {
+                vec![(*model).to_string()]
+            }
+            Self::NoAudioTrack
+            | Self::EmptyAudio
+            | Self::GenerationStalled
+            | Self::StreamClosed => Vec::new(),
+        }
+    }
+
+    /// Seconds after which the client may retry the request
+    pub const fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::ModelUnhealthy { retry_after, .. } | Self::ModelOffline { retry_after, .. } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for InferenceError {
+    #[tracing::instrument(level = "trace", skip(f))]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedContainer(err) => {
+                write!(f, "Unable to probe the audio container: {err}")
+            }
+            Self::NoAudioTrack => write!(f, "No supported audio track found"),
+            Self::UnsupportedCodec(err) => write!(f, "Unsupported audio codec: {err}"),
+            Self::CorruptAudio(err) => write!(f, "Failed to decode audio: {err}"),
+            Self::EmptyAudio => write!(f, "Audio content is empty"),
+            Self::AudioTooLong(max) => {
+                write!(f, "Audio content must not be longer than {max} seconds")
+            }
+            Self::TokenizerLoad(err) => write!(f, "Failed to load tokenizer: {err}"),
+            Self::Tokenize(err) => write!(f, "Failed to tokenize input: {err}"),
+            Self::Detokenize(err) => write!(f, "Failed to decode tokens: {err}"),
+            Self::ModelUnavailable { model, reason } => {
+                write!(f, "Model {model} is unavailable: {reason}")
+            }
+            Self::WorkerPanic(err) => write!(f, "Inference worker panicked: {err}"),
+            Self::ModelUnhealthy { model, .. } => {
+                write!(f, "Model {model} is unhealthy due to repeated failures")
+            }
+            Self::ModelOffline { model, .. } => {
+                write!(f, "Model {model} is outside of its availability window")
+            }
+            Self::GenerationStalled => write!(f, "Generation was aborted as it made no progress"),
+            Self::StreamClosed => write!(f, "Generation was aborted as the stream was closed"),
+        }
+    }
+}
+
+impl std::error::Error for InferenceError {}
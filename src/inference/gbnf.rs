@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::inference::grammar::{Element, Grammar};
+
+/// Upper bound of counted repetitions, each repetition adds a rule
+const MAX_REPETITIONS: usize = 1000;
+
+/// Parses a grammar in the GBNF format of llama.cpp, starting at the rule named `root`
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn parse_gbnf(text: &str) -> Result<Grammar> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        grammar: Grammar::default(),
+        names: HashMap::new(),
+        defined: HashSet::new(),
+    };
+    loop {
+        parser.skip_space();
+        if parser.peek().is_none() {
+            break;
+        }
+        let name = parser.name()?;
+        parser.skip_space();
+        if !parser.consume("::=") {
+            bail!("Expected ::= after the name of rule {name}");
+        }
+        let alternatives = parser.alternatives(false)?;
+        let rule = parser.rule(&name);
+        if !parser.defined.insert(name.clone()) {
+            bail!("Rule {name} is defined more than once");
+        }
+        parser.grammar.define(rule, alternatives);
+    }
+
+    let Some(root) = parser.names.get("root") else {
+        bail!("The grammar has no root rule");
+    };
+    if let Some(name) = parser
+        .names
+        .keys()
+        .find(|name| !parser.defined.contains(*name))
+    {
+        bail!("Rule {name} is referenced but not defined");
+    }
+    parser.grammar.set_root(*root);
+    parser.grammar.check()?;
+    Ok(parser.grammar)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    grammar: Grammar,
+    /// Rules by name, including the ones referenced before their definition
+    names: HashMap<String, usize>,
+    defined: HashSet<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self
+            .peek()
+            .ok_or_else(|| anyhow!("Unexpected end of the grammar"))?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn consume(&mut self, text: &str) -> bool {
+        let matches = text
+            .chars()
+            .enumerate()
+            .all(|(offset, c)| self.chars.get(self.position + offset) == Some(&c));
+        if matches {
+            self.position += text.chars().count();
+        }
+        matches
+    }
+
+    /// Skips whitespace, newlines and comments
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.position += 1;
+                }
+            } else if c.is_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.position += 1;
+        }
+        if start == self.position {
+            bail!("Expected a rule name at position {}", self.position);
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    /// Returns whether a rule definition starts at the position, which ends the previous rule
+    fn at_definition(&mut self) -> bool {
+        let start = self.position;
+        let is_definition = self.name().is_ok() && {
+            self.skip_space();
+            self.consume("::=")
+        };
+        self.position = start;
+        is_definition
+    }
+
+    fn rule(&mut self, name: &str) -> usize {
+        if let Some(rule) = self.names.get(name) {
+            return *rule;
+        }
+        let rule = self.grammar.reserve();
+        self.names.insert(name.to_string(), rule);
+        rule
+    }
+
+    fn alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alternatives = vec![self.sequence(nested)?];
+        while self.consume("|") {
+            alternatives.push(self.sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Vec<Element>> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_space();
+            let item = match self.peek() {
+                None | Some('|') => break,
+                Some(')') if nested => break,
+                Some(')') => bail!("Unexpected ) at position {}", self.position),
+                Some('"') => {
+                    self.position += 1;
+                    let mut literal = Vec::new();
+                    loop {
+                        match self.next()? {
+                            '"' => break,
+                            '\\' => literal.push(Element::char(self.escape()?)),
+                            c => literal.push(Element::char(c)),
+                        }
+                    }
+                    literal
+                }
+                Some('[') => {
+                    self.position += 1;
+                    vec![self.class()?]
+                }
+                Some('.') => {
+                    self.position += 1;
+                    vec![Element::Chars {
+                        ranges: Vec::new(),
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.position += 1;
+                    let alternatives = self.alternatives(true)?;
+                    if !self.consume(")") {
+                        bail!("Expected ) at position {}", self.position);
+                    }
+                    vec![Element::Rule(self.grammar.rule(alternatives))]
+                }
+                Some(_) if self.at_definition() => break,
+                Some(_) => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.rule(&name))]
+                }
+            };
+            let item = self.repetition(item)?;
+            sequence.extend(item);
+        }
+        Ok(sequence)
+    }
+
+    /// Parses a character class after its opening bracket
+    fn class(&mut self) -> Result<Element> {
+        let negated = self.consume("^");
+        let mut ranges = Vec::new();
+        loop {
+            let start = match self.next()? {
+                ']' => break,
+                '\\' => self.escape()?,
+                c => c,
+            };
+            let end = if self.peek() == Some('-') && self.chars.get(self.position + 1) != Some(&']')
+            {
+                self.position += 1;
+                match self.next()? {
+                    '\\' => self.escape()?,
+                    c => c,
+                }
+            } else {
+                start
+            };
+            ranges.push((start, end));
+        }
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    /// Parses an escape sequence after its backslash
+    fn escape(&mut self) -> Result<char> {
+        let digits = match self.next()? {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            c => return Ok(c),
+        };
+        let mut code = 0;
+        for _ in 0..digits {
+            let digit = self.next()?;
+            code = code * 16
+                + digit
+                    .to_digit(16)
+                    .ok_or_else(|| anyhow!("Invalid hex digit {digit} in escape sequence"))?;
+        }
+        char::from_u32(code).ok_or_else(|| anyhow!("Invalid character code {code:x}"))
+    }
+
+    /// Applies the repetition operator following the item if there is one
+    fn repetition(&mut self, item: Vec<Element>) -> Result<Vec<Element>> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.position += 1;
+                let min = self.count()?.unwrap_or(0);
+                let max = if self.consume(",") {
+                    self.count()?
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') {
+                    bail!("Expected }} at position {}", self.position);
+                }
+                if max.unwrap_or(min) > MAX_REPETITIONS {
+                    bail!("Repetitions are limited to {MAX_REPETITIONS}");
+                }
+                if max.is_some_and(|max| max < min) {
+                    bail!("Invalid repetition {{{min},{}}}", max.unwrap_or_default());
+                }
+                (min, max)
+            }
+            _ => return Ok(item),
+        };
+        self.position += 1;
+
+        let element = match item.as_slice() {
+            [element] => element.clone(),
+            _ => Element::Rule(self.grammar.rule(vec![item])),
+        };
+        let mut sequence = vec![element.clone(); min];
+        match max {
+            None => {
+                let many = self.grammar.reserve();
+                self.grammar
+                    .define(many, vec![vec![element, Element::Rule(many)], Vec::new()]);
+                sequence.push(Element::Rule(many));
+            }
+            Some(max) if max > min => {
+                let mut optional = self.grammar.rule(vec![vec![element.clone()], Vec::new()]);
+                for _ in min + 1..max {
+                    optional = self.grammar.rule(vec![
+                        vec![element.clone(), Element::Rule(optional)],
+                        Vec::new(),
+                    ]);
+                }
+                sequence.push(Element::Rule(optional));
+            }
+            Some(_) => {}
+        }
+        Ok(sequence)
+    }
+
+    /// Parses the count of a repetition, which may be left out
+    fn count(&mut self) -> Result<Option<usize>> {
+        self.skip_space();
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        let count = if start == self.position {
+            None
+        } else {
+            let digits: String = self.chars[start..self.position].iter().collect();
+            Some(digits.parse()?)
+        };
+        self.skip_space();
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::inference::grammar::GrammarState;
+
+    fn start(text: &str) -> GrammarState {
+        Arc::new(parse_gbnf(text).unwrap()).start().unwrap()
+    }
+
+    fn matches(state: &GrammarState, text: &str) -> bool {
+        if text.is_empty() {
+            return state.is_complete();
+        }
+        state
+            .accept_str(text)
+            .is_some_and(|state| state.is_complete())
+    }
+
+    #[test]
+    fn parses_rules_literals_and_comments() {
+        let state = start(
+            r#"
+            # A greeting
+            root ::= greeting " " name
+            greeting ::= "hello" | "hi"
+            name ::= "world"
+            "#,
+        );
+        assert!(matches(&state, "hello world"));
+        assert!(matches(&state, "hi world"));
+        assert!(!matches(&state, "hey world"));
+    }
+
+    #[test]
+    fn parses_character_classes() {
+        let state = start(r"root ::= [a-c] [^0-9] .");
+        assert!(matches(&state, "bxy"));
+        assert!(!matches(&state, "dxy"));
+        assert!(!matches(&state, "b1y"));
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let state = start(r#"root ::= "\n\t\x41é" [\]]"#);
+        assert!(matches(&state, "\n\tAé]"));
+    }
+
+    #[test]
+    fn parses_repetitions() {
+        let state = start(r#"root ::= "a"* "b"+ "c"? ("d" "e"){2} "f"{1,2}"#);
+        assert!(matches(&state, "bdedef"));
+        assert!(matches(&state, "aabbbcdedeff"));
+        assert!(!matches(&state, "dedef"));
+        assert!(!matches(&state, "bdef"));
+        assert!(!matches(&state, "bdedefff"));
+    }
+
+    #[test]
+    fn rules_may_be_referenced_before_their_definition() {
+        let state = start("root ::= digit+\ndigit ::= [0-9]");
+        assert!(matches(&state, "42"));
+    }
+
+    #[test]
+    fn rejects_invalid_grammars() {
+        assert!(parse_gbnf(r#"start ::= "a""#).is_err());
+        assert!(parse_gbnf(r"root ::= missing").is_err());
+        assert!(parse_gbnf("root ::= \"a\"\nroot ::= \"b\"").is_err());
+        assert!(parse_gbnf(r#"root ::= root "a" | "a""#).is_err());
+        assert!(parse_gbnf(r#"root ::= "a"{3,2}"#).is_err());
+        assert!(parse_gbnf(r#"root ::= "a"{1001}"#).is_err());
+        assert!(parse_gbnf(r#"root ::= ("a""#).is_err());
+        assert!(parse_gbnf(r#"root ::= "a"#).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokenizers::Tokenizer;
+
+/// Upper bound of rules in a grammar
+const MAX_RULES: usize = 4096;
+/// Upper bound of the ways the output can continue that are tracked, further ones of ambiguous
+/// grammars are left out
+const MAX_STACKS: usize = 128;
+/// Upper bound of rule expansions per character, so that ambiguous grammars cost a limited amount
+/// of work per token
+const MAX_EXPANSIONS: usize = 4096;
+
+/// Part of a sequence, either a single character out of a set or a reference to another rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    /// A character in one of the inclusive ranges, or in none of them if negated
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    pub fn char(c: char) -> Self {
+        Self::Chars {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&c))
+                    != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+/// Context-free grammar that the output of a generation has to match. Every rule is a list of
+/// alternatives, each a sequence of elements.
+#[derive(Debug, Clone, Default)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+impl Grammar {
+    /// Returns the elements matching the text
+    pub fn literal(text: &str) -> Vec<Element> {
+        text.chars().map(Element::char).collect()
+    }
+
+    /// Adds a rule without alternatives yet, so that rules can refer to each other
+    pub fn reserve(&mut self) -> usize {
+        self.rules.push(Vec::new());
+        self.rules.len() - 1
+    }
+
+    pub fn define(&mut self, rule: usize, alternatives: Vec<Vec<Element>>) {
+        self.rules[rule] = alternatives;
+    }
+
+    /// Adds a rule and returns its index
+    pub fn rule(&mut self, alternatives: Vec<Vec<Element>>) -> usize {
+        let rule = self.reserve();
+        self.define(rule, alternatives);
+        rule
+    }
+
+    pub const fn set_root(&mut self, root: usize) {
+        self.root = root;
+    }
+
+    /// Verifies that every referenced rule exists and that no rule refers to itself before
+    /// matching a character, which would expand forever
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn check(&self) -> Result<()> {
+        if self.root >= self.rules.len() {
+            bail!("The grammar has no root rule");
+        }
+        if self.rules.len() > MAX_RULES {
+            bail!("The grammar has more than {MAX_RULES} rules");
+        }
+        for element in self.rules.iter().flatten().flatten() {
+            if let Element::Rule(rule) = element {
+                if *rule >= self.rules.len() {
+                    bail!("The grammar refers to an undefined rule");
+                }
+            }
+        }
+
+        let nullable = self.nullable_rules();
+        for rule in 0..self.rules.len() {
+            let mut pending = vec![rule];
+            let mut visited = HashSet::new();
+            while let Some(current) = pending.pop() {
+                for sequence in &self.rules[current] {
+                    for element in sequence {
+                        let Element::Rule(next) = element else {
+                            break;
+                        };
+                        if *next == rule {
+                            bail!("The grammar is left recursive");
+                        }
+                        if visited.insert(*next) {
+                            pending.push(*next);
+                        }
+                        if !nullable.contains(next) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the rules that can match the empty text
+    fn nullable_rules(&self) -> HashSet<usize> {
+        let mut nullable = HashSet::new();
+        loop {
+            let before = nullable.len();
+            for (rule, alternatives) in self.rules.iter().enumerate() {
+                let matches_empty = alternatives.iter().any(|sequence| {
+                    sequence.iter().all(
+                        |element| matches!(element, Element::Rule(next) if nullable.contains(next)),
+                    )
+                });
+                if matches_empty {
+                    nullable.insert(rule);
+                }
+            }
+            if nullable.len() == before {
+                return nullable;
+            }
+        }
+    }
+
+    /// Returns the state before the first character of the output
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn start(self: &Arc<Self>) -> Result<GrammarState> {
+        self.check()?;
+        let mut expansion = Expansion::default();
+        for (alternative, sequence) in self.rules[self.root].iter().enumerate() {
+            let stack = if sequence.is_empty() {
+                Vec::new()
+            } else {
+                vec![(self.root, alternative, 0)]
+            };
+            self.expand(stack, &mut expansion);
+        }
+        Ok(GrammarState {
+            grammar: Arc::clone(self),
+            stacks: expansion.stacks,
+        })
+    }
+
+    /// Replaces references to rules at the top of the stack with their alternatives until every
+    /// resulting stack expects a character or is empty, which means the output is complete
+    fn expand(&self, mut stack: Vec<Position>, expansion: &mut Expansion) {
+        expansion.steps += 1;
+        if expansion.steps > MAX_EXPANSIONS || expansion.stacks.len() >= MAX_STACKS {
+            return;
+        }
+        let Some(&(rule, alternative, index)) = stack.last() else {
+            expansion.add(stack);
+            return;
+        };
+        let sequence = &self.rules[rule][alternative];
+        let Element::Rule(next) = sequence[index] else {
+            expansion.add(stack);
+            return;
+        };
+        stack.pop();
+        if index + 1 < sequence.len() {
+            stack.push((rule, alternative, index + 1));
+        }
+        for (next_alternative, next_sequence) in self.rules[next].iter().enumerate() {
+            let mut expanded = stack.clone();
+            if !next_sequence.is_empty() {
+                expanded.push((next, next_alternative, 0));
+            }
+            self.expand(expanded, expansion);
+        }
+    }
+}
+
+/// Rule, alternative and index of an element in the grammar
+type Position = (usize, usize, usize);
+
+/// Stacks collected while expanding, in the order they were found
+#[derive(Default)]
+struct Expansion {
+    stacks: Vec<Vec<Position>>,
+    seen: HashSet<Vec<Position>>,
+    steps: usize,
+}
+
+impl Expansion {
+    fn add(&mut self, stack: Vec<Position>) {
+        if self.seen.insert(stack.clone()) {
+            self.stacks.push(stack);
+        }
+    }
+}
+
+/// The ways the output generated so far can continue within the grammar
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    grammar: Arc<Grammar>,
+    /// Positions still to be matched, the element expected next is at the end of each stack
+    stacks: Vec<Vec<Position>>,
+}
+
+impl GrammarState {
+    /// Returns the state after the character or `None` if the grammar does not allow it
+    pub fn accept(&self, c: char) -> Option<Self> {
+        let mut expansion = Expansion::default();
+        for stack in &self.stacks {
+            let Some(&(rule, alternative, index)) = stack.last() else {
+                continue;
+            };
+            let sequence = &self.grammar.rules[rule][alternative];
+            if !sequence[index].matches(c) {
+                continue;
+            }
+            let mut advanced = stack.clone();
+            advanced.pop();
+            if index + 1 < sequence.len() {
+                advanced.push((rule, alternative, index + 1));
+            }
+            self.grammar.expand(advanced, &mut expansion);
+        }
+        (!expansion.stacks.is_empty()).then(|| Self {
+            grammar: Arc::clone(&self.grammar),
+            stacks: expansion.stacks,
+        })
+    }
+
+    pub fn accept_str(&self, text: &str) -> Option<Self> {
+        let mut state = self.accept(text.chars().next()?)?;
+        for c in text.chars().skip(1) {
+            state = state.accept(c)?;
+        }
+        Some(state)
+    }
+
+    /// Returns whether the output matches the grammar as it is
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(Vec::is_empty)
+    }
+}
+
+/// The text of every token of a tokenizer in a trie, so that the tokens sharing a prefix are
+/// checked against the grammar together
+#[derive(Debug)]
+pub struct Vocabulary {
+    nodes: Vec<Node>,
+    texts: Vec<Option<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: BTreeMap<char, usize>,
+    /// Tokens whose text ends at this node
+    tokens: Vec<u32>,
+}
+
+impl Vocabulary {
+    /// Collects the text of the tokens, leaving out special tokens and tokens that are only part
+    /// of a character
+    #[tracing::instrument(level = "debug", skip(tokenizer))]
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let special = tokenizer.get_added_tokens_decoder();
+        let size = tokenizer.get_vocab_size(true);
+        let mut vocabulary = Self {
+            nodes: vec![Node::default()],
+            texts: vec![None; size],
+        };
+        for token in 0..u32::try_from(size).unwrap_or(u32::MAX) {
+            if special.contains_key(&token) {
+                continue;
+            }
+            let Some(text) = token_text(tokenizer, token) else {
+                continue;
+            };
+            let mut node = 0;
+            for c in text.chars() {
+                node = if let Some(child) = vocabulary.nodes[node].children.get(&c) {
+                    *child
+                } else {
+                    vocabulary.nodes.push(Node::default());
+                    let child = vocabulary.nodes.len() - 1;
+                    vocabulary.nodes[node].children.insert(c, child);
+                    child
+                };
+            }
+            vocabulary.nodes[node].tokens.push(token);
+            vocabulary.texts[token as usize] = Some(text);
+        }
+        vocabulary
+    }
+
+    pub fn text(&self, token: u32) -> Option<&str> {
+        self.texts.get(token as usize)?.as_deref()
+    }
+
+    /// Returns the tokens that continue the output within the grammar
+    #[tracing::instrument(level = "trace", skip(self, state))]
+    pub fn allowed(&self, state: &GrammarState) -> Vec<u32> {
+        let mut allowed = Vec::new();
+        let mut pending = vec![(0, state.clone())];
+        while let Some((node, state)) = pending.pop() {
+            for (c, child) in &self.nodes[node].children {
+                if let Some(next) = state.accept(*c) {
+                    allowed.extend(&self.nodes[*child].tokens);
+                    pending.push((*child, next));
+                }
+            }
+        }
+        allowed
+    }
+}
+
+/// Decodes the token on its own, restoring the leading space that `SentencePiece` decoders strip
+fn token_text(tokenizer: &Tokenizer, token: u32) -> Option<String> {
+    let piece = tokenizer.id_to_token(token)?;
+    let mut text = tokenizer.decode(&[token], false).ok()?;
+    if piece.starts_with('\u{2581}') && !text.starts_with(' ') {
+        text.insert(0, ' ');
+    }
+    (!text.is_empty() && !text.contains('\u{fffd}')).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::models::wordlevel::WordLevel;
+
+    use super::*;
+
+    /// `root ::= "a" ("b" | "bc")*`
+    fn grammar() -> Arc<Grammar> {
+        let mut grammar = Grammar::default();
+        let root = grammar.reserve();
+        let tail = grammar.reserve();
+        grammar.define(root, vec![vec![Element::char('a'), Element::Rule(tail)]]);
+        grammar.define(
+            tail,
+            vec![
+                vec![Element::char('b'), Element::Rule(tail)],
+                vec![Element::char('b'), Element::char('c'), Element::Rule(tail)],
+                Vec::new(),
+            ],
+        );
+        grammar.set_root(root);
+        Arc::new(grammar)
+    }
+
+    fn tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab: HashMap<String, u32> = tokens
+            .iter()
+            .zip(0..)
+            .map(|(token, id)| ((*token).to_string(), id))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token(tokens[0].to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn check_rejects_left_recursion() {
+        let mut grammar = Grammar::default();
+        let root = grammar.reserve();
+        let optional = grammar.rule(vec![vec![Element::char('x')], Vec::new()]);
+        grammar.define(
+            root,
+            vec![
+                vec![
+                    Element::Rule(optional),
+                    Element::Rule(root),
+                    Element::char('a'),
+                ],
+                vec![Element::char('a')],
+            ],
+        );
+        grammar.set_root(root);
+        assert!(grammar.check().is_err());
+    }
+
+    #[test]
+    fn check_rejects_undefined_rules() {
+        let mut grammar = Grammar::default();
+        let root = grammar.rule(vec![vec![Element::Rule(7)]]);
+        grammar.set_root(root);
+        assert!(grammar.check().is_err());
+    }
+
+    #[test]
+    fn check_accepts_right_recursion() {
+        assert!(grammar().check().is_ok());
+    }
+
+    #[test]
+    fn accept_str_follows_the_grammar() {
+        let state = grammar().start().unwrap();
+        assert!(!state.is_complete());
+        assert!(state.accept_str("a").unwrap().is_complete());
+        assert!(state.accept_str("abcbb").unwrap().is_complete());
+        assert!(state.accept_str("abbc").unwrap().is_complete());
+        assert!(state.accept_str("b").is_none());
+        assert!(state.accept_str("acc").is_none());
+    }
+
+    #[test]
+    fn allowed_returns_the_tokens_continuing_the_grammar() {
+        let tokenizer = tokenizer(&["<unk>", "a", "ab", "abc", "b", "bc", "c", "cb", "x"]);
+        let vocabulary = Vocabulary::new(&tokenizer);
+        let text = |tokens: Vec<u32>| {
+            let mut texts: Vec<&str> = tokens
+                .into_iter()
+                .filter_map(|token| vocabulary.text(token))
+                .collect();
+            texts.sort_unstable();
+            texts
+        };
+
+        let state = grammar().start().unwrap();
+        assert_eq!(text(vocabulary.allowed(&state)), ["a", "ab", "abc"]);
+        let state = state.accept_str("ab").unwrap();
+        assert_eq!(text(vocabulary.allowed(&state)), ["b", "bc", "c", "cb"]);
+    }
+
+    #[test]
+    fn ambiguous_grammars_are_bounded() {
+        // root ::= x* x* x* x* x* x* x* x* with x ::= "a"
+        let mut grammar = Grammar::default();
+        let many = grammar.reserve();
+        grammar.define(
+            many,
+            vec![vec![Element::char('a'), Element::Rule(many)], Vec::new()],
+        );
+        let root = grammar.rule(vec![vec![Element::Rule(many); 8]]);
+        grammar.set_root(root);
+        let mut state = Arc::new(grammar).start().unwrap();
+        for _ in 0..20 {
+            state = state.accept('a').unwrap();
+            assert!(state.stacks.len() <= MAX_STACKS);
+        }
+        assert!(state.is_complete());
+    }
+}
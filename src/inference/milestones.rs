@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use tracing::info;
+
+/// Number of generated tokens between progress events, zero disables generation milestone events
+static EVENT_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_milestones(interval: usize) {
+    EVENT_INTERVAL.store(interval, Ordering::Relaxed);
+}
+
+/// Records milestones of a generation as events on the current span, which OpenTelemetry exports as span events
+pub struct Milestones {
+    started: Instant,
+    interval: usize,
+    tokens: usize,
+}
+
+impl Milestones {
+    #[tracing::instrument(level = "trace")]
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            interval: EVENT_INTERVAL.load(Ordering::Relaxed),
+            tokens: 0,
+        }
+    }
+
+    /// Records that a token was generated
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn token(&mut self) {
+        self.tokens += 1;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if self.tokens == 1 {
+            info!(histogram.generation.time_to_first_token = elapsed);
+        }
+        if self.interval == 0 {
+            return;
+        }
+        if self.tokens == 1 {
+            info!(first_token_latency = elapsed, "First token generated");
+        } else if self.tokens.is_multiple_of(self.interval) {
+            info!(tokens = self.tokens, elapsed, "Tokens generated");
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn finish(&self) {
+        info!(histogram.generation.duration = self.started.elapsed().as_secs_f64());
+        if self.interval > 0 {
+            info!(
+                tokens = self.tokens,
+                elapsed = self.started.elapsed().as_secs_f64(),
+                "Generation completed"
+            );
+        }
+    }
+}
@@ -1,7 +1,25 @@
-mod audio_pipeline;
+pub mod artifact_store;
+pub mod audio_input;
+pub mod audio_pipeline;
+pub mod audio_quality;
+pub mod availability;
+pub mod coalesce;
+pub mod error;
+pub mod gbnf;
+pub mod grammar;
+pub mod milestones;
 pub mod model_config;
+pub mod model_slot;
 pub mod models;
 mod pcm_decode;
+pub mod prefix_cache;
+pub mod probe;
+pub mod queue;
+pub mod reload;
+pub mod runtime;
+pub mod streaming;
 pub mod task;
 mod text_pipeline;
 mod token_output_stream;
+pub mod watchdog;
+pub mod watermark;
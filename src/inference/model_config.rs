@@ -1,15 +1,66 @@
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
 use rand::random;
 use serde::Deserialize;
 
+/// Sampling parameters of the models unless a request overrides them
+static SAMPLING_DEFAULTS: OnceLock<(Option<usize>, Option<f64>)> = OnceLock::new();
+
+#[tracing::instrument(level = "info")]
+pub fn configure_sampling(top_k: Option<usize>, min_p: Option<f64>) -> Result<()> {
+    GeneralModelConfig {
+        top_k,
+        min_p,
+        ..GeneralModelConfig::default()
+    }
+    .check()?;
+    if SAMPLING_DEFAULTS.set((top_k, min_p)).is_err() {
+        bail!("Sampling defaults are already configured");
+    }
+    Ok(())
+}
+
+fn default_top_k() -> Option<usize> {
+    SAMPLING_DEFAULTS.get().and_then(|(top_k, _)| *top_k)
+}
+
+fn default_min_p() -> Option<f64> {
+    SAMPLING_DEFAULTS.get().and_then(|(_, min_p)| *min_p)
+}
+
 #[derive(Deserialize, Debug, Copy, Clone)]
 pub struct GeneralModelConfig {
     pub seed: Option<u64>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    /// Sample only from this many of the most likely tokens
+    #[serde(default = "default_top_k")]
+    pub top_k: Option<usize>,
+    /// Sample only from tokens at least this fraction as likely as the most likely token
+    #[serde(default = "default_min_p")]
+    pub min_p: Option<f64>,
     pub repeat_penalty: f32,
     pub repeat_context_size: usize,
 }
 
+impl GeneralModelConfig {
+    /// Verifies that the sampling parameters select at least one token
+    #[tracing::instrument(level = "trace")]
+    pub fn check(&self) -> Result<()> {
+        if self.top_k == Some(0) {
+            bail!("top_k must be at least 1");
+        }
+        if self
+            .min_p
+            .is_some_and(|min_p| !(0.0..=1.0).contains(&min_p))
+        {
+            bail!("min_p must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
 impl Default for GeneralModelConfig {
     #[tracing::instrument(level = "trace", skip())]
     fn default() -> Self {
@@ -17,6 +68,8 @@ impl Default for GeneralModelConfig {
             seed: random(),
             temperature: Some(0.6),
             top_p: Some(0.6),
+            top_k: default_top_k(),
+            min_p: default_min_p(),
             repeat_penalty: 1.1,
             repeat_context_size: 64,
         }
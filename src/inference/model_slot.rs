@@ -0,0 +1,680 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::chaos::inject_panic;
+use crate::inference::availability::closed_for;
+use crate::inference::error::InferenceError;
+use crate::inference::models::model::ModelBase;
+use crate::inference::probe::{failure_threshold, ProbeOutcome};
+use crate::inference::queue;
+use crate::inference::runtime::with_runtime;
+use crate::inference::watchdog;
+use crate::inference::watchdog::Progress;
+use crate::notifications::{notify, NotificationEvent};
+
+/// Number of consecutive failed inferences after which the circuit breaker of a model opens
+static BREAKER_THRESHOLD: AtomicU32 = AtomicU32::new(5);
+/// Seconds an open circuit breaker rejects requests before letting them through again
+static BREAKER_COOLDOWN: AtomicU64 = AtomicU64::new(30);
+/// Seconds a generation may go without progress before the watchdog aborts it, zero disables it
+static WATCHDOG_TIMEOUT: AtomicU64 = AtomicU64::new(120);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_breaker(threshold: u32, cooldown: Duration) {
+    BREAKER_THRESHOLD.store(threshold.max(1), Ordering::Relaxed);
+    BREAKER_COOLDOWN.store(cooldown.as_secs(), Ordering::Relaxed);
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_watchdog(timeout: Duration) {
+    WATCHDOG_TIMEOUT.store(timeout.as_secs(), Ordering::Relaxed);
+}
+
+/// Lazily loads a model on first use and keeps track of load failures.
+/// A model that fails to load is marked as degraded instead of panicking the worker thread.
+pub struct ModelSlot<M> {
+    pub name: &'static str,
+    /// Describes the model without loading it
+    base: fn() -> ModelBase,
+    loader: fn() -> Result<M>,
+    state: RwLock<SlotState<M>>,
+    breaker: Mutex<Breaker>,
+    warm_pool: Mutex<WarmPool<M>>,
+    /// Number of consecutive health probes of the loaded model that failed
+    probe_failures: AtomicU32,
+}
+
+enum SlotState<M> {
+    Unloaded,
+    Loaded(M),
+    Degraded(String),
+}
+
+/// Copies of the loaded model kept ready so that requests do not have to wait for one to be cloned
+struct WarmPool<M> {
+    size: usize,
+    copies: Vec<M>,
+    filling: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct WarmPoolStatus {
+    /// Number of copies that are kept warm
+    pub size: usize,
+    /// Number of copies that are currently warm
+    pub warm: usize,
+}
+
+struct Breaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    reloading: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelStatus {
+    /// The model has not been requested yet
+    Unloaded,
+    /// The model is loaded and serving requests
+    Ready,
+    /// The model failed to load or its health probes keep failing
+    Degraded,
+    /// The circuit breaker of the model is open due to repeated failures
+    Unhealthy,
+    /// The model is unloaded outside of its availability window
+    Offline,
+}
+
+/// Type-erased access to a `ModelSlot` so that all models can be inspected together
+pub trait ManagedModel: Sync {
+    fn name(&self) -> &'static str;
+    fn base(&self) -> ModelBase;
+    fn status(&self) -> ModelStatus;
+    /// Loads the model again and swaps it in if it succeeds, keeping the current one otherwise
+    fn refresh(&self);
+    /// Loads the model if required and fills its warm pool
+    fn preload(&self);
+    /// Drops the loaded model and its warm copies to free their memory
+    fn unload(&self);
+    fn warm_pool(&self) -> WarmPoolStatus;
+    /// Changes the number of copies kept warm, loading the model first if required.
+    /// Blocks until the pool is filled.
+    fn resize_warm_pool(&self, size: usize);
+    fn is_loaded(&self) -> bool;
+    /// Records the outcome of a health probe. A slow probe is passed on to the circuit breaker
+    /// as a failure, a failed one already was by the inference itself.
+    fn record_probe(&'static self, outcome: ProbeOutcome);
+}
+
+impl<M: Clone + Send + Sync + 'static> ManagedModel for ModelSlot<M> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn base(&self) -> ModelBase {
+        (self.base)()
+    }
+
+    #[tracing::instrument(level = "trace", skip(self), fields(model = self.name))]
+    fn status(&self) -> ModelStatus {
+        if closed_for(self.name).is_some() {
+            return ModelStatus::Offline;
+        }
+        if self.open_for().is_some() {
+            return ModelStatus::Unhealthy;
+        }
+        match &*self.read_state() {
+            SlotState::Unloaded => ModelStatus::Unloaded,
+            SlotState::Loaded(_)
+                if self.probe_failures.load(Ordering::Relaxed) >= failure_threshold() =>
+            {
+                ModelStatus::Degraded
+            }
+            SlotState::Loaded(_) => ModelStatus::Ready,
+            SlotState::Degraded(_) => ModelStatus::Degraded,
+        }
+    }
+
+    #[tracing::instrument(level = "info", skip(self), fields(model = self.name))]
+    fn refresh(&self) {
+        // Models that were never requested pick up the new weights once they are loaded
+        if !matches!(*self.read_state(), SlotState::Loaded(_)) {
+            return;
+        }
+
+        info!("Refreshing model {}", self.name);
+        let state = self.load();
+        if matches!(state, SlotState::Loaded(_)) {
+            self.swap_state(state);
+            self.fill_warm_pool();
+        } else {
+            warn!(
+                "Keeping current version of model {} as the refresh failed",
+                self.name
+            );
+        }
+    }
+
+    #[tracing::instrument(level = "info", skip(self), fields(model = self.name))]
+    fn preload(&self) {
+        if let Err(err) = self.get() {
+            warn!("Failed to preload model {}: {}", self.name, err);
+            return;
+        }
+        self.fill_warm_pool();
+    }
+
+    #[tracing::instrument(level = "info", skip(self), fields(model = self.name))]
+    fn unload(&self) {
+        if matches!(*self.read_state(), SlotState::Loaded(_)) {
+            self.swap_state(SlotState::Unloaded);
+            info!("Unloaded model {}", self.name);
+        }
+    }
+
+    fn warm_pool(&self) -> WarmPoolStatus {
+        let pool = self.pool();
+        WarmPoolStatus {
+            size: pool.size,
+            warm: pool.copies.len(),
+        }
+    }
+
+    #[tracing::instrument(level = "info", skip(self), fields(model = self.name))]
+    fn resize_warm_pool(&self, size: usize) {
+        let mut pool = self.pool();
+        pool.size = size;
+        pool.copies.truncate(size);
+        drop(pool);
+        info!("Keeping {} copies of model {} warm", size, self.name);
+
+        // Models outside of their availability window are warmed once it opens
+        if size > 0 && closed_for(self.name).is_none() {
+            self.preload();
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        matches!(*self.read_state(), SlotState::Loaded(_))
+    }
+
+    #[tracing::instrument(level = "trace", skip(self), fields(model = self.name))]
+    fn record_probe(&'static self, outcome: ProbeOutcome) {
+        if outcome == ProbeOutcome::Healthy {
+            if self.probe_failures.swap(0, Ordering::Relaxed) >= failure_threshold() {
+                info!("Model {} passed its health probe again", self.name);
+            }
+            return;
+        }
+        let failures = self.probe_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures == failure_threshold() {
+            warn!(
+                "Model {} is degraded after {} failed health probes",
+                self.name, failures
+            );
+        }
+        if outcome == ProbeOutcome::Slow {
+            self.record_failure();
+        }
+    }
+}
+
+impl<M: Clone + Send> ModelSlot<M> {
+    pub const fn new(
+        name: &'static str,
+        base: fn() -> ModelBase,
+        loader: fn() -> Result<M>,
+    ) -> Self {
+        Self {
+            name,
+            base,
+            loader,
+            state: RwLock::new(SlotState::Unloaded),
+            breaker: Mutex::new(Breaker {
+                consecutive_failures: 0,
+                open_until: None,
+                reloading: false,
+            }),
+            warm_pool: Mutex::new(WarmPool {
+                size: 0,
+                copies: Vec::new(),
+                filling: false,
+            }),
+            probe_failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns a copy of the loaded model, loading it first if required
+    #[tracing::instrument(level = "trace", skip(self), fields(model = self.name))]
+    pub fn get(&self) -> Result<M> {
+        if let SlotState::Loaded(model) = &*self.read_state() {
+            return Ok(model.clone());
+        }
+
+        let mut state = self.write_state();
+        if matches!(*state, SlotState::Unloaded) {
+            *state = self.load();
+        }
+
+        match &*state {
+            SlotState::Loaded(model) => Ok(model.clone()),
+            SlotState::Degraded(reason) => Err(InferenceError::ModelUnavailable {
+                model: self.name,
+                reason: reason.clone(),
+            }
+            .into()),
+            SlotState::Unloaded => Err(InferenceError::ModelUnavailable {
+                model: self.name,
+                reason: "Model is not loaded".into(),
+            }
+            .into()),
+        }
+    }
+
+    /// Runs the task with a copy of the model on a supervised blocking worker.
+    /// A panic inside the task only fails this request, the worker is replaced by the runtime and
+    /// the loaded model stays untouched as every task operates on its own copy.
+    #[tracing::instrument(level = "trace", skip(self, task), fields(model = self.name))]
+    pub async fn run<R, F>(&'static self, task: F) -> Result<R>
+    where
+        M: Send + Sync + 'static,
+        R: Send + 'static,
+        F: FnOnce(M) -> Result<R> + Send + 'static,
+    {
+        if let Some(opens_in) = closed_for(self.name) {
+            return Err(InferenceError::ModelOffline {
+                model: self.name,
+                retry_after: opens_in.as_secs().max(1),
+            }
+            .into());
+        }
+        if let Some(retry_after) = self.open_for() {
+            return Err(InferenceError::ModelUnhealthy {
+                model: self.name,
+                retry_after: retry_after.as_secs().max(1),
+            }
+            .into());
+        }
+
+        let progress = Progress::new();
+        let worker_progress = progress.clone();
+        let queued = Instant::now();
+        let worker = tokio::task::spawn_blocking(move || {
+            queue::record_wait(self.name, queued.elapsed());
+            with_runtime(self.name, || {
+                let _guard = watchdog::attach(worker_progress);
+                inject_panic(self.name);
+                task(self.take()?)
+            })
+        });
+
+        let result = match self.supervise(worker, &progress).await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => {
+                let message = panic_message(&err.into_panic());
+                error!(
+                    "Inference worker of model {} panicked: {}",
+                    self.name, message
+                );
+                info!(monotonic_counter.inference_panics = 1, model = self.name);
+                Err(InferenceError::WorkerPanic(message).into())
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        match &result {
+            Ok(_) => self.record_success(),
+            // Errors caused by the request itself say nothing about the health of the model
+            Err(err)
+                if err
+                    .downcast_ref::<InferenceError>()
+                    .is_some_and(|err| err.status().is_client_error()) => {}
+            Err(_) => self.record_failure(),
+        }
+        result
+    }
+
+    /// Returns a warm copy of the model if there is one, refilling the pool in the background,
+    /// and falls back to cloning the loaded model otherwise
+    fn take(&'static self) -> Result<M>
+    where
+        M: Send + Sync + 'static,
+    {
+        let mut pool = self.pool();
+        let Some(model) = pool.copies.pop() else {
+            drop(pool);
+            return self.get();
+        };
+        let filling = std::mem::replace(&mut pool.filling, true);
+        drop(pool);
+        if !filling {
+            tokio::task::spawn_blocking(move || {
+                self.fill_warm_pool();
+                self.pool().filling = false;
+            });
+        }
+        Ok(model)
+    }
+
+    /// Clones the loaded model until the warm pool has reached its size.
+    /// The copies are added while the state is read locked so that a swapped in model never ends
+    /// up next to copies of the previous one.
+    #[tracing::instrument(level = "trace", skip(self), fields(model = self.name))]
+    fn fill_warm_pool(&self) {
+        loop {
+            let state = self.read_state();
+            let SlotState::Loaded(model) = &*state else {
+                return;
+            };
+            if self.is_warm() {
+                return;
+            }
+            let copy = model.clone();
+            let mut pool = self.pool();
+            if pool.copies.len() >= pool.size {
+                return;
+            }
+            pool.copies.push(copy);
+            drop(pool);
+            drop(state);
+        }
+    }
+
+    fn is_warm(&self) -> bool {
+        let pool = self.pool();
+        pool.copies.len() >= pool.size
+    }
+
+    /// Waits for the worker to finish while aborting it once it stops making progress.
+    /// A stuck worker is left behind as it can not be interrupted, its next progress report fails.
+    async fn supervise<R>(
+        &self,
+        mut worker: JoinHandle<Result<R>>,
+        progress: &Progress,
+    ) -> Result<Result<R>, JoinError>
+    where
+        M: Send + Sync,
+        R: Send,
+    {
+        let timeout = Duration::from_secs(WATCHDOG_TIMEOUT.load(Ordering::Relaxed));
+        if timeout.is_zero() {
+            return worker.await;
+        }
+
+        let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_secs(1)));
+        loop {
+            tokio::select! {
+                result = &mut worker => return result,
+                _ = interval.tick() => {
+                    if progress.stalled_for().is_some_and(|stalled| stalled >= timeout) {
+                        progress.abort();
+                        error!(
+                            model = self.name,
+                            elapsed = progress.elapsed().as_secs_f64(),
+                            stalled = progress.stalled_for().unwrap_or_default().as_secs_f64(),
+                            tokens = progress.ticks(),
+                            "Watchdog aborted generation of model {} without progress for {:?}",
+                            self.name,
+                            timeout
+                        );
+                        info!(monotonic_counter.generations_stalled = 1, model = self.name);
+                        return Ok(Err(InferenceError::GenerationStalled.into()));
+                    }
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self), fields(model = self.name))]
+    fn load(&self) -> SlotState<M> {
+        match with_runtime(self.name, self.loader) {
+            Ok(model) => {
+                info!("Loaded model {}", self.name);
+                SlotState::Loaded(model)
+            }
+            Err(err) => {
+                error!("Failed to load model {}: {:?}", self.name, err);
+                notify(
+                    NotificationEvent::ModelLoadFailed,
+                    self.name,
+                    format!("Failed to load model {}: {}", self.name, err),
+                );
+                SlotState::Degraded(err.to_string())
+            }
+        }
+    }
+
+    /// Returns the remaining time until the open circuit breaker lets requests through again
+    fn open_for(&self) -> Option<Duration> {
+        self.breaker()
+            .open_until
+            .and_then(|open_until| open_until.checked_duration_since(Instant::now()))
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker();
+        breaker.consecutive_failures = 0;
+        if breaker.open_until.take().is_some() {
+            info!("Circuit breaker of model {} closed", self.name);
+            info!(counter.models_unhealthy = -1, model = self.name);
+        }
+    }
+
+    fn record_failure(&'static self)
+    where
+        M: Send + Sync + 'static,
+    {
+        info!(monotonic_counter.inference_failures = 1, model = self.name);
+        let mut breaker = self.breaker();
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures < BREAKER_THRESHOLD.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let cooldown = Duration::from_secs(BREAKER_COOLDOWN.load(Ordering::Relaxed));
+        if breaker
+            .open_until
+            .replace(Instant::now() + cooldown)
+            .is_none()
+        {
+            warn!(
+                "Circuit breaker of model {} opened after {} consecutive failures",
+                self.name, breaker.consecutive_failures
+            );
+            info!(counter.models_unhealthy = 1, model = self.name);
+            notify(
+                NotificationEvent::BreakerOpened,
+                self.name,
+                format!(
+                    "Circuit breaker of model {} opened after {} consecutive failures",
+                    self.name, breaker.consecutive_failures
+                ),
+            );
+        }
+        let reloading = std::mem::replace(&mut breaker.reloading, true);
+        drop(breaker);
+        if !reloading {
+            tokio::task::spawn_blocking(move || self.reload());
+        }
+    }
+
+    /// Replaces the current model with a freshly loaded one, closing the breaker on success.
+    /// The current model is kept if the reload fails.
+    #[tracing::instrument(level = "info", skip(self), fields(model = self.name))]
+    fn reload(&self) {
+        info!("Reloading model {}", self.name);
+        let state = self.load();
+        let loaded = matches!(state, SlotState::Loaded(_));
+        if loaded {
+            self.swap_state(state);
+            self.fill_warm_pool();
+        } else {
+            warn!(
+                "Keeping current version of model {} as the reload failed",
+                self.name
+            );
+        }
+
+        let mut breaker = self.breaker();
+        breaker.reloading = false;
+        if loaded {
+            breaker.consecutive_failures = 0;
+            if breaker.open_until.take().is_some() {
+                info!("Circuit breaker of model {} closed", self.name);
+                info!(counter.models_unhealthy = -1, model = self.name);
+            }
+        }
+    }
+
+    /// Replaces the state and drops the warm copies of the previous model
+    fn swap_state(&self, state: SlotState<M>) {
+        let mut current = self.write_state();
+        *current = state;
+        self.pool().copies.clear();
+        self.probe_failures.store(0, Ordering::Relaxed);
+        drop(current);
+    }
+
+    fn read_state(&self) -> RwLockReadGuard<'_, SlotState<M>> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_state(&self) -> RwLockWriteGuard<'_, SlotState<M>> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn breaker(&self) -> MutexGuard<'_, Breaker> {
+        self.breaker.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn pool(&self) -> MutexGuard<'_, WarmPool<M>> {
+        self.warm_pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[tracing::instrument(level = "trace", skip(panic))]
+fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(ToString::to_string)
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown panic".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use anyhow::bail;
+
+    use super::*;
+    use crate::inference::models::model::ModelDomain;
+
+    fn base() -> ModelBase {
+        ModelBase {
+            name: "Test".into(),
+            license: "MIT".into(),
+            domain: ModelDomain::Text(Vec::new()),
+            repo_id: "test/model".into(),
+            repo_revision: "main".into(),
+        }
+    }
+
+    fn failing_loader() -> Result<u32> {
+        bail!("Weights are missing")
+    }
+
+    #[allow(clippy::unnecessary_wraps)]
+    fn loader() -> Result<u32> {
+        Ok(1)
+    }
+
+    static RELOAD_FAILS: AtomicBool = AtomicBool::new(false);
+
+    fn flaky_loader() -> Result<u32> {
+        if RELOAD_FAILS.load(Ordering::Relaxed) {
+            bail!("Weights are missing");
+        }
+        Ok(1)
+    }
+
+    fn fail(slot: &'static ModelSlot<u32>, times: u32) {
+        for _ in 0..times {
+            slot.record_failure();
+        }
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_after_consecutive_failures() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("breaker-opens", base, failing_loader);
+        let threshold = BREAKER_THRESHOLD.load(Ordering::Relaxed);
+        fail(&SLOT, threshold - 1);
+        assert_eq!(SLOT.status(), ModelStatus::Unloaded);
+        assert!(SLOT.open_for().is_none());
+        fail(&SLOT, 1);
+        assert_eq!(SLOT.status(), ModelStatus::Unhealthy);
+        assert!(SLOT.open_for().is_some());
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_breaker() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("breaker-resets", base, failing_loader);
+        let threshold = BREAKER_THRESHOLD.load(Ordering::Relaxed);
+        fail(&SLOT, threshold - 1);
+        SLOT.record_success();
+        fail(&SLOT, threshold - 1);
+        assert!(SLOT.open_for().is_none());
+        fail(&SLOT, 1);
+        assert!(SLOT.open_for().is_some());
+        SLOT.record_success();
+        assert!(SLOT.open_for().is_none());
+        assert_ne!(SLOT.status(), ModelStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    async fn successful_reload_closes_the_breaker() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("breaker-reloads", base, loader);
+        fail(&SLOT, BREAKER_THRESHOLD.load(Ordering::Relaxed));
+        for _ in 0..100 {
+            if SLOT.open_for().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(SLOT.status(), ModelStatus::Ready);
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_the_current_model() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("breaker-keeps", base, flaky_loader);
+        assert_eq!(SLOT.get().unwrap(), 1);
+        RELOAD_FAILS.store(true, Ordering::Relaxed);
+        SLOT.reload();
+        assert_eq!(SLOT.get().unwrap(), 1);
+        assert_eq!(SLOT.status(), ModelStatus::Ready);
+    }
+
+    #[tokio::test]
+    async fn breaker_is_healthy_after_the_cooldown() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("breaker-cools-down", base, failing_loader);
+        SLOT.breaker().open_until = Instant::now().checked_sub(Duration::from_secs(1));
+        assert_ne!(SLOT.status(), ModelStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    async fn base_is_described_without_loading() {
+        static SLOT: ModelSlot<u32> = ModelSlot::new("base-unloaded", base, failing_loader);
+        assert_eq!(SLOT.base().repo_id, "test/model");
+        assert_eq!(SLOT.status(), ModelStatus::Unloaded);
+    }
+}
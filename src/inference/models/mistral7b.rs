@@ -1,19 +1,21 @@
 use anyhow::Result;
-use candle_transformers::generation::LogitsProcessor;
 use hf_hub::api::sync::Api;
 use hf_hub::{Repo, RepoType};
 use rand::random;
 
+use crate::inference::artifact_store::open_repo;
 use crate::inference::model_config::GeneralModelConfig;
 use crate::inference::models::model::ModelBase;
+use crate::inference::task::chat::{mistral_prompt, ChatHandler, ChatRequest, ChatResponse};
 use crate::inference::task::instruct::{InstructHandler, InstructRequest, InstructResponse};
 use crate::inference::task::raw::{RawHandler, RawRequest, RawResponse};
-use crate::inference::text_pipeline::{Model, TextGeneratorPipeline};
+use crate::inference::task::watermark::{DetectWatermarkRequest, WatermarkHandler};
+use crate::inference::text_pipeline::{logits_processor, Model, TextGeneratorPipeline};
+use crate::inference::watermark::WatermarkDetection;
 
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples/examples/mistral/main.rs
 #[derive(Clone)]
 pub struct Mistral7BModel {
-    pub base: ModelBase,
     generator_pipeline: TextGeneratorPipeline,
 }
 
@@ -29,34 +31,39 @@ impl Mistral7BModel {
         gguf_filename: &str,
         general_model_config: GeneralModelConfig,
     ) -> Result<Self> {
-        let repo = api.repo(Repo::with_revision(
-            base.repo_id.clone(),
-            RepoType::Model,
-            base.repo_revision.clone(),
-        ));
-        let mistral_repo = api.repo(Repo::with_revision(
-            "mistralai/Mistral-7B-Instruct-v0.1".into(),
-            RepoType::Model,
-            "main".into(),
-        ));
+        let repo = open_repo(
+            api,
+            Repo::with_revision(
+                base.repo_id.clone(),
+                RepoType::Model,
+                base.repo_revision.clone(),
+            ),
+        );
+        let mistral_repo = open_repo(
+            api,
+            Repo::with_revision(
+                "mistralai/Mistral-7B-Instruct-v0.1".into(),
+                RepoType::Model,
+                "main".into(),
+            ),
+        );
         let tokenizer_file = mistral_repo.get(tokenizer_filename)?;
 
         let generator_pipeline = TextGeneratorPipeline::with_quantized_gguf(
-            &repo,
+            &*repo,
             &Model::Mistral(None),
             tokenizer_file,
             gguf_filename,
             general_model_config.seed,
             general_model_config.temperature,
             general_model_config.top_p,
+            general_model_config.top_k,
+            general_model_config.min_p,
             general_model_config.repeat_penalty,
             general_model_config.repeat_context_size,
         )?;
 
-        Ok(Self {
-            base: base.clone(),
-            generator_pipeline,
-        })
+        Ok(Self { generator_pipeline })
     }
 }
 
@@ -64,20 +71,41 @@ impl RawHandler for Mistral7BModel {
     #[tracing::instrument(level = "trace", skip(self, request))]
     fn run_raw(&mut self, request: RawRequest) -> Result<RawResponse> {
         let pipeline = &mut self.generator_pipeline;
-        let logits = LogitsProcessor::new(
-            request.model_config.seed.unwrap_or_else(random),
+        let seed = request.model_config.seed.unwrap_or_else(random);
+        let logits = logits_processor(
+            seed,
             request.model_config.temperature,
+            request.model_config.top_k,
             request.model_config.top_p,
         );
 
         pipeline.repeat_penalty = request.model_config.repeat_penalty;
         pipeline.repeat_context_size = request.model_config.repeat_context_size;
+        pipeline.temperature = request.model_config.temperature;
+        pipeline.min_p = request.model_config.min_p;
         pipeline.logits_processor = logits;
 
-        let (output, inference_time) = pipeline.generate(&request.input, request.max_length)?;
+        let (output, inference_time, tokens, cut_off) = pipeline.generate(
+            &request.input,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            request.constraint.as_ref(),
+        )?;
+        let debug = if request.debug {
+            Some(pipeline.generation_debug(&request, seed)?)
+        } else {
+            None
+        };
         Ok(RawResponse {
-            output,
+            output: request.truncation.apply(output, cut_off),
             inference_time,
+            tokens,
+            id: None,
+            model: None,
+            debug,
+            runtime: request.runtime.then(|| pipeline.runtime_info()),
         })
     }
 }
@@ -86,13 +114,58 @@ impl InstructHandler for Mistral7BModel {
     #[tracing::instrument(level = "trace", skip(self, request))]
     fn run_instruct(&mut self, request: InstructRequest) -> Result<InstructResponse> {
         let prompt = format!("<s>[INST] {} [/INST]", request.input);
-        let (output, inference_time) = self
-            .generator_pipeline
-            .generate(&prompt, request.max_length)?;
+        let (output, inference_time, tokens, cut_off) = self.generator_pipeline.generate(
+            &prompt,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            request.constraint.as_ref(),
+        )?;
 
         Ok(InstructResponse {
-            output,
+            output: request.truncation.apply(output, cut_off),
             inference_time,
+            tokens,
+            id: None,
+            model: None,
+            runtime: request
+                .runtime
+                .then(|| self.generator_pipeline.runtime_info()),
+            attempts: None,
         })
     }
 }
+
+impl ChatHandler for Mistral7BModel {
+    #[tracing::instrument(level = "info", skip(self, request))]
+    fn run_chat(&mut self, request: ChatRequest) -> Result<ChatResponse> {
+        let prompt = mistral_prompt(&request.messages);
+        let (output, inference_time, tokens, cut_off) = self.generator_pipeline.generate(
+            &prompt,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            None,
+        )?;
+
+        Ok(ChatResponse {
+            output: request.truncation.apply(output, cut_off),
+            inference_time,
+            tokens,
+            id: None,
+            model: None,
+            runtime: request
+                .runtime
+                .then(|| self.generator_pipeline.runtime_info()),
+        })
+    }
+}
+
+impl WatermarkHandler for Mistral7BModel {
+    #[tracing::instrument(level = "info", skip(self, request))]
+    fn detect_watermark(&mut self, request: DetectWatermarkRequest) -> Result<WatermarkDetection> {
+        self.generator_pipeline.detect_watermark(&request.input)
+    }
+}
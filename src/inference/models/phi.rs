@@ -1,19 +1,25 @@
 use anyhow::Result;
-use candle_transformers::generation::LogitsProcessor;
 use candle_transformers::models::mixformer;
 use hf_hub::api::sync::Api;
 use hf_hub::{Repo, RepoType};
 use rand::random;
 
+use crate::inference::artifact_store::open_repo;
 use crate::inference::model_config::GeneralModelConfig;
+use crate::inference::task::chat::{
+    tagged_prompt, transcript_prompt, ChatHandler, ChatRequest, ChatResponse,
+};
 use crate::inference::task::instruct::{InstructHandler, InstructRequest, InstructResponse};
 use crate::inference::task::raw::{RawHandler, RawRequest, RawResponse};
-use crate::inference::text_pipeline::{Model, ModelConfig, TextGeneratorPipeline};
+use crate::inference::task::watermark::{DetectWatermarkRequest, WatermarkHandler};
+use crate::inference::text_pipeline::{
+    logits_processor, Model, ModelConfig, TextGeneratorPipeline,
+};
+use crate::inference::watermark::WatermarkDetection;
 use crate::ModelBase;
 
 #[derive(Clone)]
 pub struct PhiModel {
-    pub base: ModelBase,
     generator_pipeline: TextGeneratorPipeline,
     alt_prompt: bool,
 }
@@ -31,52 +37,57 @@ impl PhiModel {
         general_model_config: GeneralModelConfig,
         alt_prompt: bool,
     ) -> Result<Self> {
-        let phi_repo = api.repo(Repo::with_revision(
-            base.repo_id.clone(),
-            RepoType::Model,
-            base.repo_revision.clone(),
-        ));
-        let tokenizer_repo = api.repo(Repo::with_revision(
-            tokenizer_repo.into(),
-            RepoType::Model,
-            "main".into(),
-        ));
+        let phi_repo = open_repo(
+            api,
+            Repo::with_revision(
+                base.repo_id.clone(),
+                RepoType::Model,
+                base.repo_revision.clone(),
+            ),
+        );
+        let tokenizer_repo = open_repo(
+            api,
+            Repo::with_revision(tokenizer_repo.into(), RepoType::Model, "main".into()),
+        );
 
         let model_type = if alt_prompt {
             Model::Phi3(None)
         } else {
             Model::Phi2(None)
         };
-        let generator_pipeline = if phi2_config.is_some() {
+        let generator_pipeline = if let Some(phi2_config) = phi2_config {
             TextGeneratorPipeline::with_quantized_gguf_config(
-                &phi_repo,
+                &*phi_repo,
                 &model_type,
-                ModelConfig::Phi2(phi2_config.unwrap()),
+                ModelConfig::Phi2(phi2_config),
                 tokenizer_filename,
                 gguf_filename,
                 general_model_config.seed,
                 general_model_config.temperature,
                 general_model_config.top_p,
+                general_model_config.top_k,
+                general_model_config.min_p,
                 general_model_config.repeat_penalty,
                 general_model_config.repeat_context_size,
             )?
         } else {
             let tokenizer_file = tokenizer_repo.get(tokenizer_filename)?;
             TextGeneratorPipeline::with_quantized_gguf(
-                &phi_repo,
+                &*phi_repo,
                 &model_type,
                 tokenizer_file,
                 gguf_filename,
                 general_model_config.seed,
                 general_model_config.temperature,
                 general_model_config.top_p,
+                general_model_config.top_k,
+                general_model_config.min_p,
                 general_model_config.repeat_penalty,
                 general_model_config.repeat_context_size,
             )?
         };
 
         Ok(Self {
-            base: base.clone(),
             generator_pipeline,
             alt_prompt,
         })
@@ -87,20 +98,41 @@ impl RawHandler for PhiModel {
     #[tracing::instrument(level = "info", skip(self, request))]
     fn run_raw(&mut self, request: RawRequest) -> Result<RawResponse> {
         let pipeline = &mut self.generator_pipeline;
-        let logits = LogitsProcessor::new(
-            request.model_config.seed.unwrap_or_else(random),
+        let seed = request.model_config.seed.unwrap_or_else(random);
+        let logits = logits_processor(
+            seed,
             request.model_config.temperature,
+            request.model_config.top_k,
             request.model_config.top_p,
         );
 
         pipeline.repeat_penalty = request.model_config.repeat_penalty;
         pipeline.repeat_context_size = request.model_config.repeat_context_size;
+        pipeline.temperature = request.model_config.temperature;
+        pipeline.min_p = request.model_config.min_p;
         pipeline.logits_processor = logits;
 
-        let (output, inference_time) = pipeline.generate(&request.input, request.max_length)?;
+        let (output, inference_time, tokens, cut_off) = pipeline.generate(
+            &request.input,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            request.constraint.as_ref(),
+        )?;
+        let debug = if request.debug {
+            Some(pipeline.generation_debug(&request, seed)?)
+        } else {
+            None
+        };
         Ok(RawResponse {
-            output,
+            output: request.truncation.apply(output, cut_off),
             inference_time,
+            tokens,
+            id: None,
+            model: None,
+            debug,
+            runtime: request.runtime.then(|| pipeline.runtime_info()),
         })
     }
 }
@@ -113,13 +145,62 @@ impl InstructHandler for PhiModel {
         } else {
             format!("Instruct: {}\nOutput:", request.input)
         };
-        let (output, inference_time) = self
-            .generator_pipeline
-            .generate(&prompt, request.max_length)?;
+        let (output, inference_time, tokens, cut_off) = self.generator_pipeline.generate(
+            &prompt,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            request.constraint.as_ref(),
+        )?;
 
         Ok(InstructResponse {
-            output,
+            output: request.truncation.apply(output, cut_off),
+            inference_time,
+            tokens,
+            id: None,
+            model: None,
+            runtime: request
+                .runtime
+                .then(|| self.generator_pipeline.runtime_info()),
+            attempts: None,
+        })
+    }
+}
+
+impl ChatHandler for PhiModel {
+    #[tracing::instrument(level = "info", skip(self, request))]
+    fn run_chat(&mut self, request: ChatRequest) -> Result<ChatResponse> {
+        let prompt = if self.alt_prompt {
+            tagged_prompt(&request.messages, "<|end|>")
+        } else {
+            transcript_prompt(&request.messages)
+        };
+        let (output, inference_time, tokens, cut_off) = self.generator_pipeline.generate(
+            &prompt,
+            request.max_length,
+            request.watermark,
+            &request.banned_words,
+            &request.stop,
+            None,
+        )?;
+
+        Ok(ChatResponse {
+            output: request.truncation.apply(output, cut_off),
             inference_time,
+            tokens,
+            id: None,
+            model: None,
+            runtime: request
+                .runtime
+                .then(|| self.generator_pipeline.runtime_info()),
         })
     }
 }
+
+impl WatermarkHandler for PhiModel {
+    #[tracing::instrument(level = "info", skip(self, request))]
+    fn detect_watermark(&mut self, request: DetectWatermarkRequest) -> Result<WatermarkDetection> {
+        self.generator_pipeline.detect_watermark(&request.input)
+    }
+}
@@ -3,14 +3,17 @@ use hf_hub::api::sync::Api;
 use hf_hub::{Repo, RepoType};
 use rand::SeedableRng;
 
-use crate::inference::audio_pipeline::AudioGeneratorPipeline;
+use crate::inference::artifact_store::open_repo;
+use crate::inference::audio_input::AudioInput;
+use crate::inference::audio_pipeline::{AudioGeneratorPipeline, Segment};
 use crate::inference::models::model::ModelBase;
-use crate::inference::task::transcribe::{TranscribeHandler, TranscribeResponse};
+use crate::inference::task::transcribe::{
+    TranscribeHandler, TranscribeRequest, TranscribeResponse,
+};
 
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples/examples/whisper/main.rs
 #[derive(Clone)]
 pub struct WhisperModel {
-    pub base: ModelBase,
     generator_pipeline: AudioGeneratorPipeline,
 }
 
@@ -24,13 +27,16 @@ impl WhisperModel {
         gguf_filename: &str,
         mel_filters_filename: &str,
     ) -> Result<Self> {
-        let repo = api.repo(Repo::with_revision(
-            base.repo_id.clone(),
-            RepoType::Model,
-            base.repo_revision.clone(),
-        ));
+        let repo = open_repo(
+            &api,
+            Repo::with_revision(
+                base.repo_id.clone(),
+                RepoType::Model,
+                base.repo_revision.clone(),
+            ),
+        );
         let generator_pipeline = AudioGeneratorPipeline::with_gguf_model(
-            &repo,
+            &*repo,
             config_filename,
             tokenizer_filename,
             gguf_filename,
@@ -39,10 +45,7 @@ impl WhisperModel {
             rand::rngs::StdRng::from_seed([0; 32]),
         )?;
 
-        Ok(Self {
-            base: base.clone(),
-            generator_pipeline,
-        })
+        Ok(Self { generator_pipeline })
     }
 }
 
@@ -50,14 +53,36 @@ impl TranscribeHandler for WhisperModel {
     #[tracing::instrument(level = "info", skip(self, input))]
     fn run_transcribe(
         &mut self,
-        input: Box<[u8]>,
-        language_token: &str,
+        input: AudioInput,
+        request: &TranscribeRequest,
     ) -> Result<TranscribeResponse, Error> {
-        let output = self.generator_pipeline.transcribe(input, language_token)?;
+        let (output, audio_duration, warnings) = self.generator_pipeline.transcribe(
+            input,
+            &request.language,
+            request.max_decode_steps,
+        )?;
 
         Ok(TranscribeResponse {
             output,
             inference_time: 0.0,
+            audio_duration,
+            warnings,
+            punctuation_restored: None,
+            runtime: request
+                .runtime
+                .then(|| self.generator_pipeline.runtime_info()),
         })
     }
+
+    #[tracing::instrument(level = "info", skip(self, samples))]
+    fn run_transcribe_samples(
+        &mut self,
+        samples: &[f32],
+        language: &str,
+    ) -> Result<Vec<Segment>, Error> {
+        let (output, _) = self
+            .generator_pipeline
+            .transcribe_samples(samples, language, None)?;
+        Ok(output)
+    }
 }
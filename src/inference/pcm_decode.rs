@@ -1,5 +1,3 @@
-use std::io::Cursor;
-
 use symphonia::core::audio::{AudioBufferRef, Signal};
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
 use symphonia::core::conv::FromSample;
@@ -7,6 +5,9 @@ use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
 use symphonia::core::meta::MetadataOptions;
 
+use crate::inference::audio_input::AudioInput;
+use crate::inference::error::InferenceError;
+
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples/examples/whisper/pcm_decode.rs
 #[tracing::instrument(level = "trace", skip(samples, data))]
 fn conv<T>(samples: &mut Vec<f32>, data: &symphonia::core::audio::AudioBuffer<T>)
@@ -17,10 +18,17 @@ where
     samples.extend(data.chan(0).iter().map(|v| f32::from_sample(*v)));
 }
 
-#[tracing::instrument(level = "trace", skip(cursor))]
-pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)> {
+#[tracing::instrument(level = "trace", skip(input))]
+pub fn pcm_decode(input: AudioInput) -> anyhow::Result<(Vec<f32>, u32)> {
+    if input.is_empty() {
+        return Err(InferenceError::EmptyAudio.into());
+    }
+
     // Create the media source stream.
-    let mss = MediaSourceStream::new(Box::new(cursor), MediaSourceStreamOptions::default());
+    let mss = MediaSourceStream::new(
+        input.into_media_source()?,
+        MediaSourceStreamOptions::default(),
+    );
 
     // Create a probe hint using the file's extension. [Optional]
     let hint = symphonia::core::probe::Hint::new();
@@ -30,7 +38,9 @@ pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)>
     let fmt_opts = FormatOptions::default();
 
     // Probe the media source.
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &fmt_opts, &meta_opts)
+        .map_err(|e| InferenceError::UnsupportedContainer(e.to_string()))?;
     // Get the instantiated format reader.
     let mut format = probed.format;
 
@@ -39,7 +49,7 @@ pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)>
         .tracks()
         .iter()
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
+        .ok_or(InferenceError::NoAudioTrack)?;
 
     // Use the default options for the decoder.
     let dec_opts = DecoderOptions::default();
@@ -47,7 +57,7 @@ pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)>
     // Create a decoder for the track.
     let mut decoder = symphonia::default::get_codecs()
         .make(&track.codec_params, &dec_opts)
-        .expect("unsupported codec");
+        .map_err(|e| InferenceError::UnsupportedCodec(e.to_string()))?;
     let track_id = track.id;
     let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
     let mut pcm_data = Vec::new();
@@ -62,7 +72,10 @@ pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)>
         if packet.track_id() != track_id {
             continue;
         }
-        match decoder.decode(&packet)? {
+        match decoder
+            .decode(&packet)
+            .map_err(|e| InferenceError::CorruptAudio(e.to_string()))?
+        {
             AudioBufferRef::F32(buf) => pcm_data.extend(buf.chan(0)),
             AudioBufferRef::U8(data) => conv(&mut pcm_data, &data),
             AudioBufferRef::U16(data) => conv(&mut pcm_data, &data),
@@ -75,5 +88,8 @@ pub fn pcm_decode(cursor: Cursor<Box<[u8]>>) -> anyhow::Result<(Vec<f32>, u32)>
             AudioBufferRef::F64(data) => conv(&mut pcm_data, &data),
         }
     }
+    if pcm_data.is_empty() {
+        return Err(InferenceError::EmptyAudio.into());
+    }
     Ok((pcm_data, sample_rate))
 }
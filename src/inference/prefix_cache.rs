@@ -0,0 +1,180 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of prefixes whose key-value cache is kept per model, disabled at 0
+static CACHE_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Minimum number of tokens a prompt has to share with a recent one to cache their prefix
+static MIN_TOKENS: AtomicUsize = AtomicUsize::new(64);
+/// Number of recent prompts that new prompts are compared to for a shared prefix
+const RECENT_PROMPTS: usize = 8;
+
+#[tracing::instrument(level = "info")]
+pub fn configure_prefix_cache(size: usize, min_tokens: usize) {
+    CACHE_SIZE.store(size, Ordering::Relaxed);
+    MIN_TOKENS.store(min_tokens.max(1), Ordering::Relaxed);
+}
+
+pub fn prefix_cache_enabled() -> bool {
+    CACHE_SIZE.load(Ordering::Relaxed) > 0
+}
+
+/// Snapshots of a model right after it processed prompt prefixes shared by recent requests, so
+/// that later prompts starting with them only need to process the rest
+#[derive(Debug)]
+pub struct PrefixCache<M> {
+    /// Least recently used first
+    entries: VecDeque<Entry<M>>,
+    recent: VecDeque<Vec<u32>>,
+}
+
+#[derive(Debug)]
+struct Entry<M> {
+    hash: u64,
+    tokens: Vec<u32>,
+    model: M,
+}
+
+impl<M> Default for PrefixCache<M> {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl<M: Clone> PrefixCache<M> {
+    /// Returns the model with the longest cached prefix of the prompt and the length of the prefix.
+    /// At least the last token of the prompt is left to be processed.
+    #[tracing::instrument(level = "trace", skip(self, prompt))]
+    pub fn lookup(&mut self, prompt: &[u32]) -> Option<(usize, M)> {
+        let index = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry.tokens.len() < prompt.len()
+                    && hash_tokens(&prompt[..entry.tokens.len()]) == entry.hash
+                    && prompt.starts_with(&entry.tokens)
+            })
+            .max_by_key(|(_, entry)| entry.tokens.len())
+            .map(|(index, _)| index)?;
+        let entry = self.entries.remove(index)?;
+        let found = (entry.tokens.len(), entry.model.clone());
+        self.entries.push_back(entry);
+        Some(found)
+    }
+
+    /// Remembers the prompt and returns the length of the longest prefix it shares with a recent
+    /// prompt if that is worth caching
+    #[tracing::instrument(level = "trace", skip(self, prompt))]
+    pub fn shared_prefix(&mut self, prompt: &[u32]) -> Option<usize> {
+        let shared = self
+            .recent
+            .iter()
+            .map(|recent| {
+                recent
+                    .iter()
+                    .zip(prompt)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+            .min(prompt.len().saturating_sub(1));
+        self.recent.retain(|recent| recent != prompt);
+        if self.recent.len() >= RECENT_PROMPTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(prompt.to_vec());
+        (shared >= MIN_TOKENS.load(Ordering::Relaxed)).then_some(shared)
+    }
+
+    /// Adds the model that processed exactly the prefix, evicting the least recently used one
+    #[tracing::instrument(level = "trace", skip(self, prefix, model))]
+    pub fn insert(&mut self, prefix: &[u32], model: M) {
+        let hash = hash_tokens(prefix);
+        self.entries
+            .retain(|entry| entry.hash != hash || entry.tokens != prefix);
+        while self.entries.len() >= CACHE_SIZE.load(Ordering::Relaxed).max(1) {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            hash,
+            tokens: prefix.to_vec(),
+            model,
+        });
+    }
+}
+
+fn hash_tokens(tokens: &[u32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> PrefixCache<&'static str> {
+        configure_prefix_cache(2, 3);
+        PrefixCache::default()
+    }
+
+    #[test]
+    fn shared_prefix_needs_a_recent_prompt() {
+        let mut cache = cache();
+        assert_eq!(cache.shared_prefix(&[1, 2, 3, 4, 5]), None);
+        assert_eq!(cache.shared_prefix(&[1, 2, 3, 4, 9]), Some(4));
+        assert_eq!(cache.shared_prefix(&[1, 2, 7]), None);
+    }
+
+    #[test]
+    fn shared_prefix_leaves_the_last_token() {
+        let mut cache = cache();
+        cache.shared_prefix(&[1, 2, 3, 4]);
+        assert_eq!(cache.shared_prefix(&[1, 2, 3, 4]), Some(3));
+    }
+
+    #[test]
+    fn lookup_returns_the_longest_prefix() {
+        let mut cache = cache();
+        cache.insert(&[1, 2, 3], "short");
+        cache.insert(&[1, 2, 3, 4], "long");
+        assert_eq!(cache.lookup(&[1, 2, 3, 4, 5]), Some((4, "long")));
+        assert_eq!(cache.lookup(&[1, 2, 3, 5]), Some((3, "short")));
+        assert_eq!(cache.lookup(&[2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn lookup_leaves_the_last_token() {
+        let mut cache = cache();
+        cache.insert(&[1, 2, 3], "prefix");
+        assert_eq!(cache.lookup(&[1, 2, 3]), None);
+        assert_eq!(cache.lookup(&[1, 2, 3, 4]), Some((3, "prefix")));
+    }
+
+    #[test]
+    fn insert_evicts_the_least_recently_used() {
+        let mut cache = cache();
+        cache.insert(&[1, 1, 1], "first");
+        cache.insert(&[2, 2, 2], "second");
+        assert!(cache.lookup(&[1, 1, 1, 0]).is_some());
+        cache.insert(&[3, 3, 3], "third");
+        assert_eq!(cache.lookup(&[2, 2, 2, 0]), None);
+        assert_eq!(cache.lookup(&[1, 1, 1, 0]), Some((3, "first")));
+        assert_eq!(cache.lookup(&[3, 3, 3, 0]), Some((3, "third")));
+    }
+
+    #[test]
+    fn insert_replaces_the_same_prefix() {
+        let mut cache = cache();
+        cache.insert(&[1, 2, 3], "old");
+        cache.insert(&[1, 2, 3], "new");
+        cache.insert(&[4, 5, 6], "other");
+        assert_eq!(cache.lookup(&[1, 2, 3, 0]), Some((3, "new")));
+    }
+}
@@ -0,0 +1,110 @@
+use std::f32::consts::TAU;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::inference::model_slot::{ManagedModel, ModelStatus};
+
+/// Prompt text models are probed with, answering it takes only a few tokens
+pub const PROBE_PROMPT: &str = "Reply with the single word OK.";
+/// Maximum number of tokens generated for the probe prompt
+pub const PROBE_MAX_LENGTH: usize = 8;
+
+/// Seconds between two probes of every loaded model, zero disables probing
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+/// Milliseconds a probe may take before it counts as failed
+static MAX_LATENCY: AtomicU64 = AtomicU64::new(10_000);
+/// Number of consecutive failed probes after which a model is marked as degraded
+static FAILURE_THRESHOLD: AtomicU32 = AtomicU32::new(3);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_probes(interval: Duration, max_latency: Duration, failure_threshold: u32) {
+    INTERVAL.store(interval.as_secs(), Ordering::Relaxed);
+    MAX_LATENCY.store(
+        max_latency.as_millis().try_into().unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+    FAILURE_THRESHOLD.store(failure_threshold.max(1), Ordering::Relaxed);
+}
+
+pub fn probes_enabled() -> bool {
+    INTERVAL.load(Ordering::Relaxed) > 0
+}
+
+pub fn failure_threshold() -> u32 {
+    FAILURE_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// How a probe of a model went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    Healthy,
+    /// The probe succeeded but took longer than allowed
+    Slow,
+    /// The inference failed or its output did not have the expected shape
+    Failed,
+}
+
+/// One second of a 440 Hz tone at 16 kHz that transcription models are probed with
+#[allow(clippy::cast_precision_loss)]
+pub fn probe_audio() -> Vec<f32> {
+    (0..16_000)
+        .map(|sample| (TAU * 440.0 * sample as f32 / 16_000.0).sin() * 0.1)
+        .collect()
+}
+
+/// Runs the probe against every loaded model in the configured interval and reports the outcome
+/// to the model. Models that are unhealthy or offline are left alone.
+#[tracing::instrument(level = "info", skip(models, probe))]
+pub async fn run_probes<F, Fut>(models: Vec<&'static dyn ManagedModel>, probe: F)
+where
+    F: Fn(&'static str) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let interval = Duration::from_secs(INTERVAL.load(Ordering::Relaxed));
+    let max_latency = Duration::from_millis(MAX_LATENCY.load(Ordering::Relaxed));
+    loop {
+        tokio::time::sleep(interval).await;
+        for model in &models {
+            if !model.is_loaded()
+                || matches!(
+                    model.status(),
+                    ModelStatus::Unhealthy | ModelStatus::Offline
+                )
+            {
+                continue;
+            }
+
+            let started = Instant::now();
+            let result = probe(model.name()).await;
+            let latency = started.elapsed();
+            info!(
+                histogram.probe_latency_ms = latency.as_secs_f64() * 1000.0,
+                model = model.name()
+            );
+            let outcome = match result {
+                Ok(()) if latency <= max_latency => ProbeOutcome::Healthy,
+                Ok(()) => {
+                    warn!(
+                        "Probe of model {} took {:?}, more than the allowed {:?}",
+                        model.name(),
+                        latency,
+                        max_latency
+                    );
+                    ProbeOutcome::Slow
+                }
+                Err(err) => {
+                    warn!("Probe of model {} failed: {:#}", model.name(), err);
+                    ProbeOutcome::Failed
+                }
+            };
+            if outcome != ProbeOutcome::Healthy {
+                info!(monotonic_counter.probe_failures = 1, model = model.name());
+            }
+            model.record_probe(outcome);
+        }
+    }
+}
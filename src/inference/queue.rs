@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Number of recent waits per model percentiles are computed over
+const SAMPLE_COUNT: usize = 256;
+
+/// How long recent requests of each model waited for an inference worker to pick them up
+static WAITS: Mutex<BTreeMap<&'static str, VecDeque<Duration>>> = Mutex::new(BTreeMap::new());
+
+#[tracing::instrument(level = "trace")]
+pub fn record_wait(model: &'static str, wait: Duration) {
+    let mut waits = waits();
+    let samples = waits.entry(model).or_default();
+    if samples.len() == SAMPLE_COUNT {
+        samples.pop_front();
+    }
+    samples.push_back(wait);
+    drop(waits);
+}
+
+/// Returns the given percentile of recent queue waits of the model, or of all models if none is
+/// given, if any request was served yet
+#[tracing::instrument(level = "trace")]
+pub fn wait_percentile(model: Option<&str>, percentile: usize) -> Option<Duration> {
+    let mut waits: Vec<Duration> = waits()
+        .iter()
+        .filter(|(name, _)| model.is_none_or(|model| model == **name))
+        .flat_map(|(_, waits)| waits.iter().copied())
+        .collect();
+    if waits.is_empty() {
+        return None;
+    }
+    waits.sort_unstable();
+    let index = (waits.len() * percentile.min(100)).div_ceil(100).max(1) - 1;
+    Some(waits[index])
+}
+
+fn waits() -> MutexGuard<'static, BTreeMap<&'static str, VecDeque<Duration>>> {
+    WAITS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    // Waits are recorded globally, so a single test covers the percentiles over all models
+    #[test]
+    fn wait_percentiles() {
+        assert_eq!(wait_percentile(Some("queue-none"), 50), None);
+
+        for wait in 1..=10 {
+            record_wait("queue-a", millis(wait));
+        }
+        record_wait("queue-b", millis(100));
+        assert_eq!(wait_percentile(Some("queue-a"), 0), Some(millis(1)));
+        assert_eq!(wait_percentile(Some("queue-a"), 50), Some(millis(5)));
+        assert_eq!(wait_percentile(Some("queue-a"), 95), Some(millis(10)));
+        assert_eq!(wait_percentile(Some("queue-a"), 200), Some(millis(10)));
+        assert_eq!(wait_percentile(Some("queue-b"), 50), Some(millis(100)));
+        assert_eq!(wait_percentile(None, 100), Some(millis(100)));
+        assert_eq!(wait_percentile(None, 50), Some(millis(6)));
+
+        for wait in 0..SAMPLE_COUNT as u64 {
+            record_wait("queue-b", millis(wait));
+        }
+        assert_eq!(
+            wait_percentile(Some("queue-b"), 100),
+            Some(millis(SAMPLE_COUNT as u64 - 1))
+        );
+    }
+}
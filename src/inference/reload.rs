@@ -0,0 +1,56 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use cron::Schedule;
+use hf_hub::Cache;
+use tracing::{error, info, warn};
+
+use crate::inference::artifact_store::main_repo_folders;
+use crate::inference::model_slot::ManagedModel;
+
+/// Parses the cron expression of the maintenance window
+#[tracing::instrument(level = "trace")]
+pub fn parse_schedule(expression: &str) -> Result<Schedule> {
+    Schedule::from_str(expression)
+        .with_context(|| format!("Invalid cron expression for reload schedule: {expression}"))
+}
+
+/// Refreshes all loaded models each time the maintenance window of the schedule starts
+#[tracing::instrument(level = "info", skip(schedule, models))]
+pub async fn run_reload_schedule(schedule: Schedule, models: Vec<&'static dyn ManagedModel>) {
+    while let Some(next) = schedule.upcoming(Utc).next() {
+        info!("Next model reload window starts at {}", next);
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let models = models.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            invalidate_main_refs();
+            for model in models {
+                model.refresh();
+            }
+        })
+        .await;
+        if let Err(err) = result {
+            error!("Model reload window failed: {:?}", err);
+        }
+    }
+    warn!("Reload schedule has no upcoming windows");
+}
+
+/// Removes the cached `main` refs of the repos opened by the models so that the next download
+/// re-resolves them to the latest commit. Repos pinned to a commit hash and the ones of other
+/// applications sharing the cache are left untouched.
+#[tracing::instrument(level = "info")]
+fn invalidate_main_refs() {
+    let cache = Cache::default();
+    for folder in main_repo_folders() {
+        let main_ref = cache.path().join(folder).join("refs").join("main");
+        if main_ref.is_file() {
+            if let Err(err) = std::fs::remove_file(&main_ref) {
+                warn!("Failed to invalidate {}: {}", main_ref.display(), err);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType};
+use candle_core::{DType, Device};
+use candle_transformers::quantized_var_builder::VarBuilder;
+use memmap2::Mmap;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::RuntimeOverrides;
+
+static RUNTIMES: OnceLock<BTreeMap<String, ModelRuntime>> = OnceLock::new();
+/// The device models are placed on unless their runtime overrides it
+static DEFAULT_DEVICE: OnceLock<Device> = OnceLock::new();
+
+thread_local! {
+    /// Runtime of the model that is loaded or run on the current thread
+    static CURRENT: Cell<Option<&'static ModelRuntime>> = const { Cell::new(None) };
+}
+
+/// How a single model is executed where it differs from the defaults of the process
+struct ModelRuntime {
+    device: Device,
+    mmap: bool,
+    /// Dedicated thread pool the model computes on instead of the global one
+    pool: Option<ThreadPool>,
+}
+
+/// Restores the runtime of the previous model on the thread once dropped
+struct RuntimeGuard(Option<&'static ModelRuntime>);
+
+impl Drop for RuntimeGuard {
+    fn drop(&mut self) {
+        CURRENT.set(self.0);
+    }
+}
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Describes how a model is executed, allowing output and latency differences between deployments
+/// to be attributed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RuntimeInfo {
+    pub device: String,
+    /// The data type activations are computed in
+    pub dtype: String,
+    /// The predominant quantization of the model weights
+    pub quantization: String,
+    pub threads: usize,
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_runtimes(
+    device: &str,
+    overrides: &BTreeMap<String, RuntimeOverrides>,
+) -> Result<()> {
+    let device = parse_device(device).context("Unusable default device")?;
+    info!("Models run on {:?} by default", device);
+    if DEFAULT_DEVICE.set(device).is_err() {
+        warn!("The default device is already configured");
+    }
+    let runtimes = overrides
+        .iter()
+        .map(|(model, overrides)| {
+            let device = overrides
+                .device
+                .as_deref()
+                .map_or_else(|| Ok(default_device()), parse_device)
+                .with_context(|| format!("Unusable device for model {model}"))?;
+            let pool = overrides
+                .threads
+                .map(|threads| {
+                    let model = model.clone();
+                    ThreadPoolBuilder::new()
+                        .num_threads(threads)
+                        .thread_name(move |index| format!("{model}-{index}"))
+                        .build()
+                })
+                .transpose()
+                .with_context(|| format!("Failed to create thread pool for model {model}"))?;
+            info!(
+                "Model {} runs on {:?} with {} threads",
+                model,
+                device,
+                overrides.threads.unwrap_or_else(rayon::current_num_threads)
+            );
+            Ok((
+                model.clone(),
+                ModelRuntime {
+                    device,
+                    mmap: overrides.mmap,
+                    pool,
+                },
+            ))
+        })
+        .collect::<Result<_>>()?;
+    if RUNTIMES.set(runtimes).is_err() {
+        warn!("Model runtimes are already configured");
+    }
+    Ok(())
+}
+
+/// Parses a device such as `cpu`, `cuda:0` or `metal:0`
+#[tracing::instrument(level = "trace")]
+fn parse_device(device: &str) -> Result<Device> {
+    let (kind, ordinal) = device.split_once(':').unwrap_or((device, "0"));
+    let ordinal = ordinal
+        .parse()
+        .with_context(|| format!("Invalid device ordinal: {ordinal}"))?;
+    Ok(match kind {
+        "cpu" => Device::Cpu,
+        "cuda" if !cfg!(feature = "cuda") => {
+            bail!("Running on CUDA devices requires building with the cuda feature")
+        }
+        "cuda" => Device::new_cuda(ordinal)?,
+        "metal" if !cfg!(feature = "metal") => {
+            bail!("Running on Metal devices requires building with the metal feature")
+        }
+        "metal" => Device::new_metal(ordinal)?,
+        _ => bail!("Unknown device {kind}, supported are cpu, cuda and metal"),
+    })
+}
+
+/// Runs the closure with the runtime of the model, on its own thread pool if it has one
+pub fn with_runtime<R, F>(model: &str, f: F) -> R
+where
+    R: Send,
+    F: FnOnce() -> R + Send,
+{
+    let Some(runtime) = RUNTIMES.get().and_then(|runtimes| runtimes.get(model)) else {
+        return f();
+    };
+    let run = || {
+        let _guard = RuntimeGuard(CURRENT.replace(Some(runtime)));
+        f()
+    };
+    match &runtime.pool {
+        Some(pool) => pool.install(run),
+        None => run(),
+    }
+}
+
+/// The device the model loaded on the current thread is placed on
+pub fn current_device() -> Device {
+    CURRENT
+        .get()
+        .map_or_else(default_device, |runtime| runtime.device.clone())
+}
+
+fn default_device() -> Device {
+    DEFAULT_DEVICE.get().cloned().unwrap_or(Device::Cpu)
+}
+
+fn memory_mapped() -> bool {
+    CURRENT.get().is_some_and(|runtime| runtime.mmap)
+}
+
+#[tracing::instrument(level = "trace")]
+fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: The cached model artifacts are never modified while they are in use
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Opens the gguf file, through a memory map if the model loaded on the current thread uses one
+#[tracing::instrument(level = "trace")]
+pub fn open_gguf(path: &Path) -> Result<Box<dyn ReadSeek>> {
+    if memory_mapped() {
+        Ok(Box::new(Cursor::new(map_file(path)?)))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// Reads all tensors of the gguf file, through a memory map if the model loaded on the current
+/// thread uses one
+#[tracing::instrument(level = "trace", skip(device))]
+pub fn gguf_var_builder(path: &Path, device: &Device) -> Result<VarBuilder> {
+    if memory_mapped() {
+        Ok(VarBuilder::from_gguf_buffer(&map_file(path)?, device)?)
+    } else {
+        Ok(VarBuilder::from_gguf(path, device)?)
+    }
+}
+
+impl RuntimeInfo {
+    #[tracing::instrument(level = "trace")]
+    /// The data type is unknown until the model ran at least once
+    pub fn new(device: &Device, dtype: Option<DType>, quantization: &str) -> Self {
+        let device = match device {
+            Device::Cpu => "cpu",
+            Device::Cuda(_) => "cuda",
+            Device::Metal(_) => "metal",
+        };
+        Self {
+            device: device.into(),
+            dtype: dtype.map_or("unknown", |dtype| dtype.as_str()).into(),
+            quantization: quantization.into(),
+            threads: rayon::current_num_threads(),
+        }
+    }
+}
+
+/// Returns the quantization used by most tensors of the gguf model
+#[tracing::instrument(level = "trace", skip(content))]
+pub fn gguf_quantization(content: &gguf_file::Content) -> String {
+    let mut counts: HashMap<GgmlDType, usize> = HashMap::new();
+    for info in content.tensor_infos.values() {
+        *counts.entry(info.ggml_dtype).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(dtype, _)| !matches!(dtype, GgmlDType::F32))
+        .max_by_key(|(_, count)| *count)
+        .map_or_else(
+            || "none".into(),
+            |(dtype, _)| format!("{dtype:?}").to_lowercase(),
+        )
+}
+
+/// Reads the header of the gguf file to determine its quantization
+#[tracing::instrument(level = "trace")]
+pub fn gguf_file_quantization(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    Ok(gguf_quantization(&content))
+}
@@ -0,0 +1,41 @@
+use std::cell::RefCell;
+
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::inference::error::InferenceError;
+
+/// Receives the text of the tokens as they are generated
+pub type TokenSink = UnboundedSender<String>;
+
+thread_local! {
+    /// Sink of the generation running on the current worker thread
+    static CURRENT: RefCell<Option<TokenSink>> = const { RefCell::new(None) };
+}
+
+/// Detaches the sink from the worker thread once dropped, which closes it
+pub struct AttachGuard;
+
+impl Drop for AttachGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// Attaches the sink to the current worker thread
+#[must_use]
+pub fn attach(sink: TokenSink) -> AttachGuard {
+    CURRENT.with(|current| *current.borrow_mut() = Some(sink));
+    AttachGuard
+}
+
+/// Passes the text of a generated token on to the sink of the current thread if there is one.
+/// Fails once the receiver is gone so that the worker stops generating for nobody.
+pub fn emit(text: &str) -> Result<()> {
+    CURRENT.with(|current| {
+        current.borrow().as_ref().map_or(Ok(()), |sink| {
+            sink.send(text.to_string())
+                .map_err(|_| InferenceError::StreamClosed.into())
+        })
+    })
+}
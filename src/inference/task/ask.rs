@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of chunks a question may be grounded in
+pub const MAX_TOP_K: usize = 20;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AskRequest {
+    pub model: String,
+    pub query: String,
+    /// Previously uploaded documents the answer is grounded in
+    pub document_ids: Vec<String>,
+    /// Number of chunks retrieved from the documents
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    pub max_length: usize,
+}
+
+const fn default_top_k() -> usize {
+    4
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AskResponse {
+    pub output: String,
+    pub inference_time: f64,
+    /// The chunks the answer was grounded in, numbered in the prompt in this order
+    pub sources: Vec<SourceChunk>,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SourceChunk {
+    pub document_id: String,
+    /// Position of the chunk within the document
+    pub position: usize,
+    pub score: f64,
+    pub content: String,
+}
+
+/// Builds a prompt asking to answer the query only from the numbered sources
+#[tracing::instrument(level = "trace", skip(sources))]
+pub fn grounded_prompt(query: &str, sources: &[SourceChunk]) -> String {
+    let sources = sources
+        .iter()
+        .enumerate()
+        .map(|(index, source)| format!("[{}] {}", index + 1, source.content))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    format!(
+        "Answer the question using only the sources below and cite them by their number like [1]. \
+        If the sources do not contain the answer, say so.\n\n{sources}\n\nQuestion: {query}"
+    )
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::inference::task::raw::TokenCounts;
+use crate::normalization::Normalization;
+use crate::truncation::Truncation;
+
+/// Maximum number of inputs of a single batch request
+static MAX_BATCH_SIZE: AtomicUsize = AtomicUsize::new(32);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_batching(max_batch_size: usize) {
+    MAX_BATCH_SIZE.store(max_batch_size.max(1), Ordering::Relaxed);
+}
+
+pub fn max_batch_size() -> usize {
+    MAX_BATCH_SIZE.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BatchRequest {
+    pub model: String,
+    /// Instructions that are generated for independently of each other
+    pub inputs: Vec<String>,
+    /// Maximum number of tokens of every output
+    pub max_length: usize,
+    /// Strings that end a generation once produced, they are left out of the output
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Bias the generation towards the green list, set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings whose tokens are masked while sampling
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+    #[serde(flatten)]
+    pub truncation: Truncation,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct BatchResponse {
+    /// Outputs in the order of the inputs
+    pub outputs: Vec<BatchOutput>,
+    /// Time from the start of the first generation until the last one completed
+    pub inference_time: f64,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct BatchOutput {
+    pub output: String,
+    pub inference_time: f64,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+}
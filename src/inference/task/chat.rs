@@ -0,0 +1,148 @@
+use std::fmt::Write;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::inference::runtime::RuntimeInfo;
+use crate::inference::task::raw::TokenCounts;
+use crate::normalization::Normalization;
+use crate::truncation::Truncation;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+impl ChatRole {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    /// The conversation so far, ending with the message of the user to reply to
+    pub messages: Vec<ChatMessage>,
+    pub max_length: usize,
+    /// Include how the model is executed in the response
+    #[serde(default)]
+    pub runtime: bool,
+    /// Strings that end the generation once produced, they are left out of the output
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Bias the generation towards the green list, set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings whose tokens are masked while sampling
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+    #[serde(flatten)]
+    pub truncation: Truncation,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ChatResponse {
+    pub output: String,
+    pub inference_time: f64,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+    /// Identifies the response when attaching feedback, only set while interactions are logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeInfo>,
+}
+
+pub trait ChatHandler {
+    fn run_chat(&mut self, params: ChatRequest) -> Result<ChatResponse, Error>;
+}
+
+/// Renders the conversation in the `ChatML` format of `OpenHermes`
+#[tracing::instrument(level = "trace", skip(messages))]
+pub fn chatml_prompt(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        let _ = write!(
+            prompt,
+            "<|im_start|>{}\n{}<|im_end|>\n",
+            message.role.as_str(),
+            message.content
+        );
+    }
+    prompt.push_str("<|im_start|>assistant\n");
+    prompt
+}
+
+/// Renders the conversation with `<|role|>` tags, each message closed by the end token of the
+/// model, as used by Phi-3 and `StableLM` Zephyr
+#[tracing::instrument(level = "trace", skip(messages))]
+pub fn tagged_prompt(messages: &[ChatMessage], end_token: &str) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        let _ = write!(
+            prompt,
+            "<|{}|>\n{}{end_token}\n",
+            message.role.as_str(),
+            message.content
+        );
+    }
+    prompt.push_str("<|assistant|>\n");
+    prompt
+}
+
+/// Renders the conversation with `[INST]` blocks as used by Mistral, which has no system role so
+/// system messages are put in front of the next message of the user
+#[tracing::instrument(level = "trace", skip(messages))]
+pub fn mistral_prompt(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::from("<s>");
+    let mut system = Vec::new();
+    for message in messages {
+        match message.role {
+            ChatRole::System => system.push(message.content.as_str()),
+            ChatRole::User => {
+                system.push(&message.content);
+                let _ = write!(prompt, "[INST] {} [/INST]", system.join("\n\n"));
+                system.clear();
+            }
+            ChatRole::Assistant => {
+                let _ = write!(prompt, " {}</s>", message.content);
+            }
+        }
+    }
+    prompt
+}
+
+/// Renders the conversation as a plain transcript for models without a chat template
+#[tracing::instrument(level = "trace", skip(messages))]
+pub fn transcript_prompt(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        let speaker = match message.role {
+            ChatRole::System => "System",
+            ChatRole::User => "User",
+            ChatRole::Assistant => "Assistant",
+        };
+        let _ = writeln!(prompt, "{speaker}: {}", message.content);
+    }
+    prompt.push_str("Assistant:");
+    prompt
+}
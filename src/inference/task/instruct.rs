@@ -1,17 +1,89 @@
+use std::sync::Arc;
+
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+use crate::inference::grammar::Grammar;
+use crate::inference::runtime::RuntimeInfo;
+use crate::inference::task::raw::TokenCounts;
+use crate::inference::task::structured::StructuredOutput;
+use crate::normalization::Normalization;
+use crate::truncation::Truncation;
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct InstructRequest {
     pub model: String,
     pub input: String,
     pub max_length: usize,
+    /// Include how the model is executed in the response
+    #[serde(default)]
+    pub runtime: bool,
+    /// Send the tokens as server-sent events while they are generated
+    #[serde(default)]
+    pub stream: bool,
+    /// Constrain the sampling to JSON of this shape, validate the output and regenerate it when it
+    /// does not match
+    #[serde(default)]
+    pub response_format: Option<StructuredOutput>,
+    /// GBNF grammar that the output has to match, instead of a response format
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// Grammar the sampling is constrained to, derived from the response format or the grammar
+    #[serde(skip)]
+    pub constraint: Option<Arc<Grammar>>,
+    /// Bias the generation towards the green list, set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings whose tokens are masked while sampling
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+    /// Strings that end the generation once produced, they are left out of the output
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(flatten)]
+    pub truncation: Truncation,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+impl InstructRequest {
+    /// Plain request for internal generations, with every option left at its default
+    pub fn new(model: impl Into<String>, input: impl Into<String>, max_length: usize) -> Self {
+        Self {
+            model: model.into(),
+            input: input.into(),
+            max_length,
+            runtime: false,
+            stream: false,
+            response_format: None,
+            grammar: None,
+            constraint: None,
+            watermark: false,
+            banned_words: Vec::new(),
+            stop: Vec::new(),
+            truncation: Truncation::default(),
+            normalization: Normalization::default(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct InstructResponse {
     pub output: String,
     pub inference_time: f64,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+    /// Identifies the response when attaching feedback, only set while interactions are logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeInfo>,
+    /// Number of generations needed to produce output matching the response format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<usize>,
 }
 
 pub trait InstructHandler {
@@ -1,4 +1,10 @@
+pub mod ask;
+pub mod batch;
+pub mod chat;
 pub mod info;
 pub mod instruct;
 pub mod raw;
+pub mod structured;
+pub mod summarize;
 pub mod transcribe;
+pub mod watermark;
@@ -1,20 +1,136 @@
+use std::sync::Arc;
+
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
+use crate::inference::grammar::Grammar;
+use crate::inference::runtime::RuntimeInfo;
+use crate::normalization::Normalization;
+use crate::truncation::Truncation;
 use crate::GeneralModelConfig;
 
-#[derive(Deserialize, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct RawRequest {
     pub model: String,
     pub input: String,
     pub max_length: usize,
     pub model_config: GeneralModelConfig,
+    /// Share the generation with identical concurrent requests, requires an explicit seed
+    #[serde(default)]
+    pub coalesce: bool,
+    /// Include the rendered prompt and the resolved generation parameters in the response
+    #[serde(default)]
+    pub debug: bool,
+    /// Include how the model is executed in the response
+    #[serde(default)]
+    pub runtime: bool,
+    /// Bias the generation towards the green list, set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings whose tokens are masked while sampling
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+    /// Strings that end the generation once produced, they are left out of the output
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// GBNF grammar that the output has to match
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// The compiled grammar the sampling is constrained to
+    #[serde(skip)]
+    pub constraint: Option<Arc<Grammar>>,
+    #[serde(flatten)]
+    pub truncation: Truncation,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+impl RawRequest {
+    /// Returns the key identifying identical requests if the request may be coalesced
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn coalesce_key(&self) -> Option<String> {
+        (self.coalesce && self.model_config.seed.is_some()).then(|| {
+            format!(
+                "{}\0{}\0{}\0{}\0{}\0{:?}\0{:?}\0{:?}\0{:?}\0{}\0{:?}",
+                self.model,
+                self.max_length,
+                self.debug,
+                self.runtime,
+                self.watermark,
+                self.banned_words,
+                self.stop,
+                self.grammar,
+                self.truncation,
+                self.input,
+                self.model_config
+            )
+        })
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RawResponse {
     pub output: String,
     pub inference_time: f64,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+    /// Identifies the response when attaching feedback, only set while interactions are logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<GenerationDebug>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeInfo>,
+}
+
+/// The prompt and parameters a generation actually ran with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GenerationDebug {
+    /// The prompt after applying the prompt template of the model
+    pub prompt: String,
+    pub seed: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub repeat_penalty: f32,
+    pub repeat_context_size: usize,
+    pub max_length: usize,
+    /// Tokens that end the generation
+    pub stop_tokens: Vec<String>,
+}
+
+/// Number of tokens a generation processed
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+pub struct TokenCounts {
+    #[serde(rename = "prompt_tokens", default)]
+    pub prompt: usize,
+    #[serde(rename = "completion_tokens", default)]
+    pub completion: usize,
+    /// Completion tokens generated per second of inference time
+    #[serde(default)]
+    pub tokens_per_second: f64,
+}
+
+impl TokenCounts {
+    /// Counts the tokens of a generation that took the inference time in seconds
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(prompt: usize, completion: usize, inference_time: f64) -> Self {
+        let tokens_per_second = if inference_time > 0.0 {
+            completion as f64 / inference_time
+        } else {
+            0.0
+        };
+        Self {
+            prompt,
+            completion,
+            tokens_per_second,
+        }
+    }
 }
 
 pub trait RawHandler {
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, bail, Result};
+use jsonschema::JSONSchema;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::inference::grammar::{Element, Grammar};
+
+/// Number of times an output that fails validation is regenerated with the validation error
+static MAX_REPAIRS: AtomicUsize = AtomicUsize::new(2);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_structured_output(max_repairs: usize) {
+    MAX_REPAIRS.store(max_repairs, Ordering::Relaxed);
+}
+
+pub fn max_repairs() -> usize {
+    MAX_REPAIRS.load(Ordering::Relaxed)
+}
+
+/// The shape a generated output has to take
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StructuredOutput {
+    /// Any JSON object
+    JsonObject,
+    /// JSON matching the schema
+    JsonSchema { schema: Value },
+}
+
+impl StructuredOutput {
+    /// Verifies that the schema of the request can be compiled and constrained to
+    #[tracing::instrument(level = "trace")]
+    pub fn check(&self) -> Result<()> {
+        if let Self::JsonSchema { schema } = self {
+            JSONSchema::compile(schema).map_err(|e| anyhow!("Invalid JSON schema: {e}"))?;
+        }
+        self.grammar()?;
+        Ok(())
+    }
+
+    /// Returns the grammar of the JSON that the sampling is constrained to
+    #[tracing::instrument(level = "trace")]
+    pub fn grammar(&self) -> Result<Grammar> {
+        let mut json = JsonGrammar::default();
+        let root = match self {
+            Self::JsonObject => json.object(),
+            Self::JsonSchema { schema } => json.schema(schema)?,
+        };
+        json.grammar.set_root(root);
+        Ok(json.grammar)
+    }
+
+    /// Tells the model which shape the output needs to have
+    #[tracing::instrument(level = "trace")]
+    pub fn instruction(&self) -> String {
+        match self {
+            Self::JsonObject => "Respond with only a JSON object and no other text.".into(),
+            Self::JsonSchema { schema } => format!(
+                "Respond with only JSON matching the following JSON schema and no other text:\n{schema}"
+            ),
+        }
+    }
+
+    /// Extracts the JSON from the output and validates it, returning the validation errors on failure
+    #[tracing::instrument(level = "trace", skip(output))]
+    pub fn validate(&self, output: &str) -> Result<Value, String> {
+        let value = extract_json(output).ok_or("The response does not contain valid JSON")?;
+        match self {
+            Self::JsonObject if value.is_object() => Ok(value),
+            Self::JsonObject => Err("The response is not a JSON object".into()),
+            Self::JsonSchema { schema } => {
+                let schema = JSONSchema::compile(schema).map_err(|e| e.to_string())?;
+                let errors = match schema.validate(&value) {
+                    Ok(()) => None,
+                    Err(errors) => Some(
+                        errors
+                            .map(|e| format!("{} at {}", e, e.instance_path))
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                    ),
+                };
+                errors.map_or(Ok(value), Err)
+            }
+        }
+    }
+}
+
+/// Parses the outermost JSON object or array of the output, models tend to wrap it in prose
+fn extract_json(output: &str) -> Option<Value> {
+    let trimmed = output.trim();
+    if let Ok(value) = serde_json::from_str(trimmed) {
+        return Some(value);
+    }
+    let start = trimmed.find(['{', '['])?;
+    let end = trimmed.rfind(['}', ']'])?;
+    serde_json::from_str(trimmed.get(start..=end)?).ok()
+}
+
+/// Asks the model to fix its previous output with the validation error
+#[tracing::instrument(level = "trace", skip(input, output))]
+pub fn repair_prompt(input: &str, output: &str, error: &str) -> String {
+    format!(
+        "{input}\n\nYour previous response was:\n{output}\n\nIt is invalid: {error}\nRespond again with only the corrected JSON."
+    )
+}
+
+/// Builds the rules of a grammar matching compact JSON, allowing at most one whitespace character
+/// between tokens so that the model cannot pad the output until it runs out of length
+#[derive(Default)]
+struct JsonGrammar {
+    grammar: Grammar,
+    /// Rules shared by the whole grammar, added once they are first needed
+    shared: HashMap<&'static str, usize>,
+}
+
+impl JsonGrammar {
+    /// Adds the rules of the schema, supporting the keywords that determine the structure
+    fn schema(&mut self, schema: &Value) -> Result<usize> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.value()),
+            Value::Object(schema) => schema,
+            _ => bail!("Unsupported JSON schema {schema}"),
+        };
+        for keyword in ["$ref", "allOf", "not", "if", "patternProperties"] {
+            if schema.contains_key(keyword) {
+                bail!("Unsupported JSON schema keyword {keyword}");
+            }
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(self.literal(value));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let alternatives = values
+                .iter()
+                .map(|value| vec![Element::Rule(self.literal(value))])
+                .collect();
+            return Ok(self.grammar.rule(alternatives));
+        }
+        if let Some(schemas) = schema
+            .get("anyOf")
+            .or_else(|| schema.get("oneOf"))
+            .and_then(Value::as_array)
+        {
+            let alternatives = schemas
+                .iter()
+                .map(|schema| Ok(vec![Element::Rule(self.schema(schema)?)]))
+                .collect::<Result<_>>()?;
+            return Ok(self.grammar.rule(alternatives));
+        }
+
+        match schema.get("type") {
+            Some(Value::String(name)) => self.typed(name, schema),
+            Some(Value::Array(names)) => {
+                let alternatives = names
+                    .iter()
+                    .map(|name| match name {
+                        Value::String(name) => Ok(vec![Element::Rule(self.typed(name, schema)?)]),
+                        _ => bail!("Unsupported JSON schema type {name}"),
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(self.grammar.rule(alternatives))
+            }
+            Some(name) => bail!("Unsupported JSON schema type {name}"),
+            None if schema.contains_key("properties") => self.typed("object", schema),
+            None if schema.contains_key("items") => self.typed("array", schema),
+            None => Ok(self.value()),
+        }
+    }
+
+    fn typed(&mut self, name: &str, schema: &Map<String, Value>) -> Result<usize> {
+        Ok(match name {
+            "object" => match schema.get("properties").and_then(Value::as_object) {
+                Some(properties) if !properties.is_empty() => {
+                    let required = schema
+                        .get("required")
+                        .and_then(Value::as_array)
+                        .map(|names| names.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+                        .unwrap_or_default();
+                    self.properties(properties, &required)?
+                }
+                _ => self.object(),
+            },
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.schema(items)?,
+                    None => self.value(),
+                };
+                self.array(item)
+            }
+            "string" => self.shared("string"),
+            "number" => self.shared("number"),
+            "integer" => self.shared("integer"),
+            "boolean" => self.shared("boolean"),
+            "null" => self.shared("null"),
+            _ => bail!("Unsupported JSON schema type {name}"),
+        })
+    }
+
+    /// Adds an object with the properties in the order of the schema, of which the ones that are
+    /// not required may be left out
+    fn properties(&mut self, properties: &Map<String, Value>, required: &[&str]) -> Result<usize> {
+        let ws = self.shared("ws");
+        let members = properties
+            .iter()
+            .map(|(name, schema)| {
+                let value = self.schema(schema)?;
+                let mut member = Grammar::literal(&Value::String(name.clone()).to_string());
+                member.extend([Element::Rule(ws), Element::char(':'), Element::Rule(ws)]);
+                member.extend([Element::Rule(value), Element::Rule(ws)]);
+                Ok((
+                    self.grammar.rule(vec![member]),
+                    required.contains(&name.as_str()),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // The members following at least one other member, which are preceded by a comma
+        let mut rest = self.grammar.rule(vec![Vec::new()]);
+        // The members of the object from the first one included
+        let mut first = rest;
+        for (member, is_required) in members.into_iter().rev() {
+            let after_comma = vec![
+                Element::char(','),
+                Element::Rule(ws),
+                Element::Rule(member),
+                Element::Rule(rest),
+            ];
+            let leading = vec![Element::Rule(member), Element::Rule(rest)];
+            if is_required {
+                rest = self.grammar.rule(vec![after_comma]);
+                first = self.grammar.rule(vec![leading]);
+            } else {
+                let skipped = vec![Element::Rule(rest)];
+                rest = self.grammar.rule(vec![after_comma, skipped]);
+                first = self.grammar.rule(vec![leading, vec![Element::Rule(first)]]);
+            }
+        }
+        Ok(self.grammar.rule(vec![vec![
+            Element::char('{'),
+            Element::Rule(ws),
+            Element::Rule(first),
+            Element::char('}'),
+        ]]))
+    }
+
+    fn literal(&mut self, value: &Value) -> usize {
+        self.grammar
+            .rule(vec![Grammar::literal(&value.to_string())])
+    }
+
+    /// Adds an array of the items
+    fn array(&mut self, item: usize) -> usize {
+        let ws = self.shared("ws");
+        let items = self.grammar.reserve();
+        self.grammar.define(
+            items,
+            vec![
+                vec![Element::Rule(item), Element::Rule(ws)],
+                vec![
+                    Element::Rule(item),
+                    Element::Rule(ws),
+                    Element::char(','),
+                    Element::Rule(ws),
+                    Element::Rule(items),
+                ],
+            ],
+        );
+        self.grammar.rule(vec![
+            vec![Element::char('['), Element::Rule(ws), Element::char(']')],
+            vec![
+                Element::char('['),
+                Element::Rule(ws),
+                Element::Rule(items),
+                Element::char(']'),
+            ],
+        ])
+    }
+
+    /// Adds an object of any members
+    fn object(&mut self) -> usize {
+        if let Some(rule) = self.shared.get("object") {
+            return *rule;
+        }
+        let object = self.grammar.reserve();
+        self.shared.insert("object", object);
+        let ws = self.shared("ws");
+        let string = self.shared("string");
+        let value = self.value();
+        let member = self.grammar.rule(vec![vec![
+            Element::Rule(string),
+            Element::Rule(ws),
+            Element::char(':'),
+            Element::Rule(ws),
+            Element::Rule(value),
+            Element::Rule(ws),
+        ]]);
+        let members = self.grammar.reserve();
+        self.grammar.define(
+            members,
+            vec![
+                vec![Element::Rule(member)],
+                vec![
+                    Element::Rule(member),
+                    Element::char(','),
+                    Element::Rule(ws),
+                    Element::Rule(members),
+                ],
+            ],
+        );
+        self.grammar.define(
+            object,
+            vec![
+                vec![Element::char('{'), Element::Rule(ws), Element::char('}')],
+                vec![
+                    Element::char('{'),
+                    Element::Rule(ws),
+                    Element::Rule(members),
+                    Element::char('}'),
+                ],
+            ],
+        );
+        object
+    }
+
+    /// Adds any JSON value
+    fn value(&mut self) -> usize {
+        if let Some(rule) = self.shared.get("value") {
+            return *rule;
+        }
+        let value = self.grammar.reserve();
+        self.shared.insert("value", value);
+        let object = self.object();
+        let array = self.array(value);
+        let alternatives = [object, array]
+            .into_iter()
+            .chain(["string", "number", "boolean", "null"].map(|name| self.shared(name)))
+            .map(|rule| vec![Element::Rule(rule)])
+            .collect();
+        self.grammar.define(value, alternatives);
+        value
+    }
+
+    /// Returns the rule of a primitive, adding it if needed
+    fn shared(&mut self, name: &'static str) -> usize {
+        if let Some(rule) = self.shared.get(name) {
+            return *rule;
+        }
+        let alternatives = match name {
+            "ws" => vec![Vec::new(), vec![chars(&[(' ', ' '), ('\t', '\n')], false)]],
+            "string" => {
+                let hex = chars(&[('0', '9'), ('a', 'f'), ('A', 'F')], false);
+                let escape = self.grammar.rule(vec![
+                    vec![chars(&[('"', '"'), ('\\', '\\'), ('/', '/')], false)],
+                    vec![chars(
+                        &[('b', 'b'), ('f', 'f'), ('n', 'n'), ('r', 'r'), ('t', 't')],
+                        false,
+                    )],
+                    vec![
+                        Element::char('u'),
+                        hex.clone(),
+                        hex.clone(),
+                        hex.clone(),
+                        hex,
+                    ],
+                ]);
+                let character = self.grammar.rule(vec![
+                    vec![chars(&[('"', '"'), ('\\', '\\'), ('\0', '\u{1f}')], true)],
+                    vec![Element::char('\\'), Element::Rule(escape)],
+                ]);
+                let characters = self.grammar.reserve();
+                self.grammar.define(
+                    characters,
+                    vec![
+                        Vec::new(),
+                        vec![Element::Rule(character), Element::Rule(characters)],
+                    ],
+                );
+                vec![vec![
+                    Element::char('"'),
+                    Element::Rule(characters),
+                    Element::char('"'),
+                ]]
+            }
+            "digits" => {
+                let digits = self.grammar.reserve();
+                self.grammar.define(
+                    digits,
+                    vec![
+                        Vec::new(),
+                        vec![chars(&[('0', '9')], false), Element::Rule(digits)],
+                    ],
+                );
+                self.shared.insert(name, digits);
+                return digits;
+            }
+            "integer" => {
+                let digits = self.shared("digits");
+                let sign = self
+                    .grammar
+                    .rule(vec![Vec::new(), vec![Element::char('-')]]);
+                vec![
+                    vec![Element::Rule(sign), Element::char('0')],
+                    vec![
+                        Element::Rule(sign),
+                        chars(&[('1', '9')], false),
+                        Element::Rule(digits),
+                    ],
+                ]
+            }
+            "number" => {
+                let digits = self.shared("digits");
+                let integer = self.shared("integer");
+                let digit = chars(&[('0', '9')], false);
+                let fraction = self.grammar.rule(vec![
+                    Vec::new(),
+                    vec![Element::char('.'), digit.clone(), Element::Rule(digits)],
+                ]);
+                let sign = self.grammar.rule(vec![
+                    Vec::new(),
+                    vec![chars(&[('+', '+'), ('-', '-')], false)],
+                ]);
+                let exponent = self.grammar.rule(vec![
+                    Vec::new(),
+                    vec![
+                        chars(&[('e', 'e'), ('E', 'E')], false),
+                        Element::Rule(sign),
+                        digit,
+                        Element::Rule(digits),
+                    ],
+                ]);
+                vec![vec![
+                    Element::Rule(integer),
+                    Element::Rule(fraction),
+                    Element::Rule(exponent),
+                ]]
+            }
+            "boolean" => vec![Grammar::literal("true"), Grammar::literal("false")],
+            _ => vec![Grammar::literal(name)],
+        };
+        let rule = self.grammar.rule(alternatives);
+        self.shared.insert(name, rule);
+        rule
+    }
+}
+
+fn chars(ranges: &[(char, char)], negated: bool) -> Element {
+    Element::Chars {
+        ranges: ranges.to_vec(),
+        negated,
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::inference::task::raw::TokenCounts;
+
+/// Maximum number of characters a single summarization step is given
+static CHUNK_LENGTH: AtomicUsize = AtomicUsize::new(6000);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_summarization(chunk_length: usize) {
+    CHUNK_LENGTH.store(chunk_length.max(1), Ordering::Relaxed);
+}
+
+pub fn chunk_length() -> usize {
+    CHUNK_LENGTH.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SummarizeRequest {
+    pub model: String,
+    #[serde(default)]
+    pub input: String,
+    /// A previously uploaded document to summarize instead of the input
+    pub document_id: Option<String>,
+    /// Maximum number of tokens of every generated summary
+    pub max_length: usize,
+    /// Set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings the model must not generate for the client
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SummarizeStrategy {
+    /// Summarizes the whole document in one generation
+    #[default]
+    Single,
+    /// Summarizes chunks of the document in parallel and merges their summaries
+    MapReduce,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SummarizeQuery {
+    #[serde(default)]
+    pub strategy: SummarizeStrategy,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SummarizeResponse {
+    pub output: String,
+    /// Sum of the inference time of all generations
+    pub inference_time: f64,
+    /// Number of chunks the document was split into
+    pub chunks: usize,
+    #[serde(skip)]
+    pub tokens: TokenCounts,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[tracing::instrument(level = "trace", skip(chunk))]
+pub fn map_prompt(chunk: &str) -> String {
+    format!("Summarize the following text concisely:\n\n{chunk}")
+}
+
+#[tracing::instrument(level = "trace", skip(summaries))]
+pub fn reduce_prompt(summaries: &str) -> String {
+    format!("Combine the following partial summaries of a document into a single summary:\n\n{summaries}")
+}
+
+/// Splits the text into chunks of at most `max_chars` characters, preferring paragraph breaks.
+/// Paragraphs longer than a chunk are split on character boundaries.
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn split_chunks(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let paragraph_chars = paragraph.chars().count();
+        if current_chars > 0 && current_chars + 2 + paragraph_chars > max_chars {
+            chunks.push(std::mem::take(&mut current));
+            current_chars = 0;
+        }
+        if paragraph_chars > max_chars {
+            let chars: Vec<char> = paragraph.chars().collect();
+            chunks.extend(chars.chunks(max_chars).map(|part| part.iter().collect()));
+            continue;
+        }
+        if current_chars > 0 {
+            current.push_str("\n\n");
+            current_chars += 2;
+        }
+        current.push_str(paragraph);
+        current_chars += paragraph_chars;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
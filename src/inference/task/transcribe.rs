@@ -1,24 +1,54 @@
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
+use crate::inference::audio_input::AudioInput;
 use crate::inference::audio_pipeline::Segment;
+use crate::inference::audio_quality::AudioWarning;
+use crate::inference::runtime::RuntimeInfo;
+use crate::segmentation::Segmentation;
 
 #[derive(Deserialize, Debug)]
 pub struct TranscribeRequest {
     pub model: String,
     pub language: String,
+    pub max_decode_steps: Option<usize>,
+    /// Include how the model is executed in the response
+    #[serde(default)]
+    pub runtime: bool,
+    /// Restore punctuation and casing of the transcript with a text model
+    #[serde(default)]
+    pub restore_punctuation: bool,
+    #[serde(flatten)]
+    pub segmentation: Segmentation,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TranscribeResponse {
     pub output: Vec<Segment>,
     pub inference_time: f64,
+    #[serde(skip)]
+    pub audio_duration: f64,
+    /// Properties of the audio that may explain a poor transcript
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<AudioWarning>,
+    /// Whether punctuation and casing were restored, only set if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub punctuation_restored: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeInfo>,
 }
 
 pub trait TranscribeHandler {
     fn run_transcribe(
         &mut self,
-        input: Box<[u8]>,
-        language_token: &str,
+        input: AudioInput,
+        request: &TranscribeRequest,
     ) -> Result<TranscribeResponse, Error>;
+
+    /// Transcribes mono samples that are already at the sampling rate of the model
+    fn run_transcribe_samples(
+        &mut self,
+        samples: &[f32],
+        language: &str,
+    ) -> Result<Vec<Segment>, Error>;
 }
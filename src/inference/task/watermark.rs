@@ -0,0 +1,18 @@
+use anyhow::Error;
+use serde::Deserialize;
+
+use crate::inference::watermark::WatermarkDetection;
+
+#[derive(Deserialize, Debug)]
+pub struct DetectWatermarkRequest {
+    /// The model whose tokenizer the text is scored with, the one that generated it
+    pub model: String,
+    pub input: String,
+}
+
+pub trait WatermarkHandler {
+    fn detect_watermark(
+        &mut self,
+        request: DetectWatermarkRequest,
+    ) -> Result<WatermarkDetection, Error>;
+}
@@ -1,21 +1,33 @@
 use std::fmt::{Debug, Formatter};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use candle_core::quantized::gguf_file;
 use candle_core::{DType, Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::models::mixformer;
 use candle_transformers::models::quantized_llama::ModelWeights;
 use candle_transformers::models::quantized_mixformer::MixFormerSequentialForCausalLM;
 use candle_transformers::models::quantized_stable_lm::Model as QStableLM;
 use candle_transformers::models::stable_lm::Config as StableLmConfig;
-use candle_transformers::quantized_var_builder::VarBuilder;
-use hf_hub::api::sync::ApiRepo;
 use rand::random;
 use tokenizers::Tokenizer;
+use tracing::{info, warn};
 
+use crate::inference::artifact_store::ArtifactStore;
+use crate::inference::error::InferenceError;
+use crate::inference::grammar::{Grammar, Vocabulary};
+use crate::inference::milestones::Milestones;
+use crate::inference::prefix_cache::{prefix_cache_enabled, PrefixCache};
+use crate::inference::runtime::{
+    current_device, gguf_file_quantization, gguf_quantization, gguf_var_builder, open_gguf,
+    RuntimeInfo,
+};
+use crate::inference::task::raw::{GenerationDebug, RawRequest, TokenCounts};
 use crate::inference::token_output_stream::TokenOutputStream;
+use crate::inference::watermark::{detect, green_bias, WatermarkDetection};
+use crate::inference::{streaming, watchdog};
 
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples
 pub struct TextGeneratorPipeline {
@@ -28,6 +40,15 @@ pub struct TextGeneratorPipeline {
     pub seed: Option<u64>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub quantization: String,
+    /// The data type of the activations of the last forward pass
+    pub dtype: Option<DType>,
+    /// Text of the tokens for constrained sampling, built on the first constrained generation
+    pub vocabulary: Arc<OnceLock<Vocabulary>>,
+    /// Key-value caches of prompt prefixes, shared by the copies of the model
+    pub prefix_cache: Arc<Mutex<PrefixCache<Model>>>,
 }
 
 #[derive(Clone, Debug)]
@@ -57,6 +78,10 @@ impl Debug for TextGeneratorPipeline {
             .field("seed", &self.seed)
             .field("temperature", &self.temperature)
             .field("top_p", &self.top_p)
+            .field("top_k", &self.top_k)
+            .field("min_p", &self.min_p)
+            .field("quantization", &self.quantization)
+            .field("dtype", &self.dtype)
             .finish_non_exhaustive()
     }
 }
@@ -68,9 +93,10 @@ impl Clone for TextGeneratorPipeline {
             model: self.model.clone(),
             device: self.device.clone(),
             tokenizer: self.tokenizer.clone(),
-            logits_processor: LogitsProcessor::new(
+            logits_processor: logits_processor(
                 self.seed.unwrap_or_else(random),
                 self.temperature,
+                self.top_k,
                 self.top_p,
             ),
             repeat_penalty: self.repeat_penalty,
@@ -78,6 +104,12 @@ impl Clone for TextGeneratorPipeline {
             seed: self.seed,
             temperature: self.temperature,
             top_p: self.top_p,
+            top_k: self.top_k,
+            min_p: self.min_p,
+            quantization: self.quantization.clone(),
+            dtype: self.dtype,
+            vocabulary: Arc::clone(&self.vocabulary),
+            prefix_cache: Arc::clone(&self.prefix_cache),
         }
     }
 }
@@ -86,7 +118,7 @@ impl TextGeneratorPipeline {
     #[tracing::instrument(level = "debug", skip(repo))]
     #[allow(clippy::too_many_arguments)]
     pub fn with_quantized_gguf_config(
-        repo: &ApiRepo,
+        repo: &dyn ArtifactStore,
         model: &Model,
         config: ModelConfig,
         tokenizer_filename: &str,
@@ -94,14 +126,17 @@ impl TextGeneratorPipeline {
         seed: Option<u64>,
         temperature: Option<f64>,
         top_p: Option<f64>,
+        top_k: Option<usize>,
+        min_p: Option<f64>,
         repeat_penalty: f32,
         repeat_context_size: usize,
     ) -> Result<Self> {
         let tokenizer_file = repo.get(tokenizer_filename)?;
         let gguf_file = repo.get(gguf_filename)?;
 
-        let device = Device::Cpu;
-        let vb = VarBuilder::from_gguf(gguf_file, &device)?;
+        let device = current_device();
+        let quantization = gguf_file_quantization(&gguf_file)?;
+        let vb = gguf_var_builder(&gguf_file, &device)?;
         let model = match model {
             Model::Phi2(_) => {
                 let ModelConfig::Phi2(config) = config else {
@@ -119,18 +154,32 @@ impl TextGeneratorPipeline {
             }
             _ => bail!("Unsupported model"),
         };
-        let tokenizer = TokenOutputStream::new(Tokenizer::from_file(tokenizer_file).unwrap());
+        let tokenizer = TokenOutputStream::new(
+            Tokenizer::from_file(tokenizer_file)
+                .map_err(|e| InferenceError::TokenizerLoad(e.to_string()))?,
+        );
 
         let pipeline = Self {
             model,
             device,
             tokenizer,
-            logits_processor: LogitsProcessor::new(seed.unwrap_or_else(random), temperature, top_p),
+            logits_processor: logits_processor(
+                seed.unwrap_or_else(random),
+                temperature,
+                top_k,
+                top_p,
+            ),
             repeat_penalty,
             repeat_context_size,
             seed,
             temperature,
             top_p,
+            top_k,
+            min_p,
+            quantization,
+            dtype: None,
+            vocabulary: Arc::default(),
+            prefix_cache: Arc::default(),
         };
 
         Ok(pipeline)
@@ -139,24 +188,30 @@ impl TextGeneratorPipeline {
     #[tracing::instrument(level = "debug", skip(repo))]
     #[allow(clippy::too_many_arguments)]
     pub fn with_quantized_gguf(
-        repo: &ApiRepo,
+        repo: &dyn ArtifactStore,
         model: &Model,
         tokenizer_file: PathBuf,
         gguf_filename: &str,
         seed: Option<u64>,
         temperature: Option<f64>,
         top_p: Option<f64>,
+        top_k: Option<usize>,
+        min_p: Option<f64>,
         repeat_penalty: f32,
         repeat_context_size: usize,
     ) -> Result<Self> {
         let gguf_file = repo.get(gguf_filename)?;
-        let mut file = std::fs::File::open(&gguf_file)?;
+        let mut file = open_gguf(&gguf_file)?;
 
-        let device = Device::Cpu;
+        let device = current_device();
         let model_reader =
             gguf_file::Content::read(&mut file).map_err(|e| e.with_path(gguf_file))?;
+        let quantization = gguf_quantization(&model_reader);
         let model_weights = Some(ModelWeights::from_gguf(model_reader, &mut file, &device)?);
-        let tokenizer = TokenOutputStream::new(Tokenizer::from_file(tokenizer_file).unwrap());
+        let tokenizer = TokenOutputStream::new(
+            Tokenizer::from_file(tokenizer_file)
+                .map_err(|e| InferenceError::TokenizerLoad(e.to_string()))?,
+        );
 
         let pipeline = Self {
             model: match model {
@@ -165,20 +220,43 @@ impl TextGeneratorPipeline {
                 Model::OpenHermes(_) => Model::OpenHermes(model_weights),
                 _ => bail!("Unsupported model"),
             },
-            device: Device::Cpu,
+            device,
             tokenizer,
-            logits_processor: LogitsProcessor::new(seed.unwrap_or_else(random), temperature, top_p),
+            logits_processor: logits_processor(
+                seed.unwrap_or_else(random),
+                temperature,
+                top_k,
+                top_p,
+            ),
             repeat_penalty,
             repeat_context_size,
             seed,
             temperature,
             top_p,
+            top_k,
+            min_p,
+            quantization,
+            dtype: None,
+            vocabulary: Arc::default(),
+            prefix_cache: Arc::default(),
         };
 
         Ok(pipeline)
     }
-    #[tracing::instrument(level = "info", skip(prompt))]
-    pub fn generate(&mut self, prompt: &str, max_length: usize) -> Result<(String, f64)> {
+    /// Returns the output, the inference time, the token counts and whether the generation was
+    /// cut off at the maximum length. The generation ends once a stop sequence is produced, which
+    /// is left out of the output. With a grammar only tokens that keep the output within it are
+    /// sampled.
+    #[tracing::instrument(level = "info", skip(prompt, banned_words, stop, grammar))]
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        max_length: usize,
+        watermark: bool,
+        banned_words: &[String],
+        stop: &[String],
+        grammar: Option<&Arc<Grammar>>,
+    ) -> Result<(String, f64, TokenCounts, bool)> {
         if let Model::Phi2(Some(ref mut m)) = self.model {
             m.clear_kv_cache();
         }
@@ -187,48 +265,34 @@ impl TextGeneratorPipeline {
             .tokenizer
             .tokenizer()
             .encode(prompt, true)
-            .unwrap()
+            .map_err(|e| InferenceError::Tokenize(e.to_string()))?
             .get_ids()
             .to_vec();
         if tokens.is_empty() {
             bail!("Prompt is empty");
         }
 
-        let eos_token = match self.model {
-            Model::Mistral(_) => match self.tokenizer.tokenizer().get_vocab(true).get("</s>") {
-                Some(token) => *token,
-                None => bail!("Cannot find </s> token"),
-            },
-            Model::OpenHermes(_) => 32000,
-            Model::Phi3(_) => match self.tokenizer.tokenizer().get_vocab(true).get("<|end|>") {
-                Some(token) => *token,
-                None => bail!("Cannot find <|end|> token"),
-            },
-            Model::Phi2(_) | Model::StableLm(_) => match self
-                .tokenizer
-                .tokenizer()
-                .get_vocab(true)
-                .get("<|endoftext|>")
-            {
-                Some(token) => *token,
-                None => bail!("Cannot find <|endoftext|> token"),
-            },
-        };
+        let cached = self.restore_prefix(&tokens)?;
+
+        let eos_token = self.eos_token()?;
+        let prompt_tokens = tokens.len();
+        let banned_sequences = self.banned_sequences(banned_words)?;
+        let vocabulary = Arc::clone(&self.vocabulary);
+        let vocabulary =
+            grammar.map(|_| vocabulary.get_or_init(|| Vocabulary::new(self.tokenizer.tokenizer())));
+        let mut constraint = grammar.map(Grammar::start).transpose()?;
 
         let mut output = String::new();
+        let mut stop = StopSequences::new(stop);
+        let mut cut_off = true;
+        let mut stopped = false;
         let start_gen = std::time::Instant::now();
+        let mut milestones = Milestones::start();
         for index in 0..max_length {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let start_pos = tokens.len().saturating_sub(context_size);
-            let input = Tensor::new(&tokens[start_pos..], &self.device)?.unsqueeze(0)?;
-            let logits = match &mut self.model {
-                Model::Phi2(Some(model)) => model.forward(&input)?,
-                Model::Phi3(Some(model)) => model.forward(&input, start_pos)?,
-                Model::Mistral(Some(model)) => model.forward(&input, start_pos)?,
-                Model::OpenHermes(Some(model)) => model.forward(&input, start_pos)?,
-                Model::StableLm(Some(model)) => model.forward(&input, start_pos)?,
-                _ => bail!("Model not initialized"),
-            };
+            watchdog::tick()?;
+            let start_pos = if index > 0 { tokens.len() - 1 } else { cached };
+            let logits = self.forward(&tokens, start_pos)?;
+            self.dtype = Some(logits.dtype());
             let logits = match self.model {
                 Model::Phi2(_) => logits.squeeze(0)?.to_dtype(DType::F32)?,
                 Model::Phi3(_) => logits.squeeze(0)?.to_dtype(DType::F32)?,
@@ -246,31 +310,338 @@ impl TextGeneratorPipeline {
                     &tokens[start_at..],
                 )?
             };
+            let vocab_size = logits.dim(0)?;
+            let mut bias = watermark
+                .then(|| green_bias(tokens[tokens.len() - 1], vocab_size))
+                .flatten();
+            for token in blocked_tokens(&tokens, &banned_sequences) {
+                let bias = bias.get_or_insert_with(|| vec![0.0; vocab_size]);
+                if let Some(value) = bias.get_mut(token as usize) {
+                    *value = f32::NEG_INFINITY;
+                }
+            }
+            if let (Some(state), Some(vocabulary)) = (&constraint, vocabulary) {
+                let mut allowed = vocabulary.allowed(state);
+                if state.is_complete() {
+                    allowed.push(eos_token);
+                }
+                if allowed.is_empty() {
+                    warn!("No token continues the output within the grammar");
+                    break;
+                }
+                let mut masked = vec![f32::NEG_INFINITY; vocab_size];
+                for token in allowed {
+                    if let Some(value) = masked.get_mut(token as usize) {
+                        *value = bias.as_ref().map_or(0.0, |bias| bias[token as usize]);
+                    }
+                }
+                bias = Some(masked);
+            }
+            let logits = match bias {
+                Some(bias) => (logits + Tensor::from_vec(bias, vocab_size, &self.device)?)?,
+                None => logits,
+            };
+            let logits = match (self.min_p, self.temperature) {
+                (Some(min_p), Some(temperature)) => min_p_filter(&logits, min_p, temperature)?,
+                _ => logits,
+            };
 
             let next_token = self.logits_processor.sample(&logits)?;
             tokens.push(next_token);
+            milestones.token();
             if next_token == eos_token {
+                cut_off = false;
                 break;
             }
+            if let (Some(state), Some(vocabulary)) = (&mut constraint, vocabulary) {
+                *state = vocabulary
+                    .text(next_token)
+                    .and_then(|text| state.accept_str(text))
+                    .ok_or_else(|| anyhow!("Sampled a token outside of the grammar"))?;
+            }
 
-            match self.tokenizer.next_token(next_token) {
-                Ok(text) => {
-                    if let Some(text) = text {
-                        output.push_str(&text);
-                    }
+            if let Some(text) = self.tokenizer.next_token(next_token)? {
+                output.push_str(&text);
+                if stop.check(&mut output)? {
+                    cut_off = false;
+                    stopped = true;
+                    break;
                 }
-                Err(err) => bail!("Cannot decode tokens: {err}"),
-            };
+            }
         }
-        match self.tokenizer.decode_rest() {
-            Ok(text) => {
-                if let Some(text) = text {
-                    output.push_str(&text);
+        if !stopped {
+            if let Some(text) = self.tokenizer.decode_rest()? {
+                output.push_str(&text);
+                if stop.check(&mut output)? {
+                    cut_off = false;
+                }
+            }
+        }
+        stop.flush(&output)?;
+        milestones.finish();
+
+        let inference_time = start_gen.elapsed().as_secs_f64();
+        let counts = TokenCounts::new(prompt_tokens, tokens.len() - prompt_tokens, inference_time);
+        Ok((output, inference_time, counts, cut_off))
+    }
+
+    /// Tokenizes the banned words as they appear at the start of the text and after a space, in
+    /// lowercase and capitalized
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn banned_sequences(&self, banned_words: &[String]) -> Result<Vec<Vec<u32>>> {
+        let mut sequences = Vec::new();
+        for word in banned_words {
+            let capitalized = word
+                .chars()
+                .take(1)
+                .flat_map(char::to_uppercase)
+                .chain(word.chars().skip(1))
+                .collect::<String>();
+            for variant in [
+                word.clone(),
+                format!(" {word}"),
+                capitalized.clone(),
+                format!(" {capitalized}"),
+            ] {
+                let sequence = self
+                    .tokenizer
+                    .tokenizer()
+                    .encode(variant, false)
+                    .map_err(|e| InferenceError::Tokenize(e.to_string()))?
+                    .get_ids()
+                    .to_vec();
+                if !sequence.is_empty() && !sequences.contains(&sequence) {
+                    sequences.push(sequence);
                 }
             }
-            Err(err) => bail!("Cannot decode tokens: {err}"),
+        }
+        Ok(sequences)
+    }
+
+    /// Scores the text for the watermark with the tokenizer of the model
+    #[tracing::instrument(level = "trace", skip(self, text))]
+    pub fn detect_watermark(&self, text: &str) -> Result<WatermarkDetection> {
+        let tokens = self
+            .tokenizer
+            .tokenizer()
+            .encode(text, false)
+            .map_err(|e| InferenceError::Tokenize(e.to_string()))?;
+        match detect(tokens.get_ids()) {
+            Some(detection) => Ok(detection),
+            None => bail!("Watermarking is not configured"),
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn runtime_info(&self) -> RuntimeInfo {
+        RuntimeInfo::new(&self.device, self.dtype, &self.quantization)
+    }
+
+    /// Forwards the tokens from `start_pos` on, following the ones already in the key-value cache,
+    /// and returns the logits of the last one
+    fn forward(&mut self, tokens: &[u32], start_pos: usize) -> Result<Tensor> {
+        // Past a cached prefix the attention masks of these models only fit a single token
+        let chunk_size = match self.model {
+            Model::StableLm(_) => tokens.len() - start_pos,
+            _ if start_pos == 0 => tokens.len(),
+            _ => 1,
         };
+        let mut logits = None;
+        for position in (start_pos..tokens.len()).step_by(chunk_size.max(1)) {
+            let chunk = &tokens[position..(position + chunk_size).min(tokens.len())];
+            let input = Tensor::new(chunk, &self.device)?.unsqueeze(0)?;
+            logits = Some(match &mut self.model {
+                Model::Phi2(Some(model)) => model.forward(&input)?,
+                Model::Phi3(Some(model))
+                | Model::Mistral(Some(model))
+                | Model::OpenHermes(Some(model)) => model.forward(&input, position)?,
+                Model::StableLm(Some(model)) => model.forward(&input, position)?,
+                _ => bail!("Model not initialized"),
+            });
+        }
+        logits.ok_or_else(|| anyhow!("No tokens to forward"))
+    }
 
-        Ok((output, start_gen.elapsed().as_secs_f64()))
+    /// Restores the key-value cache of the longest cached prefix of the prompt and caches the
+    /// prefix it shares with a recent prompt if that is longer. Returns the number of tokens in
+    /// the key-value cache.
+    #[tracing::instrument(level = "debug", skip(self, tokens))]
+    fn restore_prefix(&mut self, tokens: &[u32]) -> Result<usize> {
+        if !prefix_cache_enabled() {
+            return Ok(0);
+        }
+        let (found, shared) = {
+            let mut cache = self
+                .prefix_cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            (cache.lookup(tokens), cache.shared_prefix(tokens))
+        };
+
+        let mut cached = found.map_or(0, |(length, model)| {
+            info!(monotonic_counter.prefix_cache_hits = 1, length);
+            self.model = model;
+            length
+        });
+        if let Some(shared) = shared.filter(|shared| *shared > cached) {
+            self.forward(&tokens[..shared], cached)?;
+            self.prefix_cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(&tokens[..shared], self.model.clone());
+            cached = shared;
+        }
+        Ok(cached)
+    }
+
+    /// Returns the token that ends the generation of the model
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn eos_token(&self) -> Result<u32> {
+        Ok(match self.model {
+            Model::Mistral(_) => match self.tokenizer.tokenizer().get_vocab(true).get("</s>") {
+                Some(token) => *token,
+                None => bail!("Cannot find </s> token"),
+            },
+            Model::OpenHermes(_) => 32000,
+            Model::Phi3(_) => match self.tokenizer.tokenizer().get_vocab(true).get("<|end|>") {
+                Some(token) => *token,
+                None => bail!("Cannot find <|end|> token"),
+            },
+            Model::Phi2(_) | Model::StableLm(_) => match self
+                .tokenizer
+                .tokenizer()
+                .get_vocab(true)
+                .get("<|endoftext|>")
+            {
+                Some(token) => *token,
+                None => bail!("Cannot find <|endoftext|> token"),
+            },
+        })
     }
+
+    /// Returns the prompt and the parameters that a raw request was generated with
+    #[tracing::instrument(level = "trace", skip(self, request))]
+    pub fn generation_debug(&self, request: &RawRequest, seed: u64) -> Result<GenerationDebug> {
+        let eos_token = self.eos_token()?;
+        Ok(GenerationDebug {
+            prompt: request.input.clone(),
+            seed,
+            temperature: request.model_config.temperature,
+            top_p: request.model_config.top_p,
+            top_k: request.model_config.top_k,
+            min_p: request.model_config.min_p,
+            repeat_penalty: self.repeat_penalty,
+            repeat_context_size: self.repeat_context_size,
+            max_length: request.max_length,
+            stop_tokens: self
+                .tokenizer
+                .tokenizer()
+                .id_to_token(eos_token)
+                .into_iter()
+                .collect(),
+        })
+    }
+}
+
+/// Ends the output before the first stop sequence and streams the text generated so far, holding
+/// back text that may turn out to be the start of a stop sequence
+struct StopSequences<'a> {
+    sequences: Vec<&'a str>,
+    /// Length of the output streamed so far
+    emitted: usize,
+}
+
+impl<'a> StopSequences<'a> {
+    fn new(sequences: &'a [String]) -> Self {
+        Self {
+            sequences: sequences
+                .iter()
+                .map(String::as_str)
+                .filter(|sequence| !sequence.is_empty())
+                .collect(),
+            emitted: 0,
+        }
+    }
+
+    /// Returns whether the output ended with a stop sequence, which is removed from it
+    fn check(&mut self, output: &mut String) -> Result<bool> {
+        // Stop sequences can only start in text that was held back or is new
+        let end = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| output[self.emitted..].find(sequence))
+            .min();
+        if let Some(end) = end {
+            output.truncate(self.emitted + end);
+            return Ok(true);
+        }
+        let held = self
+            .sequences
+            .iter()
+            .flat_map(|sequence| {
+                sequence
+                    .char_indices()
+                    .skip(1)
+                    .map(|(index, _)| &sequence[..index])
+            })
+            .filter(|prefix| output.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        self.emit_until(output, output.len() - held)?;
+        Ok(false)
+    }
+
+    /// Streams the text that was held back
+    fn flush(&mut self, output: &str) -> Result<()> {
+        self.emit_until(output, output.len())
+    }
+
+    fn emit_until(&mut self, output: &str, end: usize) -> Result<()> {
+        if end > self.emitted {
+            streaming::emit(&output[self.emitted..end])?;
+            self.emitted = end;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the logits processor sampling from the `top_k` most likely tokens and of those from the
+/// ones within `top_p`, only the most likely token is taken without a temperature
+pub fn logits_processor(
+    seed: u64,
+    temperature: Option<f64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+) -> LogitsProcessor {
+    let sampling = temperature
+        .filter(|temperature| *temperature >= 1e-7)
+        .map_or(Sampling::ArgMax, |temperature| match (top_k, top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        });
+    LogitsProcessor::from_sampling(seed, sampling)
+}
+
+/// Masks the tokens whose probability at the temperature is below `min_p` times the probability
+/// of the most likely token
+#[allow(clippy::cast_possible_truncation)]
+fn min_p_filter(logits: &Tensor, min_p: f64, temperature: f64) -> Result<Tensor> {
+    let max = logits.max(0)?.to_scalar::<f32>()?;
+    let threshold = max + (temperature * min_p.ln()) as f32;
+    let masked = Tensor::full(f32::NEG_INFINITY, logits.shape(), logits.device())?;
+    Ok(logits.ge(threshold)?.where_cond(logits, &masked)?)
+}
+
+/// Returns the tokens that would complete one of the banned sequences after the tokens
+fn blocked_tokens<'a>(
+    tokens: &'a [u32],
+    sequences: &'a [Vec<u32>],
+) -> impl Iterator<Item = u32> + 'a {
+    sequences.iter().filter_map(|sequence| {
+        let (last, prefix) = sequence.split_last()?;
+        tokens.ends_with(prefix).then_some(*last)
+    })
 }
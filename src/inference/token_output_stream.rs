@@ -1,4 +1,6 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
+
+use crate::inference::error::InferenceError;
 
 // Taken from https://github.com/huggingface/candle/blob/main/candle-examples/src/token_output_stream.rs
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
@@ -26,7 +28,7 @@ impl TokenOutputStream {
     fn decode(&self, tokens: &[u32]) -> Result<String> {
         match self.tokenizer.decode(tokens, true) {
             Ok(str) => Ok(str),
-            Err(err) => bail!("cannot decode: {err}"),
+            Err(err) => Err(InferenceError::Detokenize(err.to_string()).into()),
         }
     }
 
@@ -41,7 +43,7 @@ impl TokenOutputStream {
         };
         self.tokens.push(token);
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphabetic() {
+        if text.len() > prev_text.len() && text.chars().last().is_some_and(char::is_alphabetic) {
             let text = text.split_at(prev_text.len());
             self.prev_index = self.current_index;
             self.current_index = self.tokens.len();
@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::inference::error::InferenceError;
+
+thread_local! {
+    /// Progress tracker of the generation running on the current worker thread
+    static CURRENT: RefCell<Option<Arc<Progress>>> = const { RefCell::new(None) };
+}
+
+/// Tracks the token progress of a single generation so that stuck workers can be detected
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    /// Milliseconds since `started` of the last progress, zero until the generation begins
+    last_tick: AtomicU64,
+    ticks: AtomicU64,
+    aborted: AtomicBool,
+}
+
+impl Progress {
+    #[tracing::instrument(level = "trace")]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            last_tick: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            aborted: AtomicBool::new(false),
+        })
+    }
+
+    /// Time since the last progress, `None` while the generation has not started yet
+    pub fn stalled_for(&self) -> Option<Duration> {
+        if self.ticks() == 0 {
+            return None;
+        }
+        let last_tick = Duration::from_millis(self.last_tick.load(Ordering::Relaxed));
+        Some(self.started.elapsed().saturating_sub(last_tick))
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Makes the next progress report of the generation fail
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn tick(&self) -> Result<()> {
+        if self.aborted.load(Ordering::Relaxed) {
+            return Err(InferenceError::GenerationStalled.into());
+        }
+        self.last_tick
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Detaches the progress tracker from the worker thread once dropped
+pub struct AttachGuard;
+
+impl Drop for AttachGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// Attaches the progress tracker to the current worker thread
+#[must_use]
+pub fn attach(progress: Arc<Progress>) -> AttachGuard {
+    CURRENT.with(|current| *current.borrow_mut() = Some(progress));
+    AttachGuard
+}
+
+/// Reports progress of the generation running on the current thread.
+/// Fails once the watchdog aborted the generation so that the worker stops as soon as it resumes.
+pub fn tick() -> Result<()> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map_or(Ok(()), |progress| progress.tick())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_is_not_stalled_before_the_first_tick() {
+        let progress = Progress::new();
+        assert_eq!(progress.stalled_for(), None);
+        progress.tick().unwrap();
+        assert_eq!(progress.ticks(), 1);
+        assert!(progress
+            .stalled_for()
+            .is_some_and(|stalled| stalled < Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stalled_for_grows_without_ticks() {
+        let progress = Progress::new();
+        progress.tick().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(progress
+            .stalled_for()
+            .is_some_and(|stalled| stalled >= Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn tick_fails_once_aborted() {
+        let progress = Progress::new();
+        let guard = attach(Arc::clone(&progress));
+        tick().unwrap();
+        progress.abort();
+        assert!(tick().is_err());
+        assert_eq!(progress.ticks(), 1);
+        drop(guard);
+        assert!(tick().is_ok());
+    }
+}
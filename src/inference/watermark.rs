@@ -0,0 +1,117 @@
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use tracing::warn;
+
+/// Share of the vocabulary on the green list of every position, a quarter of the hash range
+const GREEN_FRACTION: f64 = 0.25;
+const GREEN_LIMIT: u64 = u64::MAX / 4;
+/// Texts scoring at least this many standard deviations above chance are considered watermarked
+const DETECTION_THRESHOLD: f64 = 4.0;
+
+struct Watermark {
+    key: u64,
+    strength: f32,
+    clients: Vec<String>,
+}
+
+static WATERMARK: OnceLock<Watermark> = OnceLock::new();
+
+/// Enables watermarking with the secret key, the logit bias of green tokens and the names of
+/// the clients whose generations are marked, all clients if empty
+#[tracing::instrument(level = "info", skip(key))]
+pub fn configure_watermark(key: &str, strength: f32, clients: Vec<String>) {
+    let watermark = Watermark {
+        key: hash_key(key),
+        strength,
+        clients,
+    };
+    if WATERMARK.set(watermark).is_err() {
+        warn!("Watermark is already configured");
+    }
+}
+
+/// Whether generations for the client have to be watermarked
+#[tracing::instrument(level = "trace")]
+pub fn watermark_enabled(client: Option<&str>) -> bool {
+    WATERMARK.get().is_some_and(|watermark| {
+        watermark.clients.is_empty()
+            || client.is_some_and(|name| watermark.clients.iter().any(|c| c == name))
+    })
+}
+
+pub fn watermark_configured() -> bool {
+    WATERMARK.get().is_some()
+}
+
+#[derive(Serialize, Debug)]
+pub struct WatermarkDetection {
+    /// Number of tokens scored, the first token has no predecessor and is skipped
+    pub tokens: usize,
+    pub green_tokens: usize,
+    /// Standard deviations the share of green tokens lies above chance
+    pub z_score: f64,
+    pub watermarked: bool,
+}
+
+fn hash_key(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+const fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// Whether the token is on the green list seeded by the key and the previous token
+const fn is_green(key: u64, previous: u32, token: u32) -> bool {
+    let seed = mix(key ^ previous as u64);
+    mix(seed ^ token as u64) < GREEN_LIMIT
+}
+
+/// Returns the bias added to the logits of the next token, or none if watermarking is disabled
+#[tracing::instrument(level = "trace")]
+pub fn green_bias(previous: u32, vocab_size: usize) -> Option<Vec<f32>> {
+    let watermark = WATERMARK.get()?;
+    Some(
+        (0..vocab_size)
+            .map(|token| {
+                let token = u32::try_from(token).unwrap_or(u32::MAX);
+                if is_green(watermark.key, previous, token) {
+                    watermark.strength
+                } else {
+                    0.0
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Tests the tokens of a text for a share of green tokens unlikely to occur by chance
+#[tracing::instrument(level = "trace", skip(tokens))]
+#[allow(clippy::cast_precision_loss)]
+pub fn detect(tokens: &[u32]) -> Option<WatermarkDetection> {
+    let watermark = WATERMARK.get()?;
+    let green_tokens = tokens
+        .windows(2)
+        .filter(|pair| is_green(watermark.key, pair[0], pair[1]))
+        .count();
+    let scored = tokens.len().saturating_sub(1);
+    let expected = GREEN_FRACTION * scored as f64;
+    let deviation = (scored as f64 * GREEN_FRACTION * (1.0 - GREEN_FRACTION)).sqrt();
+    let z_score = if scored == 0 {
+        0.0
+    } else {
+        (green_tokens as f64 - expected) / deviation
+    };
+    Some(WatermarkDetection {
+        tokens: scored,
+        green_tokens,
+        z_score,
+        watermarked: z_score >= DETECTION_THRESHOLD,
+    })
+}
@@ -0,0 +1,51 @@
+use std::sync::OnceLock;
+
+use regex::RegexSet;
+
+/// Phrasings common in prompt injection and jailbreak attempts with the weight they add
+const PATTERNS: [(&str, f64); 12] = [
+    (
+        r"(?i)\b(ignore|disregard|forget)\b.{0,30}\b(previous|prior|above|earlier|all)\b.{0,20}\b(instructions?|rules|prompts?|directions)",
+        0.8,
+    ),
+    (
+        r"(?i)\b(reveal|print|show|repeat|output)\b.{0,30}\b(system prompt|initial instructions|hidden instructions)",
+        0.7,
+    ),
+    (r"(?i)\byou are now\b", 0.4),
+    (r"(?i)\b(developer|god|jailbreak|unrestricted) mode\b", 0.7),
+    (r"\bDAN\b|(?i:\bdo anything now\b)", 0.6),
+    (
+        r"(?i)\b(pretend|act as if|roleplay as)\b.{0,40}\b(no|without)\b.{0,20}\b(restrictions|rules|filters|limits)",
+        0.6,
+    ),
+    (r"(?i)\bnew instructions?\s*:", 0.5),
+    (
+        r"(?i)<\|(im_start|im_end|system|user|assistant|end)\|>",
+        0.6,
+    ),
+    (r"(?i)^\s*(#{2,}|\[)\s*(system|instruction)s?\b", 0.4),
+    (
+        r"(?i)\b(override|bypass)\b.{0,30}\b(safety|guardrails?|content polic(y|ies)|filters?)",
+        0.6,
+    ),
+    (r"(?i)\bthis is (not )?a test\b.{0,40}\b(comply|obey)", 0.3),
+    (r"[A-Za-z0-9+/]{200,}={0,2}", 0.3),
+];
+
+static PATTERN_SET: OnceLock<RegexSet> = OnceLock::new();
+
+/// Scores how likely the text tries to override the instructions of the model with heuristics,
+/// from 0 for nothing suspicious towards 1 for several strong indicators
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn injection_score(text: &str) -> f64 {
+    let set = PATTERN_SET.get_or_init(|| {
+        RegexSet::new(PATTERNS.iter().map(|(pattern, _)| pattern))
+            .expect("Injection patterns are valid")
+    });
+    let unlikely = set
+        .matches(text)
+        .iter()
+        .fold(1.0, |unlikely, index| unlikely * (1.0 - PATTERNS[index].1));
+    1.0 - unlikely
+}
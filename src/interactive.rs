@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use axum::extract::ws::{Message, WebSocket};
+use axum::http::StatusCode;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::error::{HttpErrorResponse, ModelResult, ModelRunnerError};
+use crate::inference::streaming::TokenSink;
+use crate::inference::task::instruct::{InstructRequest, InstructResponse};
+use crate::runner;
+
+/// Messages sent to the client of an interactive generation connection
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GenerationEvent {
+    /// Text of the next generated token
+    Token { text: String },
+    /// The generation completed, the output may differ from the streamed tokens once truncated
+    Done {
+        #[serde(flatten)]
+        response: InstructResponse,
+        /// Milliseconds between receiving the request and sending its first token
+        #[serde(skip_serializing_if = "Option::is_none")]
+        time_to_first_token_ms: Option<f64>,
+        /// Milliseconds between receiving the request and completing the generation
+        total_time_ms: f64,
+    },
+    Error {
+        #[serde(flatten)]
+        error: HttpErrorResponse,
+    },
+}
+
+/// Runs the instruct requests received as JSON text messages over the socket one after another
+/// until the client closes it, streaming the tokens of each and ending it with a `done` or `error`
+/// message. Requests received during a generation wait for it to complete.
+#[tracing::instrument(level = "info", skip(socket, generate))]
+pub async fn run_interactive<F, Fut>(mut socket: WebSocket, generate: F)
+where
+    F: Fn(InstructRequest, TokenSink) -> Fut,
+    Fut: Future<Output = ModelResult<InstructResponse>> + Send + 'static,
+{
+    let mut requests = VecDeque::new();
+    loop {
+        let message = match requests.pop_front() {
+            Some(message) => message,
+            None => match socket.recv().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let received = Instant::now();
+        let request = match serde_json::from_str::<InstructRequest>(&message) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = runner!(StatusCode::BAD_REQUEST, "Invalid request: {}", err);
+                if send(
+                    &mut socket,
+                    &GenerationEvent::Error {
+                        error: error.message,
+                    },
+                )
+                .await
+                .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let (sink, tokens) = mpsc::unbounded_channel();
+        let generation = tokio::spawn(generate(request, sink));
+        if let Err(err) =
+            forward_generation(&mut socket, &mut requests, tokens, generation, received).await
+        {
+            debug!("Interactive generation client went away: {}", err);
+            break;
+        }
+    }
+}
+
+/// Sends the tokens of the generation as they arrive followed by its outcome, queueing the
+/// requests received in the meantime. Returning early drops the tokens, which aborts the generation.
+async fn forward_generation(
+    socket: &mut WebSocket,
+    requests: &mut VecDeque<String>,
+    mut tokens: mpsc::UnboundedReceiver<String>,
+    mut generation: JoinHandle<ModelResult<InstructResponse>>,
+    received: Instant,
+) -> Result<()> {
+    let mut first_token = None;
+    let result = loop {
+        tokio::select! {
+            Some(text) = tokens.recv() => {
+                first_token.get_or_insert_with(|| received.elapsed());
+                send(socket, &GenerationEvent::Token { text }).await?;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => requests.push_back(text),
+                Some(Ok(Message::Close(_)) | Err(_)) | None => bail!("Connection closed"),
+                Some(Ok(_)) => {}
+            },
+            joined = &mut generation => break joined,
+        }
+    };
+    // The worker is done, so every token it generated is waiting in the channel
+    while let Ok(text) = tokens.try_recv() {
+        first_token.get_or_insert_with(|| received.elapsed());
+        send(socket, &GenerationEvent::Token { text }).await?;
+    }
+
+    let event = match result {
+        Ok(Ok(response)) => GenerationEvent::Done {
+            time_to_first_token_ms: first_token.map(|elapsed| elapsed.as_secs_f64() * 1000.0),
+            total_time_ms: received.elapsed().as_secs_f64() * 1000.0,
+            response,
+        },
+        Ok(Err(err)) => GenerationEvent::Error { error: err.message },
+        Err(err) => GenerationEvent::Error {
+            error: runner!(StatusCode::INTERNAL_SERVER_ERROR, "{}", err).message,
+        },
+    };
+    send(socket, &event).await
+}
+
+async fn send(socket: &mut WebSocket, event: &GenerationEvent) -> Result<()> {
+    let message = serde_json::to_string(event)?;
+    if let Err(err) = socket.send(Message::Text(message)).await {
+        warn!("Failed to send generation event: {}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
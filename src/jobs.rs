@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::DateTime;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::{error, info, warn};
+
+use crate::api::clock::unix_now;
+
+/// How often the scheduler looks for jobs that are due
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(15);
+/// Time since the last modification after which a file is considered completely written
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// Something a job does each time its schedule fires
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobTask {
+    /// Transcribes the WAV files of the directory, writing `<file>.transcript.json` for each
+    TranscribeDirectory {
+        directory: PathBuf,
+        /// Where the outputs are written, next to the inputs if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output_directory: Option<PathBuf>,
+        model: String,
+        language: String,
+    },
+    /// Summarizes the text and markdown files of the directory, writing `<file>.summary.txt` for
+    /// each
+    SummarizeDirectory {
+        directory: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output_directory: Option<PathBuf>,
+        model: String,
+        /// Maximum number of tokens of every generated summary
+        max_length: usize,
+    },
+}
+
+impl JobTask {
+    pub fn directory(&self) -> &Path {
+        match self {
+            Self::TranscribeDirectory { directory, .. }
+            | Self::SummarizeDirectory { directory, .. } => directory,
+        }
+    }
+
+    pub fn output_directory(&self) -> &Path {
+        match self {
+            Self::TranscribeDirectory {
+                directory,
+                output_directory,
+                ..
+            }
+            | Self::SummarizeDirectory {
+                directory,
+                output_directory,
+                ..
+            } => output_directory.as_deref().unwrap_or(directory),
+        }
+    }
+
+    const fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Self::TranscribeDirectory { .. } => &["wav"],
+            Self::SummarizeDirectory { .. } => &["txt", "md"],
+        }
+    }
+
+    const fn output_suffix(&self) -> &'static str {
+        match self {
+            Self::TranscribeDirectory { .. } => ".transcript.json",
+            Self::SummarizeDirectory { .. } => ".summary.txt",
+        }
+    }
+
+    /// The file the output for the input file is written to
+    pub fn output_path(&self, input: &Path) -> PathBuf {
+        let mut name = input.file_name().unwrap_or_default().to_os_string();
+        name.push(self.output_suffix());
+        self.output_directory().join(name)
+    }
+
+    /// Input files of the directory that have no output yet and are not being written to, ordered
+    /// by name. Outputs written next to the inputs are not inputs themselves.
+    #[tracing::instrument(level = "trace")]
+    pub fn pending_files(&self) -> Result<Vec<PathBuf>> {
+        let entries = std::fs::read_dir(self.directory())
+            .with_context(|| format!("Failed to read {}", self.directory().display()))?;
+        let mut files = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .and_then(|extension| extension.to_str())
+                        .is_some_and(|extension| {
+                            self.extensions()
+                                .contains(&extension.to_lowercase().as_str())
+                        })
+                    && !path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().ends_with(self.output_suffix()))
+                    && !self.output_path(path).exists()
+                    && modified(path)
+                        .and_then(|modified| modified.elapsed().ok())
+                        .is_some_and(|age| age >= SETTLE_TIME)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JobScheduleRequest {
+    pub name: String,
+    /// Cron expression with seconds, e.g. `0 0 2 * * *` for every night at 2 am UTC
+    pub cron: String,
+    pub task: JobTask,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobSchedule {
+    pub id: String,
+    pub name: String,
+    pub cron: String,
+    pub task: JobTask,
+    pub created_by: String,
+    pub created_at: i64,
+    pub last_run_at: Option<i64>,
+    pub next_run_at: Option<i64>,
+}
+
+struct JobScheduleRecord {
+    id: String,
+    name: String,
+    cron: String,
+    task: String,
+    created_by: String,
+    created_at: i64,
+    last_run_at: Option<i64>,
+}
+
+impl TryFrom<JobScheduleRecord> for JobSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(record: JobScheduleRecord) -> Result<Self> {
+        let schedule = parse_cron(&record.cron)?;
+        Ok(Self {
+            next_run_at: next_run(&schedule, record.last_run_at.unwrap_or(record.created_at)),
+            id: record.id,
+            name: record.name,
+            cron: record.cron,
+            task: serde_json::from_str(&record.task)?,
+            created_by: record.created_by,
+            created_at: record.created_at,
+            last_run_at: record.last_run_at,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "running" => Self::Running,
+            "succeeded" => Self::Succeeded,
+            "failed" => Self::Failed,
+            _ => bail!("Invalid job status {s}"),
+        })
+    }
+}
+
+/// The files a run processed
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct JobOutput {
+    pub processed: Vec<PathBuf>,
+    pub failed: Vec<JobFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobFailure {
+    pub file: PathBuf,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobRun {
+    pub id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<JobOutput>,
+    /// Why the run failed as a whole, failures of single files are part of the output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+struct JobRunRecord {
+    id: String,
+    status: String,
+    output: Option<String>,
+    error: Option<String>,
+    started_at: i64,
+    finished_at: Option<i64>,
+}
+
+impl TryFrom<JobRunRecord> for JobRun {
+    type Error = anyhow::Error;
+
+    fn try_from(record: JobRunRecord) -> Result<Self> {
+        Ok(Self {
+            id: record.id,
+            status: record.status.parse()?,
+            output: record
+                .output
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()?,
+            error: record.error,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+        })
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Parses the cron expression of a job schedule
+#[tracing::instrument(level = "trace")]
+pub fn parse_cron(expression: &str) -> Result<Schedule> {
+    Schedule::from_str(expression)
+        .with_context(|| format!("Invalid cron expression for job schedule: {expression}"))
+}
+
+/// The first time the schedule fires after the unix timestamp
+fn next_run(schedule: &Schedule, since: i64) -> Option<i64> {
+    let since = DateTime::from_timestamp(since, 0)?;
+    schedule.after(&since).next().map(|next| next.timestamp())
+}
+
+#[tracing::instrument(level = "info", skip(pool))]
+pub async fn create_schedule(
+    request: &JobScheduleRequest,
+    created_by: &str,
+    pool: &SqlitePool,
+) -> Result<JobSchedule> {
+    let schedule = parse_cron(&request.cron)?;
+    let id = format!("{:032x}", rand::random::<u128>());
+    let task = serde_json::to_string(&request.task)?;
+    let now = unix_now()?;
+    sqlx::query!(
+        "INSERT INTO job_schedules (id, name, cron, task, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        id,
+        request.name,
+        request.cron,
+        task,
+        created_by,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(JobSchedule {
+        next_run_at: next_run(&schedule, now),
+        id,
+        name: request.name.clone(),
+        cron: request.cron.clone(),
+        task: request.task.clone(),
+        created_by: created_by.into(),
+        created_at: now,
+        last_run_at: None,
+    })
+}
+
+/// Lists all job schedules, oldest first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn list_schedules(pool: &SqlitePool) -> Result<Vec<JobSchedule>> {
+    sqlx::query_as!(
+        JobScheduleRecord,
+        "SELECT id, name, cron, task, created_by, created_at, last_run_at FROM job_schedules ORDER BY created_at"
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(JobSchedule::try_from)
+    .collect()
+}
+
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn get_schedule(id: &str, pool: &SqlitePool) -> Result<Option<JobSchedule>> {
+    sqlx::query_as!(
+        JobScheduleRecord,
+        "SELECT id, name, cron, task, created_by, created_at, last_run_at FROM job_schedules WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(JobSchedule::try_from)
+    .transpose()
+}
+
+/// Removes a job schedule with all of its runs, returning whether it existed. A run in progress
+/// finishes but is not recorded.
+#[tracing::instrument(level = "info", skip(pool))]
+pub async fn delete_schedule(id: &str, pool: &SqlitePool) -> Result<bool> {
+    let result = sqlx::query!("DELETE FROM job_schedules WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lists the most recent runs of the job schedule, newest first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn list_runs(schedule_id: &str, limit: u32, pool: &SqlitePool) -> Result<Vec<JobRun>> {
+    sqlx::query_as!(
+        JobRunRecord,
+        "SELECT id, status, output, error, started_at, finished_at FROM job_runs \
+        WHERE schedule_id = ? ORDER BY started_at DESC LIMIT ?",
+        schedule_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(JobRun::try_from)
+    .collect()
+}
+
+/// Schedules that are due and not running, a schedule that missed several runs is run once
+#[tracing::instrument(level = "trace", skip(pool))]
+async fn due_schedules(pool: &SqlitePool) -> Result<Vec<JobSchedule>> {
+    let running = JobStatus::Running.as_str();
+    let running = sqlx::query_scalar!("SELECT schedule_id FROM job_runs WHERE status = ?", running)
+        .fetch_all(pool)
+        .await?;
+    let now = unix_now()?;
+    Ok(list_schedules(pool)
+        .await?
+        .into_iter()
+        .filter(|schedule| {
+            schedule.next_run_at.is_some_and(|next| next <= now) && !running.contains(&schedule.id)
+        })
+        .collect())
+}
+
+/// Records the start of a run of the schedule, returning the id of the run
+#[tracing::instrument(level = "trace", skip(pool))]
+async fn start_run(schedule_id: &str, pool: &SqlitePool) -> Result<String> {
+    let id = format!("{:032x}", rand::random::<u128>());
+    let status = JobStatus::Running.as_str();
+    let now = unix_now()?;
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE job_schedules SET last_run_at = ? WHERE id = ?",
+        now,
+        schedule_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO job_runs (id, schedule_id, status, started_at) VALUES (?, ?, ?, ?)",
+        id,
+        schedule_id,
+        status,
+        now
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(id)
+}
+
+#[tracing::instrument(level = "trace", skip(result, pool))]
+async fn finish_run(id: &str, result: Result<JobOutput>, pool: &SqlitePool) -> Result<()> {
+    let (status, output, error) = match result {
+        Ok(output) if output.processed.is_empty() && !output.failed.is_empty() => (
+            JobStatus::Failed,
+            Some(serde_json::to_string(&output)?),
+            None,
+        ),
+        Ok(output) => (
+            JobStatus::Succeeded,
+            Some(serde_json::to_string(&output)?),
+            None,
+        ),
+        Err(err) => (JobStatus::Failed, None, Some(format!("{err:#}"))),
+    };
+    let status = status.as_str();
+    let now = unix_now()?;
+    sqlx::query!(
+        "UPDATE job_runs SET status = ?, output = ?, error = ?, finished_at = ? WHERE id = ?",
+        status,
+        output,
+        error,
+        now,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Marks runs left running by a previous process as failed so that their schedules run again
+#[tracing::instrument(level = "info", skip(pool))]
+async fn fail_interrupted_runs(pool: &SqlitePool) -> Result<()> {
+    let running = JobStatus::Running.as_str();
+    let failed = JobStatus::Failed.as_str();
+    let now = unix_now()?;
+    let result = sqlx::query!(
+        "UPDATE job_runs SET status = ?, error = 'Interrupted by a restart', finished_at = ? WHERE status = ?",
+        failed,
+        now,
+        running
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() > 0 {
+        warn!(
+            "Marked {} interrupted job runs as failed",
+            result.rows_affected()
+        );
+    }
+    Ok(())
+}
+
+/// Starts the job of every schedule that is due with the executor and records its outcome
+#[tracing::instrument(level = "info", skip(pool, execute))]
+pub async fn run_job_scheduler<F, Fut>(pool: SqlitePool, execute: F)
+where
+    F: Fn(JobTask) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<JobOutput>> + Send + 'static,
+{
+    if let Err(err) = fail_interrupted_runs(&pool).await {
+        error!("Failed to clean up interrupted job runs: {:?}", err);
+    }
+    loop {
+        match due_schedules(&pool).await {
+            Ok(due) => {
+                for schedule in due {
+                    let run_id = match start_run(&schedule.id, &pool).await {
+                        Ok(run_id) => run_id,
+                        Err(err) => {
+                            error!("Failed to start job {}: {:?}", schedule.name, err);
+                            continue;
+                        }
+                    };
+                    info!("Running job {} ({})", schedule.name, run_id);
+                    let pool = pool.clone();
+                    let execute = execute.clone();
+                    tokio::spawn(async move {
+                        let result = tokio::spawn(execute(schedule.task))
+                            .await
+                            .unwrap_or_else(|err| Err(anyhow!("Job panicked: {err}")));
+                        if let Err(err) = finish_run(&run_id, result, &pool).await {
+                            error!("Failed to record job run {}: {:?}", run_id, err);
+                        }
+                    });
+                }
+            }
+            Err(err) => error!("Failed to look up due jobs: {:?}", err),
+        }
+        tokio::time::sleep(SCHEDULER_INTERVAL).await;
+    }
+}
+
+/// Processes files appearing in the directory of the task with the executor as soon as they are
+/// completely written. A file that failed is only retried once it is modified again.
+#[tracing::instrument(level = "info", skip(execute))]
+pub async fn run_directory_watcher<F, Fut>(task: JobTask, interval: Duration, execute: F)
+where
+    F: Fn(JobTask, Vec<PathBuf>) -> Fut + Send,
+    Fut: Future<Output = JobOutput> + Send,
+{
+    info!("Watching {} for new files", task.directory().display());
+    let mut failed = HashMap::new();
+    loop {
+        match task.pending_files() {
+            Ok(files) => {
+                let files = files
+                    .into_iter()
+                    .filter(|file| failed.get(file) != modified(file).as_ref())
+                    .collect::<Vec<_>>();
+                if !files.is_empty() {
+                    let output = execute(task.clone(), files).await;
+                    info!(
+                        "Processed {} new files in {}, {} failed",
+                        output.processed.len(),
+                        task.directory().display(),
+                        output.failed.len()
+                    );
+                    for failure in output.failed {
+                        if let Some(modified) = modified(&failure.file) {
+                            failed.insert(failure.file, modified);
+                        }
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to scan watched directory: {:#}", err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn summarize(directory: &Path) -> JobTask {
+        JobTask::SummarizeDirectory {
+            directory: directory.to_path_buf(),
+            output_directory: None,
+            model: "phi3".to_string(),
+            max_length: 100,
+        }
+    }
+
+    /// Creates a file last modified the given time ago
+    fn create(path: &Path, age: Duration) {
+        let file = File::create(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn pending_files_skip_processed_unsettled_and_other_files() {
+        let directory =
+            std::env::temp_dir().join(format!("jobs-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&directory).unwrap();
+        let old = Duration::from_mins(1);
+        create(&directory.join("b.txt"), old);
+        create(&directory.join("a.MD"), old);
+        create(&directory.join("done.txt"), old);
+        create(&directory.join("done.txt.summary.txt"), old);
+        create(&directory.join("writing.txt"), Duration::ZERO);
+        create(&directory.join("audio.wav"), old);
+
+        let pending = summarize(&directory).pending_files();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            pending.unwrap(),
+            [directory.join("a.MD"), directory.join("b.txt")]
+        );
+    }
+
+    #[test]
+    fn output_path_uses_the_output_directory() {
+        let mut task = summarize(Path::new("/in"));
+        assert_eq!(
+            task.output_path(Path::new("/in/notes.md")),
+            Path::new("/in/notes.md.summary.txt")
+        );
+        if let JobTask::SummarizeDirectory {
+            output_directory, ..
+        } = &mut task
+        {
+            *output_directory = Some(PathBuf::from("/out"));
+        }
+        assert_eq!(
+            task.output_path(Path::new("/in/notes.md")),
+            Path::new("/out/notes.md.summary.txt")
+        );
+    }
+
+    #[test]
+    fn next_run_is_the_first_time_after_since() {
+        let schedule = parse_cron("0 0 2 * * *").unwrap();
+        // 2024-01-01 00:00:00 UTC
+        let since = 1_704_067_200;
+        assert_eq!(next_run(&schedule, since), Some(since + 2 * 3600));
+        assert_eq!(
+            next_run(&schedule, since + 2 * 3600),
+            Some(since + 26 * 3600)
+        );
+    }
+
+    #[test]
+    fn parse_cron_rejects_invalid_expressions() {
+        assert!(parse_cron("every night").is_err());
+        assert!(parse_cron("0 0 25 * * *").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use tracing::{info, warn};
+
+static REQUESTS_SERVED: AtomicU64 = AtomicU64::new(0);
+static REQUESTS_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+/// Requests that were dropped before a response was produced, e.g. by a forced shutdown
+static REQUESTS_ABORTED: AtomicU64 = AtomicU64::new(0);
+static SHUTDOWN: OnceLock<ShutdownSnapshot> = OnceLock::new();
+
+struct ShutdownSnapshot {
+    started: Instant,
+    in_flight: u64,
+    aborted: u64,
+}
+
+/// Tracks a request from the moment it is received until its response is produced
+pub struct RequestGuard {
+    completed: bool,
+}
+
+impl RequestGuard {
+    #[tracing::instrument(level = "trace")]
+    pub fn begin() -> Self {
+        REQUESTS_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self { completed: false }
+    }
+
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        REQUESTS_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        if self.completed {
+            REQUESTS_SERVED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            REQUESTS_ABORTED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Remembers the requests in flight once the graceful shutdown starts
+#[tracing::instrument(level = "trace")]
+pub fn shutdown_started() {
+    SHUTDOWN.get_or_init(|| ShutdownSnapshot {
+        started: Instant::now(),
+        in_flight: REQUESTS_IN_FLIGHT.load(Ordering::Relaxed),
+        aborted: REQUESTS_ABORTED.load(Ordering::Relaxed),
+    });
+}
+
+/// Logs how the server shut down, distinguishing a clean drain from a forced one
+#[tracing::instrument(level = "trace")]
+pub fn log_shutdown_report() {
+    let served = REQUESTS_SERVED.load(Ordering::Relaxed);
+    let Some(snapshot) = SHUTDOWN.get() else {
+        info!(requests_served = served, "Shutdown report: server stopped");
+        return;
+    };
+
+    let aborted = REQUESTS_ABORTED
+        .load(Ordering::Relaxed)
+        .saturating_sub(snapshot.aborted)
+        .min(snapshot.in_flight);
+    let drained = snapshot.in_flight - aborted;
+    let drain_duration = snapshot.started.elapsed().as_secs_f64();
+    if aborted == 0 {
+        info!(
+            requests_served = served,
+            requests_drained = drained,
+            requests_aborted = aborted,
+            drain_duration,
+            clean = true,
+            "Shutdown report: drained all requests"
+        );
+    } else {
+        warn!(
+            requests_served = served,
+            requests_drained = drained,
+            requests_aborted = aborted,
+            drain_duration,
+            clean = false,
+            "Shutdown report: aborted requests that did not finish in time"
+        );
+    }
+}
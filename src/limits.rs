@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+use crate::response::ResponseFormat;
+
+/// Maximum body size of text and management requests in bytes
+pub const TEXT_BODY_LIMIT: usize = 2_000_000;
+/// Maximum body size of audio uploads in bytes
+pub const AUDIO_BODY_LIMIT: usize = 10_000_000;
+/// Maximum body size of document uploads in bytes
+pub const DOCUMENT_BODY_LIMIT: usize = 20_000_000;
+
+/// Maximum number of tokens a text generation may be asked to produce, zero disables the limit
+static MAX_LENGTH: AtomicUsize = AtomicUsize::new(0);
+/// Maximum duration of uploaded audio in seconds, zero disables the limit
+static MAX_AUDIO_DURATION: AtomicU64 = AtomicU64::new(0);
+
+/// As per <https://developer.mozilla.org/en-US/docs/Web/Media/Formats/Containers#wave_wav/>
+pub static VALID_WAV_MIME_TYPES: [&str; 4] =
+    ["audio/wave", "audio/wav", "audio/x-wav", "audio/x-pn-wav"];
+
+#[tracing::instrument(level = "info")]
+pub fn configure_limits(max_length: usize, max_audio_duration: u64) {
+    MAX_LENGTH.store(max_length, Ordering::Relaxed);
+    MAX_AUDIO_DURATION.store(max_audio_duration, Ordering::Relaxed);
+}
+
+/// Returns the maximum number of tokens of a text generation if a limit is set
+pub fn max_length() -> Option<usize> {
+    Some(MAX_LENGTH.load(Ordering::Relaxed)).filter(|length| *length > 0)
+}
+
+/// Returns the maximum duration of uploaded audio in seconds if a limit is set
+pub fn max_audio_duration() -> Option<u64> {
+    Some(MAX_AUDIO_DURATION.load(Ordering::Relaxed)).filter(|duration| *duration > 0)
+}
+
+/// Limits and features of this instance, allowing clients to configure themselves
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    pub max_text_body_size: usize,
+    pub max_audio_body_size: usize,
+    pub max_length: Option<usize>,
+    pub max_audio_duration: Option<u64>,
+    pub audio_mime_types: &'static [&'static str],
+    pub response_formats: Vec<&'static str>,
+    /// Whether text generations can be streamed as server-sent events or over a WebSocket
+    pub streaming: bool,
+}
+
+impl Capabilities {
+    #[tracing::instrument(level = "trace")]
+    pub fn current() -> Self {
+        Self {
+            max_text_body_size: TEXT_BODY_LIMIT,
+            max_audio_body_size: AUDIO_BODY_LIMIT,
+            max_length: max_length(),
+            max_audio_duration: max_audio_duration(),
+            audio_mime_types: &VALID_WAV_MIME_TYPES,
+            response_formats: ResponseFormat::ALL
+                .iter()
+                .map(|format| format.content_type())
+                .collect(),
+            streaming: true,
+        }
+    }
+}
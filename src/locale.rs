@@ -0,0 +1,260 @@
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+
+tokio::task_local! {
+    /// Language negotiated for the request currently being handled
+    static LANGUAGE: Language;
+}
+
+/// Languages user-facing error messages are translated to, English messages are used as is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+}
+
+impl Language {
+    #[tracing::instrument(level = "trace")]
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split('-').next()?.trim().to_ascii_lowercase();
+        match primary.as_str() {
+            "en" | "*" => Some(Self::English),
+            "de" => Some(Self::German),
+            "fr" => Some(Self::French),
+            "es" => Some(Self::Spanish),
+            _ => None,
+        }
+    }
+
+    /// Picks the first supported language in the order given by the client, quality values are not weighed
+    #[tracing::instrument(level = "trace")]
+    fn negotiate(accept_language: &str) -> Self {
+        accept_language
+            .split(',')
+            .filter_map(|tag| tag.split(';').next())
+            .find_map(Self::from_tag)
+            .unwrap_or(Self::English)
+    }
+}
+
+/// Negotiates the language of error messages through the `Accept-Language` header
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn negotiate_language(request: Request, next: Next) -> Response {
+    let language = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Language::English, Language::negotiate);
+    LANGUAGE.scope(language, next.run(request)).await
+}
+
+/// Returns the translated message for the error code in the language of the current request,
+/// replacing the placeholders `{0}`, `{1}`, ... with the parameters at these positions
+#[tracing::instrument(level = "trace")]
+pub fn localized_message(code: &str, params: &[String]) -> Option<String> {
+    let language = LANGUAGE.try_with(|language| *language).ok()?;
+    let template =
+        CATALOG
+            .iter()
+            .find(|entry| entry.code == code)
+            .and_then(|entry| match language {
+                Language::English => None,
+                Language::German => Some(entry.german),
+                Language::French => Some(entry.french),
+                Language::Spanish => Some(entry.spanish),
+            })?;
+    Some(
+        params
+            .iter()
+            .enumerate()
+            .fold(template.to_string(), |message, (index, param)| {
+                message.replace(&format!("{{{index}}}"), param)
+            }),
+    )
+}
+
+struct CatalogEntry {
+    code: &'static str,
+    german: &'static str,
+    french: &'static str,
+    spanish: &'static str,
+}
+
+/// Translations of user-facing errors, keyed by their machine-readable code
+const CATALOG: &[CatalogEntry] = &[
+    CatalogEntry {
+        code: "model_not_found",
+        german: "Das angefragte Modell {0} existiert nicht",
+        french: "Le modèle demandé {0} n'existe pas",
+        spanish: "El modelo solicitado {0} no existe",
+    },
+    CatalogEntry {
+        code: "model_unavailable",
+        german: "Das Modell {0} ist derzeit nicht verfügbar: {1}",
+        french: "Le modèle {0} est actuellement indisponible : {1}",
+        spanish: "El modelo {0} no está disponible en este momento: {1}",
+    },
+    CatalogEntry {
+        code: "model_unhealthy",
+        german: "Das Modell {0} ist nach wiederholten Fehlern vorübergehend deaktiviert",
+        french: "Le modèle {0} est temporairement désactivé après des échecs répétés",
+        spanish: "El modelo {0} está desactivado temporalmente tras fallos repetidos",
+    },
+    CatalogEntry {
+        code: "model_offline",
+        german: "Das Modell {0} ist außerhalb seiner Verfügbarkeitszeiten nicht geladen",
+        french: "Le modèle {0} n'est pas chargé en dehors de ses heures de disponibilité",
+        spanish: "El modelo {0} no está cargado fuera de su horario de disponibilidad",
+    },
+    CatalogEntry {
+        code: "max_length_exceeded",
+        german: "max_length darf {0} nicht überschreiten",
+        french: "max_length ne doit pas dépasser {0}",
+        spanish: "max_length no debe superar {0}",
+    },
+    CatalogEntry {
+        code: "invalid_max_decode_steps",
+        german: "max_decode_steps muss größer als null sein",
+        french: "max_decode_steps doit être supérieur à zéro",
+        spanish: "max_decode_steps debe ser mayor que cero",
+    },
+    CatalogEntry {
+        code: "invalid_max_characters",
+        german: "max_characters muss größer als null sein",
+        french: "max_characters doit être supérieur à zéro",
+        spanish: "max_characters debe ser mayor que cero",
+    },
+    CatalogEntry {
+        code: "invalid_merge_shorter_than",
+        german: "merge_shorter_than muss eine positive Anzahl Sekunden sein",
+        french: "merge_shorter_than doit être un nombre positif de secondes",
+        spanish: "merge_shorter_than debe ser un número positivo de segundos",
+    },
+    CatalogEntry {
+        code: "invalid_job_schedule",
+        german: "Der Cron-Ausdruck des Jobs ist ungültig: {0}",
+        french: "L'expression cron de la tâche n'est pas valide : {0}",
+        spanish: "La expresión cron del trabajo no es válida: {0}",
+    },
+    CatalogEntry {
+        code: "invalid_job_directory",
+        german: "Das Verzeichnis {0} des Jobs existiert nicht",
+        french: "Le répertoire {0} de la tâche n'existe pas",
+        spanish: "El directorio {0} del trabajo no existe",
+    },
+    CatalogEntry {
+        code: "injected_fault",
+        german: "Absichtlich herbeigeführter Fehler",
+        french: "Erreur provoquée volontairement",
+        spanish: "Error provocado intencionadamente",
+    },
+    CatalogEntry {
+        code: "audio_unsupported_container",
+        german: "Das Audioformat wird nicht unterstützt: {0}",
+        french: "Le format audio n'est pas pris en charge : {0}",
+        spanish: "El formato de audio no es compatible: {0}",
+    },
+    CatalogEntry {
+        code: "audio_no_track",
+        german: "Keine unterstützte Audiospur gefunden",
+        french: "Aucune piste audio prise en charge trouvée",
+        spanish: "No se encontró ninguna pista de audio compatible",
+    },
+    CatalogEntry {
+        code: "audio_unsupported_codec",
+        german: "Der Audiocodec wird nicht unterstützt: {0}",
+        french: "Le codec audio n'est pas pris en charge : {0}",
+        spanish: "El códec de audio no es compatible: {0}",
+    },
+    CatalogEntry {
+        code: "audio_corrupt",
+        german: "Die Audiodaten konnten nicht dekodiert werden: {0}",
+        french: "Les données audio n'ont pas pu être décodées : {0}",
+        spanish: "No se pudieron decodificar los datos de audio: {0}",
+    },
+    CatalogEntry {
+        code: "audio_empty",
+        german: "Die Audiodaten sind leer",
+        french: "Les données audio sont vides",
+        spanish: "Los datos de audio están vacíos",
+    },
+    CatalogEntry {
+        code: "audio_too_long",
+        german: "Die Audiodaten dürfen nicht länger als {0} Sekunden sein",
+        french: "Les données audio ne doivent pas dépasser {0} secondes",
+        spanish: "Los datos de audio no deben durar más de {0} segundos",
+    },
+    CatalogEntry {
+        code: "tokenize_failed",
+        german: "Die Eingabe konnte nicht in Tokens umgewandelt werden: {0}",
+        french: "L'entrée n'a pas pu être convertie en jetons : {0}",
+        spanish: "La entrada no se pudo convertir en tokens: {0}",
+    },
+    CatalogEntry {
+        code: "generation_stalled",
+        german: "Die Generierung wurde abgebrochen, da sie keinen Fortschritt machte",
+        french: "La génération a été interrompue faute de progrès",
+        spanish: "La generación se canceló porque no avanzaba",
+    },
+    CatalogEntry {
+        code: "invalid_batch_size",
+        german: "Ein Batch muss zwischen 1 und {0} Eingaben haben",
+        french: "Un lot doit contenir entre 1 et {0} entrées",
+        spanish: "Un lote debe tener entre 1 y {0} entradas",
+    },
+    CatalogEntry {
+        code: "invalid_chat",
+        german: "Die letzte Nachricht eines Chats muss vom Benutzer stammen",
+        french: "Le dernier message d'une conversation doit provenir de l'utilisateur",
+        spanish: "El último mensaje de un chat debe ser del usuario",
+    },
+    CatalogEntry {
+        code: "stream_closed",
+        german: "Die Generierung wurde abgebrochen, da der Stream geschlossen wurde",
+        french: "La génération a été interrompue car le flux a été fermé",
+        spanish: "La generación se canceló porque se cerró el flujo",
+    },
+    CatalogEntry {
+        code: "stream_policy",
+        german: "Streaming wird nicht unterstützt, solange Richtlinien für Ausgaben gelten",
+        french:
+            "Le streaming n'est pas pris en charge tant que des règles s'appliquent aux sorties",
+        spanish: "El streaming no es compatible mientras se apliquen políticas a las salidas",
+    },
+    CatalogEntry {
+        code: "stream_unsupported",
+        german: "Streaming wird zusammen mit einem Antwortformat nicht unterstützt",
+        french: "Le streaming n'est pas pris en charge avec un format de réponse",
+        spanish: "El streaming no es compatible con un formato de respuesta",
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_the_first_supported_language() {
+        assert_eq!(Language::negotiate("nl, fr-CH;q=0.9, de"), Language::French);
+        assert_eq!(Language::negotiate("nl"), Language::English);
+    }
+
+    #[test]
+    fn interpolates_params_into_translations() {
+        let message = LANGUAGE.sync_scope(Language::German, || {
+            localized_message("max_length_exceeded", &["512".into()])
+        });
+        assert_eq!(
+            message.as_deref(),
+            Some("max_length darf 512 nicht überschreiten")
+        );
+        let message = LANGUAGE.sync_scope(Language::English, || {
+            localized_message("max_length_exceeded", &["512".into()])
+        });
+        assert_eq!(message, None);
+    }
+}
@@ -15,16 +15,26 @@
     clippy::cargo_common_metadata
 )]
 
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::option::Option;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use axum::extract::ws::WebSocketUpgrade;
 use axum::extract::MatchedPath;
-use axum::extract::{DefaultBodyLimit, FromRef, Multipart, Request, State};
+use axum::extract::Path as RoutePath;
+use axum::extract::{DefaultBodyLimit, FromRef, Multipart, Query, Request, State};
 use axum::http::StatusCode;
 use axum::middleware::Next;
-use axum::response::Response;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::delete;
 use axum::routing::get;
 use axum::routing::post;
 use axum::{middleware, Extension, Json, Router};
@@ -37,9 +47,17 @@ use candle_transformers::models::mixformer;
 use clap::Parser;
 use clap_serde_derive::ClapSerde;
 use hf_hub::api::sync::Api;
+use hf_hub::Cache;
+use hyper_util::rt::TokioTimer;
 use lazy_static::lazy_static;
+use password_hash::PasswordHash;
+use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use tower_http::trace::TraceLayer;
 use tracing::instrument;
 use tracing::{error, info, warn};
@@ -47,13 +65,48 @@ use tracing::{error, info, warn};
 #[cfg(unix)]
 use tikv_jemallocator::Jemalloc;
 
+use crate::api::anonymization::{configure_anonymization, recorded_client_id};
 use crate::api::auth::{Auth, AuthToken};
 use crate::api::client::{ApiClient, ApiClientCreateRequest, ApiClientDeleteRequest, Permission};
 use crate::api::client::{ApiClientStatusRequest, ApiClientUpdateRequest};
-use crate::config::Config;
+use crate::api::erasure::{delete_client_data, list_data_deletions, DataDeletion};
+use crate::api::interactions::{
+    configure_interaction_log, insert_feedback, insert_interaction, interaction_log_enabled,
+    FeedbackRequest,
+};
+use crate::api::registration::{
+    claim_registration, create_registration, decide_registration, list_registrations, Registration,
+    RegistrationClaim, RegistrationClaimRequest, RegistrationDecisionRequest,
+    RegistrationListQuery, RegistrationRequest, RegistrationTicket,
+};
+use crate::api::retention::run_purger;
+use crate::api::usage::{
+    client_usage, configure_costs, model_stats, ClientUsage, ClientUsageRequest, Consumption,
+    ModelStats, ModelStatsRequest, UsageRecord,
+};
+use crate::banned_words::{banned_words, configure_banned_words};
+use crate::captions::{run_captions, CaptionQuery};
+use crate::chaos::{chaos_enabled, configure_chaos, inject_faults};
+use crate::config::{ClientDefinition, Config};
+use crate::documents::{
+    delete_document, document_chunks, document_info, extract_text, insert_document, list_documents,
+    rank_chunks, DocumentInfo, VALID_DOCUMENT_MIME_TYPES,
+};
 use crate::error::ModelRunnerError;
 use crate::error::{HttpErrorResponse, ModelResult};
-use crate::inference::model_config::GeneralModelConfig;
+use crate::fallback::{configure_fallbacks, fallback_chain, is_preferred};
+use crate::inference::artifact_store::{configure_artifacts, configure_downloads, parse_source};
+use crate::inference::audio_input::AudioInput;
+use crate::inference::audio_pipeline::Segment;
+use crate::inference::availability::{configure_availability, run_availability_schedule};
+use crate::inference::coalesce::Coalescer;
+use crate::inference::gbnf::parse_gbnf;
+use crate::inference::grammar::Grammar;
+use crate::inference::milestones::configure_milestones;
+use crate::inference::model_config::{configure_sampling, GeneralModelConfig};
+use crate::inference::model_slot::{
+    configure_breaker, configure_watchdog, ManagedModel, ModelSlot, ModelStatus, WarmPoolStatus,
+};
 use crate::inference::models::mistral7b::Mistral7BModel;
 use crate::inference::models::model::AudioTask;
 use crate::inference::models::model::ModelBase;
@@ -63,23 +116,111 @@ use crate::inference::models::openhermes::OpenHermesModel;
 use crate::inference::models::phi::PhiModel;
 use crate::inference::models::stablelm2::StableLm2Model;
 use crate::inference::models::whisper::WhisperModel;
+use crate::inference::prefix_cache::configure_prefix_cache;
+use crate::inference::probe::{
+    configure_probes, probe_audio, probes_enabled, run_probes, PROBE_MAX_LENGTH, PROBE_PROMPT,
+};
+use crate::inference::reload::{parse_schedule, run_reload_schedule};
+use crate::inference::runtime::configure_runtimes;
+use crate::inference::streaming::{self, TokenSink};
+use crate::inference::task::ask::{
+    grounded_prompt, AskRequest, AskResponse, SourceChunk, MAX_TOP_K,
+};
+use crate::inference::task::batch::{
+    configure_batching, max_batch_size, BatchOutput, BatchRequest, BatchResponse,
+};
+use crate::inference::task::chat::{ChatHandler, ChatMessage, ChatRequest, ChatResponse, ChatRole};
 use crate::inference::task::info::InfoRequest;
 use crate::inference::task::instruct::{InstructHandler, InstructRequest, InstructResponse};
-use crate::inference::task::raw::{RawHandler, RawRequest, RawResponse};
+use crate::inference::task::raw::{RawHandler, RawRequest, RawResponse, TokenCounts};
+use crate::inference::task::structured::{configure_structured_output, max_repairs, repair_prompt};
+use crate::inference::task::summarize::{
+    chunk_length, configure_summarization, map_prompt, reduce_prompt, split_chunks, SummarizeQuery,
+    SummarizeRequest, SummarizeResponse, SummarizeStrategy,
+};
 use crate::inference::task::transcribe::{
     TranscribeHandler, TranscribeRequest, TranscribeResponse,
 };
-use crate::telemetry::init_telemetry;
+use crate::inference::task::watermark::{DetectWatermarkRequest, WatermarkHandler};
+use crate::inference::watermark::{
+    configure_watermark, watermark_configured, watermark_enabled, WatermarkDetection,
+};
+use crate::interactive::run_interactive;
+use crate::jobs::{
+    create_schedule, delete_schedule, get_schedule, list_runs, list_schedules, parse_cron,
+    run_directory_watcher, run_job_scheduler, JobFailure, JobOutput, JobRun, JobSchedule,
+    JobScheduleRequest, JobTask,
+};
+use crate::lifecycle::{log_shutdown_report, shutdown_started, RequestGuard};
+use crate::limits::{
+    configure_limits, max_length, Capabilities, AUDIO_BODY_LIMIT, DOCUMENT_BODY_LIMIT,
+    TEXT_BODY_LIMIT, VALID_WAV_MIME_TYPES,
+};
+use crate::locale::negotiate_language;
+use crate::migration::{
+    lock_database, pending_migrations, run_migrations, schema_info, unknown_versions, SchemaInfo,
+};
+use crate::notifications::{configure_notifications, run_monitor};
+use crate::plugins::{apply_plugins, configure_plugins};
+use crate::policy::{checks_output, configure_policies, enforce_policies};
+use crate::punctuation::{
+    configure_punctuation, punctuation_model, punctuation_prompt, redistribute,
+};
+use crate::response::{Negotiated, ResponseFormat};
+use crate::routing::{configure_auto_routing, configure_routing, route_model};
+use crate::security::{configure_security_headers, security_headers};
+use crate::segmentation::Segmentation;
+use crate::sessions::{
+    append_exchange, conversation_prompt, create_session, delete_session, get_session,
+    list_sessions, Session, SessionCreateRequest, SessionInfo, SessionMessageRequest,
+};
+use crate::status::StatusReport;
+use crate::telemetry::{init_telemetry, run_trace_cleanup};
+use crate::tls::server_config;
+use crate::tools::{
+    agent_format, agent_prompt, allowed_tools, configure_tools, final_answer, max_depth,
+    parse_tool_call, run_tool, AgentRequest, AgentResponse, Tool,
+};
+use crate::upload::{configure_spooling, read_audio_field};
+use crate::workdir::{configure_work_dir, prepare_work_dir, temp_dir};
 
 #[cfg(unix)]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
 pub mod api;
+mod banned_words;
+mod captions;
+mod chaos;
 mod config;
+mod documents;
 pub mod error;
+mod fallback;
 mod inference;
+mod injection;
+mod interactive;
+mod jobs;
+mod lifecycle;
+mod limits;
+mod locale;
+mod migration;
+mod normalization;
+mod notifications;
+mod plugins;
+mod policy;
+mod punctuation;
+mod response;
+mod routing;
+mod security;
+mod segmentation;
+mod sessions;
+mod status;
 mod telemetry;
+mod tls;
+mod tools;
+mod truncation;
+mod upload;
+mod workdir;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -97,123 +238,167 @@ struct Args {
 struct AppState {
     db_pool: SqlitePool,
     auth: Auth,
+    /// Routes that may be requested without a token
+    anonymous_routes: Arc<[String]>,
 }
 
+/// Read-only routes that do not depend on the requesting client and may be exempted from authentication
+const ANONYMOUS_CAPABLE_ROUTES: [&str; 4] = ["/health", "/capabilities", "/status", "/model/info"];
+/// Routes requesting and claiming a client, anonymous when registration is allowed
+const REGISTRATION_ROUTES: [&str; 2] = ["/auth/register", "/auth/register/claim"];
+
 lazy_static! {
-    static ref PHI2_MODEL: PhiModel = PhiModel::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+    static ref PHI2_MODEL: ModelSlot<PhiModel> = ModelSlot::new(
+        "phi2",
+        || ModelBase {
             name: "Quantized Puffin Phi2".into(),
             license: "MIT".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct]),
             repo_id: "lmz/candle-quantized-phi".into(),
             repo_revision: "main".into(),
         },
-        "lmz/candle-quantized-phi",
-        "tokenizer-puffin-phi-v2.json",
-        "model-puffin-phi-v2-q80.gguf",
-        Some(mixformer::Config::puffin_phi_v2()),
-        GeneralModelConfig::default(),
-        false,
-    )
-    .map_err(|e| error!("Failed to create Phi2 model: {}", e))
-    .unwrap();
-    static ref PHI3_MODEL: PhiModel = PhiModel::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || PhiModel::new(
+            &Api::new()?,
+            &PHI2_MODEL.base(),
+            "lmz/candle-quantized-phi",
+            "tokenizer-puffin-phi-v2.json",
+            "model-puffin-phi-v2-q80.gguf",
+            Some(mixformer::Config::puffin_phi_v2()),
+            GeneralModelConfig::default(),
+            false,
+        ),
+    );
+    static ref PHI3_MODEL: ModelSlot<PhiModel> = ModelSlot::new(
+        "phi3",
+        || ModelBase {
             name: "Quantized Phi3 Instruct".into(),
             license: "MIT".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct]),
             repo_id: "microsoft/Phi-3-mini-4k-instruct-gguf".into(),
             repo_revision: "5eef2ce24766d31909c0b269fe90c817a8f263fb".into(),
         },
-        "microsoft/Phi-3-mini-4k-instruct",
-        "tokenizer.json",
-        "Phi-3-mini-4k-instruct-q4.gguf",
-        None,
-        GeneralModelConfig::default(),
-        true,
-    )
-    .map_err(|e| error!("Failed to create Phi3 model: {}", e))
-    .unwrap();
-    static ref WHISPER_MODEL: WhisperModel = WhisperModel::new(
-        Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || PhiModel::new(
+            &Api::new()?,
+            &PHI3_MODEL.base(),
+            "microsoft/Phi-3-mini-4k-instruct",
+            "tokenizer.json",
+            "Phi-3-mini-4k-instruct-q4.gguf",
+            None,
+            GeneralModelConfig::default(),
+            true,
+        ),
+    );
+    static ref WHISPER_MODEL: ModelSlot<WhisperModel> = ModelSlot::new(
+        "whisper",
+        || ModelBase {
             name: "Quantized Whisper".into(),
             license: "MIT".into(),
             domain: ModelDomain::Audio(AudioTask::Transcribe),
             repo_id: "lmz/candle-whisper".into(),
             repo_revision: "main".into(),
         },
-        "config-tiny.json",
-        "tokenizer-tiny.json",
-        "model-tiny-q4k.gguf",
-        "melfilters.bytes",
-    )
-    .map_err(|e| error!("Failed to create Whisper model: {}", e))
-    .unwrap();
-    static ref MISTRAL7B_INSTRUCT_MODEL: Mistral7BModel = Mistral7BModel::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || WhisperModel::new(
+            Api::new()?,
+            &WHISPER_MODEL.base(),
+            "config-tiny.json",
+            "tokenizer-tiny.json",
+            "model-tiny-q4k.gguf",
+            "melfilters.bytes",
+        ),
+    );
+    static ref MISTRAL7B_INSTRUCT_MODEL: ModelSlot<Mistral7BModel> = ModelSlot::new(
+        "mistral7b",
+        || ModelBase {
             name: "Quantized Mistral7B Instruct".into(),
             license: "Apache 2.0".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct,]),
             repo_id: "TheBloke/Mistral-7B-Instruct-v0.2-GGUF".into(),
             repo_revision: "main".into(),
         },
-        "tokenizer.json",
-        "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
-        GeneralModelConfig::default(),
-    )
-    .map_err(|e| error!("Failed to create Mistral7B model: {}", e))
-    .unwrap();
-    static ref OPENHERMES_MODEL: OpenHermesModel = OpenHermesModel::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || Mistral7BModel::new(
+            &Api::new()?,
+            &MISTRAL7B_INSTRUCT_MODEL.base(),
+            "tokenizer.json",
+            "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
+            GeneralModelConfig::default(),
+        ),
+    );
+    static ref OPENHERMES_MODEL: ModelSlot<OpenHermesModel> = ModelSlot::new(
+        "openhermes",
+        || ModelBase {
             name: "Quantized OpenHermes-2.5 Mistral7B".into(),
             license: "Apache 2.0".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct,]),
             repo_id: "TheBloke/OpenHermes-2.5-Mistral-7B-GGUF".into(),
             repo_revision: "main".into(),
         },
-        "tokenizer.json",
-        "openhermes-2.5-mistral-7b.Q4_K_M.gguf",
-        GeneralModelConfig::default(),
-    )
-    .map_err(|e| error!("Failed to create OpenHermes model: {}", e))
-    .unwrap();
-    static ref STABLELM2_ZEPHYR_MODEL: StableLm2Model = StableLm2Model::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || OpenHermesModel::new(
+            &Api::new()?,
+            &OPENHERMES_MODEL.base(),
+            "tokenizer.json",
+            "openhermes-2.5-mistral-7b.Q4_K_M.gguf",
+            GeneralModelConfig::default(),
+        ),
+    );
+    static ref STABLELM2_ZEPHYR_MODEL: ModelSlot<StableLm2Model> = ModelSlot::new(
+        "stablelm2zephyr",
+        || ModelBase {
             name: "Quantized StableLM 2 Zephyr 1.6B".into(),
             license: "StabilityAI Non-Commercial Research Community License".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct]),
             repo_id: "lmz/candle-stablelm".into(),
             repo_revision: "main".into(),
         },
-        "tokenizer-gpt4.json",
-        "stablelm-2-zephyr-1_6b-q4k.gguf",
-        &GeneralModelConfig::default(),
-        true,
-    )
-    .map_err(|e| error!("Failed to create StableLM2 model: {}", e))
-    .unwrap();
-    static ref STABLELM2_MODEL: StableLm2Model = StableLm2Model::new(
-        &Api::new().expect("Failed to create API"),
-        &ModelBase {
+        || StableLm2Model::new(
+            &Api::new()?,
+            &STABLELM2_ZEPHYR_MODEL.base(),
+            "tokenizer-gpt4.json",
+            "stablelm-2-zephyr-1_6b-q4k.gguf",
+            &GeneralModelConfig::default(),
+            true,
+        ),
+    );
+    static ref STABLELM2_MODEL: ModelSlot<StableLm2Model> = ModelSlot::new(
+        "stablelm2",
+        || ModelBase {
             name: "Quantized StableLM 2 1.6B".into(),
             license: "StabilityAI Non-Commercial Research Community License".into(),
             domain: ModelDomain::Text(vec![TextTask::Chat, TextTask::Instruct]),
             repo_id: "lmz/candle-stablelm".into(),
             repo_revision: "main".into(),
         },
-        "tokenizer-gpt4.json",
-        "stablelm-2-1_6b-q4k.gguf",
-        &GeneralModelConfig::default(),
-        false,
-    )
-    .map_err(|e| error!("Failed to create StableLM2 model: {}", e))
-    .unwrap();
+        || StableLm2Model::new(
+            &Api::new()?,
+            &STABLELM2_MODEL.base(),
+            "tokenizer-gpt4.json",
+            "stablelm-2-1_6b-q4k.gguf",
+            &GeneralModelConfig::default(),
+            false,
+        ),
+    );
+}
+
+/// In-flight raw generations that identical requests can be coalesced onto
+static RAW_REQUESTS: Coalescer<(String, RawResponse)> = Coalescer::new("raw");
+
+/// All models served by this instance
+fn managed_models() -> [&'static dyn ManagedModel; 7] {
+    [
+        &*PHI2_MODEL,
+        &*PHI3_MODEL,
+        &*WHISPER_MODEL,
+        &*MISTRAL7B_INSTRUCT_MODEL,
+        &*OPENHERMES_MODEL,
+        &*STABLELM2_ZEPHYR_MODEL,
+        &*STABLELM2_MODEL,
+    ]
+}
+
+/// Returns the served model with the given name
+fn managed_model(name: &str) -> Option<&'static dyn ManagedModel> {
+    managed_models()
+        .into_iter()
+        .find(|model| model.name() == name)
 }
 
 #[allow(clippy::too_many_lines)]
@@ -237,8 +422,42 @@ async fn main() -> Result<()> {
         }
     };
 
+    // The working directory is checked before telemetry as local traces are written into it
+    let work_dir_check = config.work_dir.as_deref().map(prepare_work_dir);
+    let trace_dir_check = config.trace_dir.as_deref().map(prepare_work_dir);
+    let trace_dir = config
+        .trace_dir
+        .as_deref()
+        .filter(|_| matches!(trace_dir_check, Some(Ok(()))))
+        .or_else(|| {
+            config
+                .work_dir
+                .as_deref()
+                .filter(|_| matches!(work_dir_check, Some(Ok(()))))
+        });
+
     // Init telemetry
-    let _guards = init_telemetry(&config.otel_endpoint, config.console, config.trace_local);
+    let _guards = init_telemetry(
+        &config.otel_endpoint,
+        config.console,
+        config.trace_local,
+        trace_dir,
+    );
+    if let Some(Err(err)) = work_dir_check {
+        exit_err!(1, "Unusable working directory: {:#}", err);
+    }
+    if let Some(Err(err)) = trace_dir_check {
+        exit_err!(1, "Unusable trace directory: {:#}", err);
+    }
+    if config.trace_local && config.trace_retention > 0 {
+        tokio::spawn(run_trace_cleanup(
+            trace_dir.map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+            Duration::from_secs(config.trace_retention * 24 * 60 * 60),
+        ));
+    }
+    if let Some(work_dir) = &config.work_dir {
+        configure_work_dir(work_dir.clone());
+    }
 
     info!(
         "model_runner v{}",
@@ -251,74 +470,417 @@ async fn main() -> Result<()> {
         candle_core::utils::with_simd128(),
         candle_core::utils::with_f16c()
     );
+    let missing_features = missing_cpu_features();
+    if !missing_features.is_empty() {
+        warn!(
+            "The host supports {:?} but this binary was compiled without them, quantized inference will be considerably slower. Rebuild with RUSTFLAGS=\"-C target-cpu=native\"",
+            missing_features
+        );
+        if config.require_optimized_build {
+            exit_err!(
+                1,
+                "Refusing to start as the binary lacks supported CPU features {:?}",
+                missing_features
+            );
+        }
+    }
+
+    configure_breaker(
+        config.breaker_threshold,
+        Duration::from_secs(config.breaker_cooldown),
+    );
+    configure_watchdog(Duration::from_secs(config.watchdog_timeout));
+    configure_probes(
+        Duration::from_secs(config.probe_interval),
+        Duration::from_millis(config.probe_max_latency),
+        config.probe_failure_threshold,
+    );
+    configure_milestones(config.milestone_interval);
+    configure_artifacts(parse_source(&config.artifact_source)?);
+    configure_downloads(
+        config.max_concurrent_downloads,
+        config.download_bandwidth_limit,
+    );
+    configure_limits(config.max_length, config.max_audio_duration);
+    if let Err(err) = configure_sampling(config.top_k, config.min_p) {
+        exit_err!(1, "Invalid sampling defaults: {:#}", err);
+    }
+    configure_spooling(config.spool_threshold);
+    configure_plugins(&config.plugins);
+    configure_tools(&config.tools, config.tool_max_depth)?;
+    if let Some(path) = &config.policy_file {
+        configure_policies(path)?;
+    }
+    configure_auto_routing(config.auto_routing.clone());
+    configure_fallbacks(
+        config.fallbacks.clone(),
+        Duration::from_millis(config.fallback_queue_latency),
+    );
+    for name in config.availability.keys() {
+        if managed_model(name).is_none() {
+            exit_err!(
+                1,
+                "Availability window configured for unknown model {}",
+                name
+            );
+        }
+    }
+    configure_availability(&config.availability)?;
+    for name in config.runtime.keys() {
+        if managed_model(name).is_none() {
+            exit_err!(1, "Runtime configured for unknown model {}", name);
+        }
+    }
+    configure_runtimes(&config.device, &config.runtime)?;
+    tokio::spawn(run_availability_schedule(managed_models().to_vec()));
+    if probes_enabled() {
+        tokio::spawn(run_probes(managed_models().to_vec(), probe_model));
+        info!(
+            "Probing loaded models every {} seconds",
+            config.probe_interval
+        );
+    }
+    for (name, size) in &config.warm_pools {
+        let Some(model) = managed_model(name) else {
+            exit_err!(1, "Warm pool configured for unknown model {}", name);
+        };
+        let size = *size;
+        tokio::task::spawn_blocking(move || model.resize_warm_pool(size));
+    }
+    configure_costs(config.costs.clone());
+    configure_interaction_log(config.log_interactions);
+    configure_chaos(config.chaos.clone())?;
+    if config.anonymize_clients {
+        match &config.anonymization_salt {
+            Some(salt) if !salt.is_empty() => configure_anonymization(salt.clone()),
+            _ => exit_err!(1, "Anonymizing clients requires an anonymization salt"),
+        }
+    }
+    configure_summarization(config.summarize_chunk_length);
+    configure_batching(config.max_batch_size);
+    configure_prefix_cache(config.prefix_cache_size, config.prefix_cache_min_tokens);
+    configure_structured_output(config.structured_output_repairs);
+    configure_punctuation(config.punctuation_model.clone());
+    if let Some(key) = &config.watermark_key {
+        configure_watermark(
+            key,
+            config.watermark_strength,
+            config.watermark_clients.clone(),
+        );
+    }
+    configure_banned_words(config.banned_words.clone());
+    if let Some(script) = &config.routing_script {
+        configure_routing(script)?;
+    }
+    if let Some(expression) = &config.reload_schedule {
+        let schedule = parse_schedule(expression)?;
+        tokio::spawn(run_reload_schedule(schedule, managed_models().to_vec()));
+        info!("Scheduled model reloads enabled with {}", expression);
+    }
+    if !config.webhooks.is_empty() {
+        configure_notifications(config.webhooks.clone())?;
+        info!(
+            "Notifying {} webhooks of operational events",
+            config.webhooks.len()
+        );
+    }
+    if !config.webhooks.is_empty() || config.tls.certificate.is_some() {
+        // Disk space is only checked for webhooks, the certificate expiry is exported as a metric too
+        let mut paths = Vec::new();
+        if !config.webhooks.is_empty() {
+            let database_dir = Path::new(&config.sqlite_file_path)
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+            paths = vec![database_dir, temp_dir(), Cache::default().path().clone()];
+            paths.retain(|path| path.exists());
+            paths.dedup();
+        }
+        tokio::spawn(run_monitor(
+            paths,
+            config.min_free_disk_space * 1024 * 1024,
+            config.tls.certificate.clone(),
+            Duration::from_secs(config.certificate_expiry_warning * 24 * 60 * 60),
+        ));
+    }
 
+    // Held until shutdown so a second instance can not migrate or write the same database
+    let _database_lock = match lock_database(&config.sqlite_file_path) {
+        Ok(lock) => lock,
+        Err(err) => exit_err!(1, "Refusing to start: {}", err),
+    };
     let sqlite_options = SqliteConnectOptions::new()
         .create_if_missing(true)
-        .filename(config.sqlite_file_path);
+        .filename(&config.sqlite_file_path);
     let db_pool = SqlitePool::connect_with(sqlite_options)
         .await
         .context("Failed to connect to Sqlite")?;
-    sqlx::migrate!()
-        .run(&db_pool)
-        .await
-        .context("Failed to run migrations")?;
+    let unknown_versions = unknown_versions(&db_pool).await?;
+    if !unknown_versions.is_empty() {
+        exit_err!(
+            1,
+            "Database was migrated by a newer version of model_runner (migrations {:?}), refusing to start",
+            unknown_versions
+        );
+    }
+    if config.skip_migrations {
+        let pending = pending_migrations(&db_pool).await?;
+        if !pending.is_empty() {
+            exit_err!(
+                1,
+                "Database has {} pending migrations, apply them with the migrate command of the CLI",
+                pending.len()
+            );
+        }
+    } else {
+        run_migrations(&db_pool)
+            .await
+            .context("Failed to run migrations")?;
+    }
+    if let Some(route) = config
+        .anonymous_routes
+        .iter()
+        .find(|route| !ANONYMOUS_CAPABLE_ROUTES.contains(&route.as_str()))
+    {
+        exit_err!(
+            1,
+            "Route {} can not be accessed anonymously, supported are {:?}",
+            route,
+            ANONYMOUS_CAPABLE_ROUTES
+        );
+    }
+    let registration = config.allow_registration && !config.auth_read_only;
+    let mut anonymous_routes = config.anonymous_routes.clone();
+    if registration {
+        anonymous_routes.extend(REGISTRATION_ROUTES.map(String::from));
+    }
     let app_state = AppState {
         db_pool,
         auth: Auth::default(),
+        anonymous_routes: anonymous_routes.into(),
     };
+    bootstrap_clients(&app_state.auth, &config.clients, &app_state.db_pool)
+        .await
+        .context("Failed to provision clients from configuration")?;
+
+    tokio::spawn(run_job_scheduler(app_state.db_pool.clone(), run_job));
+    if config.retention.is_enabled() {
+        tokio::spawn(run_purger(
+            config.retention.clone(),
+            app_state.db_pool.clone(),
+        ));
+    }
+    for task in &config.watch {
+        if let Err(err) = validate_job_task(task) {
+            exit_err!(1, "Invalid watched directory: {}", err);
+        }
+        tokio::spawn(run_directory_watcher(
+            task.clone(),
+            Duration::from_secs(config.watch_interval.max(1)),
+            run_job_files,
+        ));
+    }
 
     let model_router = Router::new().route("/info", post(handle_model_info_request));
 
     let text_router = Router::new()
         .route("/raw", post(handle_raw_request))
-        .route("/instruct", post(handle_instruct_request));
+        .route("/instruct", post(handle_instruct_request))
+        .route("/chat", post(handle_chat_request))
+        .route("/batch", post(handle_batch_request))
+        .route("/summarize", post(handle_summarize_request))
+        .route("/ask", post(handle_ask_request))
+        .route("/agent", post(handle_agent_request))
+        .route("/detect_watermark", post(handle_detect_watermark_request))
+        .route("/ws", get(handle_text_socket_request))
+        .layer(DefaultBodyLimit::max(TEXT_BODY_LIMIT));
 
     let audio_router = Router::new()
         .route("/transcribe", post(handle_transcribe_request))
-        // 10 MB limit
-        .layer(DefaultBodyLimit::max(10_000_000));
+        .route("/live", get(handle_live_caption_request))
+        .layer(DefaultBodyLimit::max(AUDIO_BODY_LIMIT));
+
+    let document_router = Router::new()
+        .route(
+            "/",
+            post(handle_document_upload_request).get(handle_document_list_request),
+        )
+        .route(
+            "/:id",
+            get(handle_document_info_request).delete(handle_document_delete_request),
+        )
+        .layer(DefaultBodyLimit::max(DOCUMENT_BODY_LIMIT));
+
+    let session_router = Router::new()
+        .route(
+            "/",
+            post(handle_session_create_request).get(handle_session_list_request),
+        )
+        .route(
+            "/:id",
+            get(handle_session_request).delete(handle_session_delete_request),
+        )
+        .route("/:id/messages", post(handle_session_message_request))
+        .layer(DefaultBodyLimit::max(TEXT_BODY_LIMIT));
+
+    let job_router = Router::new()
+        .route(
+            "/schedules",
+            post(handle_job_schedule_create_request).get(handle_job_schedule_list_request),
+        )
+        .route(
+            "/schedules/:id",
+            get(handle_job_schedule_request).delete(handle_job_schedule_delete_request),
+        )
+        .route("/schedules/:id/runs", get(handle_job_run_list_request));
 
-    let auth_router = Router::new()
+    let mut auth_router = Router::new()
         .route("/status", post(handle_status_request))
-        .route("/create", post(handle_create_request))
-        .route("/delete", post(handle_delete_request))
-        .route("/update", post(handle_update_request));
+        .route("/usage", get(handle_usage_request))
+        .route(
+            "/clients/:id/data",
+            delete(handle_client_data_delete_request),
+        );
+    if config.auth_read_only {
+        info!("Auth is read-only, clients can not be changed over HTTP");
+    } else {
+        auth_router = auth_router
+            .route("/create", post(handle_create_request))
+            .route("/delete", post(handle_delete_request))
+            .route("/update", post(handle_update_request));
+    }
 
-    let router = Router::new()
-        .nest("/model", model_router)
+    let mut admin_router = Router::new()
+        .route("/info", get(handle_admin_info_request))
+        .route("/stats/models", get(handle_admin_model_stats_request))
+        .route("/data_deletions", get(handle_data_deletion_list_request))
+        .route(
+            "/warm_pools",
+            get(handle_warm_pool_list_request).post(handle_warm_pool_resize_request),
+        );
+    if registration {
+        info!("Registration is allowed, clients are issued after approval");
+        auth_router = auth_router
+            .route("/register", post(handle_register_request))
+            .route("/register/claim", post(handle_register_claim_request));
+        admin_router = admin_router
+            .route("/registrations", get(handle_registration_list_request))
+            .route(
+                "/registrations/approve",
+                post(handle_registration_approve_request),
+            )
+            .route(
+                "/registrations/deny",
+                post(handle_registration_deny_request),
+            );
+    } else if config.allow_registration {
+        warn!("Registration is disabled as auth is read-only");
+    }
+
+    // Jobs read and write server directories, so they are only served next to the admin endpoints
+    let management_router = Router::new()
+        .nest("/admin", admin_router)
         .nest("/auth", auth_router)
+        .nest("/jobs", job_router);
+
+    let mut router = Router::new()
+        .nest("/model", model_router)
         .nest("/text", text_router)
         .nest("/audio", audio_router)
-        .layer(middleware::from_fn_with_state(
-            app_state.clone(),
-            auth_middleware,
-        ))
+        .nest("/documents", document_router)
+        .nest("/sessions", session_router)
+        .route("/feedback", post(handle_feedback_request));
+    if chaos_enabled() {
+        // Added before the health and status routes so that only requests of clients are affected
+        router = router.layer(middleware::from_fn(inject_faults));
+    }
+    router = router
         .route("/health", get(handle_health_request))
-        .layer(TraceLayer::new_for_http())
-        .layer(middleware::from_fn(track_request))
-        .with_state(app_state);
+        .route("/capabilities", get(handle_capabilities_request));
+    if !config.disable_status_page {
+        router = router.route("/status", get(handle_status_page_request));
+    }
 
     let addr = format!("{}:{}", config.address, config.port)
         .parse::<SocketAddr>()
         .context("Failed to create socket from address and port")?;
-    info!("Listening on {}", addr);
-
     let shutdown_handle = Handle::new();
     tokio::spawn(shutdown_handler(shutdown_handle.clone()));
+    configure_security_headers(
+        config
+            .security_headers
+            .unwrap_or_else(|| config.tls.certificate.is_some()),
+        config.hsts_max_age,
+    );
 
-    match (config.tls.certificate, config.tls.private_key) {
-        (Some(certificate), Some(private_key)) => {
-            let tls_config = RustlsConfig::from_pem_file(certificate, private_key)
-                .await
-                .context("Failed to create TLS configuration")?;
+    if let Some(admin_address) = &config.admin_address {
+        let admin_addr = admin_address
+            .parse::<SocketAddr>()
+            .context("Failed to create socket from admin address")?;
+        let admin = serve(
+            admin_addr,
+            apply_layers(management_router, app_state.clone()),
+            &config,
+            shutdown_handle.clone(),
+        );
+        let public = serve(
+            addr,
+            apply_layers(router, app_state),
+            &config,
+            shutdown_handle,
+        );
+        tokio::try_join!(public, admin)?;
+    } else {
+        let router = apply_layers(router.merge(management_router), app_state);
+        serve(addr, router, &config, shutdown_handle).await?;
+    }
+    log_shutdown_report();
+
+    Ok(())
+}
+
+/// Wraps the routes in the middleware shared by all listeners
+fn apply_layers(router: Router<AppState>, app_state: AppState) -> Router {
+    router
+        .layer(middleware::from_fn(enforce_policies))
+        .layer(middleware::from_fn(apply_plugins))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth_middleware,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .layer(middleware::from_fn(negotiate_language))
+        .layer(middleware::from_fn(track_request))
+        .layer(middleware::from_fn(security_headers))
+        .with_state(app_state)
+}
+
+/// Serves the routes on the address with TLS if configured until the handle shuts it down
+#[tracing::instrument(level = "info", skip(router, config, shutdown_handle))]
+async fn serve(
+    addr: SocketAddr,
+    router: Router,
+    config: &Config,
+    shutdown_handle: Handle,
+) -> Result<()> {
+    info!("Listening on {}", addr);
+    match (&config.tls.certificate, &config.tls.private_key) {
+        (Some(_), Some(_)) => {
+            let tls_config = RustlsConfig::from_config(Arc::new(
+                server_config(&config.tls).context("Failed to create TLS configuration")?,
+            ));
             info!("TLS support for HTTPS enabled");
-            axum_server::bind_rustls(addr, tls_config)
+            let mut server = axum_server::bind_rustls(addr, tls_config);
+            configure_http(&mut server, config, false);
+            server
                 .handle(shutdown_handle)
                 .serve(router.into_make_service())
                 .await?;
         }
         (None, None) => {
-            axum_server::bind(addr)
+            let mut server = axum_server::bind(addr);
+            configure_http(&mut server, config, config.disable_h2c);
+            server
                 .handle(shutdown_handle)
                 .serve(router.into_make_service())
                 .await?;
@@ -328,10 +890,123 @@ async fn main() -> Result<()> {
             "Both certificate and private key must be provided to enable TLS support."
         ),
     };
+    Ok(())
+}
 
+/// Returns the CPU features the host supports but this binary was not compiled with
+#[tracing::instrument(level = "trace")]
+fn missing_cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut missing = vec![];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if std::arch::is_x86_feature_detected!("avx2") && !cfg!(target_feature = "avx2") {
+            missing.push("avx2");
+        }
+        if std::arch::is_x86_feature_detected!("fma") && !cfg!(target_feature = "fma") {
+            missing.push("fma");
+        }
+        if std::arch::is_x86_feature_detected!("f16c") && !cfg!(target_feature = "f16c") {
+            missing.push("f16c");
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") && !cfg!(target_feature = "neon") {
+            missing.push("neon");
+        }
+    }
+    missing
+}
+
+/// Creator id of the clients provisioned from the configuration file
+const CONFIG_CREATOR_ID: &str = "config";
+
+/// Creates or updates the clients defined in the configuration file so that restarts are idempotent
+#[tracing::instrument(level = "info", skip(auth, clients, pool))]
+async fn bootstrap_clients(
+    auth: &Auth,
+    clients: &[ClientDefinition],
+    pool: &SqlitePool,
+) -> Result<()> {
+    for definition in clients {
+        let permission = definition
+            .permissions
+            .iter()
+            .map(|name| Permission::from_str(name))
+            .collect::<Result<Permission>>()
+            .with_context(|| format!("Invalid permissions for client {}", definition.name))?;
+
+        match (&definition.id, &definition.key_hash) {
+            (Some(id), Some(key_hash)) => {
+                let key_hash = PasswordHash::new(key_hash).map_err(|e| {
+                    anyhow!("Invalid key hash for client {}: {}", definition.name, e)
+                })?;
+                ApiClient::upsert_with_token(
+                    id,
+                    &key_hash,
+                    &definition.name,
+                    &permission,
+                    CONFIG_CREATOR_ID,
+                    pool,
+                )
+                .await?;
+            }
+            (None, None) => {
+                if let Some(id) =
+                    ApiClient::find_id_by_name(&definition.name, CONFIG_CREATOR_ID, pool).await?
+                {
+                    let client = ApiClient::with_id(&id, pool).await?;
+                    if client.permissions.bits() != permission.bits() {
+                        client.update(&definition.name, &permission, pool).await?;
+                    }
+                } else {
+                    let client = ApiClient::new(
+                        auth,
+                        &definition.name,
+                        &permission,
+                        &Some(CONFIG_CREATOR_ID.into()),
+                        pool,
+                    )
+                    .await?;
+                    // Printed instead of logged so that the token stays out of log files and
+                    // exported traces
+                    println!(
+                        "Created client {} with token {}, the token will not be shown again",
+                        definition.name, client.token
+                    );
+                }
+            }
+            _ => bail!(
+                "Both id and key hash must be provided to use a fixed token for client {}",
+                definition.name
+            ),
+        }
+        info!("Provisioned client {}", definition.name);
+    }
     Ok(())
 }
 
+/// Applies the connection settings shared by the TLS and plaintext listeners
+#[tracing::instrument(level = "trace", skip(server, config))]
+fn configure_http<A>(server: &mut axum_server::Server<A>, config: &Config, http1_only: bool) {
+    let builder = server.http_builder();
+    if http1_only {
+        *builder = builder.clone().http1_only();
+        return;
+    }
+
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(
+            (config.keep_alive_interval > 0)
+                .then(|| Duration::from_secs(config.keep_alive_interval)),
+        )
+        .keep_alive_timeout(Duration::from_secs(config.keep_alive_timeout));
+}
+
 #[allow(clippy::redundant_pub_crate)]
 #[tracing::instrument(level = "info", skip(handle))]
 async fn shutdown_handler(handle: Handle) {
@@ -352,22 +1027,43 @@ async fn shutdown_handler(handle: Handle) {
         }
     };
 
-    #[cfg(not(unix))]
+    #[cfg(windows)]
+    let terminate_signal = async {
+        match tokio::signal::windows::ctrl_break() {
+            Ok(mut signal) => {
+                signal.recv().await;
+                info!("Received ctrl-break signal");
+            }
+            Err(e) => error!("Failed to listen for ctrl-break signal: {}", e),
+        }
+    };
+
+    #[cfg(not(any(unix, windows)))]
     let terminate_signal = std::future::pending::<()>();
 
     tokio::select! {
-        () = ctrl_c_signal => handle.graceful_shutdown(Some(Duration::from_secs(45))),
-        () = terminate_signal => handle.graceful_shutdown(Some(Duration::from_secs(45))),
+        () = ctrl_c_signal => {},
+        () = terminate_signal => {},
     }
+    shutdown_started();
+    handle.graceful_shutdown(Some(Duration::from_secs(45)));
 }
 
 #[instrument(skip_all)]
 async fn auth_middleware(
     State(state): State<AppState>,
-    TypedHeader(auth_header): TypedHeader<Authorization<Bearer>>,
+    auth_header: Option<TypedHeader<Authorization<Bearer>>>,
     mut request: Request,
     next: Next,
 ) -> ModelResult<Response> {
+    // Tokens sent to anonymous routes are ignored, so an expired one does not fail the request
+    if state.anonymous_routes.contains(&get_path(&request)) {
+        info!(monotonic_counter.requests_anonymous = 1);
+        return Ok(next.run(request).await);
+    }
+    let Some(TypedHeader(auth_header)) = auth_header else {
+        bail_runner!(StatusCode::UNAUTHORIZED, "Missing bearer token");
+    };
     let client = ApiClient::with_token(
         &state.auth,
         AuthToken::from_raw_str(auth_header.token())?,
@@ -407,7 +1103,9 @@ async fn track_request(req: Request, next: Next) -> ModelResult<Response> {
     let version = req.version();
     let scheme = get_scheme(&req);
 
+    let guard = RequestGuard::begin();
     let response = next.run(req).await;
+    guard.complete();
     info!(
         histogram.http.server.request.duration = start.elapsed().as_secs_f64(),
         ?method,
@@ -419,10 +1117,103 @@ async fn track_request(req: Request, next: Next) -> ModelResult<Response> {
     Ok(response)
 }
 
+#[derive(Serialize, Debug)]
+struct HealthResponse {
+    models: BTreeMap<&'static str, ModelStatus>,
+}
+
 #[tracing::instrument(level = "trace", skip())]
 #[axum_macros::debug_handler]
-async fn handle_health_request() -> ModelResult<StatusCode> {
-    Ok(StatusCode::OK)
+async fn handle_health_request() -> ModelResult<(StatusCode, Json<HealthResponse>)> {
+    let models = managed_models()
+        .iter()
+        .map(|model| (model.name(), model.status()))
+        .collect();
+    Ok((StatusCode::OK, Json(HealthResponse { models })))
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_status_page_request() -> ModelResult<(StatusCode, Json<StatusReport>)> {
+    Ok((
+        StatusCode::OK,
+        Json(StatusReport::current(&managed_models())),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_capabilities_request() -> ModelResult<(StatusCode, Json<Capabilities>)> {
+    Ok((StatusCode::OK, Json(Capabilities::current())))
+}
+
+#[derive(Serialize, Debug)]
+struct AdminInfoResponse {
+    version: &'static str,
+    schema: SchemaInfo,
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_admin_info_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<AdminInfoResponse>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    Ok((
+        StatusCode::OK,
+        Json(AdminInfoResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            schema: schema_info(&state.db_pool).await?,
+        }),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_admin_model_stats_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Query(req): Query<ModelStatsRequest>,
+) -> ModelResult<(StatusCode, Json<Vec<ModelStats>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    Ok((
+        StatusCode::OK,
+        Json(model_stats(req.window, &state.db_pool).await?),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_warm_pool_list_request(
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<BTreeMap<&'static str, WarmPoolStatus>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    let pools = managed_models()
+        .iter()
+        .map(|model| (model.name(), model.warm_pool()))
+        .collect();
+    Ok((StatusCode::OK, Json(pools)))
+}
+
+#[derive(Deserialize, Debug)]
+struct WarmPoolResizeRequest {
+    model: String,
+    size: usize,
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_warm_pool_resize_request(
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<WarmPoolResizeRequest>,
+) -> ModelResult<(StatusCode, Json<WarmPoolStatus>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    let Some(model) = managed_model(&req.model) else {
+        bail_runner!(StatusCode::NOT_FOUND, "Failed to find model {}", req.model);
+    };
+    tokio::task::spawn_blocking(move || model.resize_warm_pool(req.size)).await?;
+    Ok((StatusCode::OK, Json(model.warm_pool())))
 }
 
 #[tracing::instrument(level = "trace", skip(req))]
@@ -449,6 +1240,32 @@ async fn handle_status_request(
     Ok((StatusCode::OK, Json(client)))
 }
 
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_usage_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Query(req): Query<ClientUsageRequest>,
+) -> ModelResult<(StatusCode, Json<ClientUsage>)> {
+    let id = match req.id {
+        Some(id) if id != client.token.id => {
+            client.has_permission(&Permission::STATUS_OTHER)?;
+            id
+        }
+        _ => {
+            client.has_permission(&Permission::STATUS_SELF)?;
+            client.token.id.clone()
+        }
+    };
+    Ok((
+        StatusCode::OK,
+        Json(ClientUsage {
+            id: id.clone(),
+            ..client_usage(&recorded_client_id(&id), req.window, &state.db_pool).await?
+        }),
+    ))
+}
+
 #[tracing::instrument(level = "trace", skip())]
 #[axum_macros::debug_handler]
 async fn handle_create_request(
@@ -483,6 +1300,41 @@ async fn handle_delete_request(
     Ok(StatusCode::OK)
 }
 
+/// Deletes the data stored for the client, which may already be deleted itself
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_client_data_delete_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<(StatusCode, Json<DataDeletion>)> {
+    if id == client.token.id {
+        client.has_permission(&Permission::DELETE_SELF)?;
+    } else {
+        client.has_permission(&Permission::DELETE_OTHER)?;
+    }
+    let deletion = delete_client_data(&id, &client.token.id, &state.db_pool).await?;
+    info!(
+        "Deleted data of client {} on request of {}",
+        recorded_client_id(&id),
+        recorded_client_id(&client.token.id)
+    );
+    Ok((StatusCode::OK, Json(deletion)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_data_deletion_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<Vec<DataDeletion>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    Ok((
+        StatusCode::OK,
+        Json(list_data_deletions(&state.db_pool).await?),
+    ))
+}
+
 #[tracing::instrument(level = "trace", skip(req))]
 #[axum_macros::debug_handler]
 async fn handle_update_request(
@@ -511,78 +1363,1347 @@ async fn handle_update_request(
     Ok(StatusCode::OK)
 }
 
-#[tracing::instrument(level = "trace", skip())]
+#[tracing::instrument(level = "trace", skip(state))]
 #[axum_macros::debug_handler]
-async fn handle_model_info_request(
-    Json(req): Json<InfoRequest>,
-) -> ModelResult<(StatusCode, Json<ModelBase>)> {
-    match req.model.as_str() {
-        "phi2" => Ok((StatusCode::OK, Json(PHI2_MODEL.base.clone()))),
-        "phi3" => Ok((StatusCode::OK, Json(PHI3_MODEL.base.clone()))),
-        "mistral7b" => Ok((StatusCode::OK, Json(MISTRAL7B_INSTRUCT_MODEL.base.clone()))),
-        "openhermes" => Ok((StatusCode::OK, Json(OPENHERMES_MODEL.base.clone()))),
-        "stablelm2zephyr" => Ok((StatusCode::OK, Json(STABLELM2_ZEPHYR_MODEL.base.clone()))),
-        "stablelm2" => Ok((StatusCode::OK, Json(STABLELM2_MODEL.base.clone()))),
-        "whisper" => Ok((StatusCode::OK, Json(WHISPER_MODEL.base.clone()))),
-        _ => bail_runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model),
+async fn handle_register_request(
+    State(state): State<AppState>,
+    Json(req): Json<RegistrationRequest>,
+) -> ModelResult<(StatusCode, Json<RegistrationTicket>)> {
+    if req.name.trim().is_empty() {
+        bail_runner!(StatusCode::BAD_REQUEST, "Name must not be empty");
     }
+    let ticket = create_registration(&state.auth, &req, &state.db_pool).await?;
+    info!(monotonic_counter.registrations = 1, id = ticket.id);
+    Ok((StatusCode::CREATED, Json(ticket)))
 }
 
-#[tracing::instrument(level = "trace", skip())]
+#[tracing::instrument(level = "trace", skip(state, req))]
 #[axum_macros::debug_handler]
-async fn handle_raw_request(
-    Json(req): Json<RawRequest>,
-) -> ModelResult<(StatusCode, Json<RawResponse>)> {
-    match req.model.as_str() {
-        "phi2" => Ok((StatusCode::OK, Json(PHI2_MODEL.clone().run_raw(req)?))),
-        "phi3" => Ok((StatusCode::OK, Json(PHI3_MODEL.clone().run_raw(req)?))),
-        "mistral7b" => Ok((
-            StatusCode::OK,
-            Json(MISTRAL7B_INSTRUCT_MODEL.clone().run_raw(req)?),
-        )),
-        "openhermes" => Ok((StatusCode::OK, Json(OPENHERMES_MODEL.clone().run_raw(req)?))),
-        "stablelm2zephyr" => Ok((
-            StatusCode::OK,
-            Json(STABLELM2_ZEPHYR_MODEL.clone().run_raw(req)?),
-        )),
-        "stablelm2" => Ok((StatusCode::OK, Json(STABLELM2_MODEL.clone().run_raw(req)?))),
-        _ => bail_runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model),
-    }
+async fn handle_register_claim_request(
+    State(state): State<AppState>,
+    Json(req): Json<RegistrationClaimRequest>,
+) -> ModelResult<(StatusCode, Json<RegistrationClaim>)> {
+    let claim = claim_registration(
+        &state.auth,
+        &AuthToken::from_raw_str(&req.token)?,
+        &state.db_pool,
+    )
+    .await
+    .map_err(|_| runner!(StatusCode::NOT_FOUND, "Failed to find registration"))?;
+    Ok((StatusCode::OK, Json(claim)))
 }
 
-#[tracing::instrument(level = "trace", skip())]
+#[tracing::instrument(level = "trace", skip(state))]
 #[axum_macros::debug_handler]
-async fn handle_instruct_request(
-    Json(req): Json<InstructRequest>,
-) -> ModelResult<(StatusCode, Json<InstructResponse>)> {
-    match req.model.as_str() {
-        "phi2" => Ok((StatusCode::OK, Json(PHI2_MODEL.clone().run_instruct(req)?))),
-        "phi3" => Ok((StatusCode::OK, Json(PHI3_MODEL.clone().run_instruct(req)?))),
-        "mistral7b" => Ok((
-            StatusCode::OK,
-            Json(MISTRAL7B_INSTRUCT_MODEL.clone().run_instruct(req)?),
-        )),
-        "openhermes" => Ok((
-            StatusCode::OK,
-            Json(OPENHERMES_MODEL.clone().run_instruct(req)?),
-        )),
-        "stablelm2zephyr" => Ok((
-            StatusCode::OK,
-            Json(STABLELM2_ZEPHYR_MODEL.clone().run_instruct(req)?),
-        )),
-        "stablelm2" => Ok((
-            StatusCode::OK,
-            Json(STABLELM2_MODEL.clone().run_instruct(req)?),
-        )),
-        _ => bail_runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model),
-    }
+async fn handle_registration_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Query(req): Query<RegistrationListQuery>,
+) -> ModelResult<(StatusCode, Json<Vec<Registration>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    Ok((
+        StatusCode::OK,
+        Json(list_registrations(req.status, &state.db_pool).await?),
+    ))
 }
 
-#[tracing::instrument(level = "trace", skip(multipart))]
+#[tracing::instrument(level = "trace", skip(state))]
 #[axum_macros::debug_handler]
-async fn handle_transcribe_request(
-    mut multipart: Multipart,
-) -> ModelResult<(StatusCode, Json<TranscribeResponse>)> {
+async fn handle_registration_approve_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<RegistrationDecisionRequest>,
+) -> ModelResult<StatusCode> {
+    client.has_permission(&Permission::ADMIN)?;
+    let permissions = req
+        .permissions
+        .map(|permissions| permissions.into_iter().collect::<Permission>());
+    decide(&state, &client, &req.id, true, permissions.as_ref()).await
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_registration_deny_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<RegistrationDecisionRequest>,
+) -> ModelResult<StatusCode> {
+    client.has_permission(&Permission::ADMIN)?;
+    decide(&state, &client, &req.id, false, None).await
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+async fn decide(
+    state: &AppState,
+    client: &ApiClient,
+    id: &str,
+    approve: bool,
+    permissions: Option<&Permission>,
+) -> ModelResult<StatusCode> {
+    if !decide_registration(id, approve, permissions, &client.token.id, &state.db_pool).await? {
+        bail_runner!(StatusCode::NOT_FOUND, "Failed to find pending registration");
+    }
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_model_info_request(
+    Json(req): Json<InfoRequest>,
+) -> ModelResult<(StatusCode, Json<ModelBase>)> {
+    let Some(model) = managed_model(&req.model) else {
+        return Err(
+            runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model)
+                .with_code("model_not_found")
+                .with_param(&req.model),
+        );
+    };
+    Ok((StatusCode::OK, Json(model.base())))
+}
+
+#[tracing::instrument(level = "trace")]
+fn validate_max_length(requested: usize) -> ModelResult<()> {
+    match max_length() {
+        Some(max) if requested > max => Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "max_length must not exceed {}",
+            max
+        )
+        .with_code("max_length_exceeded")
+        .with_param(max)),
+        _ => Ok(()),
+    }
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_raw_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<RawRequest>,
+) -> ModelResult<(StatusCode, Negotiated<RawResponse>)> {
+    validate_max_length(req.max_length)?;
+    req.model_config
+        .check()
+        .map_err(|e| runner!(StatusCode::BAD_REQUEST, "{}", e))?;
+    req.constraint = compile_grammar(req.grammar.as_deref())?;
+    req.input = req.normalization.apply(std::mem::take(&mut req.input));
+    let requested = std::mem::take(&mut req.model);
+    req.model = route_model(&client, "raw", &requested, &req.input)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    let model = req.model.clone();
+    let started = Instant::now();
+    // Resolved up front as well so that only requests with the same banned words are coalesced
+    req.banned_words = banned_words(&model, client.name.as_deref());
+    let run = |candidate: String| {
+        run_raw(RawRequest {
+            banned_words: banned_words(&candidate, client.name.as_deref()),
+            model: candidate,
+            ..req.clone()
+        })
+    };
+    let result = match req.coalesce_key() {
+        Some(key) => {
+            RAW_REQUESTS
+                .run(key, || run_with_fallbacks(&model, run))
+                .await
+        }
+        None => run_with_fallbacks(&model, run).await,
+    };
+    let (model, result) = match result {
+        Ok((used, response)) => (used, Ok(response)),
+        Err(err) => (model, Err(err)),
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "raw",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    response.id =
+        log_interaction(&state, &client, &model, "raw", &req.input, &response.output).await;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Runs the request on the model or, while it is unavailable or fails, on its fallbacks in order.
+/// Returns the model that produced the response.
+#[tracing::instrument(level = "trace", skip(run))]
+async fn run_with_fallbacks<R, F, Fut>(model: &str, run: F) -> ModelResult<(String, R)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = ModelResult<R>>,
+{
+    let mut chain = fallback_chain(model);
+    let last = chain.pop().unwrap_or_else(|| model.to_string());
+    for candidate in chain {
+        let preferred = managed_models()
+            .iter()
+            .find(|managed| managed.name() == candidate)
+            .is_none_or(|managed| is_preferred(*managed));
+        if !preferred {
+            info!("Skipping model {} in favour of its fallback", candidate);
+            continue;
+        }
+        match run(candidate.clone()).await {
+            Err(err) if err.status.is_server_error() => {
+                warn!("Model {} failed, falling back: {}", candidate, err);
+                info!(monotonic_counter.fallbacks = 1, model = candidate);
+            }
+            result => return result.map(|response| (candidate, response)),
+        }
+    }
+    run(last.clone()).await.map(|response| (last, response))
+}
+
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_raw(req: RawRequest) -> ModelResult<RawResponse> {
+    Ok(match req.model.as_str() {
+        "phi2" => PHI2_MODEL.run(|mut model| model.run_raw(req)).await?,
+        "phi3" => PHI3_MODEL.run(|mut model| model.run_raw(req)).await?,
+        "mistral7b" => {
+            MISTRAL7B_INSTRUCT_MODEL
+                .run(|mut model| model.run_raw(req))
+                .await?
+        }
+        "openhermes" => OPENHERMES_MODEL.run(|mut model| model.run_raw(req)).await?,
+        "stablelm2zephyr" => {
+            STABLELM2_ZEPHYR_MODEL
+                .run(|mut model| model.run_raw(req))
+                .await?
+        }
+        "stablelm2" => STABLELM2_MODEL.run(|mut model| model.run_raw(req)).await?,
+        _ => {
+            return Err(
+                runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model)
+                    .with_code("model_not_found")
+                    .with_param(&req.model),
+            )
+        }
+    })
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_instruct_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<InstructRequest>,
+) -> ModelResult<Response> {
+    let requested = prepare_instruct(&client, &mut req)?;
+    if req.stream {
+        return Ok(stream_instruct(state, client, requested, req).into_response());
+    }
+    let model = req.model.clone();
+    let started = Instant::now();
+    let result = run_with_fallbacks(&model, |candidate| {
+        run_structured(InstructRequest {
+            banned_words: banned_words(&candidate, client.name.as_deref()),
+            model: candidate,
+            ..req.clone()
+        })
+    })
+    .await;
+    let (model, result) = match result {
+        Ok((used, response)) => (used, Ok(response)),
+        Err(err) => (model, Err(err)),
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "instruct",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    response.id = log_interaction(
+        &state,
+        &client,
+        &model,
+        "instruct",
+        &req.input,
+        &response.output,
+    )
+    .await;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)).into_response())
+}
+
+#[tracing::instrument(level = "trace", skip(state, upgrade))]
+#[axum_macros::debug_handler]
+async fn handle_text_socket_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| {
+        run_interactive(socket, move |mut req, sink| {
+            let state = state.clone();
+            let client = client.clone();
+            async move {
+                req.stream = true;
+                let requested = prepare_instruct(&client, &mut req)?;
+                run_instruct_for_client(&state, &client, requested, req, sink).await
+            }
+        })
+    })
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_chat_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<ChatRequest>,
+) -> ModelResult<(StatusCode, Negotiated<ChatResponse>)> {
+    validate_max_length(req.max_length)?;
+    let input = validate_chat(&req.messages)?;
+    for message in &mut req.messages {
+        message.content = req
+            .normalization
+            .apply(std::mem::take(&mut message.content));
+    }
+    let requested = std::mem::take(&mut req.model);
+    let model = route_model(&client, "chat", &requested, &input)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    let started = Instant::now();
+    let result = run_with_fallbacks(&model, |candidate| {
+        run_chat(ChatRequest {
+            banned_words: banned_words(&candidate, client.name.as_deref()),
+            model: candidate,
+            ..req.clone()
+        })
+    })
+    .await;
+    let (model, result) = match result {
+        Ok((used, response)) => (used, Ok(response)),
+        Err(err) => (model, Err(err)),
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "chat",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    response.id = log_interaction(&state, &client, &model, "chat", &input, &response.output).await;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Checks that the chat ends with a message of the user and returns it
+#[tracing::instrument(level = "trace", skip(messages))]
+fn validate_chat(messages: &[ChatMessage]) -> ModelResult<String> {
+    match messages.last() {
+        Some(message) if message.role == ChatRole::User => Ok(message.content.clone()),
+        _ => Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "The last message of a chat must be from the user"
+        )
+        .with_code("invalid_chat")),
+    }
+}
+
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_chat(req: ChatRequest) -> ModelResult<ChatResponse> {
+    Ok(match req.model.as_str() {
+        "phi2" => PHI2_MODEL.run(|mut model| model.run_chat(req)).await?,
+        "phi3" => PHI3_MODEL.run(|mut model| model.run_chat(req)).await?,
+        "mistral7b" => {
+            MISTRAL7B_INSTRUCT_MODEL
+                .run(|mut model| model.run_chat(req))
+                .await?
+        }
+        "openhermes" => {
+            OPENHERMES_MODEL
+                .run(|mut model| model.run_chat(req))
+                .await?
+        }
+        "stablelm2zephyr" => {
+            STABLELM2_ZEPHYR_MODEL
+                .run(|mut model| model.run_chat(req))
+                .await?
+        }
+        "stablelm2" => STABLELM2_MODEL.run(|mut model| model.run_chat(req)).await?,
+        _ => {
+            return Err(
+                runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model)
+                    .with_code("model_not_found")
+                    .with_param(&req.model),
+            )
+        }
+    })
+}
+
+/// Validates and normalizes the request and routes it to a model. Returns the requested model.
+#[tracing::instrument(level = "trace", skip(client, req))]
+fn prepare_instruct(client: &ApiClient, req: &mut InstructRequest) -> ModelResult<String> {
+    validate_max_length(req.max_length)?;
+    req.input = req.normalization.apply(std::mem::take(&mut req.input));
+    let requested = std::mem::take(&mut req.model);
+    req.model = route_model(client, "instruct", &requested, &req.input)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    if req.stream && checks_output(client) {
+        return Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "Streaming is not supported while output policies apply"
+        )
+        .with_code("stream_policy"));
+    }
+    if let Some(format) = &req.response_format {
+        if req.stream {
+            return Err(runner!(
+                StatusCode::BAD_REQUEST,
+                "Streaming is not supported together with a response format"
+            )
+            .with_code("stream_unsupported"));
+        }
+        if req.grammar.is_some() {
+            return Err(runner!(
+                StatusCode::BAD_REQUEST,
+                "A grammar is not supported together with a response format"
+            ));
+        }
+        format
+            .check()
+            .map_err(|e| runner!(StatusCode::BAD_REQUEST, "{}", e))?;
+    }
+    req.constraint = compile_grammar(req.grammar.as_deref())?;
+    Ok(requested)
+}
+
+/// Parses the GBNF grammar of a request
+#[tracing::instrument(level = "trace", skip(grammar))]
+fn compile_grammar(grammar: Option<&str>) -> ModelResult<Option<Arc<Grammar>>> {
+    grammar
+        .map(|grammar| {
+            parse_gbnf(grammar)
+                .map(Arc::new)
+                .map_err(|e| runner!(StatusCode::BAD_REQUEST, "Invalid grammar: {}", e))
+        })
+        .transpose()
+}
+
+/// Runs the prepared request, passing the text of the tokens on to the sink, and records its
+/// usage. Fallbacks are not tried as the tokens of a failed generation may already have been sent.
+#[tracing::instrument(level = "trace", skip(state, client, req, sink))]
+async fn run_instruct_for_client(
+    state: &AppState,
+    client: &ApiClient,
+    requested: String,
+    mut req: InstructRequest,
+    sink: TokenSink,
+) -> ModelResult<InstructResponse> {
+    req.banned_words = banned_words(&req.model, client.name.as_deref());
+    let model = req.model.clone();
+    let started = Instant::now();
+    let result = run_instruct_streaming(req.clone(), Some(sink)).await;
+    record_usage(
+        state,
+        client,
+        &model,
+        "instruct",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    response.id = log_interaction(
+        state,
+        client,
+        &model,
+        "instruct",
+        &req.input,
+        &response.output,
+    )
+    .await;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok(response)
+}
+
+#[derive(Serialize, Debug)]
+struct TokenEvent<'a> {
+    text: &'a str,
+}
+
+/// Streams the text of the tokens as `token` events while they are generated. The stream ends with
+/// a `done` event carrying the complete response or an `error` event.
+#[tracing::instrument(level = "trace", skip(state, client, req))]
+fn stream_instruct(
+    state: AppState,
+    client: ApiClient,
+    requested: String,
+    req: InstructRequest,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (events, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (sink, mut tokens) = mpsc::unbounded_channel::<String>();
+        let forwarder = {
+            let events = events.clone();
+            tokio::spawn(async move {
+                while let Some(text) = tokens.recv().await {
+                    // Dropping the tokens once the client is gone aborts the generation
+                    if events
+                        .send(sse_event("token", &TokenEvent { text: &text }))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            })
+        };
+        let result = run_instruct_for_client(&state, &client, requested, req, sink).await;
+        let _ = forwarder.await;
+        let event = match result {
+            Ok(response) => sse_event("done", &response),
+            Err(err) => sse_event("error", &err.message),
+        };
+        let _ = events.send(event);
+    });
+    Sse::new(UnboundedReceiverStream::new(receiver).map(Ok)).keep_alive(KeepAlive::default())
+}
+
+#[tracing::instrument(level = "trace", skip(data))]
+fn sse_event(name: &str, data: &impl Serialize) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(data)
+        .unwrap_or_else(|_| Event::default().event("error"))
+}
+
+/// Runs the request and, if it has a response format, constrains the sampling to it, validates
+/// the output and regenerates it with the validation error until it matches or the repairs are
+/// used up
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_structured(req: InstructRequest) -> ModelResult<InstructResponse> {
+    let Some(format) = req.response_format.clone() else {
+        return run_instruct(req).await;
+    };
+    let grammar = format
+        .grammar()
+        .map_err(|e| runner!(StatusCode::BAD_REQUEST, "{}", e))?;
+    let constraint = Some(Arc::new(grammar));
+    let instructed = format!("{}\n\n{}", req.input, format.instruction());
+    let mut input = instructed.clone();
+    let mut inference_time = 0.0;
+    let mut tokens = TokenCounts::default();
+    let mut error = String::new();
+    let attempts = 1 + max_repairs();
+    for attempt in 1..=attempts {
+        let mut response = run_instruct(InstructRequest {
+            input,
+            response_format: None,
+            constraint: constraint.clone(),
+            ..req.clone()
+        })
+        .await?;
+        inference_time += response.inference_time;
+        tokens.prompt += response.tokens.prompt;
+        tokens.completion += response.tokens.completion;
+        match format.validate(&response.output) {
+            Ok(value) => {
+                info!(monotonic_counter.structured_output_repairs = attempt - 1);
+                response.output = value.to_string();
+                response.inference_time = inference_time;
+                response.tokens =
+                    TokenCounts::new(tokens.prompt, tokens.completion, inference_time);
+                response.attempts = Some(attempt);
+                return Ok(response);
+            }
+            Err(e) => {
+                warn!("Output does not match the response format: {}", e);
+                input = repair_prompt(&instructed, &response.output, &e);
+                error = e;
+            }
+        }
+    }
+    Err(runner!(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Output does not match the response format after {} attempts: {}",
+        attempts,
+        error
+    )
+    .with_code("invalid_structured_output"))
+}
+
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_instruct(req: InstructRequest) -> ModelResult<InstructResponse> {
+    run_instruct_streaming(req, None).await
+}
+
+/// Runs the request, passing the text of the tokens on to the sink as they are generated
+#[tracing::instrument(level = "trace", skip(req, sink))]
+async fn run_instruct_streaming(
+    req: InstructRequest,
+    sink: Option<TokenSink>,
+) -> ModelResult<InstructResponse> {
+    Ok(match req.model.as_str() {
+        "phi2" => {
+            PHI2_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        "phi3" => {
+            PHI3_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        "mistral7b" => {
+            MISTRAL7B_INSTRUCT_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        "openhermes" => {
+            OPENHERMES_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        "stablelm2zephyr" => {
+            STABLELM2_ZEPHYR_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        "stablelm2" => {
+            STABLELM2_MODEL
+                .run(|mut model| {
+                    let _sink = sink.map(streaming::attach);
+                    model.run_instruct(req)
+                })
+                .await?
+        }
+        _ => {
+            return Err(
+                runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model)
+                    .with_code("model_not_found")
+                    .with_param(&req.model),
+            )
+        }
+    })
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_detect_watermark_request(
+    format: ResponseFormat,
+    Json(req): Json<DetectWatermarkRequest>,
+) -> ModelResult<(StatusCode, Negotiated<WatermarkDetection>)> {
+    if !watermark_configured() {
+        return Err(
+            runner!(StatusCode::BAD_REQUEST, "Watermarking is not configured")
+                .with_code("watermark_not_configured"),
+        );
+    }
+    let detection = match req.model.as_str() {
+        "phi2" => {
+            PHI2_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        "phi3" => {
+            PHI3_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        "mistral7b" => {
+            MISTRAL7B_INSTRUCT_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        "openhermes" => {
+            OPENHERMES_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        "stablelm2zephyr" => {
+            STABLELM2_ZEPHYR_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        "stablelm2" => {
+            STABLELM2_MODEL
+                .run(|mut model| model.detect_watermark(req))
+                .await?
+        }
+        _ => {
+            return Err(
+                runner!(StatusCode::NOT_FOUND, "Model {} not found", req.model)
+                    .with_code("model_not_found")
+                    .with_param(&req.model),
+            )
+        }
+    };
+    Ok((StatusCode::OK, Negotiated(format, detection)))
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_summarize_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Query(query): Query<SummarizeQuery>,
+    Json(mut req): Json<SummarizeRequest>,
+) -> ModelResult<(StatusCode, Negotiated<SummarizeResponse>)> {
+    validate_max_length(req.max_length)?;
+    if let Some(id) = &req.document_id {
+        let chunks = document_chunks(id, &client.token.id, &state.db_pool).await?;
+        if chunks.is_empty() {
+            return Err(runner!(StatusCode::NOT_FOUND, "Document {} not found", id)
+                .with_code("document_not_found"));
+        }
+        req.input = chunks.join("\n\n");
+    }
+    let requested = std::mem::take(&mut req.model);
+    req.model = route_model(&client, "summarize", &requested, &req.input)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    req.banned_words = banned_words(&req.model, client.name.as_deref());
+    let model = req.model.clone();
+    let started = Instant::now();
+    let result = run_summarize(req, query.strategy).await;
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "summarize",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Summarizes the document, with map reduce the chunks and then groups of their summaries are
+/// summarized in parallel until a single summary is left
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_summarize(
+    req: SummarizeRequest,
+    strategy: SummarizeStrategy,
+) -> ModelResult<SummarizeResponse> {
+    let mut response = SummarizeResponse {
+        output: String::new(),
+        inference_time: 0.0,
+        chunks: 1,
+        tokens: TokenCounts::default(),
+        model: None,
+    };
+    let mut summaries = match strategy {
+        SummarizeStrategy::Single => vec![map_prompt(&req.input)],
+        SummarizeStrategy::MapReduce => {
+            let chunks = split_chunks(&req.input, chunk_length());
+            response.chunks = chunks.len();
+            chunks.iter().map(|chunk| map_prompt(chunk)).collect()
+        }
+    };
+    summaries = summarize_parts(&req, summaries, &mut response).await?;
+
+    while summaries.len() > 1 {
+        let merged = summaries.join("\n\n");
+        let groups = split_chunks(&merged, chunk_length());
+        // Summaries that cannot be grouped any further are merged in one last generation
+        let prompts = if groups.len() == 1 || groups.len() >= summaries.len() {
+            vec![reduce_prompt(&merged)]
+        } else {
+            groups.iter().map(|group| reduce_prompt(group)).collect()
+        };
+        summaries = summarize_parts(&req, prompts, &mut response).await?;
+    }
+    response.output = summaries.pop().unwrap_or_default();
+    Ok(response)
+}
+
+#[tracing::instrument(level = "trace", skip())]
+#[axum_macros::debug_handler]
+async fn handle_batch_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<BatchRequest>,
+) -> ModelResult<(StatusCode, Negotiated<BatchResponse>)> {
+    validate_max_length(req.max_length)?;
+    if req.inputs.is_empty() || req.inputs.len() > max_batch_size() {
+        return Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "A batch must have between 1 and {} inputs",
+            max_batch_size()
+        )
+        .with_code("invalid_batch_size")
+        .with_param(max_batch_size()));
+    }
+    for input in &mut req.inputs {
+        *input = req.normalization.apply(std::mem::take(input));
+    }
+    let requested = std::mem::take(&mut req.model);
+    let joined = req.inputs.join("\n\n");
+    req.model = route_model(&client, "batch", &requested, &joined)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    req.banned_words = banned_words(&req.model, client.name.as_deref());
+    let model = req.model.clone();
+    let started = Instant::now();
+    let result = run_batch(req.clone()).await;
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "batch",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    for (input, output) in req.inputs.iter().zip(&response.outputs) {
+        log_interaction(&state, &client, &model, "batch", input, &output.output).await;
+    }
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Runs the inputs in parallel so that the generations fill the workers of the model, returning
+/// their outputs in order. One failed generation fails the whole batch.
+#[tracing::instrument(level = "trace", skip(req))]
+async fn run_batch(req: BatchRequest) -> ModelResult<BatchResponse> {
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for (index, input) in req.inputs.iter().enumerate() {
+        let request = InstructRequest {
+            stop: req.stop.clone(),
+            watermark: req.watermark,
+            banned_words: req.banned_words.clone(),
+            truncation: req.truncation.clone(),
+            ..InstructRequest::new(req.model.clone(), input.clone(), req.max_length)
+        };
+        tasks.spawn(async move { (index, run_instruct(request).await) });
+    }
+
+    let mut outputs = vec![BatchOutput::default(); req.inputs.len()];
+    let mut tokens = TokenCounts::default();
+    while let Some(joined) = tasks.join_next().await {
+        let (index, result) = joined?;
+        let response = result?;
+        tokens.prompt += response.tokens.prompt;
+        tokens.completion += response.tokens.completion;
+        outputs[index] = BatchOutput {
+            output: response.output,
+            inference_time: response.inference_time,
+            tokens: response.tokens,
+        };
+    }
+    let inference_time = started.elapsed().as_secs_f64();
+    Ok(BatchResponse {
+        outputs,
+        inference_time,
+        tokens: TokenCounts::new(tokens.prompt, tokens.completion, inference_time),
+        model: None,
+    })
+}
+
+/// Runs the prompts in parallel, returning their outputs in order
+#[tracing::instrument(level = "trace", skip(req, prompts, response))]
+async fn summarize_parts(
+    req: &SummarizeRequest,
+    prompts: Vec<String>,
+    response: &mut SummarizeResponse,
+) -> ModelResult<Vec<String>> {
+    let total = prompts.len();
+    let mut tasks = JoinSet::new();
+    for (index, input) in prompts.into_iter().enumerate() {
+        let request = InstructRequest {
+            watermark: req.watermark,
+            banned_words: req.banned_words.clone(),
+            ..InstructRequest::new(req.model.clone(), input, req.max_length)
+        };
+        tasks.spawn(async move { (index, run_instruct(request).await) });
+    }
+
+    let mut outputs = vec![String::new(); total];
+    let mut done = 0;
+    while let Some(joined) = tasks.join_next().await {
+        let (index, result) = joined?;
+        let part = result?;
+        response.inference_time += part.inference_time;
+        response.tokens.prompt += part.tokens.prompt;
+        response.tokens.completion += part.tokens.completion;
+        outputs[index] = part.output;
+        done += 1;
+        info!(
+            model = req.model,
+            done, total, "Summarized part of document"
+        );
+    }
+    Ok(outputs)
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_ask_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<AskRequest>,
+) -> ModelResult<(StatusCode, Negotiated<AskResponse>)> {
+    validate_max_length(req.max_length)?;
+    if req.top_k == 0 || req.top_k > MAX_TOP_K {
+        bail_runner!(
+            StatusCode::BAD_REQUEST,
+            "top_k must be between 1 and {}",
+            MAX_TOP_K
+        );
+    }
+    if req.document_ids.is_empty() {
+        bail_runner!(StatusCode::BAD_REQUEST, "document_ids must not be empty");
+    }
+
+    let mut chunks = Vec::new();
+    for id in &req.document_ids {
+        let document = document_chunks(id, &client.token.id, &state.db_pool).await?;
+        if document.is_empty() {
+            return Err(runner!(StatusCode::NOT_FOUND, "Document {} not found", id)
+                .with_code("document_not_found"));
+        }
+        chunks.extend(
+            document
+                .into_iter()
+                .enumerate()
+                .map(|(position, content)| (id.clone(), position, content)),
+        );
+    }
+    let contents: Vec<&str> = chunks
+        .iter()
+        .map(|(_, _, content)| content.as_str())
+        .collect();
+    let sources: Vec<SourceChunk> = rank_chunks(&req.query, &contents, req.top_k)
+        .into_iter()
+        .map(|(index, score)| {
+            let (document_id, position, content) = &chunks[index];
+            SourceChunk {
+                document_id: document_id.clone(),
+                position: *position,
+                score,
+                content: content.clone(),
+            }
+        })
+        .collect();
+
+    let requested = std::mem::take(&mut req.model);
+    let model = route_model(&client, "ask", &requested, &req.query)?;
+    let watermark = watermark_enabled(client.name.as_deref());
+    let input = grounded_prompt(&req.query, &sources);
+    let started = Instant::now();
+    let result = run_with_fallbacks(&model, |candidate| {
+        run_instruct(InstructRequest {
+            banned_words: banned_words(&candidate, client.name.as_deref()),
+            watermark,
+            ..InstructRequest::new(candidate, input.clone(), req.max_length)
+        })
+    })
+    .await;
+    let (model, result) = match result {
+        Ok((used, response)) => (used, Ok(response)),
+        Err(err) => (model, Err(err)),
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "ask",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let answer = result?;
+    Ok((
+        StatusCode::OK,
+        Negotiated(
+            format,
+            AskResponse {
+                output: answer.output,
+                inference_time: answer.inference_time,
+                sources,
+                model: (model != requested).then_some(model),
+            },
+        ),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_agent_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    Json(mut req): Json<AgentRequest>,
+) -> ModelResult<(StatusCode, Negotiated<AgentResponse>)> {
+    validate_max_length(req.max_length)?;
+    let tools = allowed_tools(&req.tools)
+        .map_err(|e| runner!(StatusCode::BAD_REQUEST, "{}", e).with_code("tool_not_found"))?;
+    let requested = std::mem::take(&mut req.model);
+    let model = route_model(&client, "agent", &requested, &req.input)?;
+    req.watermark = watermark_enabled(client.name.as_deref());
+    req.banned_words = banned_words(&model, client.name.as_deref());
+    let started = Instant::now();
+    let result = run_agent(&model, &req, &tools).await;
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "agent",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Generates until the model answers without calling a tool, feeding every tool result back
+#[tracing::instrument(level = "trace", skip(req, tools))]
+async fn run_agent(
+    model: &str,
+    req: &AgentRequest,
+    tools: &[&'static dyn Tool],
+) -> ModelResult<AgentResponse> {
+    let mut response = AgentResponse {
+        output: String::new(),
+        inference_time: 0.0,
+        steps: Vec::new(),
+        tokens: TokenCounts::default(),
+        model: None,
+    };
+    let constraint = Some(Arc::new(agent_format(tools).grammar()?));
+    loop {
+        let generation = run_instruct(InstructRequest {
+            constraint: constraint.clone(),
+            watermark: req.watermark,
+            banned_words: req.banned_words.clone(),
+            ..InstructRequest::new(
+                model,
+                agent_prompt(&req.input, tools, &response.steps),
+                req.max_length,
+            )
+        })
+        .await?;
+        response.inference_time += generation.inference_time;
+        response.tokens.prompt += generation.tokens.prompt;
+        response.tokens.completion += generation.tokens.completion;
+
+        let Some((tool, arguments)) = parse_tool_call(&generation.output, tools) else {
+            response.output = final_answer(generation.output);
+            return Ok(response);
+        };
+        if response.steps.len() >= max_depth() {
+            return Err(runner!(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Model exceeded the limit of {} tool calls",
+                max_depth()
+            )
+            .with_code("tool_depth_exceeded"));
+        }
+        info!(model, tool, "Calling tool");
+        response.steps.push(run_tool(tools, tool, arguments).await);
+    }
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_session_create_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<SessionCreateRequest>,
+) -> ModelResult<(StatusCode, Json<SessionInfo>)> {
+    validate_max_length(req.max_length)?;
+    let session =
+        create_session(&client.token.id, &req.model, req.max_length, &state.db_pool).await?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_session_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<Vec<SessionInfo>>)> {
+    Ok((
+        StatusCode::OK,
+        Json(list_sessions(&client.token.id, &state.db_pool).await?),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+async fn find_session(state: &AppState, client: &ApiClient, id: &str) -> ModelResult<Session> {
+    get_session(id, &client.token.id, &state.db_pool)
+        .await?
+        .ok_or_else(|| {
+            runner!(StatusCode::NOT_FOUND, "Session {} not found", id)
+                .with_code("session_not_found")
+        })
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_session_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<(StatusCode, Json<Session>)> {
+    Ok((
+        StatusCode::OK,
+        Json(find_session(&state, &client, &id).await?),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_session_delete_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<StatusCode> {
+    if !delete_session(&id, &client.token.id, &state.db_pool).await? {
+        return Err(runner!(StatusCode::NOT_FOUND, "Session {} not found", id)
+            .with_code("session_not_found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Continues the conversation of a session with a new message and stores the exchange
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_session_message_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    RoutePath(id): RoutePath<String>,
+    Json(req): Json<SessionMessageRequest>,
+) -> ModelResult<(StatusCode, Negotiated<InstructResponse>)> {
+    let session = find_session(&state, &client, &id).await?;
+    let input = conversation_prompt(&session.messages, &req.input);
+    let max_length = usize::try_from(session.info.max_length)?;
+    let requested = session.info.model;
+    let model = route_model(&client, "instruct", &requested, &input)?;
+    let watermark = watermark_enabled(client.name.as_deref());
+    let started = Instant::now();
+    let result = run_with_fallbacks(&model, |candidate| {
+        run_instruct(InstructRequest {
+            banned_words: banned_words(&candidate, client.name.as_deref()),
+            watermark,
+            ..InstructRequest::new(candidate, input.clone(), max_length)
+        })
+    })
+    .await;
+    let (model, result) = match result {
+        Ok((used, response)) => (used, Ok(response)),
+        Err(err) => (model, Err(err)),
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "instruct",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    response.id = log_interaction(
+        &state,
+        &client,
+        &model,
+        "instruct",
+        &input,
+        &response.output,
+    )
+    .await;
+    append_exchange(&id, &req.input, &response.output, &state.db_pool).await?;
+    if model != requested {
+        response.model = Some(model);
+    }
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+#[tracing::instrument(level = "trace", skip(state, multipart))]
+#[axum_macros::debug_handler]
+async fn handle_document_upload_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    mut multipart: Multipart,
+) -> ModelResult<(StatusCode, Json<DocumentInfo>)> {
+    let Some(field) = multipart.next_field().await? else {
+        bail_runner!(
+            StatusCode::BAD_REQUEST,
+            "Missing field file in multipart form"
+        );
+    };
+    if field.name() != Some("file") {
+        bail_runner!(
+            StatusCode::BAD_REQUEST,
+            "Unknown field {}",
+            field.name().unwrap_or_default()
+        );
+    }
+    let Some(content_type) = field
+        .content_type()
+        .filter(|content| VALID_DOCUMENT_MIME_TYPES.contains(content))
+        .map(ToString::to_string)
+    else {
+        bail_runner!(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Invalid mime type in content-type header for file field"
+        );
+    };
+    let name = field.file_name().unwrap_or("document").to_string();
+    let bytes = field.bytes().await?;
+    let text = extract_text(&content_type, &bytes)
+        .map_err(|e| runner!(StatusCode::UNPROCESSABLE_ENTITY, "{}", e))?;
+    let document = insert_document(
+        &client.token.id,
+        &name,
+        &content_type,
+        &text,
+        &state.db_pool,
+    )
+    .await?;
+    info!(monotonic_counter.documents_uploaded = 1);
+    Ok((StatusCode::CREATED, Json(document)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_document_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<Vec<DocumentInfo>>)> {
+    Ok((
+        StatusCode::OK,
+        Json(list_documents(&client.token.id, &state.db_pool).await?),
+    ))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_document_info_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<(StatusCode, Json<DocumentInfo>)> {
+    let document = document_info(&id, &client.token.id, &state.db_pool)
+        .await?
+        .ok_or_else(|| {
+            runner!(StatusCode::NOT_FOUND, "Document {} not found", id)
+                .with_code("document_not_found")
+        })?;
+    Ok((StatusCode::OK, Json(document)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_document_delete_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<StatusCode> {
+    if !delete_document(&id, &client.token.id, &state.db_pool).await? {
+        return Err(runner!(StatusCode::NOT_FOUND, "Document {} not found", id)
+            .with_code("document_not_found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(level = "trace", skip(multipart))]
+#[axum_macros::debug_handler]
+async fn handle_transcribe_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    format: ResponseFormat,
+    mut multipart: Multipart,
+) -> ModelResult<(StatusCode, Negotiated<TranscribeResponse>)> {
     let mut opt_request = None;
     let mut opt_file_bytes = None;
 
@@ -613,7 +2734,7 @@ async fn handle_transcribe_request(
                             "Invalid mime type in content-type header for audio_content field"
                         );
                     }
-                    opt_file_bytes = Some(field.bytes().await?);
+                    opt_file_bytes = Some(read_audio_field(field).await?);
                 }
                 _ => bail_runner!(StatusCode::BAD_REQUEST, "Unknown field {}", name),
             }
@@ -632,29 +2753,454 @@ async fn handle_transcribe_request(
             missing_field
         );
     }
-    let file_bytes = opt_file_bytes.unwrap().to_vec().into_boxed_slice();
-    let request = opt_request.as_ref().unwrap();
+    let file_bytes = opt_file_bytes.unwrap();
+    let Json(request) = opt_request.unwrap();
+    if request.max_decode_steps == Some(0) {
+        return Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "max_decode_steps must be greater than zero"
+        )
+        .with_code("invalid_max_decode_steps"));
+    }
+    if request.segmentation.max_characters == Some(0) {
+        return Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "max_characters must be greater than zero"
+        )
+        .with_code("invalid_max_characters"));
+    }
+    if request
+        .segmentation
+        .merge_shorter_than
+        .is_some_and(|seconds| !seconds.is_finite() || seconds < 0.0)
+    {
+        return Err(runner!(
+            StatusCode::BAD_REQUEST,
+            "merge_shorter_than must be a positive number of seconds"
+        )
+        .with_code("invalid_merge_shorter_than"));
+    }
 
-    match request.model.to_lowercase().as_str() {
-        "whisper" => Ok((
-            StatusCode::OK,
-            Json(
-                WHISPER_MODEL
-                    .clone()
-                    .run_transcribe(file_bytes, &request.language)?,
-            ),
-        )),
-        _ => bail_runner!(
+    let model = request.model.to_lowercase();
+    let restore_punctuation = request.restore_punctuation;
+    let segmentation = request.segmentation.clone();
+    let started = Instant::now();
+    let result = match model.as_str() {
+        "whisper" => WHISPER_MODEL
+            .run(move |mut model| model.run_transcribe(file_bytes, &request))
+            .await
+            .map_err(ModelRunnerError::from),
+        _ => {
+            return Err(
+                runner!(StatusCode::NOT_FOUND, "Model {} not found", request.model)
+                    .with_code("model_not_found")
+                    .with_param(&request.model),
+            )
+        }
+    };
+    record_usage(
+        &state,
+        &client,
+        &model,
+        "transcribe",
+        started,
+        &result,
+        |response| Consumption {
+            audio_seconds: response.audio_duration,
+            ..Consumption::default()
+        },
+    )
+    .await;
+    let mut response = result?;
+    if restore_punctuation {
+        response.punctuation_restored =
+            Some(restore_transcript_punctuation(&state, &client, &mut response.output).await);
+    }
+    response.output = segmentation.apply(response.output);
+    Ok((StatusCode::OK, Negotiated(format, response)))
+}
+
+/// Restores punctuation and casing of the segments with the punctuation model, leaving them as
+/// they are if the model fails or changes any of their words. Returns whether they were restored.
+#[tracing::instrument(level = "trace", skip(state, client, segments))]
+async fn restore_transcript_punctuation(
+    state: &AppState,
+    client: &ApiClient,
+    segments: &mut [Segment],
+) -> bool {
+    let texts: Vec<&str> = segments
+        .iter()
+        .map(|segment| segment.dr.text.trim())
+        .collect();
+    let transcript = texts.join(" ");
+    if transcript.trim().is_empty() {
+        return true;
+    }
+    let model = punctuation_model();
+    // Punctuation adds about one token for every word
+    let request = InstructRequest::new(
+        model,
+        punctuation_prompt(&transcript),
+        (transcript.split_whitespace().count() * 2 + 32).min(max_length().unwrap_or(usize::MAX)),
+    );
+    let started = Instant::now();
+    let result = run_instruct(request).await;
+    record_usage(
+        state,
+        client,
+        model,
+        "punctuation",
+        started,
+        &result,
+        |response| Consumption {
+            prompt_tokens: response.tokens.prompt,
+            completion_tokens: response.tokens.completion,
+            ..Consumption::default()
+        },
+    )
+    .await;
+
+    let restored = match result {
+        Ok(response) => redistribute(&texts, &response.output),
+        Err(err) => {
+            warn!("Failed to restore punctuation: {}", err);
+            return false;
+        }
+    };
+    let Some(restored) = restored else {
+        warn!("Discarded restored punctuation as the words of the transcript changed");
+        return false;
+    };
+    for (segment, text) in segments.iter_mut().zip(restored) {
+        if !text.is_empty() {
+            segment.dr.text = format!(" {text}");
+        }
+    }
+    true
+}
+
+/// Stores the interaction if enabled, returning its id
+#[tracing::instrument(level = "trace", skip(state, client, input, output))]
+async fn log_interaction(
+    state: &AppState,
+    client: &ApiClient,
+    model: &str,
+    task: &str,
+    input: &str,
+    output: &str,
+) -> Option<String> {
+    if !interaction_log_enabled() {
+        return None;
+    }
+    insert_interaction(
+        &recorded_client_id(&client.token.id),
+        model,
+        task,
+        input,
+        output,
+        &state.db_pool,
+    )
+    .await
+    .inspect_err(|e| warn!("Failed to log interaction: {}", e))
+    .ok()
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_feedback_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<FeedbackRequest>,
+) -> ModelResult<StatusCode> {
+    if !insert_feedback(&recorded_client_id(&client.token.id), &req, &state.db_pool).await? {
+        return Err(runner!(
             StatusCode::NOT_FOUND,
-            "Model {} not found",
-            &opt_request.unwrap().model
-        ),
+            "Response {} not found",
+            req.response_id
+        )
+        .with_code("response_not_found"));
+    }
+    info!(monotonic_counter.feedback_received = 1);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(level = "trace", skip(state, upgrade))]
+#[axum_macros::debug_handler]
+async fn handle_live_caption_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Query(query): Query<CaptionQuery>,
+    upgrade: WebSocketUpgrade,
+) -> ModelResult<Response> {
+    let model = query.model.to_lowercase();
+    if model != "whisper" {
+        return Err(
+            runner!(StatusCode::NOT_FOUND, "Model {} not found", query.model)
+                .with_code("model_not_found")
+                .with_param(&query.model),
+        );
+    }
+
+    Ok(upgrade.on_upgrade(move |socket| async move {
+        let started = Instant::now();
+        let audio_seconds = run_captions(socket, &WHISPER_MODEL, query.language).await;
+        record_usage(
+            &state,
+            &client,
+            &model,
+            "live_transcribe",
+            started,
+            &Ok(()),
+            |()| Consumption {
+                audio_seconds,
+                ..Consumption::default()
+            },
+        )
+        .await;
+    }))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_job_schedule_create_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    Json(req): Json<JobScheduleRequest>,
+) -> ModelResult<(StatusCode, Json<JobSchedule>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    if let Err(err) = parse_cron(&req.cron) {
+        return Err(runner!(StatusCode::BAD_REQUEST, "{:#}", err)
+            .with_code("invalid_job_schedule")
+            .with_param(format!("{err:#}")));
+    }
+    validate_job_task(&req.task)?;
+    let schedule = create_schedule(&req, &client.token.id, &state.db_pool).await?;
+    Ok((StatusCode::CREATED, Json(schedule)))
+}
+
+/// Checks that the model of the task exists and its directories do
+#[tracing::instrument(level = "trace")]
+fn validate_job_task(task: &JobTask) -> ModelResult<()> {
+    let (model, known) = match task {
+        JobTask::TranscribeDirectory { model, .. } => (model, model == "whisper"),
+        JobTask::SummarizeDirectory {
+            model, max_length, ..
+        } => {
+            validate_max_length(*max_length)?;
+            (model, model != "whisper" && managed_model(model).is_some())
+        }
+    };
+    if !known {
+        return Err(runner!(StatusCode::NOT_FOUND, "Model {} not found", model)
+            .with_code("model_not_found")
+            .with_param(model));
     }
+    for directory in [task.directory(), task.output_directory()] {
+        if !directory.is_dir() {
+            return Err(runner!(
+                StatusCode::BAD_REQUEST,
+                "Directory {} not found",
+                directory.display()
+            )
+            .with_code("invalid_job_directory")
+            .with_param(directory.display()));
+        }
+    }
+    Ok(())
 }
 
-/// As per <https://developer.mozilla.org/en-US/docs/Web/Media/Formats/Containers#wave_wav/>
-static VALID_WAV_MIME_TYPES: [&str; 4] =
-    ["audio/wave", "audio/wav", "audio/x-wav", "audio/x-pn-wav"];
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_job_schedule_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+) -> ModelResult<(StatusCode, Json<Vec<JobSchedule>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    Ok((StatusCode::OK, Json(list_schedules(&state.db_pool).await?)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_job_schedule_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<(StatusCode, Json<JobSchedule>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    let Some(schedule) = get_schedule(&id, &state.db_pool).await? else {
+        bail_runner!(StatusCode::NOT_FOUND, "Job schedule {} not found", id);
+    };
+    Ok((StatusCode::OK, Json(schedule)))
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_job_schedule_delete_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+) -> ModelResult<StatusCode> {
+    client.has_permission(&Permission::ADMIN)?;
+    if !delete_schedule(&id, &state.db_pool).await? {
+        bail_runner!(StatusCode::NOT_FOUND, "Job schedule {} not found", id);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+struct JobRunListQuery {
+    #[serde(default = "default_job_run_limit")]
+    limit: u32,
+}
+
+const fn default_job_run_limit() -> u32 {
+    20
+}
+
+#[tracing::instrument(level = "trace", skip(state))]
+#[axum_macros::debug_handler]
+async fn handle_job_run_list_request(
+    State(state): State<AppState>,
+    Extension(client): Extension<ApiClient>,
+    RoutePath(id): RoutePath<String>,
+    Query(query): Query<JobRunListQuery>,
+) -> ModelResult<(StatusCode, Json<Vec<JobRun>>)> {
+    client.has_permission(&Permission::ADMIN)?;
+    if get_schedule(&id, &state.db_pool).await?.is_none() {
+        bail_runner!(StatusCode::NOT_FOUND, "Job schedule {} not found", id);
+    }
+    Ok((
+        StatusCode::OK,
+        Json(list_runs(&id, query.limit, &state.db_pool).await?),
+    ))
+}
+
+/// Runs the fixed probe prompt or audio through the model and checks the shape of its output
+#[tracing::instrument(level = "trace")]
+async fn probe_model(name: &'static str) -> Result<()> {
+    if name == "whisper" {
+        let segments = WHISPER_MODEL
+            .run(|mut model| model.run_transcribe_samples(&probe_audio(), "en"))
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+        if segments
+            .iter()
+            .any(|segment| !segment.start.is_finite() || !segment.duration.is_finite())
+        {
+            bail!("Probe transcript has segments without a valid time span");
+        }
+        return Ok(());
+    }
+
+    let response = run_instruct(InstructRequest::new(name, PROBE_PROMPT, PROBE_MAX_LENGTH))
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+    if response.tokens.completion == 0 || response.output.trim().is_empty() {
+        bail!("Probe prompt produced no output");
+    }
+    Ok(())
+}
+
+/// Processes the files of the job directory that have no output yet, a failing file does not stop
+/// the others
+#[tracing::instrument(level = "info")]
+async fn run_job(task: JobTask) -> Result<JobOutput> {
+    let files = task.pending_files()?;
+    Ok(run_job_files(task, files).await)
+}
+
+#[tracing::instrument(level = "info", skip(files))]
+async fn run_job_files(task: JobTask, files: Vec<PathBuf>) -> JobOutput {
+    let mut output = JobOutput::default();
+    for file in files {
+        match run_job_file(&task, &file).await {
+            Ok(()) => output.processed.push(file),
+            Err(err) => {
+                warn!("Job failed to process {}: {:#}", file.display(), err);
+                output.failed.push(JobFailure {
+                    file,
+                    error: format!("{err:#}"),
+                });
+            }
+        }
+    }
+    output
+}
+
+#[tracing::instrument(level = "trace", skip(task))]
+async fn run_job_file(task: &JobTask, file: &Path) -> Result<()> {
+    let contents = match task {
+        JobTask::TranscribeDirectory {
+            model, language, ..
+        } => {
+            let request = TranscribeRequest {
+                model: model.clone(),
+                language: language.clone(),
+                max_decode_steps: None,
+                runtime: false,
+                restore_punctuation: false,
+                segmentation: Segmentation::default(),
+            };
+            let input = AudioInput::Memory(tokio::fs::read(file).await?.into());
+            let response = WHISPER_MODEL
+                .run(move |mut model| model.run_transcribe(input, &request))
+                .await?;
+            serde_json::to_string_pretty(&response)?
+        }
+        JobTask::SummarizeDirectory {
+            model, max_length, ..
+        } => {
+            let req = SummarizeRequest {
+                model: model.clone(),
+                input: tokio::fs::read_to_string(file).await?,
+                document_id: None,
+                max_length: *max_length,
+                watermark: false,
+                banned_words: banned_words(model, None),
+            };
+            run_summarize(req, SummarizeStrategy::MapReduce)
+                .await
+                .map_err(|err| anyhow!("{}", err))?
+                .output
+        }
+    };
+    // Written under a temporary name first so that an interrupted job leaves no partial output
+    let output = task.output_path(file);
+    let mut partial = output.clone().into_os_string();
+    partial.push(".partial");
+    tokio::fs::write(&partial, contents).await?;
+    tokio::fs::rename(&partial, &output).await?;
+    Ok(())
+}
+
+/// Records the outcome of an inference request, requests for unknown models are not recorded
+#[tracing::instrument(level = "trace", skip(state, client, result, consumption))]
+async fn record_usage<T: Sync>(
+    state: &AppState,
+    client: &ApiClient,
+    model: &str,
+    task: &str,
+    started: Instant,
+    result: &ModelResult<T>,
+    consumption: impl FnOnce(&T) -> Consumption + Send,
+) {
+    if matches!(result, Err(err) if err.status == StatusCode::NOT_FOUND) {
+        return;
+    }
+    let consumption = result.as_ref().map(consumption).unwrap_or_default();
+    let client_id = recorded_client_id(&client.token.id);
+    let record = UsageRecord {
+        client_id: &client_id,
+        model,
+        task,
+        success: result.is_ok(),
+        consumption,
+        duration: started.elapsed(),
+    };
+    if let Err(e) = record.insert(&state.db_pool).await {
+        warn!("Failed to record usage: {}", e);
+    }
+}
 
 #[macro_export]
 macro_rules! exit_err {
@@ -0,0 +1,217 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::SqlitePool;
+use tracing::info;
+
+/// The migrations embedded into this binary
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Version of the migration replacing the `api_clients` and `api_client_permission_scopes` tables
+/// with the `client` table storing permissions as bit flags
+const LEGACY_CLIENTS_VERSION: i64 = 20_240_410_080_417;
+
+#[derive(Serialize, Debug)]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: String,
+}
+
+/// Takes an exclusive advisory lock on a file next to the database that is held until the file
+/// is dropped, the operating system releases it when the process exits or crashes
+#[tracing::instrument(level = "trace")]
+pub fn lock_database(database: &str) -> Result<File> {
+    let path = format!("{database}.lock");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file {path}"))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let holder = std::fs::read_to_string(&path).unwrap_or_default();
+            bail!(
+                "Database {database} is in use by another instance (process {}), lock file {path}",
+                holder.trim()
+            );
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("Failed to lock {path}"));
+        }
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
+/// Applies the pending migrations, converting the clients of the legacy schema first
+#[tracing::instrument(level = "info", skip(pool))]
+pub async fn run_migrations(pool: &SqlitePool) -> Result<()> {
+    if let Some(converted) = migrate_legacy_clients(pool).await? {
+        info!(
+            "Converted {} clients of the legacy schema into the client table",
+            converted
+        );
+    }
+    MIGRATOR.run(pool).await?;
+    Ok(())
+}
+
+/// Moves the clients and their permission scopes of a database that still has the legacy
+/// `api_clients` tables into the `client` table and drops the legacy tables. The migrations doing
+/// so fail on clients with permission scopes or without any, which kept such databases from being
+/// upgraded, so they are recorded as applied instead of being run. Keys stay valid as their hashes
+/// are taken over. Returns the number of converted clients.
+#[tracing::instrument(level = "info", skip(pool))]
+pub async fn migrate_legacy_clients(pool: &SqlitePool) -> Result<Option<u64>> {
+    let has_legacy_tables: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'api_clients')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_legacy_tables {
+        return Ok(None);
+    }
+
+    // Databases created before clients recorded their creator lack the column
+    let has_created_by: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info('api_clients') WHERE name = 'created_by')",
+    )
+    .fetch_one(pool)
+    .await?;
+    let created_by = if has_created_by { "created_by" } else { "NULL" };
+    let applied = applied_versions(pool).await?;
+
+    let mut transaction = pool.begin().await?;
+    transaction.ensure_migrations_table().await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS client \
+        (id text primary key not null, name text, key text not null, permissions integer not null, \
+        created_at integer not null, updated_at integer not null, created_by text)",
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // Each legacy permission maps to its counterpart for the client itself
+    let converted = sqlx::query(&format!(
+        "WITH legacy_permissions (permission, value) AS \
+        (VALUES ('use', 1), ('status', 4), ('create', 16), ('delete', 64), ('update', 256)) \
+        INSERT OR IGNORE INTO client (id, name, key, permissions, created_at, updated_at, created_by) \
+        SELECT id, name, key, \
+            COALESCE((SELECT SUM(value) FROM (SELECT DISTINCT legacy_permissions.value \
+                FROM api_client_permission_scopes \
+                INNER JOIN permission_scopes ON api_client_permission_scopes.scope_id = permission_scopes.id \
+                INNER JOIN legacy_permissions ON permission_scopes.permission = legacy_permissions.permission \
+                WHERE api_client_permission_scopes.api_client_id = api_clients.id)), 0), \
+            created_at, updated_at, {created_by} \
+        FROM api_clients"
+    ))
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    for table in [
+        "api_client_permission_scopes",
+        "permission_scopes",
+        "api_clients",
+    ] {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+            .execute(&mut *transaction)
+            .await?;
+    }
+    // The migrations up to the conversion only changed the legacy tables, which are gone now
+    for migration in MIGRATOR.iter().filter(|migration| {
+        !migration.migration_type.is_down_migration()
+            && migration.version <= LEGACY_CLIENTS_VERSION
+            && !applied.contains(&migration.version)
+    }) {
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) \
+            VALUES (?, ?, TRUE, ?, -1)",
+        )
+        .bind(migration.version)
+        .bind(&*migration.description)
+        .bind(&*migration.checksum)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(Some(converted))
+}
+
+/// Returns the versions of all migrations that were successfully applied to the database
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn applied_versions(pool: &SqlitePool) -> Result<Vec<i64>> {
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_table {
+        return Ok(Vec::new());
+    }
+
+    Ok(sqlx::query_scalar(
+        "SELECT version FROM _sqlx_migrations WHERE success = 1 ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Returns the migrations of this binary that are not applied to the database yet
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn pending_migrations(pool: &SqlitePool) -> Result<Vec<MigrationInfo>> {
+    let applied = applied_versions(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| MigrationInfo {
+            version: migration.version,
+            description: migration.description.to_string(),
+        })
+        .collect())
+}
+
+/// Returns the version of the newest migration embedded into this binary
+#[tracing::instrument(level = "trace")]
+pub fn latest_known_version() -> Option<i64> {
+    MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| migration.version)
+        .max()
+}
+
+/// Returns the applied migrations that are newer than this binary, which happens after a rollback
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn unknown_versions(pool: &SqlitePool) -> Result<Vec<i64>> {
+    let latest = latest_known_version().unwrap_or_default();
+    Ok(applied_versions(pool)
+        .await?
+        .into_iter()
+        .filter(|version| *version > latest)
+        .collect())
+}
+
+#[derive(Serialize, Debug)]
+pub struct SchemaInfo {
+    /// The newest migration applied to the database
+    pub applied_version: Option<i64>,
+    /// The newest migration embedded into this binary
+    pub binary_version: Option<i64>,
+    pub pending: Vec<MigrationInfo>,
+}
+
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn schema_info(pool: &SqlitePool) -> Result<SchemaInfo> {
+    Ok(SchemaInfo {
+        applied_version: applied_versions(pool).await?.into_iter().max(),
+        binary_version: latest_known_version(),
+        pending: pending_migrations(pool).await?,
+    })
+}
@@ -0,0 +1,55 @@
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+/// Rewrites applied to the input before it is tokenized, all disabled by default
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct Normalization {
+    /// Compose the input to Unicode normalization form C
+    pub normalize_nfc: bool,
+    /// Replace runs of spaces and tabs with a single space, trim lines and drop repeated blank lines
+    pub collapse_whitespace: bool,
+    /// Remove control characters other than newlines and tabs
+    pub strip_control_characters: bool,
+}
+
+impl Normalization {
+    #[tracing::instrument(level = "trace", skip(input))]
+    pub fn apply(self, input: String) -> String {
+        let mut input = input;
+        if self.strip_control_characters {
+            input = input
+                .chars()
+                .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+                .collect();
+        }
+        if self.normalize_nfc {
+            input = input.nfc().collect();
+        }
+        if self.collapse_whitespace {
+            input = collapse_whitespace(&input);
+        }
+        input
+    }
+}
+
+fn collapse_whitespace(input: &str) -> String {
+    let mut lines = Vec::new();
+    let mut blank = false;
+    for line in input.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            if !blank && !lines.is_empty() {
+                lines.push(line);
+            }
+            blank = true;
+        } else {
+            lines.push(line);
+            blank = false;
+        }
+    }
+    if blank {
+        lines.pop();
+    }
+    lines.join("\n")
+}
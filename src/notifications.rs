@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::config::Webhook;
+use crate::tls::certificate_expiry;
+
+/// Interval of the disk space and certificate checks
+const MONITOR_INTERVAL: Duration = Duration::from_mins(5);
+
+/// Operational events webhooks are notified of
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    ModelLoadFailed,
+    BreakerOpened,
+    DiskLow,
+    CertificateExpiring,
+}
+
+impl NotificationEvent {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::ModelLoadFailed => "model_load_failed",
+            Self::BreakerOpened => "breaker_opened",
+            Self::DiskLow => "disk_low",
+            Self::CertificateExpiring => "certificate_expiring",
+        }
+    }
+
+    /// The same event of the same subject is sent at most once in this interval
+    const fn repeat_interval(self) -> Duration {
+        match self {
+            Self::CertificateExpiring => Duration::from_hours(24),
+            _ => Duration::from_hours(1),
+        }
+    }
+}
+
+struct Notifier {
+    webhooks: Vec<Webhook>,
+    client: reqwest::Client,
+    runtime: Handle,
+    sent: Mutex<HashMap<(NotificationEvent, String), Instant>>,
+}
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+/// Enables posting events to the webhooks, has to be called from within the runtime
+#[tracing::instrument(level = "info", skip(webhooks))]
+pub fn configure_notifications(webhooks: Vec<Webhook>) -> Result<()> {
+    let notifier = Notifier {
+        webhooks,
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?,
+        runtime: Handle::try_current().context("Notifications require a runtime")?,
+        sent: Mutex::new(HashMap::new()),
+    };
+    if NOTIFIER.set(notifier).is_err() {
+        warn!("Notifications are already configured");
+    }
+    Ok(())
+}
+
+/// Posts the event in the background to all webhooks subscribed to it
+#[tracing::instrument(level = "trace")]
+pub fn notify(event: NotificationEvent, subject: &str, message: String) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    let mut sent = notifier.sent.lock().unwrap_or_else(PoisonError::into_inner);
+    let key = (event, subject.to_string());
+    if sent
+        .get(&key)
+        .is_some_and(|at| at.elapsed() < event.repeat_interval())
+    {
+        return;
+    }
+    sent.insert(key, Instant::now());
+    drop(sent);
+
+    // Slack only reads `text`, the remaining fields are for other receivers
+    let payload = json!({
+        "text": format!("ModelRunner: {message}"),
+        "event": event.as_str(),
+        "subject": subject,
+    })
+    .to_string();
+    for webhook in &notifier.webhooks {
+        if !webhook.events.is_empty() && !webhook.events.contains(&event) {
+            continue;
+        }
+        let request = notifier
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.clone());
+        notifier.runtime.spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!(
+                        monotonic_counter.notifications_sent = 1,
+                        event = event.as_str()
+                    );
+                }
+                Ok(response) => warn!(
+                    "Webhook responded with status {} to {} notification",
+                    response.status(),
+                    event.as_str()
+                ),
+                Err(err) => warn!("Failed to send {} notification: {}", event.as_str(), err),
+            }
+        });
+    }
+}
+
+/// Periodically notifies of directories running out of space and the certificate nearing expiry,
+/// exporting the days left until the certificate expires
+#[tracing::instrument(level = "info", skip(paths))]
+pub async fn run_monitor(
+    paths: Vec<PathBuf>,
+    min_free_space: u64,
+    certificate: Option<String>,
+    expiry_warning: Duration,
+) {
+    let mut interval = tokio::time::interval(MONITOR_INTERVAL);
+    loop {
+        interval.tick().await;
+        for path in &paths {
+            match free_space(path) {
+                Ok(free) if free < min_free_space => notify(
+                    NotificationEvent::DiskLow,
+                    &path.display().to_string(),
+                    format!(
+                        "Only {} MiB of disk space left for {}",
+                        free / (1024 * 1024),
+                        path.display()
+                    ),
+                ),
+                Ok(_) => {}
+                Err(err) => warn!("Failed to check disk space of {}: {}", path.display(), err),
+            }
+        }
+        if let Some(certificate) = &certificate {
+            match certificate_expiry(certificate) {
+                Ok(expiry) => {
+                    info!(
+                        gauge.tls_certificate_expiry_days = (expiry - Utc::now()).num_days(),
+                        certificate
+                    );
+                    let left = (expiry - Utc::now()).to_std().unwrap_or_default();
+                    if left < expiry_warning {
+                        notify(
+                            NotificationEvent::CertificateExpiring,
+                            certificate,
+                            format!("TLS certificate {certificate} expires at {expiry}"),
+                        );
+                    }
+                }
+                Err(err) => warn!("Failed to check certificate expiry: {:?}", err),
+            }
+        }
+    }
+}
+
+/// Returns the bytes available to unprivileged users on the filesystem of the path
+#[cfg(unix)]
+fn free_space(path: &Path) -> Result<u64> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Result<u64> {
+    anyhow::bail!("Disk space can only be checked on unix")
+}
@@ -0,0 +1,164 @@
+use std::sync::OnceLock;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::PluginDefinition;
+use crate::error::{HttpErrorResponse, ModelResult};
+use crate::limits::TEXT_BODY_LIMIT;
+use crate::{bail_runner, runner};
+
+/// A compiled-in extension that inspects and rewrites JSON payloads around the handlers
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Runs before the handler with the JSON body of the request, returning an error rejects it
+    fn on_request(&self, _path: &str, _body: &mut Value) -> ModelResult<()> {
+        Ok(())
+    }
+
+    /// Runs after the handler with the JSON body of a successful response
+    fn on_response(&self, _path: &str, _body: &mut Value) -> ModelResult<()> {
+        Ok(())
+    }
+}
+
+static PLUGINS: OnceLock<Vec<Box<dyn Plugin>>> = OnceLock::new();
+
+#[tracing::instrument(level = "info")]
+pub fn configure_plugins(definitions: &[PluginDefinition]) {
+    let plugins = definitions
+        .iter()
+        .map(|definition| -> Box<dyn Plugin> {
+            match definition {
+                PluginDefinition::SystemPrompt { prompt } => Box::new(SystemPrompt {
+                    prompt: prompt.clone(),
+                }),
+                PluginDefinition::BlockWords { words } => Box::new(BlockWords {
+                    words: words.iter().map(|word| word.to_lowercase()).collect(),
+                }),
+            }
+        })
+        .collect();
+    if PLUGINS.set(plugins).is_err() {
+        warn!("Plugins are already configured");
+    }
+}
+
+fn plugins() -> &'static [Box<dyn Plugin>] {
+    PLUGINS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Passes JSON request and response bodies through the configured plugins in order.
+/// Other content types like multipart uploads or binary response formats are passed through as is.
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn apply_plugins(request: Request, next: Next) -> ModelResult<Response> {
+    if plugins().is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let path = request.uri().path().to_string();
+    let request = if is_json(request.headers().get(header::CONTENT_TYPE)) {
+        let (parts, body) = request.into_parts();
+        let bytes = to_bytes(body, TEXT_BODY_LIMIT).await.map_err(|_| {
+            runner!(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body exceeds the limit"
+            )
+        })?;
+        let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+            // Malformed bodies are rejected by the handler with its usual error
+            return Ok(next
+                .run(Request::from_parts(parts, Body::from(bytes)))
+                .await);
+        };
+        for plugin in plugins() {
+            plugin.on_request(&path, &mut value)?;
+        }
+        Request::from_parts(parts, Body::from(serde_json::to_vec(&value)?))
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    if !response.status().is_success() || !is_json(response.headers().get(header::CONTENT_TYPE)) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = to_bytes(body, usize::MAX).await?;
+    let mut value = serde_json::from_slice::<Value>(&bytes)?;
+    for plugin in plugins() {
+        plugin.on_response(&path, &mut value)?;
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(
+        parts,
+        Body::from(serde_json::to_vec(&value)?),
+    ))
+}
+
+pub fn is_json(content_type: Option<&HeaderValue>) -> bool {
+    content_type
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/// Prepends a fixed instruction to the input of text generations
+struct SystemPrompt {
+    prompt: String,
+}
+
+impl Plugin for SystemPrompt {
+    fn name(&self) -> &'static str {
+        "system_prompt"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, body))]
+    fn on_request(&self, path: &str, body: &mut Value) -> ModelResult<()> {
+        if !path.starts_with("/text/") {
+            return Ok(());
+        }
+        if let Some(Value::String(input)) = body.get_mut("input") {
+            *input = format!("{}\n{}", self.prompt, input);
+        }
+        Ok(())
+    }
+}
+
+/// Rejects text generations whose input contains any of the words, ignoring case
+struct BlockWords {
+    words: Vec<String>,
+}
+
+impl Plugin for BlockWords {
+    fn name(&self) -> &'static str {
+        "block_words"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, body))]
+    fn on_request(&self, path: &str, body: &mut Value) -> ModelResult<()> {
+        if !path.starts_with("/text/") {
+            return Ok(());
+        }
+        let Some(input) = body.get("input").and_then(Value::as_str) else {
+            return Ok(());
+        };
+        let input = input.to_lowercase();
+        if self.words.iter().any(|word| input.contains(word.as_str())) {
+            warn!(
+                plugin = self.name(),
+                path, "Rejected request with blocked word"
+            );
+            bail_runner!(
+                StatusCode::FORBIDDEN,
+                "Request was rejected by the content policy"
+            );
+        }
+        Ok(())
+    }
+}
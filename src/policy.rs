@@ -0,0 +1,380 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::api::client::ApiClient;
+use crate::error::{HttpErrorResponse, ModelResult, ModelRunnerError};
+use crate::injection::injection_score;
+use crate::limits::TEXT_BODY_LIMIT;
+use crate::plugins::is_json;
+use crate::response::ResponseFormat;
+use crate::runner;
+use crate::truncation::truncate_graphemes;
+
+/// Request fields holding text sent to a model at any depth, such as the `messages` of chats and
+/// the `inputs` of batches
+const INPUT_FIELDS: [&str; 4] = ["input", "query", "inputs", "content"];
+/// Response fields holding generated text at any depth, such as the `outputs` of batches and the
+/// messages of sessions
+const OUTPUT_FIELDS: [&str; 2] = ["output", "content"];
+
+#[derive(Deserialize, Debug)]
+struct PolicyFile {
+    rules: Vec<RuleDefinition>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RuleDefinition {
+    name: String,
+    #[serde(default)]
+    stage: Stage,
+    #[serde(flatten)]
+    condition: ConditionDefinition,
+    action: Action,
+    /// Names of the clients the rule applies to, all clients if empty
+    #[serde(default)]
+    clients: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Stage {
+    Input,
+    Output,
+    #[default]
+    Both,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ConditionDefinition {
+    /// Matches text containing the regular expression
+    Regex(String),
+    /// Matches text longer than this many graphemes
+    MaxGraphemes(usize),
+    /// Matches text whose prompt injection score is at least this threshold between 0 and 1
+    InjectionScore(f64),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    /// Rejects the request
+    Block,
+    /// Replaces the matched text, or cuts off text that is too long
+    Redact,
+    /// Lets the text pass and reports the rule in the `x-policy-flags` header
+    Flag,
+}
+
+enum Condition {
+    Regex(Regex),
+    MaxGraphemes(usize),
+    InjectionScore(f64),
+}
+
+struct Rule {
+    name: String,
+    stage: Stage,
+    condition: Condition,
+    action: Action,
+    clients: Vec<String>,
+}
+
+impl Rule {
+    fn applies(&self, stage: Stage, client: Option<&ApiClient>) -> bool {
+        (self.stage == Stage::Both || self.stage == stage)
+            && (self.clients.is_empty()
+                || client
+                    .and_then(|client| client.name.as_ref())
+                    .is_some_and(|name| self.clients.contains(name)))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match &self.condition {
+            Condition::Regex(regex) => regex.is_match(text),
+            Condition::MaxGraphemes(length) => text.graphemes(true).count() > *length,
+            Condition::InjectionScore(threshold) => injection_score(text) >= *threshold,
+        }
+    }
+
+    fn redact(&self, text: &str) -> String {
+        match &self.condition {
+            Condition::Regex(regex) => regex.replace_all(text, "[REDACTED]").into_owned(),
+            Condition::MaxGraphemes(length) => truncate_graphemes(text, *length),
+            // Suspicious prompts can not be partially removed
+            Condition::InjectionScore(_) => String::new(),
+        }
+    }
+}
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+/// Loads the guardrail rules from a YAML file
+#[tracing::instrument(level = "info")]
+pub fn configure_policies(path: &Path) -> Result<()> {
+    let file = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read policy file {}", path.display()))?;
+    let policy: PolicyFile = serde_yaml::from_str(&file)
+        .with_context(|| format!("Invalid policy file {}", path.display()))?;
+    let rules = policy
+        .rules
+        .into_iter()
+        .map(|rule| {
+            let condition = match rule.condition {
+                ConditionDefinition::Regex(pattern) => Condition::Regex(
+                    Regex::new(&pattern)
+                        .with_context(|| format!("Invalid regex in rule {}", rule.name))?,
+                ),
+                ConditionDefinition::MaxGraphemes(length) => Condition::MaxGraphemes(length),
+                ConditionDefinition::InjectionScore(threshold) => {
+                    Condition::InjectionScore(threshold)
+                }
+            };
+            Ok(Rule {
+                name: rule.name,
+                stage: rule.stage,
+                condition,
+                action: rule.action,
+                clients: rule.clients,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    info!("Loaded {} guardrail rules", rules.len());
+    if RULES.set(rules).is_err() {
+        warn!("Policies are already configured");
+    }
+    Ok(())
+}
+
+fn rules() -> &'static [Rule] {
+    RULES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Whether any rule scores prompts for injection, the score is then reported to clients
+fn scores_injection() -> bool {
+    rules()
+        .iter()
+        .any(|rule| matches!(rule.condition, Condition::InjectionScore(_)))
+}
+
+/// Whether output rules apply to the client, streamed tokens can not be checked against them
+pub fn checks_output(client: &ApiClient) -> bool {
+    rules()
+        .iter()
+        .any(|rule| rule.applies(Stage::Output, Some(client)))
+}
+
+/// Evaluates the guardrail rules against the text fields of JSON requests before and of JSON
+/// responses after the handler. Every decision is logged with the rule, action and client.
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn enforce_policies(request: Request, next: Next) -> ModelResult<Response> {
+    if rules().is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let client = request.extensions().get::<ApiClient>().cloned();
+    let path = request.uri().path().to_string();
+    let mut flags = Vec::new();
+    let mut score = None;
+    let request = if is_json(request.headers().get(header::CONTENT_TYPE)) {
+        let (parts, body) = request.into_parts();
+        let bytes = to_bytes(body, TEXT_BODY_LIMIT).await.map_err(|_| {
+            runner!(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body exceeds the limit"
+            )
+        })?;
+        let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+            return Ok(next
+                .run(Request::from_parts(parts, Body::from(bytes)))
+                .await);
+        };
+        if scores_injection() {
+            score = text_fields(&mut value, &INPUT_FIELDS)
+                .into_iter()
+                .map(|text| injection_score(text))
+                .reduce(f64::max);
+        }
+        flags = evaluate(
+            Stage::Input,
+            &INPUT_FIELDS,
+            &mut value,
+            client.as_ref(),
+            &path,
+        )?;
+        Request::from_parts(parts, Body::from(serde_json::to_vec(&value)?))
+    } else {
+        request
+    };
+
+    let mut response = next.run(request).await;
+    if let Some(score) = score {
+        response.headers_mut().insert(
+            "x-injection-score",
+            HeaderValue::from_str(&format!("{score:.2}"))?,
+        );
+    }
+    // Rules also apply to the other negotiated formats, which are decoded the same way
+    let format = ResponseFormat::from_content_type(response.headers().get(header::CONTENT_TYPE));
+    let Some(format) = format.filter(|_| response.status().is_success()) else {
+        return Ok(response);
+    };
+    let (mut parts, body) = response.into_parts();
+    let bytes = to_bytes(body, TEXT_BODY_LIMIT).await.map_err(|_| {
+        runner!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Response body exceeds the limit"
+        )
+    })?;
+    let mut value = format
+        .deserialize::<Value>(&bytes)
+        .map_err(|e| runner!(StatusCode::INTERNAL_SERVER_ERROR, "Invalid response: {}", e))?;
+    flags.extend(evaluate(
+        Stage::Output,
+        &OUTPUT_FIELDS,
+        &mut value,
+        client.as_ref(),
+        &path,
+    )?);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if !flags.is_empty() {
+        parts
+            .headers
+            .insert("x-policy-flags", HeaderValue::from_str(&flags.join(","))?);
+    }
+    let bytes = format.serialize(&value).map_err(|e| {
+        runner!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to serialize response: {}",
+            e
+        )
+    })?;
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+/// Returns the strings under the fields anywhere in the value, including the elements of arrays
+fn text_fields<'a>(value: &'a mut Value, fields: &[&str]) -> Vec<&'a mut String> {
+    fn collect<'a>(
+        value: &'a mut Value,
+        fields: &[&str],
+        selected: bool,
+        texts: &mut Vec<&'a mut String>,
+    ) {
+        match value {
+            Value::String(text) if selected => texts.push(text),
+            Value::Array(values) => {
+                for value in values {
+                    collect(value, fields, selected, texts);
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map {
+                    collect(value, fields, fields.contains(&key.as_str()), texts);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut texts = Vec::new();
+    collect(value, fields, false, &mut texts);
+    texts
+}
+
+/// Applies the rules of the stage to the fields, returning the names of the flagged rules
+#[tracing::instrument(level = "trace", skip(value, client))]
+fn evaluate(
+    stage: Stage,
+    fields: &[&str],
+    value: &mut Value,
+    client: Option<&ApiClient>,
+    path: &str,
+) -> ModelResult<Vec<String>> {
+    let client_id = client.map_or("", |client| client.token.id.as_str());
+    let mut flags = Vec::new();
+    for text in text_fields(value, fields) {
+        for rule in rules() {
+            if !rule.applies(stage, client) || !rule.matches(text) {
+                continue;
+            }
+            warn!(
+                rule = rule.name,
+                action = ?rule.action,
+                stage = ?stage,
+                client_id,
+                path,
+                "Guardrail rule matched"
+            );
+            match rule.action {
+                Action::Block => {
+                    info!(monotonic_counter.policy_blocked = 1);
+                    return Err(runner!(
+                        StatusCode::FORBIDDEN,
+                        "Request was blocked by policy {}",
+                        rule.name
+                    )
+                    .with_code("policy_violation"));
+                }
+                Action::Redact => {
+                    info!(monotonic_counter.policy_redacted = 1);
+                    *text = rule.redact(text);
+                }
+                Action::Flag => {
+                    info!(monotonic_counter.policy_flagged = 1);
+                    flags.push(rule.name.clone());
+                }
+            }
+        }
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn text_fields_are_found_at_any_depth() {
+        let mut value = json!({
+            "model": "phi3",
+            "input": "top level",
+            "messages": [{"role": "user", "content": "chat message"}],
+            "inputs": ["first", "second"],
+            "stop": ["not checked"],
+        });
+        let mut texts: Vec<String> = text_fields(&mut value, &INPUT_FIELDS)
+            .into_iter()
+            .map(|text| text.clone())
+            .collect();
+        texts.sort();
+        assert_eq!(texts, ["chat message", "first", "second", "top level"]);
+    }
+
+    #[test]
+    fn text_fields_can_be_redacted() {
+        let mut value = json!({"outputs": [{"output": "secret"}, {"output": "public"}]});
+        for text in text_fields(&mut value, &OUTPUT_FIELDS) {
+            if text == "secret" {
+                *text = "[REDACTED]".to_string();
+            }
+        }
+        assert_eq!(
+            value,
+            json!({"outputs": [{"output": "[REDACTED]"}, {"output": "public"}]})
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::OnceLock;
+
+use tracing::warn;
+use unicode_segmentation::UnicodeSegmentation;
+
+static MODEL: OnceLock<String> = OnceLock::new();
+
+#[tracing::instrument(level = "info")]
+pub fn configure_punctuation(model: String) {
+    if MODEL.set(model).is_err() {
+        warn!("Punctuation model is already configured");
+    }
+}
+
+/// The text model restoring punctuation and casing of transcripts
+pub fn punctuation_model() -> &'static str {
+    MODEL.get().map_or("stablelm2zephyr", String::as_str)
+}
+
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn punctuation_prompt(text: &str) -> String {
+    format!(
+        "Add punctuation and capitalization to the following transcript. Do not add, remove or \
+         change any words. Reply with the corrected transcript only.\n\n{text}"
+    )
+}
+
+/// Words of the text ignoring their casing, which the restored text has to keep unchanged
+fn words(text: &str) -> Vec<String> {
+    text.unicode_words().map(str::to_lowercase).collect()
+}
+
+/// Distributes the restored text over the segments it was created from by their number of words.
+/// Returns `None` if the model changed, added or dropped any word.
+#[tracing::instrument(level = "trace", skip(segments, restored))]
+pub fn redistribute(segments: &[&str], restored: &str) -> Option<Vec<String>> {
+    if words(&segments.join(" ")) != words(restored) {
+        return None;
+    }
+
+    let mut counts = segments
+        .iter()
+        .map(|segment| segment.unicode_words().count());
+    let mut parts = vec![String::new()];
+    let mut remaining = counts.next().unwrap_or_default();
+    for token in restored.trim().split_word_bounds() {
+        if token.chars().any(char::is_alphanumeric) {
+            // Segments without any words keep their empty text
+            while remaining == 0 && parts.len() < segments.len() {
+                parts.push(String::new());
+                remaining = counts.next().unwrap_or_default();
+            }
+            remaining = remaining.saturating_sub(1);
+        }
+        if let Some(part) = parts.last_mut() {
+            part.push_str(token);
+        }
+    }
+    parts.resize(segments.len(), String::new());
+    Some(
+        parts
+            .into_iter()
+            .map(|part| part.trim().to_string())
+            .collect(),
+    )
+}
@@ -0,0 +1,124 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{HttpErrorResponse, ModelRunnerError};
+use crate::runner;
+
+/// Serialization format of a response body, negotiated through the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl ResponseFormat {
+    pub const ALL: [Self; 3] = [Self::Json, Self::MessagePack, Self::Cbor];
+
+    #[tracing::instrument(level = "trace")]
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Self::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::MessagePack)
+            }
+            "application/cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Returns the format of a body with the content type
+    pub fn from_content_type(content_type: Option<&HeaderValue>) -> Option<Self> {
+        let media_type = content_type?.to_str().ok()?.split(';').next()?.trim();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.content_type() == media_type)
+    }
+
+    pub fn serialize<T: Serialize>(self, body: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(body).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(body).map_err(|e| e.to_string()),
+            Self::Cbor => {
+                let mut bytes = vec![];
+                ciborium::into_writer(body, &mut bytes)
+                    .map(|()| bytes)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = ModelRunnerError;
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(accept) = parts.headers.get(header::ACCEPT) else {
+            return Ok(Self::Json);
+        };
+        let accept = accept
+            .to_str()
+            .map_err(|_| runner!(StatusCode::BAD_REQUEST, "Invalid accept header"))?;
+
+        // Media types are picked in the order given by the client, quality values are not weighed
+        Ok(accept
+            .split(',')
+            .filter_map(|media_type| media_type.split(';').next())
+            .find_map(|media_type| Self::from_media_type(media_type.trim()))
+            .unwrap_or(Self::Json))
+    }
+}
+
+/// Response body serialized in the negotiated `ResponseFormat`
+pub struct Negotiated<T>(pub ResponseFormat, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn into_response(self) -> Response {
+        let Self(format, body) = self;
+        if format == ResponseFormat::Json {
+            return Json(body).into_response();
+        }
+
+        match format.serialize(&body) {
+            Ok(bytes) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(err) => runner!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to serialize response: {}",
+                err
+            )
+            .into_response(),
+        }
+    }
+}
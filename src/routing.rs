@@ -0,0 +1,180 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, Timelike, Utc};
+use rhai::{Dynamic, Engine, Scope, AST};
+use tracing::{debug, warn};
+
+use crate::api::client::ApiClient;
+use crate::config::AutoRoutingPolicy;
+
+/// Pseudo model that picks a model by the characteristics of the prompt
+pub const AUTO_MODEL: &str = "auto";
+
+/// Maximum number of operations a routing script may run per request
+const MAX_OPERATIONS: u64 = 100_000;
+
+static ROUTING: OnceLock<RoutingScript> = OnceLock::new();
+static AUTO_ROUTING: OnceLock<AutoRoutingPolicy> = OnceLock::new();
+
+/// A Rhai script deciding which model handles a request before it is dispatched
+struct RoutingScript {
+    engine: Engine,
+    ast: AST,
+}
+
+/// Compiles the routing script, which can read `model`, `task`, `prompt_length`, `client`,
+/// `hour` and `weekday` and returns the name of the model to use or nothing to keep the requested one
+#[tracing::instrument(level = "info", skip(script))]
+pub fn configure_routing(script: &str) -> Result<()> {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    let ast = engine
+        .compile(script)
+        .context("Failed to compile routing script")?;
+    if ROUTING.set(RoutingScript { engine, ast }).is_err() {
+        warn!("Routing script is already configured");
+    }
+    Ok(())
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_auto_routing(policy: AutoRoutingPolicy) {
+    if AUTO_ROUTING.set(policy).is_err() {
+        warn!("Auto routing is already configured");
+    }
+}
+
+/// Returns the model that should handle the request according to the routing script,
+/// resolving the `auto` model afterwards
+#[tracing::instrument(level = "trace", skip(client, input))]
+pub fn route_model(client: &ApiClient, task: &str, model: &str, input: &str) -> Result<String> {
+    let routed = run_script(client, task, model, input)?;
+    if routed == AUTO_MODEL {
+        return Ok(auto_route(input));
+    }
+    Ok(routed)
+}
+
+#[tracing::instrument(level = "trace", skip(client, input))]
+fn run_script(client: &ApiClient, task: &str, model: &str, input: &str) -> Result<String> {
+    let Some(routing) = ROUTING.get() else {
+        return Ok(model.into());
+    };
+
+    let now = Utc::now();
+    let mut scope = Scope::new();
+    scope.push_constant("model", model.to_string());
+    scope.push_constant("task", task.to_string());
+    scope.push_constant("prompt_length", i64::try_from(input.chars().count())?);
+    scope.push_constant("client", client.name.clone().unwrap_or_default());
+    scope.push_constant("hour", i64::from(now.hour()));
+    scope.push_constant("weekday", i64::from(now.weekday().num_days_from_monday()));
+
+    let result: Dynamic = routing
+        .engine
+        .eval_ast_with_scope(&mut scope, &routing.ast)
+        .map_err(|e| anyhow!("Routing script failed: {e}"))?;
+    if result.is_unit() {
+        return Ok(model.into());
+    }
+    let routed = result
+        .into_string()
+        .map_err(|kind| anyhow!("Routing script must return a model name, got {kind}"))?;
+    if routed != model {
+        debug!("Routing script moved request from {} to {}", model, routed);
+    }
+    Ok(routed)
+}
+
+/// Picks the model of the policy the prompt matches first
+#[tracing::instrument(level = "trace", skip(input))]
+fn auto_route(input: &str) -> String {
+    let default_policy = AutoRoutingPolicy::default();
+    let policy = AUTO_ROUTING.get().unwrap_or(&default_policy);
+    let model = if looks_like_code(input) {
+        &policy.code
+    } else if is_mostly_non_latin(input) {
+        &policy.multilingual
+    } else if input.chars().count() <= policy.short_prompt_length {
+        &policy.short
+    } else {
+        &policy.long
+    };
+    debug!("Auto routing picked {}", model);
+    model.clone()
+}
+
+/// Code fences or a high share of lines ending like statements or blocks indicate source code
+fn looks_like_code(input: &str) -> bool {
+    if input.contains("```") {
+        return true;
+    }
+    let lines: Vec<&str> = input
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let code_lines = lines
+        .iter()
+        .filter(|line| line.ends_with([';', '{', '}', ')', ':']))
+        .count();
+    lines.len() >= 3 && code_lines * 2 >= lines.len()
+}
+
+fn is_mostly_non_latin(input: &str) -> bool {
+    let (latin, other) = input.chars().filter(|char| char.is_alphabetic()).fold(
+        (0usize, 0usize),
+        |(latin, other), char| {
+            // Latin letters including their accented forms end before the Greek block
+            if u32::from(char) < 0x0370 {
+                (latin + 1, other)
+            } else {
+                (latin, other + 1)
+            }
+        },
+    );
+    other > latin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_fences_are_code() {
+        assert!(looks_like_code("Why does this fail?\n```\nlet x = 1\n```"));
+    }
+
+    #[test]
+    fn statement_lines_are_code() {
+        assert!(looks_like_code(
+            "fn main() {\n    let x = compute();\n    println!(\"{x}\");\n}"
+        ));
+        assert!(looks_like_code("def f(x):\n    return g(x)\n\nprint(f(1))"));
+    }
+
+    #[test]
+    fn prose_is_not_code() {
+        assert!(!looks_like_code("Summarize the following text."));
+        assert!(!looks_like_code(
+            "Dear team,\nthe meeting moves to Friday.\nPlease note the new room:\nBest regards"
+        ));
+        // Too few lines to tell
+        assert!(!looks_like_code("x = 1;\ny = 2;"));
+    }
+
+    #[test]
+    fn non_latin_scripts_are_detected() {
+        assert!(is_mostly_non_latin("Привет, как дела?"));
+        assert!(is_mostly_non_latin("これは日本語の文です"));
+        assert!(is_mostly_non_latin("Translate: 你好世界，今天天气很好"));
+    }
+
+    #[test]
+    fn latin_scripts_with_accents_are_not_non_latin() {
+        assert!(!is_mostly_non_latin("Hello world"));
+        assert!(!is_mostly_non_latin("Ça va très bien, grüße aus Málaga"));
+        assert!(!is_mostly_non_latin("12345 !?"));
+    }
+}
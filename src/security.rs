@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Only the API is served, so nothing may be loaded or framed from its responses
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; frame-ancestors 'none'; base-uri 'none'; form-action 'none'";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HSTS_MAX_AGE: AtomicU64 = AtomicU64::new(0);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_security_headers(enabled: bool, hsts_max_age: u64) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    HSTS_MAX_AGE.store(hsts_max_age, Ordering::Relaxed);
+}
+
+/// Adds HSTS, `X-Content-Type-Options`, `Referrer-Policy` and a restrictive CSP to all responses,
+/// keeping headers a handler already set
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn security_headers(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if !ENABLED.load(Ordering::Relaxed) {
+        return response;
+    }
+    let headers = response.headers_mut();
+    let max_age = HSTS_MAX_AGE.load(Ordering::Relaxed);
+    if max_age > 0 {
+        if let Ok(value) = HeaderValue::from_str(&format!("max-age={max_age}; includeSubDomains")) {
+            headers
+                .entry(header::STRICT_TRANSPORT_SECURITY)
+                .or_insert(value);
+        }
+    }
+    headers
+        .entry(header::X_CONTENT_TYPE_OPTIONS)
+        .or_insert(HeaderValue::from_static("nosniff"));
+    headers
+        .entry(header::REFERRER_POLICY)
+        .or_insert(HeaderValue::from_static("no-referrer"));
+    headers
+        .entry(header::CONTENT_SECURITY_POLICY)
+        .or_insert(HeaderValue::from_static(CONTENT_SECURITY_POLICY));
+    response
+}
@@ -0,0 +1,244 @@
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::inference::audio_pipeline::{DecodingResult, Segment};
+use crate::truncation::SENTENCE_TERMINATORS;
+
+/// How the segments of a transcript are merged and split before they are returned
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Segmentation {
+    /// Segments shorter than this many seconds are merged with their neighbours
+    pub merge_shorter_than: Option<f64>,
+    /// Splits segments containing several sentences into a segment per sentence
+    pub split_sentences: bool,
+    /// Splits segments with more characters at word boundaries
+    pub max_characters: Option<usize>,
+}
+
+impl Segmentation {
+    /// Splits the segments into sentences, merges the short ones and caps their length in this
+    /// order, the time of a split segment is divided by the characters of its parts
+    #[tracing::instrument(level = "trace", skip(segments))]
+    pub fn apply(&self, mut segments: Vec<Segment>) -> Vec<Segment> {
+        if self.split_sentences {
+            segments = segments
+                .into_iter()
+                .flat_map(|segment| {
+                    let sentences = segment
+                        .dr
+                        .text
+                        .split_sentence_bounds()
+                        .map(String::from)
+                        .collect();
+                    divide(segment, sentences)
+                })
+                .collect();
+        }
+        if let Some(min_duration) = self.merge_shorter_than {
+            segments = self.merge_short(segments, min_duration);
+        }
+        if let Some(max_characters) = self.max_characters {
+            segments = segments
+                .into_iter()
+                .flat_map(|segment| {
+                    let parts = word_chunks(&segment.dr.text, max_characters);
+                    divide(segment, parts)
+                })
+                .collect();
+        }
+        segments
+    }
+
+    fn merge_short(&self, segments: Vec<Segment>, min_duration: f64) -> Vec<Segment> {
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            if let Some(last) = merged.last_mut() {
+                let short = last.duration < min_duration || segment.duration < min_duration;
+                let fits = self.max_characters.is_none_or(|max| {
+                    last.dr.text.trim().chars().count() + 1 + segment.dr.text.trim().chars().count()
+                        <= max
+                });
+                // Sentences that were split apart on request stay apart
+                let ends_sentence =
+                    self.split_sentences && last.dr.text.trim_end().ends_with(SENTENCE_TERMINATORS);
+                if short && fits && !ends_sentence {
+                    absorb(last, &segment);
+                    continue;
+                }
+            }
+            merged.push(segment);
+        }
+        merged
+    }
+}
+
+/// Appends the following segment to the segment, weighting the decoding statistics by duration
+fn absorb(segment: &mut Segment, next: &Segment) {
+    let (duration, next_duration) = (segment.duration, next.duration);
+    let weighted = |a: f64, b: f64| {
+        if duration + next_duration > 0.0 {
+            a.mul_add(duration, b * next_duration) / (duration + next_duration)
+        } else {
+            a
+        }
+    };
+    let dr = &mut segment.dr;
+    dr.avg_logprob = weighted(dr.avg_logprob, next.dr.avg_logprob);
+    dr.no_speech_prob = weighted(dr.no_speech_prob, next.dr.no_speech_prob);
+    dr.compression_ratio = weighted(dr.compression_ratio, next.dr.compression_ratio);
+    dr.temperature = dr.temperature.max(next.dr.temperature);
+    dr.truncated |= next.dr.truncated;
+    dr.text = format!("{} {}", dr.text.trim_end(), next.dr.text.trim_start());
+    segment.duration = next.start + next.duration - segment.start;
+}
+
+/// Groups the words of the text into parts of at most the given number of characters, a single
+/// word longer than that becomes a part of its own
+fn word_chunks(text: &str, max_characters: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    for word in text.split_word_bounds() {
+        let length = current.trim().chars().count() + word.trim_end().chars().count();
+        if length > max_characters && !current.trim().is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+        current.push_str(word);
+    }
+    parts.push(current);
+    parts
+}
+
+/// Divides the segment into the parts of its text, giving each part the share of the time of the
+/// segment its characters make up
+#[allow(clippy::cast_precision_loss)]
+fn divide(segment: Segment, parts: Vec<String>) -> Vec<Segment> {
+    if parts.len() <= 1 {
+        return vec![segment];
+    }
+    let total = parts
+        .iter()
+        .map(|part| part.chars().count())
+        .sum::<usize>()
+        .max(1) as f64;
+    let mut start = segment.start;
+    parts
+        .into_iter()
+        .filter_map(|part| {
+            let duration = segment.duration * part.chars().count() as f64 / total;
+            let divided = Segment {
+                start,
+                duration,
+                dr: DecodingResult {
+                    text: part.trim().to_string(),
+                    ..segment.dr.clone()
+                },
+            };
+            start += duration;
+            (!divided.dr.text.is_empty()).then_some(divided)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start: f64, duration: f64, text: &str) -> Segment {
+        Segment {
+            start,
+            duration,
+            dr: DecodingResult {
+                text: text.to_string(),
+                avg_logprob: -0.5,
+                no_speech_prob: 0.1,
+                temperature: 0.0,
+                compression_ratio: 1.5,
+                truncated: false,
+            },
+        }
+    }
+
+    fn summary(segments: &[Segment]) -> Vec<(f64, f64, &str)> {
+        segments
+            .iter()
+            .map(|segment| (segment.start, segment.duration, segment.text()))
+            .collect()
+    }
+
+    #[test]
+    fn split_sentences_divides_the_time_by_characters() {
+        let segmentation = Segmentation {
+            split_sentences: true,
+            ..Segmentation::default()
+        };
+        let segments = segmentation.apply(vec![segment(0.0, 4.0, "One two. Three four.")]);
+        assert_eq!(
+            summary(&segments),
+            [(0.0, 1.8, "One two."), (1.8, 2.2, "Three four.")]
+        );
+    }
+
+    #[test]
+    fn merge_short_joins_neighbours() {
+        let segmentation = Segmentation {
+            merge_shorter_than: Some(1.0),
+            ..Segmentation::default()
+        };
+        let segments = segmentation.apply(vec![
+            segment(0.0, 1.0, "a"),
+            segment(1.0, 0.5, "b"),
+            segment(1.5, 3.0, "c"),
+        ]);
+        assert_eq!(summary(&segments), [(0.0, 1.5, "a b"), (1.5, 3.0, "c")]);
+    }
+
+    #[test]
+    fn merge_short_keeps_split_sentences_apart() {
+        let segmentation = Segmentation {
+            merge_shorter_than: Some(2.0),
+            split_sentences: true,
+            ..Segmentation::default()
+        };
+        let segments = segmentation.apply(vec![
+            segment(0.0, 1.0, "Done. And"),
+            segment(1.0, 0.5, "more"),
+        ]);
+        assert_eq!(summary(&segments).len(), 2);
+        assert_eq!(segments[1].text(), "And more");
+    }
+
+    #[test]
+    fn merge_short_weights_statistics_by_duration() {
+        let mut first = segment(0.0, 1.0, "a");
+        let mut second = segment(1.0, 3.0, "b");
+        first.dr.avg_logprob = -1.0;
+        second.dr.avg_logprob = -0.2;
+        second.dr.truncated = true;
+        absorb(&mut first, &second);
+        assert!((first.dr.avg_logprob + 0.4).abs() < 1e-9);
+        assert!(first.dr.truncated);
+        assert!((first.duration - 4.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn max_characters_splits_at_words() {
+        let segmentation = Segmentation {
+            max_characters: Some(9),
+            ..Segmentation::default()
+        };
+        let segments = segmentation.apply(vec![segment(0.0, 1.8, "one two three four")]);
+        assert_eq!(
+            summary(&segments),
+            [(0.0, 0.8, "one two"), (0.8, 1.0, "three four")]
+        );
+    }
+
+    #[test]
+    fn word_chunks_keep_long_words_whole() {
+        assert_eq!(
+            word_chunks("a supercalifragilistic b", 5),
+            ["a ", "supercalifragilistic", " b"]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::api::clock::unix_now;
+
+#[derive(Deserialize, Debug)]
+pub struct SessionCreateRequest {
+    pub model: String,
+    /// Maximum number of tokens of every reply
+    pub max_length: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionMessageRequest {
+    pub input: String,
+}
+
+/// A stored conversation of a client with a model
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub model: String,
+    pub max_length: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    pub messages: Vec<SessionMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionMessage {
+    /// Either `user` or `assistant`
+    pub role: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn create_session(
+    client_id: &str,
+    model: &str,
+    max_length: usize,
+    pool: &SqlitePool,
+) -> Result<SessionInfo> {
+    let id = format!("{:032x}", rand::random::<u128>());
+    let max_length: i64 = max_length.try_into()?;
+    let now = unix_now()?;
+    sqlx::query!(
+        "INSERT INTO sessions (id, client_id, model, max_length, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?)",
+        id,
+        client_id,
+        model,
+        max_length,
+        now,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(SessionInfo {
+        id,
+        model: model.into(),
+        max_length,
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+/// Lists the sessions of the client, most recently used first
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn list_sessions(client_id: &str, pool: &SqlitePool) -> Result<Vec<SessionInfo>> {
+    Ok(sqlx::query_as!(
+        SessionInfo,
+        "SELECT id, model, max_length, created_at, updated_at FROM sessions \
+        WHERE client_id = ? ORDER BY updated_at DESC",
+        client_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Returns a session of the client with all of its messages in order
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn get_session(id: &str, client_id: &str, pool: &SqlitePool) -> Result<Option<Session>> {
+    let Some(info) = sqlx::query_as!(
+        SessionInfo,
+        "SELECT id, model, max_length, created_at, updated_at FROM sessions \
+        WHERE id = ? AND client_id = ?",
+        id,
+        client_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let messages = sqlx::query_as!(
+        SessionMessage,
+        "SELECT role, content, created_at FROM session_messages WHERE session_id = ? ORDER BY position",
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(Session { info, messages }))
+}
+
+/// Appends a message of the user and the reply of the model to the session
+#[tracing::instrument(level = "trace", skip(input, reply, pool))]
+pub async fn append_exchange(id: &str, input: &str, reply: &str, pool: &SqlitePool) -> Result<()> {
+    let now = unix_now()?;
+    let mut transaction = pool.begin().await?;
+    for (role, content) in [("user", input), ("assistant", reply)] {
+        sqlx::query!(
+            "INSERT INTO session_messages (session_id, position, role, content, created_at) \
+            VALUES (?, (SELECT COUNT(*) FROM session_messages WHERE session_id = ?), ?, ?, ?)",
+            id,
+            id,
+            role,
+            content,
+            now
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    sqlx::query!("UPDATE sessions SET updated_at = ? WHERE id = ?", now, id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Removes a session of the client, returning whether it existed
+#[tracing::instrument(level = "trace", skip(pool))]
+pub async fn delete_session(id: &str, client_id: &str, pool: &SqlitePool) -> Result<bool> {
+    let result = sqlx::query!(
+        "DELETE FROM sessions WHERE id = ? AND client_id = ?",
+        id,
+        client_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Renders the previous messages and the new input as a single instruction
+#[tracing::instrument(level = "trace", skip(messages, input))]
+pub fn conversation_prompt(messages: &[SessionMessage], input: &str) -> String {
+    if messages.is_empty() {
+        return input.into();
+    }
+    let history = messages
+        .iter()
+        .map(|message| {
+            let speaker = if message.role == "user" {
+                "User"
+            } else {
+                "Assistant"
+            };
+            format!("{speaker}: {}", message.content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("Continue the following conversation.\n\n{history}\nUser: {input}\nAssistant:")
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::inference::model_slot::{ManagedModel, ModelStatus};
+use crate::inference::queue::wait_percentile;
+
+/// Overall availability of the instance
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Availability {
+    /// All models are able to serve requests
+    Operational,
+    /// Some models are unable to serve requests
+    Degraded,
+    /// No model is able to serve requests
+    Outage,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelAvailability {
+    Up,
+    Degraded,
+    /// The model is unloaded outside of its availability window
+    Offline,
+}
+
+impl From<ModelStatus> for ModelAvailability {
+    fn from(status: ModelStatus) -> Self {
+        match status {
+            ModelStatus::Unloaded | ModelStatus::Ready => Self::Up,
+            ModelStatus::Degraded | ModelStatus::Unhealthy => Self::Degraded,
+            ModelStatus::Offline => Self::Offline,
+        }
+    }
+}
+
+/// Public summary of the instance for status pages, leaving out any internals
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    pub status: Availability,
+    pub models: BTreeMap<&'static str, ModelAvailability>,
+    /// The 95th percentile of the recent time requests waited for a worker in milliseconds
+    pub queue_latency_p95_ms: Option<f64>,
+}
+
+impl StatusReport {
+    #[tracing::instrument(level = "trace", skip(models))]
+    pub fn current(models: &[&dyn ManagedModel]) -> Self {
+        let models: BTreeMap<_, _> = models
+            .iter()
+            .map(|model| (model.name(), ModelAvailability::from(model.status())))
+            .collect();
+        let degraded = models
+            .values()
+            .filter(|availability| **availability == ModelAvailability::Degraded)
+            .count();
+        let status = match degraded {
+            0 => Availability::Operational,
+            degraded if degraded == models.len() => Availability::Outage,
+            _ => Availability::Degraded,
+        };
+        Self {
+            status,
+            models,
+            queue_latency_p95_ms: wait_percentile(None, 95).map(|wait| wait.as_secs_f64() * 1000.0),
+        }
+    }
+}
@@ -1,13 +1,16 @@
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use opentelemetry::global;
+use opentelemetry::trace::TracerProvider as _;
 use opentelemetry::KeyValue;
 use opentelemetry_otlp::{TonicExporterBuilder, WithExportConfig};
 use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::trace::Config;
 use opentelemetry_sdk::{runtime, Resource};
 use opentelemetry_semantic_conventions::resource::{SERVICE_NAME, SERVICE_VERSION};
+use tracing::{info, warn};
 use tracing_chrome::ChromeLayerBuilder;
 use tracing_opentelemetry::{MetricsLayer, OpenTelemetryLayer};
 use tracing_subscriber::layer::SubscriberExt;
@@ -19,6 +22,7 @@ pub fn init_telemetry(
     endpoint: &Option<String>,
     console: bool,
     tracing_chrome: bool,
+    trace_dir: Option<&Path>,
 ) -> Vec<impl Drop> {
     let service_resource = Resource::new(vec![
         KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
@@ -33,13 +37,15 @@ pub fn init_telemetry(
 
     // Additions to the layer
     if let Some(endpoint) = endpoint {
+        // Since opentelemetry-otlp 0.17 the pipeline installs a provider, the tracer is taken from it
         let tracer = opentelemetry_otlp::new_pipeline()
             .tracing()
             .with_exporter(build_tonic_exporter(endpoint))
             .with_trace_config(Config::default().with_resource(service_resource.clone()))
             .install_batch(runtime::Tokio)
             .context("Failed to install tracer")
-            .unwrap();
+            .unwrap()
+            .tracer(env!("CARGO_PKG_NAME"));
 
         let meter = opentelemetry_otlp::new_pipeline()
             .metrics(runtime::Tokio)
@@ -58,7 +64,15 @@ pub fn init_telemetry(
         layer = layer.and_then(tracing_subscriber::fmt::layer()).boxed();
     }
     if tracing_chrome {
-        let (chrome_layer, chrome_guard) = ChromeLayerBuilder::new().build();
+        let mut builder = ChromeLayerBuilder::new();
+        if let Some(trace_dir) = trace_dir {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros();
+            builder = builder.file(trace_dir.join(format!("trace-{timestamp}.json")));
+        }
+        let (chrome_layer, chrome_guard) = builder.build();
         guards.push(chrome_guard);
 
         layer = layer.and_then(chrome_layer).boxed();
@@ -77,3 +91,79 @@ fn build_tonic_exporter(endpoint: &String) -> TonicExporterBuilder {
         .with_endpoint(endpoint)
         .with_timeout(Duration::from_secs(15))
 }
+
+/// Interval between removals of expired local traces
+const TRACE_CLEANUP_INTERVAL: Duration = Duration::from_hours(1);
+
+/// Removes expired local traces periodically, as a long-running instance keeps writing them
+pub async fn run_trace_cleanup(dir: PathBuf, retention: Duration) {
+    loop {
+        let task_dir = dir.clone();
+        if let Err(err) =
+            tokio::task::spawn_blocking(move || remove_old_traces(&task_dir, retention)).await
+        {
+            warn!("Failed to remove expired traces: {:?}", err);
+        }
+        tokio::time::sleep(TRACE_CLEANUP_INTERVAL).await;
+    }
+}
+
+/// Deletes `trace-*.json` files in the directory that were last modified before the retention period
+#[tracing::instrument(level = "info")]
+fn remove_old_traces(dir: &Path, retention: Duration) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read trace directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with("trace-") || !name.ends_with(".json") {
+            continue;
+        }
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > retention);
+        if expired {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => info!("Removed expired trace {}", entry.path().display()),
+                Err(e) => warn!("Failed to remove trace {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn removes_only_expired_traces() {
+        let dir = std::env::temp_dir().join(format!("traces-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let old = SystemTime::now() - Duration::from_hours(3 * 24);
+        for (name, modified) in [
+            ("trace-1.json", old),
+            ("trace-2.json", SystemTime::now()),
+            ("other.json", old),
+        ] {
+            File::create(dir.join(name))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+
+        remove_old_traces(&dir, Duration::from_hours(24));
+        assert!(!dir.join("trace-1.json").exists());
+        assert!(dir.join("trace-2.json").exists());
+        assert!(dir.join("other.json").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap_serde_derive::ClapSerde;
+use rustls::crypto::aws_lc_rs::default_provider;
+use rustls::version::{TLS12, TLS13};
+use rustls::{ServerConfig, SupportedProtocolVersion};
+
+use crate::config::TlsConfig;
+
+/// Builds the rustls configuration from the certificate, key, protocol and cipher settings
+#[tracing::instrument(level = "info", skip(tls))]
+pub fn server_config(tls: &<TlsConfig as ClapSerde>::Opt) -> Result<ServerConfig> {
+    let (Some(certificate), Some(private_key)) = (&tls.certificate, &tls.private_key) else {
+        bail!("Both certificate and private key must be provided to enable TLS support.");
+    };
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(certificate).with_context(|| format!("Failed to open {certificate}"))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Failed to read certificate")?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(private_key).with_context(|| format!("Failed to open {private_key}"))?,
+    ))
+    .context("Failed to read private key")?
+    .ok_or_else(|| anyhow!("No private key found in {private_key}"))?;
+
+    let versions: &[&'static SupportedProtocolVersion] =
+        match tls.min_version.as_deref().unwrap_or("1.2") {
+            "1.2" => &[&TLS13, &TLS12],
+            "1.3" => &[&TLS13],
+            version => bail!("Unsupported minimum TLS version {version}, expected 1.2 or 1.3"),
+        };
+
+    let mut provider = default_provider();
+    if let Some(names) = tls.cipher_suites.as_ref().filter(|names| !names.is_empty()) {
+        // Preference follows the order of the configured names
+        provider.cipher_suites = names
+            .iter()
+            .map(|name| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| anyhow!("Unsupported cipher suite {name}"))
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .context("Cipher suites do not support the TLS versions")?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .context("Invalid certificate or private key")?;
+    config.alpn_protocols = tls
+        .alpn_protocols
+        .as_ref()
+        .filter(|protocols| !protocols.is_empty())
+        .map_or_else(
+            || vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            |protocols| protocols.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        );
+    Ok(config)
+}
+
+/// Reads the end of the validity period of the leaf certificate in the pem file
+#[tracing::instrument(level = "trace")]
+pub fn certificate_expiry(certificate: &str) -> Result<DateTime<Utc>> {
+    let leaf = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(certificate).with_context(|| format!("Failed to open {certificate}"))?,
+    ))
+    .next()
+    .ok_or_else(|| anyhow!("No certificate found in {certificate}"))?
+    .context("Failed to read certificate")?;
+    not_after(&leaf).ok_or_else(|| anyhow!("Failed to parse validity of {certificate}"))
+}
+
+/// Walks the DER of the certificate to the `notAfter` field of its validity
+fn not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, certificate, _) = der_element(der)?;
+    let (_, tbs_certificate, _) = der_element(certificate)?;
+    let (tag, _, rest) = der_element(tbs_certificate)?;
+    // The version is optional and tagged explicitly
+    let mut fields = if tag == 0xa0 { rest } else { tbs_certificate };
+    // Skip the serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        fields = der_element(fields)?.2;
+    }
+    let (_, validity, _) = der_element(fields)?;
+    let (_, _, rest) = der_element(validity)?;
+    let (tag, time, _) = der_element(rest)?;
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(std::str::from_utf8(time).ok()?, format).ok()?;
+    Some(time.and_utc())
+}
+
+/// Splits the DER element at the start of the input into its tag, contents and the following input
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        let length = bytes
+            .iter()
+            .fold(0, |length, byte| (length << 8) | usize::from(*byte));
+        (length, rest)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(length);
+    Some((tag, contents, rest))
+}
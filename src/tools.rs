@@ -0,0 +1,415 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use axum::async_trait;
+use chrono::{Duration as ChronoDuration, Utc};
+use rhai::packages::{ArithmeticPackage, Package};
+use rhai::{Dynamic, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::config::ToolDefinition;
+use crate::inference::task::raw::TokenCounts;
+use crate::inference::task::structured::StructuredOutput;
+use crate::truncation::truncate_graphemes;
+
+/// Tool results are cut off after this many graphemes before they are passed to the model
+const MAX_RESULT_LENGTH: usize = 4000;
+
+/// A function a model may call while answering an agent request
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Tells the model what the tool does and which arguments it expects
+    fn description(&self) -> &str;
+
+    async fn call(&self, arguments: Value) -> Result<Value>;
+}
+
+static TOOLS: OnceLock<Vec<Box<dyn Tool>>> = OnceLock::new();
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(4);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_tools(definitions: &[ToolDefinition], max_depth: usize) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let tools = definitions
+        .iter()
+        .map(|definition| -> Box<dyn Tool> {
+            match definition {
+                ToolDefinition::Http {
+                    name,
+                    description,
+                    url,
+                } => Box::new(HttpTool {
+                    name: name.clone(),
+                    description: description.clone(),
+                    url: url.clone(),
+                    client: client.clone(),
+                }),
+                ToolDefinition::Calculator => Box::new(Calculator::new()),
+                ToolDefinition::Datetime => Box::new(Datetime),
+            }
+        })
+        .collect();
+    if TOOLS.set(tools).is_err() {
+        warn!("Tools are already configured");
+    }
+    MAX_DEPTH.store(max_depth, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn max_depth() -> usize {
+    MAX_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Returns the configured tools, limited to the requested names if any are given
+#[tracing::instrument(level = "trace")]
+pub fn allowed_tools(requested: &[String]) -> Result<Vec<&'static dyn Tool>> {
+    let tools = TOOLS.get().map_or(&[][..], Vec::as_slice);
+    if requested.is_empty() {
+        return Ok(tools.iter().map(AsRef::as_ref).collect());
+    }
+    requested
+        .iter()
+        .map(|name| {
+            tools
+                .iter()
+                .find(|tool| tool.name() == name)
+                .map(AsRef::as_ref)
+                .ok_or_else(|| anyhow!("Tool {name} is not configured"))
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AgentRequest {
+    pub model: String,
+    pub input: String,
+    /// Maximum number of tokens of every generation
+    pub max_length: usize,
+    /// Names of the tools the model may use, all configured tools if empty
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// Set for clients whose output is watermarked
+    #[serde(skip)]
+    pub watermark: bool,
+    /// Strings the model must not generate for the client
+    #[serde(skip)]
+    pub banned_words: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AgentResponse {
+    pub output: String,
+    /// Sum of the inference time of all generations
+    pub inference_time: f64,
+    /// The tool calls in the order the model made them
+    pub steps: Vec<ToolStep>,
+    #[serde(skip)]
+    pub tokens: TokenCounts,
+    /// The model that handled the request if it was routed away from the requested one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ToolStep {
+    pub tool: String,
+    pub arguments: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ToolCall {
+    tool: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct FinalAnswer {
+    answer: String,
+}
+
+/// Returns the shape the replies of the model are constrained to, either a call of one of the
+/// tools or the final answer
+#[tracing::instrument(level = "trace", skip(tools))]
+pub fn agent_format(tools: &[&dyn Tool]) -> StructuredOutput {
+    let mut replies = vec![json!({
+        "type": "object",
+        "properties": {"answer": {"type": "string"}},
+        "required": ["answer"],
+    })];
+    if !tools.is_empty() {
+        let names: Vec<&str> = tools.iter().map(|tool| tool.name()).collect();
+        replies.insert(
+            0,
+            json!({
+                "type": "object",
+                "properties": {"tool": {"enum": names}, "arguments": {"type": "object"}},
+                "required": ["tool", "arguments"],
+            }),
+        );
+    }
+    StructuredOutput::JsonSchema {
+        schema: json!({ "anyOf": replies }),
+    }
+}
+
+/// Parses the output as tool call if all of it is a JSON object naming one of the tools
+#[tracing::instrument(level = "trace", skip(tools))]
+pub fn parse_tool_call(output: &str, tools: &[&dyn Tool]) -> Option<(String, Value)> {
+    let call: ToolCall = serde_json::from_str(output.trim()).ok()?;
+    tools
+        .iter()
+        .any(|tool| tool.name() == call.tool)
+        .then_some((call.tool, call.arguments))
+}
+
+/// Returns the final answer of the reply, or all of it if the generation was cut off before the
+/// answer was complete
+#[tracing::instrument(level = "trace")]
+pub fn final_answer(output: String) -> String {
+    serde_json::from_str::<FinalAnswer>(output.trim()).map_or(output, |reply| reply.answer)
+}
+
+/// Calls the tool and records the outcome, failures are passed back to the model instead of
+/// ending the request
+#[tracing::instrument(level = "trace", skip(tools))]
+pub async fn run_tool(tools: &[&dyn Tool], name: String, arguments: Value) -> ToolStep {
+    let Some(tool) = tools.iter().find(|tool| tool.name() == name) else {
+        return ToolStep {
+            tool: name,
+            arguments,
+            result: None,
+            error: Some("Unknown tool".into()),
+        };
+    };
+    match tool.call(arguments.clone()).await {
+        Ok(result) => ToolStep {
+            tool: name,
+            arguments,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => ToolStep {
+            tool: name,
+            arguments,
+            result: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Builds the instruction describing the tools, the task and the tool calls made so far
+#[tracing::instrument(level = "trace", skip(tools, steps))]
+pub fn agent_prompt(input: &str, tools: &[&dyn Tool], steps: &[ToolStep]) -> String {
+    let descriptions = tools
+        .iter()
+        .map(|tool| format!("- {}: {}", tool.name(), tool.description()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut prompt = format!(
+        "You can use the following tools:\n{descriptions}\n\n\
+        To use a tool, reply with only a JSON object like {{\"tool\": \"name\", \"arguments\": {{}}}}. \
+        Otherwise reply with only a JSON object like {{\"answer\": \"text\"}} holding the final answer.\n\n\
+        Task: {input}"
+    );
+    for step in steps {
+        let outcome = match (&step.result, &step.error) {
+            (Some(result), _) => truncate(&result.to_string()),
+            (None, Some(error)) => format!("error: {error}"),
+            (None, None) => String::new(),
+        };
+        let _ = write!(
+            prompt,
+            "\n\nCalled {} with {}, it returned: {outcome}",
+            step.tool, step.arguments
+        );
+    }
+    prompt
+}
+
+fn truncate(text: &str) -> String {
+    truncate_graphemes(text, MAX_RESULT_LENGTH)
+}
+
+/// Posts the arguments to an allowlisted endpoint
+struct HttpTool {
+    name: String,
+    description: String,
+    url: String,
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl Tool for HttpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    #[tracing::instrument(level = "trace", skip(self), fields(tool = self.name))]
+    async fn call(&self, arguments: Value) -> Result<Value> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&arguments)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Tool responded with status {}", response.status());
+        }
+        let body = response.text().await?;
+        Ok(serde_json::from_str(&body).unwrap_or(Value::String(body)))
+    }
+}
+
+/// Evaluates arithmetic with a bare Rhai engine that only accepts expressions
+struct Calculator {
+    engine: Engine,
+}
+
+impl Calculator {
+    fn new() -> Self {
+        let mut engine = Engine::new_raw();
+        ArithmeticPackage::new().register_into_engine(&mut engine);
+        engine.set_max_operations(10_000);
+        engine.set_max_expr_depths(32, 0);
+        Self { engine }
+    }
+}
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression with + - * / % and parentheses, arguments: {\"expression\": string}"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn call(&self, arguments: Value) -> Result<Value> {
+        let Some(expression) = arguments.get("expression").and_then(Value::as_str) else {
+            bail!("Missing expression argument");
+        };
+        let result: Dynamic = self
+            .engine
+            .eval_expression(expression)
+            .map_err(|e| anyhow!("Invalid expression: {e}"))?;
+        if let Some(value) = result.clone().try_cast::<i64>() {
+            return Ok(Value::from(value));
+        }
+        if let Some(value) = result.try_cast::<f64>() {
+            return Ok(Value::from(value));
+        }
+        bail!("Expression does not evaluate to a number")
+    }
+}
+
+/// Tells the model the current date and time, optionally shifted by a UTC offset
+struct Datetime;
+
+#[async_trait]
+impl Tool for Datetime {
+    fn name(&self) -> &'static str {
+        "datetime"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current date and time, arguments: {\"utc_offset_hours\": number, optional}"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn call(&self, arguments: Value) -> Result<Value> {
+        let offset_hours = arguments
+            .get("utc_offset_hours")
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+        if !(-14..=14).contains(&offset_hours) {
+            bail!("utc_offset_hours must be between -14 and 14");
+        }
+        let now = Utc::now().naive_utc() + ChronoDuration::hours(offset_hours);
+        Ok(serde_json::json!({
+            "datetime": now.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            "weekday": now.format("%A").to_string(),
+            "utc_offset_hours": offset_hours,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn tools() -> [&'static dyn Tool; 2] {
+        static CALCULATOR: OnceLock<Calculator> = OnceLock::new();
+        [CALCULATOR.get_or_init(Calculator::new), &Datetime]
+    }
+
+    #[test]
+    fn only_whole_outputs_are_tool_calls() {
+        let tools = tools();
+        assert_eq!(
+            parse_tool_call(r#" {"tool": "datetime", "arguments": {}} "#, &tools),
+            Some(("datetime".into(), json!({})))
+        );
+        assert_eq!(
+            parse_tool_call(r#"Use {"tool": "datetime", "arguments": {}}"#, &tools),
+            None
+        );
+        assert_eq!(
+            parse_tool_call(r#"{"tool": "weather", "arguments": {}}"#, &tools),
+            None
+        );
+        assert_eq!(parse_tool_call(r#"{"answer": "{}"}"#, &tools), None);
+    }
+
+    #[test]
+    fn final_answer_falls_back_to_the_output() {
+        assert_eq!(
+            final_answer(r#"{"answer": "{\"tool\": 1}"}"#.into()),
+            r#"{"tool": 1}"#
+        );
+        assert_eq!(
+            final_answer(r#"{"answer": "cut"#.into()),
+            r#"{"answer": "cut"#
+        );
+    }
+
+    #[test]
+    fn replies_are_constrained_to_calls_of_the_tools_or_an_answer() {
+        let state = Arc::new(agent_format(&tools()).grammar().unwrap())
+            .start()
+            .unwrap();
+        let matches = |text: &str| {
+            state
+                .accept_str(text)
+                .is_some_and(|state| state.is_complete())
+        };
+        assert!(matches(
+            r#"{"tool": "calculator", "arguments": {"expression": "1+1"}}"#
+        ));
+        assert!(matches(r#"{"answer": "It is {\"tool\": \"datetime\"}"}"#));
+        assert!(!matches(r#"{"tool": "weather", "arguments": {}}"#));
+        assert!(!matches("The answer"));
+    }
+}
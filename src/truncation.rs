@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Characters ending a complete sentence
+pub const SENTENCE_TERMINATORS: [char; 8] = ['.', '!', '?', '…', '。', '！', '？', '"'];
+
+/// The boundary output that was cut off at the maximum length is trimmed back to
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncateAt {
+    /// Only removes a trailing partial character or grapheme
+    #[default]
+    Grapheme,
+    /// Removes the trailing word as it may be partial
+    Word,
+    /// Removes a trailing incomplete sentence
+    Sentence,
+}
+
+/// How generations that reach the maximum length are cut
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Truncation {
+    pub truncate_at: TruncateAt,
+    /// Appended to output that was cut off, e.g. `…`
+    pub truncation_marker: Option<String>,
+}
+
+impl Truncation {
+    /// Trims the output if the generation was cut off, leaving complete outputs alone
+    #[tracing::instrument(level = "trace", skip(output))]
+    pub fn apply(&self, output: String, cut_off: bool) -> String {
+        if !cut_off {
+            return output;
+        }
+        let mut trimmed = trim_to_boundary(&output, self.truncate_at).to_string();
+        if let Some(marker) = &self.truncation_marker {
+            trimmed.push_str(marker);
+        }
+        trimmed
+    }
+}
+
+/// Keeps at most this many graphemes of the text
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn truncate_graphemes(text: &str, max: usize) -> String {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Removes the trailing incomplete part of text that ends in the middle of a unit, keeping the
+/// text if no complete unit is left
+#[tracing::instrument(level = "trace", skip(text))]
+pub fn trim_to_boundary(text: &str, at: TruncateAt) -> &str {
+    let text = trim_partial_grapheme(text);
+    let end = match at {
+        TruncateAt::Grapheme => return text,
+        TruncateAt::Word => text
+            .split_word_bound_indices()
+            .next_back()
+            .filter(|(_, word)| word.chars().any(char::is_alphanumeric))
+            .map_or(text.len(), |(start, _)| start),
+        TruncateAt::Sentence => text
+            .split_sentence_bound_indices()
+            .last()
+            .filter(|(_, sentence)| !sentence.trim_end().ends_with(SENTENCE_TERMINATORS))
+            .map_or(text.len(), |(start, _)| start),
+    };
+    match text[..end].trim_end() {
+        "" => text,
+        trimmed => trimmed,
+    }
+}
+
+/// Drops replacement characters left by a token that ended within a character and joiners
+/// waiting for the rest of their grapheme
+fn trim_partial_grapheme(text: &str) -> &str {
+    text.trim_end_matches(['\u{FFFD}', '\u{200D}'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grapheme_trims_partial_characters() {
+        assert_eq!(trim_to_boundary("abc\u{FFFD}", TruncateAt::Grapheme), "abc");
+        assert_eq!(trim_to_boundary("👨\u{200D}", TruncateAt::Grapheme), "👨");
+        assert_eq!(trim_to_boundary("abc ", TruncateAt::Grapheme), "abc ");
+    }
+
+    #[test]
+    fn word_trims_the_trailing_word() {
+        assert_eq!(
+            trim_to_boundary("The quick brown fo", TruncateAt::Word),
+            "The quick brown"
+        );
+        assert_eq!(
+            trim_to_boundary("Hello world.", TruncateAt::Word),
+            "Hello world."
+        );
+        assert_eq!(trim_to_boundary("Hello", TruncateAt::Word), "Hello");
+    }
+
+    #[test]
+    fn sentence_trims_the_incomplete_sentence() {
+        assert_eq!(
+            trim_to_boundary("First one. Second is cut", TruncateAt::Sentence),
+            "First one."
+        );
+        assert_eq!(
+            trim_to_boundary("Done. All done!", TruncateAt::Sentence),
+            "Done. All done!"
+        );
+        assert_eq!(
+            trim_to_boundary("Only partial", TruncateAt::Sentence),
+            "Only partial"
+        );
+    }
+
+    #[test]
+    fn apply_only_trims_cut_off_output() {
+        let truncation = Truncation {
+            truncate_at: TruncateAt::Word,
+            truncation_marker: Some("…".to_string()),
+        };
+        assert_eq!(
+            truncation.apply("Complete outp".to_string(), false),
+            "Complete outp"
+        );
+        assert_eq!(
+            truncation.apply("Cut off outp".to_string(), true),
+            "Cut off…"
+        );
+    }
+
+    #[test]
+    fn truncate_graphemes_keeps_whole_graphemes() {
+        assert_eq!(truncate_graphemes("e\u{301}abc", 2), "e\u{301}a");
+        assert_eq!(truncate_graphemes("abc", 5), "abc");
+        assert_eq!(truncate_graphemes("abc", 0), "");
+    }
+}
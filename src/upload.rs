@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use axum::extract::multipart::Field;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+use crate::inference::audio_input::{AudioInput, SpooledFile};
+use crate::workdir::temp_dir;
+
+/// Uploads larger than this many bytes are spooled to a temporary file, zero disables spooling
+static SPOOL_THRESHOLD: AtomicUsize = AtomicUsize::new(1_000_000);
+
+#[tracing::instrument(level = "info")]
+pub fn configure_spooling(threshold: usize) {
+    SPOOL_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Reads the audio field chunk by chunk, moving it to a temporary file once it grows past the
+/// spool threshold. Chunks are only pulled from the connection after the previous write finished.
+#[tracing::instrument(level = "trace", skip(field))]
+pub async fn read_audio_field(mut field: Field<'_>) -> Result<AudioInput> {
+    let threshold = SPOOL_THRESHOLD.load(Ordering::Relaxed);
+    let mut buffer = Vec::new();
+    let mut spooled = None;
+
+    while let Some(chunk) = field.chunk().await? {
+        if spooled.is_none() && threshold > 0 && buffer.len() + chunk.len() > threshold {
+            spooled = Some(spool_file().await?);
+        }
+        match &mut spooled {
+            Some((file, spooled_file)) => {
+                if !buffer.is_empty() {
+                    file.write_all(&buffer).await?;
+                    spooled_file.len += buffer.len() as u64;
+                    buffer = Vec::new();
+                }
+                file.write_all(&chunk).await?;
+                spooled_file.len += chunk.len() as u64;
+            }
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    Ok(match spooled {
+        Some((mut file, spooled_file)) => {
+            // Data is read back right away, so it is flushed but never synced to disk
+            file.flush().await?;
+            debug!(
+                "Spooled {} bytes of audio to {}",
+                spooled_file.len,
+                spooled_file.path.display()
+            );
+            AudioInput::Spooled(spooled_file)
+        }
+        None => AudioInput::Memory(buffer.into_boxed_slice()),
+    })
+}
+
+/// Creates a new temporary file, which is removed again if the upload is aborted
+#[tracing::instrument(level = "trace")]
+async fn spool_file() -> Result<(tokio::fs::File, SpooledFile)> {
+    let path = temp_dir().join(format!("upload-{:016x}.part", rand::random::<u64>()));
+    let file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await?;
+    Ok((file, SpooledFile { path, len: 0 }))
+}
@@ -0,0 +1,42 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+
+static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Creates the working directory if needed and verifies that files can be created in it
+#[tracing::instrument(level = "info")]
+pub fn prepare_work_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)
+        .with_context(|| format!("Failed to create working directory {}", path.display()))?;
+    if !path.is_dir() {
+        bail!("Working directory {} is not a directory", path.display());
+    }
+    if std::fs::metadata(path)?.permissions().readonly() {
+        bail!("Working directory {} is read-only", path.display());
+    }
+
+    let probe = path.join(format!(".probe-{}", std::process::id()));
+    std::fs::write(&probe, [])
+        .with_context(|| format!("Working directory {} is not writable", path.display()))?;
+    std::fs::remove_file(&probe).with_context(|| {
+        format!(
+            "Failed to remove files in working directory {}",
+            path.display()
+        )
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(level = "info")]
+pub fn configure_work_dir(path: PathBuf) {
+    if WORK_DIR.set(path).is_err() {
+        tracing::warn!("Working directory is already configured");
+    }
+}
+
+/// Returns the directory temporary files like spooled uploads are written to
+pub fn temp_dir() -> PathBuf {
+    WORK_DIR.get().cloned().unwrap_or_else(std::env::temp_dir)
+}